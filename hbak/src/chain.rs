@@ -0,0 +1,115 @@
+// hbak is a tool for distributed incremental btrfs snapshotting.
+// Copyright (C) 2024  Himbeer <himbeerserverde@gmail.com>
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use crate::error::*;
+
+use hbak_common::proto::Snapshot;
+
+use std::fs;
+use std::io;
+use std::path::PathBuf;
+
+use chrono::NaiveDateTime;
+
+/// A `BackupFile` is a backup exported by `hbak export-backup`,
+/// identified by its file name.
+#[derive(Clone, Debug)]
+pub struct BackupFile {
+    /// The backup stored in the file.
+    pub snapshot: Snapshot,
+    /// The location of the file.
+    pub path: PathBuf,
+}
+
+/// Collects the backup files at the specified paths, including the files
+/// directly inside of directories. Directory entries that aren't named
+/// after a snapshot identifier are ignored, e.g. incomplete `.part` files.
+/// If multiple files have the same name, the first one is used.
+pub fn collect(paths: &[PathBuf]) -> Result<Vec<BackupFile>> {
+    let mut files: Vec<BackupFile> = Vec::new();
+    let mut insert = |snapshot: Snapshot, path: PathBuf| {
+        if !files.iter().any(|file| file.snapshot == snapshot) {
+            files.push(BackupFile { snapshot, path });
+        }
+    };
+
+    for path in paths {
+        if path.is_dir() {
+            let mut entries = fs::read_dir(path)?
+                .map(|entry| entry.map(|entry| entry.path()))
+                .collect::<io::Result<Vec<_>>>()?;
+            entries.sort_unstable();
+
+            for entry in entries.into_iter().filter(|entry| entry.is_file()) {
+                if let Ok(snapshot) = Snapshot::try_from(&*entry) {
+                    insert(snapshot, entry);
+                }
+            }
+        } else {
+            insert(Snapshot::try_from(path.as_path())?, path.clone());
+        }
+    }
+
+    Ok(files)
+}
+
+/// Selects the files to restore the specified subvolume of a node from:
+/// A full backup followed by its incremental backups in ascending order.
+///
+/// If `at` is set, only backups taken at or before that time are considered
+/// and the latest full backup among them is used. Otherwise the files
+/// must contain exactly one full backup so that chains aren't mixed up.
+pub fn select<'a>(
+    files: &'a [BackupFile],
+    node_name: &str,
+    subvol: &str,
+    at: Option<NaiveDateTime>,
+) -> Result<Vec<&'a BackupFile>> {
+    let candidates: Vec<_> = files
+        .iter()
+        .filter(|file| {
+            file.snapshot.node_name() == node_name
+                && file.snapshot.subvol() == subvol
+                && at.is_none_or(|at| file.snapshot.taken() <= at)
+        })
+        .collect();
+
+    let mut fulls = candidates
+        .iter()
+        .filter(|file| !file.snapshot.is_incremental());
+    let full = match at {
+        Some(_) => fulls.max_by_key(|file| file.snapshot.taken()),
+        None => match (fulls.next(), fulls.count()) {
+            (full, 0) => full,
+            (_, n) => return Err(Error::AmbiguousChain(subvol.to_string(), n + 1)),
+        },
+    }
+    .ok_or(Error::NoFullBackupFile(subvol.to_string()))?;
+
+    let mut incrementals: Vec<_> = candidates
+        .iter()
+        .copied()
+        .filter(|file| {
+            file.snapshot.is_incremental() && file.snapshot.taken() > full.snapshot.taken()
+        })
+        .collect();
+    incrementals.sort_unstable_by_key(|file| file.snapshot.taken());
+
+    let mut chain = vec![*full];
+    chain.extend(incrementals);
+
+    Ok(chain)
+}
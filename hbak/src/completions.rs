@@ -0,0 +1,199 @@
+// hbak is a tool for distributed incremental btrfs snapshotting.
+// Copyright (C) 2024  Himbeer <himbeerserverde@gmail.com>
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use crate::Cli;
+
+use hbak_common::config::NodeConfig;
+use hbak_common::output;
+
+use std::io::{self, Write};
+
+use clap::CommandFactory;
+use clap_complete::Shell;
+
+/// The subcommands whose arguments are tracked subvolumes.
+const SUBVOL_COMMANDS: &[&str] = &["snapshot", "untrack"];
+/// The subcommands whose arguments are configured remote node addresses.
+const REMOTE_COMMANDS: &[&str] = &[
+    "synchronize",
+    "diff",
+    "rm-remote",
+    "remote-verify",
+    "rotate-auth",
+];
+/// The subcommands whose arguments are the node names of granted nodes.
+const NODE_COMMANDS: &[&str] = &["grant", "set-perms", "revoke"];
+
+/// The kinds of values the dynamic completion helpers print.
+#[derive(Clone, Copy, Debug)]
+pub enum Candidates {
+    Subvols,
+    Remotes,
+    Nodes,
+}
+
+impl Candidates {
+    const ALL: [Self; 3] = [Self::Subvols, Self::Remotes, Self::Nodes];
+
+    /// Returns the name of the hidden subcommand printing the candidates.
+    fn helper(&self) -> &'static str {
+        match self {
+            Self::Subvols => "__complete-subvols",
+            Self::Remotes => "__complete-remotes",
+            Self::Nodes => "__complete-nodes",
+        }
+    }
+
+    /// Returns the subcommands whose arguments are completed with the candidates.
+    fn commands(&self) -> &'static [&'static str] {
+        match self {
+            Self::Subvols => SUBVOL_COMMANDS,
+            Self::Remotes => REMOTE_COMMANDS,
+            Self::Nodes => NODE_COMMANDS,
+        }
+    }
+}
+
+/// Writes the completion script for the specified shell to stdout.
+/// The scripts of bash, zsh and fish additionally complete the arguments
+/// of some subcommands from the configuration using the hidden helper subcommands.
+///
+/// The script is assembled in memory first because `clap_complete` panics
+/// if it cannot write, e.g. to a closed pipe.
+pub fn generate(shell: Shell) -> io::Result<()> {
+    let mut script = Vec::new();
+
+    clap_complete::generate(shell, &mut Cli::command(), "hbak", &mut script);
+
+    match shell {
+        Shell::Bash => write_bash(&mut script)?,
+        Shell::Zsh => write_zsh(&mut script)?,
+        Shell::Fish => write_fish(&mut script)?,
+        _ => {}
+    }
+
+    output::print(format_args!("{}", String::from_utf8_lossy(&script)));
+    Ok(())
+}
+
+/// Prints the specified completion candidates one per line.
+/// Prints nothing if the configuration is unreadable, e.g. without root privileges.
+pub fn print(candidates: Candidates) {
+    let Ok(node_config) = NodeConfig::load() else {
+        return;
+    };
+
+    let values: Vec<String> = match candidates {
+        Candidates::Subvols => node_config.subvol_names(),
+        Candidates::Remotes => node_config
+            .remotes
+            .iter()
+            .map(|remote_node| remote_node.address.to_string())
+            .collect(),
+        Candidates::Nodes => node_config
+            .auth
+            .iter()
+            .map(|auth| auth.node_name.clone())
+            .collect(),
+    };
+
+    let mut stdout = io::stdout().lock();
+    for value in values {
+        if writeln!(stdout, "{}", value).is_err() {
+            return;
+        }
+    }
+}
+
+fn write_bash<W: Write>(w: &mut W) -> io::Result<()> {
+    writeln!(w)?;
+    writeln!(w, "_hbak_dynamic() {{")?;
+    writeln!(
+        w,
+        "    local cur=\"${{COMP_WORDS[COMP_CWORD]}}\" prev=\"${{COMP_WORDS[COMP_CWORD-1]}}\" helper"
+    )?;
+    writeln!(w, "    case \"${{COMP_WORDS[1]}}\" in")?;
+    for candidates in Candidates::ALL {
+        writeln!(
+            w,
+            "        {}) helper={} ;;",
+            candidates.commands().join("|"),
+            candidates.helper()
+        )?;
+    }
+    writeln!(w, "    esac")?;
+    writeln!(
+        w,
+        "    if [[ -n \"$helper\" && \"$cur\" != -* && \"$prev\" != -* ]]; then"
+    )?;
+    writeln!(
+        w,
+        "        COMPREPLY=($(compgen -W \"$(hbak \"$helper\" 2>/dev/null)\" -- \"$cur\"))"
+    )?;
+    writeln!(w, "        return 0")?;
+    writeln!(w, "    fi")?;
+    writeln!(w, "    _hbak \"$@\"")?;
+    writeln!(w, "}}")?;
+    writeln!(
+        w,
+        "complete -F _hbak_dynamic -o bashdefault -o default hbak"
+    )
+}
+
+fn write_zsh<W: Write>(w: &mut W) -> io::Result<()> {
+    writeln!(w)?;
+    writeln!(w, "_hbak_dynamic() {{")?;
+    writeln!(w, "    local helper")?;
+    writeln!(w, "    case \"${{words[2]}}\" in")?;
+    for candidates in Candidates::ALL {
+        writeln!(
+            w,
+            "        {}) helper={} ;;",
+            candidates.commands().join("|"),
+            candidates.helper()
+        )?;
+    }
+    writeln!(w, "    esac")?;
+    writeln!(
+        w,
+        "    if [[ -n \"$helper\" && \"${{words[CURRENT]}}\" != -* && \"${{words[CURRENT-1]}}\" != -* ]]; then"
+    )?;
+    writeln!(w, "        local -a values")?;
+    writeln!(
+        w,
+        "        values=(${{(f)\"$(hbak \"$helper\" 2>/dev/null)\"}})"
+    )?;
+    writeln!(w, "        compadd -a values")?;
+    writeln!(w, "        return")?;
+    writeln!(w, "    fi")?;
+    writeln!(w, "    _hbak \"$@\"")?;
+    writeln!(w, "}}")?;
+    writeln!(w, "compdef _hbak_dynamic hbak")
+}
+
+fn write_fish<W: Write>(w: &mut W) -> io::Result<()> {
+    writeln!(w)?;
+    for candidates in Candidates::ALL {
+        writeln!(
+            w,
+            "complete -c hbak -n \"__fish_seen_subcommand_from {}\" -f -a \"(hbak {} 2>/dev/null)\"",
+            candidates.commands().join(" "),
+            candidates.helper()
+        )?;
+    }
+
+    Ok(())
+}
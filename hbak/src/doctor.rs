@@ -0,0 +1,355 @@
+// hbak is a tool for distributed incremental btrfs snapshotting.
+// Copyright (C) 2024  Himbeer <himbeerserverde@gmail.com>
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use hbak_common::config::{permission_errors, NodeConfig, RemoteAddress};
+use hbak_common::paths::{self, StorageLayout};
+use hbak_common::proto::{LocalNode, Mode, Snapshot};
+use hbak_common::system;
+
+use std::ffi::OsStr;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::os::unix::fs::{FileTypeExt, PermissionsExt};
+use std::path::{Path, PathBuf};
+
+/// The length of verifiers and keys generated by `hbak export-pass` in bytes.
+const SECRET_LEN: usize = 32;
+
+/// The outcome of a pre-flight check.
+#[derive(Clone, Copy, Debug, Eq, Ord, PartialEq, PartialOrd)]
+pub enum Status {
+    Pass,
+    Warn,
+    Fail,
+}
+
+impl fmt::Display for Status {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::Pass => write!(f, "pass"),
+            Self::Warn => write!(f, "warn"),
+            Self::Fail => write!(f, "fail"),
+        }
+    }
+}
+
+/// A `Finding` is the result of a single pre-flight check.
+#[derive(Clone, Debug)]
+pub struct Finding {
+    /// What was checked.
+    pub check: String,
+    /// Whether the check passed.
+    pub status: Status,
+    /// A description of the result.
+    pub message: String,
+}
+
+impl Finding {
+    fn new<C: Into<String>, M: fmt::Display>(check: C, status: Status, message: M) -> Self {
+        Self {
+            check: check.into(),
+            status,
+            message: message.to_string(),
+        }
+    }
+}
+
+/// Runs all pre-flight checks. Checks that depend on a valid configuration
+/// or a mounted file system are skipped if those are unavailable.
+/// Returns the findings along with the mounted [`LocalNode`], if any.
+pub fn run() -> (Vec<Finding>, Option<LocalNode>) {
+    let mut findings = vec![config_permissions(&NodeConfig::path())];
+
+    let node_config = match NodeConfig::load() {
+        Ok(node_config) => {
+            findings.push(Finding::new("config", Status::Pass, "valid"));
+            node_config
+        }
+        Err(e) => {
+            findings.push(Finding::new("config", Status::Fail, e));
+            return (findings, None);
+        }
+    };
+
+    findings.extend(remote_addresses(&node_config));
+    findings.extend(auth_secrets(&node_config));
+    findings.extend(grant_permissions(&node_config));
+
+    let local_node = match LocalNode::with_config(Mode::Client, node_config) {
+        Ok(local_node) => {
+            findings.push(Finding::new("device", Status::Pass, "mountable"));
+            local_node
+        }
+        Err(e) => {
+            findings.push(Finding::new("device", Status::Fail, e));
+            return (findings, None);
+        }
+    };
+
+    let storage = storage_subvolumes(local_node.layout());
+    let storage_ok = storage.iter().all(|finding| finding.status != Status::Fail);
+    findings.extend(storage);
+
+    if storage_ok {
+        findings.extend(storage_entries(local_node.layout()));
+    }
+
+    findings.extend(tracked_subvolumes(&local_node));
+
+    (findings, Some(local_node))
+}
+
+/// Checks that the configuration file is only accessible by its owner.
+pub fn config_permissions(path: &Path) -> Finding {
+    let check = "config permissions";
+
+    match fs::metadata(path) {
+        Ok(metadata) if metadata.permissions().mode() & 0o7077 > 0 => Finding::new(
+            check,
+            Status::Fail,
+            format!(
+                "{:o}, must not be accessible by group or others",
+                metadata.permissions().mode() & 0o7777
+            ),
+        ),
+        Ok(metadata) => Finding::new(
+            check,
+            Status::Pass,
+            format!("{:o}", metadata.permissions().mode() & 0o7777),
+        ),
+        Err(e) => Finding::new(check, Status::Fail, e),
+    }
+}
+
+/// Checks that the addresses of all remote nodes and upstreams resolve
+/// and that their Unix domain sockets exist.
+pub fn remote_addresses(node_config: &NodeConfig) -> Vec<Finding> {
+    node_config
+        .remotes
+        .iter()
+        .chain(
+            node_config
+                .upstreams
+                .iter()
+                .map(|upstream| &upstream.remote),
+        )
+        .map(|remote_node| {
+            let check = format!("remote {}", remote_node.address);
+
+            if let RemoteAddress::Unix(path) = &remote_node.address {
+                return match fs::metadata(path) {
+                    Ok(metadata) if metadata.file_type().is_socket() => {
+                        Finding::new(check, Status::Pass, "socket exists")
+                    }
+                    Ok(_) => Finding::new(check, Status::Fail, "not a socket"),
+                    Err(e) => Finding::new(check, Status::Fail, e),
+                };
+            }
+
+            match remote_node.address.resolve() {
+                Ok(addrs) if addrs.is_empty() => {
+                    Finding::new(check, Status::Fail, "resolves to no addresses")
+                }
+                Ok(addrs) => Finding::new(
+                    check,
+                    Status::Pass,
+                    format!("resolves to {} address(es)", addrs.len()),
+                ),
+                Err(e) => Finding::new(check, Status::Fail, e),
+            }
+        })
+        .collect()
+}
+
+/// Checks that the verifiers and keys of all granted nodes have the expected length.
+pub fn auth_secrets(node_config: &NodeConfig) -> Vec<Finding> {
+    node_config
+        .auth
+        .iter()
+        .map(|auth| {
+            let check = format!("grant {}", auth.node_name);
+
+            if auth.verifier.len() != SECRET_LEN {
+                Finding::new(
+                    check,
+                    Status::Fail,
+                    format!(
+                        "verifier is {} bytes long, expected {}",
+                        auth.verifier.len(),
+                        SECRET_LEN
+                    ),
+                )
+            } else if auth.key.len() != SECRET_LEN {
+                Finding::new(
+                    check,
+                    Status::Fail,
+                    format!(
+                        "key is {} bytes long, expected {}",
+                        auth.key.len(),
+                        SECRET_LEN
+                    ),
+                )
+            } else {
+                Finding::new(check, Status::Pass, "well-formed secrets")
+            }
+        })
+        .collect()
+}
+
+/// Checks that the push and pull permissions of all granted nodes can take effect.
+pub fn grant_permissions(node_config: &NodeConfig) -> Vec<Finding> {
+    node_config
+        .auth
+        .iter()
+        .map(|auth| {
+            let check = format!("permissions {}", auth.node_name);
+            let errors = permission_errors(node_config, &auth.push, &auth.pull);
+
+            if errors.is_empty() {
+                Finding::new(check, Status::Pass, "effective")
+            } else {
+                Finding::new(
+                    check,
+                    Status::Warn,
+                    errors
+                        .iter()
+                        .map(|e| e.to_string())
+                        .collect::<Vec<_>>()
+                        .join(", "),
+                )
+            }
+        })
+        .collect()
+}
+
+/// Checks that the snapshot and backup directories exist as btrfs subvolumes.
+pub fn storage_subvolumes(layout: &StorageLayout) -> Vec<Finding> {
+    [layout.snapshot_dir(), layout.backup_dir()]
+        .into_iter()
+        .map(|dir| {
+            let check = dir.display().to_string();
+
+            if !dir.exists() {
+                return Finding::new(
+                    check,
+                    Status::Fail,
+                    "missing, was the node initialized with --config-only?",
+                );
+            }
+
+            match system::is_subvolume(dir) {
+                Ok(true) => Finding::new(check, Status::Pass, "btrfs subvolume"),
+                Ok(false) => Finding::new(check, Status::Fail, "not a btrfs subvolume"),
+                Err(e) => Finding::new(check, Status::Warn, e),
+            }
+        })
+        .collect()
+}
+
+/// Checks that all entries of the snapshot, backup and archive directories
+/// are valid snapshot identifiers. Incomplete backups and manifests are ignored.
+/// Other entries are skipped by all operations but are reported here
+/// so that leftovers can be cleaned up.
+pub fn storage_entries(layout: &StorageLayout) -> Vec<Finding> {
+    // Backups of the nested layout are stored two levels deep.
+    let mut dirs = vec![(layout.snapshot_dir(), 0), (layout.backup_dir(), 2)];
+    dirs.extend(
+        layout
+            .archive_dir()
+            .filter(|dir| dir.exists())
+            .map(|dir| (dir, 2)),
+    );
+
+    dirs.into_iter()
+        .map(|(dir, max_depth)| {
+            let check = format!("{} entries", dir.display());
+
+            match unparseable_entries(dir, max_depth) {
+                Ok(unparseable) if unparseable.is_empty() => {
+                    Finding::new(check, Status::Pass, "all identifiers valid")
+                }
+                Ok(unparseable) => Finding::new(
+                    check,
+                    Status::Warn,
+                    format!(
+                        "ignoring invalid identifier(s): {}",
+                        unparseable
+                            .iter()
+                            .map(|path| path.display().to_string())
+                            .collect::<Vec<_>>()
+                            .join(", ")
+                    ),
+                ),
+                Err(e) => Finding::new(check, Status::Fail, e),
+            }
+        })
+        .collect()
+}
+
+/// Checks that the subvolumes owned by the local node exist.
+pub fn tracked_subvolumes(local_node: &LocalNode) -> Vec<Finding> {
+    local_node
+        .config()
+        .subvols
+        .iter()
+        .map(|subvol| {
+            let check = format!("subvolume {}", subvol.name);
+            let path = local_node.subvol_path(&subvol.name);
+
+            if !path.exists() {
+                return Finding::new(check, Status::Fail, "does not exist, typo?");
+            }
+
+            match system::is_subvolume(&path) {
+                Ok(true) => Finding::new(check, Status::Pass, "exists"),
+                Ok(false) => Finding::new(check, Status::Fail, "not a btrfs subvolume"),
+                Err(e) => Finding::new(check, Status::Warn, e),
+            }
+        })
+        .collect()
+}
+
+/// Returns the entries of the specified directory that aren't valid snapshot identifiers,
+/// descending into subdirectories that aren't snapshots up to the specified depth.
+fn unparseable_entries(dir: &Path, max_depth: usize) -> io::Result<Vec<PathBuf>> {
+    let mut unparseable = Vec::new();
+    let mut dirs = vec![(dir.to_path_buf(), 0)];
+
+    while let Some((dir, depth)) = dirs.pop() {
+        for entry in fs::read_dir(&dir)? {
+            let entry = entry?;
+            let path = entry.path();
+
+            // Snapshots are subvolumes, i.e. directories.
+            if entry.file_type()?.is_dir() && Snapshot::try_from(&*path).is_err() {
+                if depth < max_depth {
+                    dirs.push((path, depth + 1));
+                } else {
+                    unparseable.push(path);
+                }
+            } else if path.extension() != Some(OsStr::new("part"))
+                && !paths::is_manifest(&path)
+                && Snapshot::try_from(&*path).is_err()
+            {
+                unparseable.push(path);
+            }
+        }
+    }
+
+    unparseable.sort_unstable();
+    Ok(unparseable)
+}
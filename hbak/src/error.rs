@@ -14,6 +14,10 @@
 // You should have received a copy of the GNU Affero General Public License
 // along with this program.  If not, see <https://www.gnu.org/licenses/>.
 
+use hbak_common::config::HumanDuration;
+use hbak_common::proto::Snapshot;
+
+use std::path::PathBuf;
 use std::{io, net};
 
 use thiserror::Error;
@@ -24,13 +28,73 @@ pub enum Error {
     Mounted(String),
     #[error("No mountpoint in mount entry \"{0}\"")]
     NoMountpoint(String),
+    #[error("Remote \"{0}\" is not configured")]
+    NoSuchRemote(String),
+    #[error("Node \"{0}\" has not been granted access")]
+    NoSuchGrant(String),
+    #[error("{0} permission(s) cannot take effect")]
+    IneffectivePermissions(usize),
+    #[error("{0} backup(s) failed verification")]
+    VerificationFailed(usize),
+    #[error("{0} pre-flight check(s) failed")]
+    ChecksFailed(usize),
+    #[error("{0} remote node(s) haven't been synchronized with successfully within {1}")]
+    StaleRemotes(usize, HumanDuration),
+    #[error(
+        "Synchronization with {0} is degraded, received snapshots don't match the expectations"
+    )]
+    Degraded(String),
+    #[error("Remote {0} is shutting down, partial sync completed")]
+    PartialSync(String),
+    #[error("Remote {0} is shutting down, partial restore completed, the snapshot chain may be incomplete")]
+    PartialRestore(String),
+    #[error("Malformed {0}: {1}")]
+    MalformedSecret(&'static str, hex::FromHexError),
+    #[error("The {0} must be 32 bytes long, got {1}")]
+    SecretLength(&'static str, usize),
+    #[error("No {1} in file \"{}\"", .0.display())]
+    MissingSecret(PathBuf, &'static str),
+    #[error("Passphrase must not be empty")]
+    EmptyPassphrase,
+    #[error("Passphrases don't match")]
+    PassphraseMismatch,
+    #[error("Current passphrase is incorrect")]
+    IncorrectPassphrase,
+    #[error("New passphrase is the same as the current one")]
+    UnchangedPassphrase,
+    #[error("Passphrase is not stored in the configuration, change the passphrase_command or the prompted passphrase instead")]
+    PassphraseNotStored,
+    #[error("{0} remote node(s) still use the old credentials")]
+    RotationsFailed(usize),
+    #[error(
+        "Unable to replace {0} on {1}: {2}, its copy may have been deleted, run again to push it"
+    )]
+    ReplaceFailed(Snapshot, String, String),
+    #[error("Passphrase is weak, estimated entropy is only {0} bits, use a longer one or pass --allow-weak")]
+    WeakPassphrase(u32),
+    #[error("No full backup file of subvolume \"{0}\" found")]
+    NoFullBackupFile(String),
+    #[error("{1} full backup files of subvolume \"{0}\" found, use --at to select one")]
+    AmbiguousChain(String, usize),
+    #[error("No restorable snapshots or backups of node \"{0}\" found")]
+    NothingToRestore(String),
+    #[error("No restorable snapshots or backups of subvolume(s) {0} found")]
+    Unrestorable(String),
+    #[error("Invalid node name \"{0}\", must be printable ASCII without '/', '_' or whitespace")]
+    InvalidNodeName(String),
+    #[error("Subvolume \"{0}\" is renamed more than once or to a name that is already used")]
+    ConflictingRename(String),
 
     #[error("An error occured on the local node: {0}")]
     HbakLocalNode(#[from] hbak_common::LocalNodeError),
     #[error("A network error occured: {0}")]
     HbakNetwork(#[from] hbak_common::NetworkError),
+    #[error("Agent error: {0}")]
+    HbakAgent(#[from] hbak_common::AgentError),
     #[error("Unable to parse volume identifier: {0}")]
     HbakVolumeParse(#[from] hbak_common::VolumeParseError),
+    #[error("Unable to parse snapshot identifier: {0}")]
+    HbakSnapshotParse(#[from] hbak_common::SnapshotParseError),
 
     #[error("Unable to parse network address: {0}")]
     AddrParse(#[from] net::AddrParseError),
@@ -39,6 +103,20 @@ pub enum Error {
 
     #[error("Hexadecimal decoding error: {0}")]
     HexDecode(#[from] hex::FromHexError),
+    #[error("JSON serialization error: {0}")]
+    Json(#[from] serde_json::Error),
+}
+
+impl Error {
+    /// Returns the exit status of the process. Sessions interrupted
+    /// by a shutting down remote node exit with `EX_TEMPFAIL` (75)
+    /// so that scripts can retry them later, all other errors with 1.
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            Self::PartialSync(_) | Self::PartialRestore(_) => 75,
+            _ => 1,
+        }
+    }
 }
 
 pub type Result<T> = std::result::Result<T, Error>;
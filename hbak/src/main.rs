@@ -14,32 +14,121 @@
 // You should have received a copy of the GNU Affero General Public License
 // along with this program.  If not, see <https://www.gnu.org/licenses/>.
 
+mod chain;
+use chain::BackupFile;
+
+mod completions;
+use completions::Candidates;
+
+mod doctor;
+use doctor::Status;
+
 mod error;
 use error::*;
 
-use hbak_common::config::{NodeConfig, RemoteNode, RemoteNodeAuth};
-use hbak_common::conn::{AuthConn, DEFAULT_PORT};
-use hbak_common::message::SyncInfo;
-use hbak_common::proto::{LocalNode, Mode, Node, Snapshot, Volume};
-use hbak_common::stream::CHUNKSIZE;
-use hbak_common::system;
-use hbak_common::{LocalNodeError, RemoteError};
-
-use std::collections::HashMap;
-use std::fs::{self, File};
-use std::io::{BufRead, BufReader, BufWriter, Empty};
-use std::net::{SocketAddr, ToSocketAddrs};
+mod relabel;
+use relabel::Relabel;
+
+mod verbosity;
+use verbosity::Verbosity;
+
+use hbak_common::agent::{self, Agent, AgentClient};
+use hbak_common::config::{
+    parse_bind_addr, permission_errors, ByteSize, HumanDuration, NodeConfig, RemoteAddress,
+    RemoteNode, RemoteNodeAuth, SubvolConfig,
+};
+use hbak_common::conn::{
+    AuthConn, Idle, StreamConn, SyncStats, TransferOutcome, Window, MAX_CHALLENGES, VERIFY_RATE,
+};
+use hbak_common::json::SnapshotJson;
+use hbak_common::message::{Challenge, Credentials, Integrity, SyncInfo, Target};
+use hbak_common::output;
+use hbak_common::paths::StorageLayout;
+use hbak_common::proto::{
+    self, LocalNode, Mode, Node, Promotion, Snapshot, Tier, Volume, VolumeSpec, MAX_SEND_PROTOCOL,
+};
+use hbak_common::report::{self, FailureReport};
+use hbak_common::state::{RemoteSyncState, RevokeImpact, ServerState, SyncState};
+use hbak_common::sync::{self, RestoreChains, Role as SyncRole, SyncEvent, SyncSession};
+use hbak_common::system::{self, Adopted};
+use hbak_common::{LocalNodeError, NetworkError, RemoteError};
+
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::fs::{self, File, OpenOptions, Permissions};
+use std::io::{self, BufRead, BufReader, BufWriter, Empty, Write};
+use std::net::SocketAddr;
+use std::os::unix::fs::{OpenOptionsExt, PermissionsExt};
+use std::path::{Path, PathBuf};
 use std::sync::Mutex;
+use std::time::Duration;
+use std::{cmp, iter, process, thread};
+
+use chrono::prelude::*;
+use clap::{Parser, Subcommand, ValueEnum};
+use clap_complete::Shell;
+use ipnet::IpNet;
+use rand::seq::SliceRandom;
+
+/// The estimated entropy in bits below which new passphrases are considered weak.
+const MIN_PASSPHRASE_ENTROPY: u32 = 60;
+
+/// Prints informational output to stderr unless `--quiet` is set.
+macro_rules! info {
+    ($($arg:tt)*) => {
+        verbosity::emit(output::Level::Info, format_args!($($arg)*))
+    };
+}
+
+/// Prints a result to stdout. Failing to do so never aborts an operation.
+macro_rules! out {
+    ($($arg:tt)*) => {
+        output::println(format_args!($($arg)*))
+    };
+}
 
-use clap::{Parser, Subcommand};
+/// Prints a warning or error to stderr. Failing to do so never aborts an operation.
+macro_rules! warn {
+    ($($arg:tt)*) => {
+        verbosity::emit(output::Level::Warn, format_args!($($arg)*))
+    };
+}
+
+/// Reports whether informational output is suppressed.
+fn quiet() -> bool {
+    verbosity::get() == Verbosity::Quiet
+}
 
 #[derive(Parser)]
 #[command(author, version, about, long_about = None)]
 struct Cli {
+    /// Suppress informational output, only print warnings, errors and results.
+    #[arg(short, long, global = true)]
+    quiet: bool,
+    /// Prefix informational output, warnings and errors with a timestamp and their level.
+    #[arg(short, long, global = true, conflicts_with = "quiet")]
+    verbose: bool,
     #[command(subcommand)]
     command: Commands,
 }
 
+/// The role a node acts in.
+#[derive(Clone, Copy, Debug, ValueEnum)]
+enum Role {
+    /// Run `hbak` to take snapshots and synchronize with servers.
+    Client,
+    /// Run `hbakd` to serve clients.
+    Server,
+}
+
+impl From<Role> for Mode {
+    fn from(role: Role) -> Self {
+        match role {
+            Role::Client => Self::Client,
+            Role::Server => Self::Server,
+        }
+    }
+}
+
 #[derive(Subcommand)]
 enum Commands {
     /// Perform basic initialization of the local node.
@@ -47,82 +136,218 @@ enum Commands {
         /// Initialize the configuration file but not the btrfs subvolumes.
         #[arg(short, long)]
         config_only: bool,
+        /// Delete and recreate existing snapshot and backup subvolumes instead of adopting them.
+        #[arg(short, long, conflicts_with = "config_only")]
+        wipe: bool,
+        /// Accept a passphrase that is estimated to be weak.
+        #[arg(long)]
+        allow_weak: bool,
+        /// The role the node primarily acts in. Only its mountpoint is created.
+        /// The mountpoint of the other role is created on demand.
+        #[arg(short, long, value_enum, default_value_t = Role::Client)]
+        role: Role,
         /// The device file the local btrfs file system is located at.
         device: String,
         /// The name to use for this node.
         node_name: String,
-        /// The network address `hbakd` binds to. The default is `[::]:20406` (dual stack).
-        bind_addr: Option<SocketAddr>,
+        /// The network addresses `hbakd` binds to. The default is `[::]:20406` (dual stack).
+        #[arg(value_parser = parse_bind_addr)]
+        bind_addr: Vec<SocketAddr>,
     },
     /// Fully clean the local node of non-binary files with optional backup removal.
     Clean {
         /// Remove the btrfs subvolumes that contain the snapshots and backups.
         #[arg(short, long)]
         backups: bool,
+        /// Don't ask for confirmation.
+        #[arg(short, long)]
+        yes: bool,
     },
     /// Mark a subvolume as owned by the local node.
     Track {
+        /// Mark the subvolume as owned even if it doesn't exist (yet).
+        #[arg(short, long)]
+        force: bool,
         /// The name of the subvolume to mark as owned.
         subvol: String,
     },
     /// Remove the local node ownership mark from a subvolume.
     Untrack {
+        /// Also delete the local snapshots of the subvolume.
+        #[arg(long)]
+        delete_snapshots: bool,
+        /// Also delete the local backups of the subvolume.
+        #[arg(long)]
+        delete_backups: bool,
+        /// Don't ask for confirmation before deleting.
+        #[arg(short, long)]
+        yes: bool,
         /// The name of the subvolume to unmark as owned.
         subvol: String,
     },
     /// Add or modify a remote to push to or pull from.
     AddRemote {
         /// The network address and optional port of the remote node.
-        address: String,
+        address: RemoteAddress,
         /// The volumes to push to the remote node.
+        /// `<node>/*` selects all volumes of a node, `*` all volumes.
         #[arg(long)]
         push: Vec<String>,
         /// The volumes to pull from the remote node.
+        /// `<node>/*` selects all volumes of a node, `*` all volumes.
         /// Subvolumes owned by the local node are silently ignored.
         #[arg(long)]
         pull: Vec<String>,
+        /// The maximum rate at which backups are pushed to the remote node
+        /// in bytes per second, e.g. `1MiB`.
+        #[arg(long)]
+        limit_rate: Option<ByteSize>,
     },
     /// Remove a remote without deleting anything.
     RmRemote {
         /// The network address and optional port of the node to forget.
-        address: String,
+        address: RemoteAddress,
     },
     /// Add or modify authentication and authorization information for a remote client.
     Grant {
         /// The name of the remote node to apply the information to.
         node_name: String,
+        /// Manage a separate grant for the node name, e.g. `breakglass`.
+        /// The remote node authenticates with any of its grants
+        /// and receives the permissions of the one matching its passphrase.
+        #[arg(long)]
+        label: Option<String>,
         /// The volumes the remote node is allowed to push.
+        /// `<node>/*` selects all volumes of a node, `*` all volumes.
         /// Subvolumes owned by the local node are silently ignored.
         #[arg(long)]
         push: Vec<String>,
         /// The volumes the remote node is allowed to pull.
+        /// `<node>/*` selects all volumes of a node, `*` all volumes.
         #[arg(long)]
         pull: Vec<String>,
+        /// The interval within which the remote node is expected to push, e.g. `1d`.
+        #[arg(long)]
+        push_interval: Option<HumanDuration>,
+        /// The maximum rate at which `hbakd` transmits backups to the remote node
+        /// in bytes per second, e.g. `1MiB`.
+        #[arg(long)]
+        limit_rate: Option<ByteSize>,
+        /// Only accept the remote node from the specified address range, e.g. `10.8.0.0/24`.
+        /// Can be passed multiple times. Any source is accepted if omitted.
+        #[arg(long)]
+        allow_from: Vec<IpNet>,
+        /// The hexadecimal verifier exported by the remote node.
+        /// Prompted for if neither it nor `--from-file` is specified.
+        #[arg(long, requires = "key", conflicts_with = "from_file")]
+        verifier: Option<String>,
+        /// The hexadecimal key exported by the remote node.
+        #[arg(long, requires = "verifier", conflicts_with = "from_file")]
+        key: Option<String>,
+        /// Read the verifier and key from a file written by `export-pass --output`.
+        /// Binds the node name to the instance identifier contained in it.
+        #[arg(long)]
+        from_file: Option<PathBuf>,
+        /// Forget the instance the node name is bound to without changing anything else.
+        /// The next instance to connect is bound instead. Use this if the machine was replaced.
+        #[arg(long, conflicts_with_all = ["push", "pull", "push_interval", "limit_rate", "allow_from", "verifier", "key", "from_file"])]
+        rebind: bool,
     },
     /// Modify permissions for a remote client without changing the passphrase.
     SetPerms {
         /// The name of the remote node to apply the information to.
         node_name: String,
+        /// The label of the grant to modify.
+        #[arg(long)]
+        label: Option<String>,
         /// The volumes the remote node is allowed to push.
+        /// `<node>/*` selects all volumes of a node, `*` all volumes.
         /// Subvolumes owned by the local node are silently ignored.
         #[arg(long)]
         push: Vec<String>,
         /// The volumes the remote node is allowed to pull.
+        /// `<node>/*` selects all volumes of a node, `*` all volumes.
         #[arg(long)]
         pull: Vec<String>,
+        /// The interval within which the remote node is expected to push, e.g. `1d`.
+        #[arg(long)]
+        push_interval: Option<HumanDuration>,
+        /// Only accept the remote node from the specified address range, e.g. `10.8.0.0/24`.
+        /// Can be passed multiple times. Any source is accepted if omitted.
+        #[arg(long)]
+        allow_from: Vec<IpNet>,
     },
     /// Revoke a remote client all access and delete local configuration about it.
     Revoke {
+        /// Only print what revoking the remote node affects.
+        #[arg(short = 'n', long)]
+        dry_run: bool,
+        /// Don't ask for confirmation.
+        #[arg(short, long)]
+        yes: bool,
         /// The name of the remote node to remove from the security configuration.
         node_name: String,
+        /// Only revoke the grant with this label. All grants of the node are revoked otherwise.
+        #[arg(long)]
+        label: Option<String>,
     },
     /// Export a random verifier and key of the local encryption passphrase.
-    ExportPass,
+    ExportPass {
+        /// Write the verifier and key to a file only accessible by its owner
+        /// instead of printing them. The file can be passed to `grant --from-file`.
+        #[arg(short, long)]
+        output: Option<PathBuf>,
+    },
+    /// Replace the authentication credentials of the local node on a remote node
+    /// with a fresh verifier and key. The previous credentials remain valid
+    /// until the remote node confirms the replacement.
+    RotateAuth {
+        /// Derive the new credentials from a newly prompted passphrase
+        /// instead of the current one. Configure the local node to use it
+        /// once the rotation succeeded.
+        #[arg(long)]
+        new_passphrase: bool,
+        /// Accept a new passphrase that is estimated to be weak.
+        #[arg(long, requires = "new_passphrase")]
+        allow_weak: bool,
+        /// The network address and optional port of the node to rotate the credentials on.
+        address: RemoteAddress,
+    },
+    /// Change the encryption passphrase stored in the configuration.
+    /// Remote nodes need to be granted the new credentials, either manually
+    /// using the printed verifier and key or by passing `--rotate`.
+    /// Existing backups remain encrypted with the old passphrase.
+    ChangePassphrase {
+        /// Accept a new passphrase that is estimated to be weak.
+        #[arg(long)]
+        allow_weak: bool,
+        /// Replace the credentials on every configured remote node
+        /// before saving the new passphrase.
+        #[arg(long)]
+        rotate: bool,
+        /// Don't ask for confirmation.
+        #[arg(short, long)]
+        yes: bool,
+    },
     /// Take a (local) snapshot of the specified subvolumes.
     Snapshot {
         /// Take incremental snapshots rather than full snapshots.
         #[arg(short, long)]
         incremental: bool,
+        /// Take incremental snapshots even if `max_incrementals_between_fulls`
+        /// calls for a full snapshot.
+        #[arg(long, requires = "incremental")]
+        force_incremental: bool,
+        /// Report the estimated size of new full snapshots without taking them.
+        #[arg(short, long, conflicts_with = "incremental")]
+        estimate: bool,
+        /// Skip subvolumes whose latest snapshot was taken
+        /// less than the specified time ago, e.g. `10m`.
+        #[arg(long, conflicts_with = "estimate")]
+        min_interval: Option<HumanDuration>,
+        /// Don't run the `pre_snapshot` and `post_snapshot` hooks of the subvolumes.
+        #[arg(long, conflicts_with = "estimate")]
+        no_hooks: bool,
         /// The subvolumes to limit snapshotting to.
         subvols: Vec<String>,
     },
@@ -134,8 +359,95 @@ enum Commands {
         /// The volumes to limit pulling to.
         #[arg(long)]
         pull: Vec<String>,
+        /// Report the snapshots that would be pushed and their estimated sizes
+        /// without transferring anything.
+        #[arg(short = 'n', long)]
+        dry_run: bool,
+        /// Print the transfer statistics of each remote node as JSON.
+        #[arg(short, long)]
+        json: bool,
+        /// Limit the rate at which backups are pushed in bytes per second, e.g. `1MiB`.
+        /// Overrides the `rate_limit` of the remote nodes.
+        #[arg(long)]
+        limit_rate: Option<ByteSize>,
+        /// Exchange the complete lists of held snapshots to send everything
+        /// the other node is missing, e.g. after it was offline for a while.
+        /// Only snapshots newer than its latest ones are sent otherwise.
+        #[arg(long)]
+        full_reconcile: bool,
+        /// The number of times to retry connecting after a transient failure,
+        /// e.g. while the remote node reboots. Rejected authentication is never retried.
+        #[arg(long, default_value_t = 3)]
+        retries: u32,
+        /// The delay before the first retry, doubling with each one, e.g. `10s`.
+        #[arg(long, default_value = "10s")]
+        retry_delay: HumanDuration,
         /// The network addresses and optional ports of the nodes to limit synchronization to.
-        remote_nodes: Vec<String>,
+        remote_nodes: Vec<RemoteAddress>,
+    },
+    /// Show the snapshots the remote nodes are missing and how far the local node
+    /// is behind them without transferring anything.
+    Diff {
+        /// The network addresses and optional ports of the nodes to limit the comparison to.
+        remote_nodes: Vec<RemoteAddress>,
+    },
+    /// Verify that a remote node holds intact copies of backups that are also stored locally.
+    /// Only backups in the local backup directory, usually those of volumes owned
+    /// by other nodes, can be verified. The snapshots of the local node are encrypted anew
+    /// for every push and the pushed bytes aren't kept, so they cannot be verified.
+    RemoteVerify {
+        /// The maximum number of randomly selected backups to verify.
+        #[arg(short, long, default_value_t = 8)]
+        sample: usize,
+        /// Print the results as JSON.
+        #[arg(short, long)]
+        json: bool,
+        /// The network address and optional port of the node to verify.
+        address: RemoteAddress,
+    },
+    /// Check the length and digest of the local backups against their manifests
+    /// without decrypting them. Backups stored by previous versions have no manifest
+    /// and are reported as unverified.
+    Verify {
+        /// Print the results as JSON.
+        #[arg(short, long)]
+        json: bool,
+    },
+    /// Store encrypted backups of the local snapshots in the backup directory,
+    /// e.g. to copy them to removable media. Backs up the latest full snapshot
+    /// of each subvolume and the incremental snapshots taken after it.
+    /// Snapshots that have already been backed up are skipped.
+    Backup {
+        /// The subvolumes to limit backing up to.
+        #[arg(short, long)]
+        subvols: Vec<String>,
+    },
+    /// Re-encrypt the backups of the local node's own volumes in the backup directory
+    /// with the current passphrase after changing it. Prompts for the old passphrase.
+    /// Backups that are already encrypted with the current passphrase are skipped,
+    /// so interrupted runs can be resumed.
+    ReencryptBackups {
+        /// The subvolumes to limit re-encrypting to.
+        #[arg(short, long)]
+        subvols: Vec<String>,
+        /// Also replace the copies on the configured remote nodes
+        /// with the re-encrypted backups. Remote copies are deleted
+        /// and pushed again one at a time.
+        #[arg(long)]
+        replace_remote: bool,
+    },
+    /// Write the encrypted stream of a snapshot or backup to a file for offline transfer.
+    ExportBackup {
+        /// The identifier of the snapshot or backup to export.
+        snapshot: String,
+        /// The file or directory to write to. Writes to stdout if omitted.
+        path: Option<PathBuf>,
+    },
+    /// Store a file created by `export-backup` as a backup.
+    /// The file name must be the identifier of the backup.
+    ImportBackup {
+        /// The file to read from.
+        path: PathBuf,
     },
     /// Restore the local node to the latest remote backup.
     Restore {
@@ -145,65 +457,363 @@ enum Commands {
         /// Do not keep the fstab file from an existing subvolume.
         #[arg(short = 'f', long)]
         ignore_fstab: bool,
+        /// Do not take a read-only snapshot of an existing subvolume before replacing it.
+        /// Safety snapshots are stored in the `pre-restore` directory
+        /// and can be deleted using `prune --pre-restore`.
+        #[arg(long)]
+        no_safety_snapshot: bool,
         /// The device file the local btrfs file system is located at.
         device: String,
         /// The name this node was previously known under.
         node_name: String,
         /// The network address and optional port of the node to download from.
-        address: Option<String>,
-        /// The subvolumes to recover.
+        address: Option<RemoteAddress>,
+        /// Restore from files created by `export-backup` instead of a remote node.
+        /// Accepts files and directories containing them.
+        #[arg(long, num_args = 1.., conflicts_with = "address")]
+        from: Vec<PathBuf>,
+        /// The subvolumes to recover. Defaults to all restorable subvolumes of the node
+        /// found locally, on the remote node or in the files passed to `--from`
+        /// after asking for confirmation.
         #[arg(short, long)]
         subvols: Vec<String>,
+        /// Don't ask for confirmation when restoring all subvolumes found.
+        #[arg(short, long)]
+        yes: bool,
+        /// Restore the state at the specified point in time instead of the latest one.
+        /// Accepts RFC 3339 or `%Y%m%d%H%M%S` (UTC) timestamps.
+        #[arg(short, long, value_parser = parse_timestamp)]
+        at: Option<NaiveDateTime>,
+        /// Restore as a node of the specified name, e.g. to seed a new machine.
+        /// Remote nodes still check the permissions of the original node name.
+        #[arg(long)]
+        as_node: Option<String>,
+        /// Restore a subvolume under a different name, e.g. next to the live one.
+        /// Takes the form `<old>=<new>` and can be passed multiple times.
+        #[arg(long, value_parser = parse_rename)]
+        rename_subvol: Vec<(String, String)>,
+        /// The number of times to retry connecting after a transient failure,
+        /// e.g. while the remote node reboots. Rejected authentication is never retried.
+        #[arg(long, default_value_t = 3)]
+        retries: u32,
+        /// The delay before the first retry, doubling with each one, e.g. `10s`.
+        #[arg(long, default_value = "10s")]
+        retry_delay: HumanDuration,
     },
-    /// Delete backups older than the latest full backup (includes remote volumes).
+    /// Delete backups older than the latest full backup (includes remote volumes)
+    /// and archive backups according to the configured policy.
     Gc {
         /// The volumes to limit garbage collection to.
         volumes: Vec<String>,
+        /// Only print what would be deleted or archived.
+        #[arg(short = 'n', long)]
+        dry_run: bool,
+    },
+    /// Delete old backups of the local node's own volumes from a remote node.
+    /// Backups are deleted in whole chains of a full backup and its incremental backups.
+    /// The remote node never deletes the last full backup of a volume.
+    Prune {
+        /// The remote node to delete the backups from.
+        #[arg(short, long, required_unless_present = "pre_restore")]
+        remote: Option<RemoteAddress>,
+        /// The number of most recent full backups per volume to keep along with their chains.
+        #[arg(long, default_value_t = 1, value_parser = clap::value_parser!(u64).range(1..))]
+        keep_full: u64,
+        /// Keep every chain containing a backup taken within this many days.
+        #[arg(long, default_value_t = 0)]
+        keep_days: u64,
+        /// Only print what would be deleted.
+        #[arg(short = 'n', long)]
+        dry_run: bool,
+        /// Delete the local safety snapshots taken by `restore` instead.
+        /// Those taken within `--keep-days` are kept.
+        #[arg(long, conflicts_with_all = ["remote", "keep_full"])]
+        pre_restore: bool,
     },
+    /// Delete incomplete backups left behind by failed transmissions.
+    CleanPartials {
+        /// The minimum time since the last modification of the incomplete backups
+        /// to delete, e.g. `12h`. The default is taken from the configuration.
+        #[arg(short, long)]
+        max_age: Option<HumanDuration>,
+    },
+    /// Delete snapshots or backups with implausible timestamps, e.g. dated into the future
+    /// by a broken clock. Garbage collection never ages them out on its own.
+    Quarantine {
+        /// The identifiers of the snapshots or backups to delete.
+        snapshots: Vec<String>,
+    },
+    /// Move backups stored in the flat layout of previous versions
+    /// to per-node, per-subvolume directories.
+    MigrateLayout,
+    /// List all local snapshots and stored backups.
+    List {
+        /// List the snapshots and backups stored on this remote node instead,
+        /// limited to the volumes the local node owns or may pull.
+        /// Snapshots missing locally are marked as such.
+        #[arg(short, long)]
+        remote: Option<RemoteAddress>,
+        /// Print the snapshots and backups as JSON.
+        #[arg(short, long)]
+        json: bool,
+    },
+    /// Show when each remote node was last synchronized with successfully
+    /// and whether the latest attempt failed.
+    Status {
+        /// Fail if any remote node hasn't been synchronized with successfully
+        /// within the specified duration, e.g. `1d`.
+        #[arg(long)]
+        max_age: Option<HumanDuration>,
+        /// Print the state of the remote nodes as JSON.
+        #[arg(short, long)]
+        json: bool,
+    },
+    /// Keep the local node mounted and serve other invocations through a Unix socket.
+    /// Supported subcommands use the agent automatically if it is running.
+    Agent,
+    /// Run pre-flight checks of the configuration and local storage,
+    /// report the protection status of the local storage
+    /// and list the reports of recently failed synchronization sessions.
+    /// Exits with an error if any check fails.
+    Doctor {
+        /// Dump the full reports as JSON.
+        #[arg(short, long)]
+        json: bool,
+    },
+    /// Check the configuration file or recover its previous version.
+    Config {
+        #[command(subcommand)]
+        command: ConfigCommands,
+    },
+    /// Print a shell completion script, e.g. `source <(hbak completions bash)`.
+    /// Arguments such as subvolumes, remote nodes and granted nodes
+    /// are completed from the configuration if it is readable.
+    Completions {
+        /// The shell to generate the script for.
+        #[arg(value_enum)]
+        shell: Shell,
+    },
+    /// Print the tracked subvolumes for shell completion.
+    #[command(name = "__complete-subvols", hide = true)]
+    CompleteSubvols,
+    /// Print the addresses of the remote nodes for shell completion.
+    #[command(name = "__complete-remotes", hide = true)]
+    CompleteRemotes,
+    /// Print the node names of the granted nodes for shell completion.
+    #[command(name = "__complete-nodes", hide = true)]
+    CompleteNodes,
+}
+
+#[derive(Subcommand)]
+enum ConfigCommands {
+    /// Parse the configuration file and check its values
+    /// without accessing the btrfs device.
+    Validate {
+        /// The file to check instead of the configuration file, e.g. its previous version.
+        path: Option<PathBuf>,
+    },
+    /// Swap the configuration file with its previous version,
+    /// which is kept whenever the configuration is changed.
+    /// Running it again undoes the swap.
+    RestoreBackup,
 }
 
 fn logic() -> Result<()> {
     let cli = Cli::parse();
 
+    verbosity::set(Verbosity::from_flags(cli.quiet, cli.verbose));
+
     match cli.command {
         Commands::Init {
             config_only,
+            wipe,
+            allow_weak,
+            role,
             device,
             node_name,
             bind_addr,
         } => {
-            let passphrase = rpassword::prompt_password("Enter new encryption passphrase: ")?;
-            system::init(config_only, device, bind_addr, node_name, passphrase)?;
+            let passphrase = prompt_new_passphrase(allow_weak)?;
+            let adopted = system::init(
+                role.into(),
+                config_only,
+                wipe,
+                device,
+                bind_addr,
+                node_name,
+                passphrase,
+            )?;
+
+            if adopted != Adopted::default() {
+                info!(
+                    "Adopted {} existing snapshots and {} existing backups",
+                    adopted.snapshots, adopted.backups
+                );
+
+                for subvol in adopted.subvols {
+                    info!("Tracking subvolume {}", subvol);
+                }
+            }
         }
-        Commands::Clean { backups } => {
-            system::deinit(backups)?;
+        Commands::Clean { backups, yes } => {
+            // Don't ask for confirmation if it's going to fail anyway.
+            if let Some(pid) = system::hbakd_pid() {
+                return Err(LocalNodeError::DaemonRunning(pid).into());
+            }
+
+            if !yes {
+                // Unparseable configurations have no node name to type.
+                let expected = NodeConfig::load()
+                    .map(|node_config| node_config.node_name)
+                    .unwrap_or_else(|_| String::from("clean"));
+
+                if backups {
+                    warn!(
+                        "Remove the configuration and delete all snapshots and backups? Type \"{}\" to confirm",
+                        expected
+                    );
+                } else {
+                    warn!("Remove the configuration? Type \"{}\" to confirm", expected);
+                }
+
+                let mut answer = String::new();
+                io::stdin().read_line(&mut answer)?;
+
+                if answer.trim() != expected {
+                    info!("Not cleaning");
+                    return Ok(());
+                }
+            }
+
+            let cleaned = system::deinit(backups)?;
+
+            for subvol in &cleaned.deleted {
+                info!("Deleted subvolume {}", subvol.display());
+            }
+            for subvol in &cleaned.kept {
+                info!("Kept subvolume {}", subvol.display());
+            }
         }
-        Commands::Track { subvol } => {
+        Commands::Track { force, subvol } => {
+            if !proto::is_valid_name(&subvol) {
+                return Err(LocalNodeError::InvalidSubvolumeName(subvol).into());
+            }
+
+            if !force {
+                let local_node = LocalNode::new(Mode::Client)?;
+                local_node.check_subvol(&subvol)?;
+            }
+
             let mut node_config = NodeConfig::load()?;
 
-            node_config.subvols.retain(|item| *item != subvol);
-            node_config.subvols.push(subvol);
+            // Keep the hooks of subvolumes that are already tracked.
+            if node_config.subvol(&subvol).is_none() {
+                node_config.subvols.push(SubvolConfig::from(subvol));
+                node_config
+                    .subvols
+                    .sort_unstable_by(|a, b| a.name.cmp(&b.name));
+            }
             node_config.save()?;
+
+            for subvol in &node_config.subvols {
+                out!("{}", subvol);
+            }
         }
-        Commands::Untrack { subvol } => {
+        Commands::Untrack {
+            delete_snapshots,
+            delete_backups,
+            yes,
+            subvol,
+        } => {
             let mut node_config = NodeConfig::load()?;
 
-            node_config.subvols.retain(|item| *item != subvol);
+            node_config.subvols.retain(|item| item.name != subvol);
             node_config.save()?;
+
+            if !delete_snapshots && !delete_backups {
+                return Ok(());
+            }
+
+            // Unmount the btrfs before potentially getting killed at prompts.
+            let (snapshots, backups) = {
+                let local_node = LocalNode::new(Mode::Client)?;
+                let is_untracked = |snapshot: &Snapshot| {
+                    local_node.owns_backup(snapshot) && snapshot.subvol() == subvol
+                };
+
+                let mut snapshots = Vec::new();
+                if delete_snapshots {
+                    snapshots = local_node.all_snapshots(None)?;
+                    snapshots.retain(is_untracked);
+                    snapshots.sort_unstable_by_key(Snapshot::taken);
+                }
+
+                let mut backups = Vec::new();
+                if delete_backups {
+                    backups = local_node.all_backups(None)?;
+                    backups.retain(is_untracked);
+                    backups.sort_unstable_by_key(Snapshot::taken);
+                }
+
+                (snapshots, backups)
+            };
+
+            if snapshots.is_empty() && backups.is_empty() {
+                info!("Nothing to delete for {}", subvol);
+                return Ok(());
+            }
+
+            for snapshot in &snapshots {
+                out!("snapshot {}", snapshot);
+            }
+            for backup in &backups {
+                out!("backup   {}", backup);
+            }
+
+            if !yes {
+                warn!(
+                    "Delete {} snapshot(s) and {} backup(s) of {}? [y/N]",
+                    snapshots.len(),
+                    backups.len(),
+                    subvol
+                );
+
+                let mut answer = String::new();
+                io::stdin().read_line(&mut answer)?;
+
+                if !answer.trim().eq_ignore_ascii_case("y") {
+                    info!("Not deleting anything");
+                    return Ok(());
+                }
+            }
+
+            let local_node = LocalNode::new(Mode::Client)?;
+
+            for snapshot in &snapshots {
+                local_node.delete_untracked(snapshot, false)?;
+                info!("Deleted snapshot {}", snapshot);
+            }
+            for backup in &backups {
+                local_node.delete_untracked(backup, true)?;
+                info!("Deleted backup {}", backup);
+            }
         }
         Commands::AddRemote {
             address,
             push,
             pull,
+            limit_rate,
         } => {
             let mut node_config = NodeConfig::load()?;
 
             node_config.remotes.retain(|item| item.address != address);
             node_config.remotes.push(RemoteNode {
                 address,
-                push: Volume::try_from_bulk(push)?,
-                pull: Volume::try_from_bulk(pull)?,
+                push: VolumeSpec::try_from_bulk(push)?,
+                pull: VolumeSpec::try_from_bulk(pull)?,
+                rate_limit: limit_rate,
             });
+            node_config.validate()?;
             node_config.save()?;
         }
         Commands::RmRemote { address } => {
@@ -214,353 +824,2268 @@ fn logic() -> Result<()> {
         }
         Commands::Grant {
             node_name,
-            mut push,
+            label,
+            push,
             pull,
+            push_interval,
+            limit_rate,
+            allow_from,
+            verifier,
+            key,
+            from_file,
+            rebind,
         } => {
-            // Unmount the btrfs before potentially getting killed at prompts.
-            {
-                let local_node = LocalNode::new(Mode::Client)?;
-
-                push.retain(|subvol| !local_node.owns_subvol(subvol));
+            if rebind {
+                let mut node_config = NodeConfig::load()?;
+
+                let auth = node_config
+                    .auth
+                    .iter_mut()
+                    .find(|item| item.is(&node_name, label.as_deref()))
+                    .ok_or_else(|| Error::NoSuchGrant(grant_name(&node_name, label.as_deref())))?;
+                auth.instance_id = None;
+
+                node_config.save()?;
+
+                info!(
+                    "Unbound {}, restart hbakd to bind the next instance",
+                    node_name
+                );
+                return Ok(());
             }
 
-            println!("Use the passphrase export results from the remote node below.");
-            let verifier_hex = rpassword::prompt_password("Enter verifier: ")?;
-            let verifier = hex::decode(verifier_hex)?;
-            let key_hex = rpassword::prompt_password("Enter key: ")?;
-            let key = hex::decode(key_hex)?;
+            // Refuse ineffective permissions before prompting for secrets.
+            let push = VolumeSpec::try_from_bulk(push)?;
+            let pull = VolumeSpec::try_from_bulk(pull)?;
+            check_permissions(&NodeConfig::load()?, &push, &pull)?;
+
+            let (verifier_hex, key_hex, instance_id) = match (verifier, key, from_file) {
+                (Some(verifier), Some(key), _) => (verifier, key, None),
+                (_, _, Some(path)) => read_pass_file(path)?,
+                _ => {
+                    out!("Use the passphrase export results from the remote node below.");
+                    (
+                        rpassword::prompt_password("Enter verifier: ")?,
+                        rpassword::prompt_password("Enter key: ")?,
+                        None,
+                    )
+                }
+            };
+
+            let verifier = decode_secret("verifier", &verifier_hex)?;
+            let key = decode_secret("key", &key_hex)?;
 
             let mut node_config = NodeConfig::load()?;
 
-            node_config.auth.retain(|item| item.node_name != node_name);
+            // Keep the existing binding unless a new one is provided.
+            let instance_id = instance_id.or_else(|| {
+                node_config
+                    .auth
+                    .iter()
+                    .find(|item| item.node_name == node_name)
+                    .and_then(|item| item.instance_id.clone())
+            });
+
+            node_config
+                .auth
+                .retain(|item| !item.is(&node_name, label.as_deref()));
             node_config.auth.push(RemoteNodeAuth {
                 node_name,
+                label,
                 verifier,
                 key,
-                push: Volume::try_from_bulk(push)?,
-                pull: Volume::try_from_bulk(pull)?,
+                push,
+                pull,
+                push_interval,
+                instance_id,
+                rate_limit: limit_rate,
+                allowed_sources: allow_from,
             });
+            node_config.validate()?;
             node_config.save()?;
         }
         Commands::SetPerms {
             node_name,
-            mut push,
+            label,
+            push,
             pull,
+            push_interval,
+            allow_from,
         } => {
-            let local_node = LocalNode::new(Mode::Client)?;
-
-            push.retain(|subvol| !local_node.owns_subvol(subvol));
+            let push = VolumeSpec::try_from_bulk(push)?;
+            let pull = VolumeSpec::try_from_bulk(pull)?;
 
             let mut node_config = NodeConfig::load()?;
+            check_permissions(&node_config, &push, &pull)?;
+
+            let auth = node_config
+                .auth
+                .iter_mut()
+                .find(|item| item.is(&node_name, label.as_deref()))
+                .ok_or_else(|| Error::NoSuchGrant(grant_name(&node_name, label.as_deref())))?;
+            auth.push = push;
+            auth.pull = pull;
+            auth.push_interval = push_interval;
+            auth.allowed_sources = allow_from;
+
+            node_config.save()?;
+        }
+        Commands::Revoke {
+            dry_run,
+            yes,
+            node_name,
+            label,
+        } => {
+            let name = grant_name(&node_name, label.as_deref());
+
+            // Unmount the btrfs before potentially getting killed at prompts.
+            let impact = {
+                let local_node = LocalNode::new(Mode::Client)?;
+
+                RevokeImpact::analyze(
+                    &node_name,
+                    label.as_deref(),
+                    &local_node.config().auth,
+                    &local_node.all_backups(None)?,
+                    &ServerState::load()?,
+                )
+                .ok_or_else(|| Error::NoSuchGrant(name.clone()))?
+            };
+
+            print_revoke_impact(&impact);
+
+            if dry_run {
+                return Ok(());
+            }
 
-            for item in &mut node_config.auth {
-                if item.node_name == node_name {
-                    item.push = Volume::try_from_bulk(push)?;
-                    item.pull = Volume::try_from_bulk(pull)?;
+            if !yes {
+                match label {
+                    Some(_) => warn!("Revoke the {} grant? [y/N]", name),
+                    None => warn!("Revoke all access of {}? [y/N]", name),
+                }
+
+                let mut answer = String::new();
+                io::stdin().read_line(&mut answer)?;
 
-                    break;
+                if !answer.trim().eq_ignore_ascii_case("y") {
+                    info!("Not revoking {}", name);
+                    return Ok(());
                 }
             }
 
-            node_config.save()?;
-        }
-        Commands::Revoke { node_name } => {
             let mut node_config = NodeConfig::load()?;
 
-            node_config.auth.retain(|item| item.node_name != node_name);
+            node_config.auth.retain(|item| match &label {
+                Some(label) => !item.is(&node_name, Some(label)),
+                None => item.node_name != node_name,
+            });
             node_config.save()?;
         }
-        Commands::ExportPass => {
+        Commands::ExportPass { output } => {
             let node_config = NodeConfig::load()?;
-            let (verifier, key) = system::hash_passphrase(node_config.passphrase)?;
+            let (verifier, key) = system::hash_passphrase(node_config.resolve_passphrase(true)?)?;
+            let export = format_export(&verifier, &key, node_config.instance_id.as_deref());
+
+            match output {
+                Some(path) => {
+                    let mut file = OpenOptions::new()
+                        .write(true)
+                        .create(true)
+                        .truncate(true)
+                        .mode(0o600)
+                        .open(&path)?;
+                    // The mode only applies to newly created files.
+                    file.set_permissions(Permissions::from_mode(0o600))?;
+                    file.write_all(export.as_bytes())?;
+
+                    info!("Wrote verifier and key to {}", path.display());
+                }
+                None => output::print(format_args!("{}", export)),
+            }
+        }
+        Commands::RotateAuth {
+            new_passphrase,
+            allow_weak,
+            address,
+        } => {
+            let local_node = LocalNode::new(Mode::Client)?;
+
+            let remote_node = local_node
+                .config()
+                .remotes
+                .iter()
+                .find(|item| item.address == address)
+                .ok_or(Error::NoSuchRemote(address.to_string()))?;
+
+            let (verifier, key) = if new_passphrase {
+                system::hash_passphrase(prompt_new_passphrase(allow_weak)?)?
+            } else {
+                system::hash_passphrase(local_node.passphrase()?)?
+            };
 
-            println!("Verifier: {}", hex::encode(verifier));
-            println!("Key:      {}", hex::encode(key));
+            let stream_conn = connect(&local_node, remote_node)?;
+            stream_conn.rotate_auth(Credentials { verifier, key })?;
+
+            info!("Rotated credentials on {}", remote_node.address);
+            if new_passphrase {
+                warn!(
+                    "Configure the new passphrase before connecting to {} again",
+                    remote_node.address
+                );
+            }
         }
+        Commands::ChangePassphrase {
+            allow_weak,
+            rotate,
+            yes,
+        } => change_passphrase(allow_weak, rotate, yes)?,
         Commands::Snapshot {
             incremental,
+            force_incremental,
+            estimate,
+            min_interval,
+            no_hooks,
             subvols,
         } => {
+            if estimate {
+                let local_node = LocalNode::new(Mode::Client)?;
+
+                let subvols = if subvols.is_empty() {
+                    local_node.config().subvol_names()
+                } else {
+                    subvols
+                };
+
+                for subvol in &subvols {
+                    out!("{}: {}", subvol, local_node.estimate_subvol_size(subvol)?);
+                }
+
+                return Ok(());
+            }
+
+            if let Some(mut agent_client) = AgentClient::connect() {
+                let subvols = if subvols.is_empty() {
+                    NodeConfig::load()?.subvol_names()
+                } else {
+                    subvols
+                };
+
+                for subvol in subvols {
+                    let promotion = if incremental {
+                        agent_client.promotion(subvol.clone())?
+                    } else {
+                        None
+                    }
+                    .filter(|promotion| !(force_incremental && promotion.can_be_overridden()));
+
+                    info!("Snapshotting {}...", subvol);
+                    let snapshot = agent_client.snapshot_now(
+                        subvol.clone(),
+                        incremental && promotion.is_none(),
+                        min_interval.map(Duration::from),
+                        !no_hooks,
+                    )?;
+
+                    report_snapshot(&subvol, snapshot, promotion);
+                }
+
+                return Ok(());
+            }
+
             let local_node = LocalNode::new(Mode::Client)?;
 
             let subvols = if subvols.is_empty() {
-                &local_node.config().subvols
+                local_node.config().subvol_names()
             } else {
-                &subvols
-            }
-            .iter();
+                subvols
+            };
 
-            for subvol in subvols {
+            for subvol in &subvols {
                 if !local_node.owns_subvol(subvol) {
                     return Err(LocalNodeError::ForeignSubvolume(subvol.clone()).into());
                 }
 
-                eprintln!("Snapshotting {}...", subvol);
-                local_node.snapshot_now(subvol.clone(), incremental)?;
+                let promotion = if incremental {
+                    local_node.promotion(subvol)?
+                } else {
+                    None
+                }
+                .filter(|promotion| !(force_incremental && promotion.can_be_overridden()));
+
+                info!("Snapshotting {}...", subvol);
+                let snapshot = local_node.snapshot_now(
+                    subvol.clone(),
+                    incremental && promotion.is_none(),
+                    min_interval.map(Duration::from),
+                    !no_hooks,
+                )?;
+
+                report_snapshot(subvol, snapshot, promotion);
             }
         }
         Commands::Synchronize {
             push,
             pull,
+            dry_run,
+            json,
+            limit_rate,
+            full_reconcile,
+            retries,
+            retry_delay,
             remote_nodes,
         } => {
             let local_node = LocalNode::new(Mode::Client)?;
 
+            let options = SyncOptions {
+                push: &push,
+                pull: &pull,
+                dry_run,
+                limit_rate,
+                full_reconcile,
+                retry: Retry::new(retries, retry_delay),
+            };
+
+            // Interrupted synchronizations are reported once all remote nodes are done.
+            let mut interrupted = None;
+
             for remote_node in local_node
                 .config()
                 .remotes
                 .iter()
                 .filter(|item| remote_nodes.is_empty() || remote_nodes.contains(&item.address))
             {
-                eprintln!("Synchronizing with {}...", remote_node.address);
-                sync(&local_node, remote_node, &push, &pull)?;
+                info!("Synchronizing with {}...", remote_node.address);
+
+                let report = Mutex::new(FailureReport::new(
+                    env!("CARGO_PKG_VERSION"),
+                    local_node.name().to_string(),
+                    remote_node.address.to_string(),
+                ));
+
+                let result = sync(&local_node, remote_node, &options, &report);
+                record_sync(&remote_node.address, &result);
+
+                match result {
+                    Ok(Some(stats)) if json => out!("{}", serde_json::to_string(&stats)?),
+                    Ok(Some(stats)) => info!(
+                        "Synchronization with {} complete, {}",
+                        remote_node.address,
+                        stats.summary("pushed", "pulled")
+                    ),
+                    Ok(None) => {}
+                    Err(e @ Error::PartialSync(_)) => {
+                        if interrupted.is_some() {
+                            warn!("{}", e);
+                        } else {
+                            interrupted = Some(e);
+                        }
+                    }
+                    Err(e) => {
+                        save_report(report.into_inner().unwrap(), &e);
+                        return Err(e);
+                    }
+                }
+            }
+
+            if let Some(e) = interrupted {
+                return Err(e);
             }
         }
-        Commands::Restore {
-            no_restore,
-            ignore_fstab,
-            device,
-            node_name,
+        Commands::Diff { remote_nodes } => {
+            let local_node = LocalNode::new(Mode::Client)?;
+
+            for remote_node in local_node
+                .config()
+                .remotes
+                .iter()
+                .filter(|item| remote_nodes.is_empty() || remote_nodes.contains(&item.address))
+            {
+                info!("Comparing with {}...", remote_node.address);
+                diff(&local_node, remote_node)?;
+            }
+        }
+        Commands::RemoteVerify {
+            sample,
+            json,
             address,
-            subvols,
         } => {
-            let passphrase = rpassword::prompt_password("Enter passphrase: ")?;
-
-            let local_node = LocalNode::with_config(
-                Mode::Client,
-                NodeConfig {
-                    device,
-                    bind_addr: None,
-                    node_name,
-                    subvols,
-                    passphrase,
-                    remotes: Vec::default(),
-                    auth: Vec::default(),
-                },
-            )?;
+            let local_node = LocalNode::new(Mode::Client)?;
 
-            if let Some(address) = &address {
-                eprintln!("Restoring from {}...", address);
-            } else {
-                eprintln!("Restoring locally...");
-            }
+            let remote_node = local_node
+                .config()
+                .remotes
+                .iter()
+                .find(|item| item.address == address)
+                .ok_or(Error::NoSuchRemote(address.to_string()))?;
 
-            restore(&local_node, address.as_deref(), no_restore, ignore_fstab)?;
+            info!("Verifying backups on {}...", remote_node.address);
+            remote_verify(&local_node, remote_node, sample, json)?;
         }
-        Commands::Gc { volumes } => {
+        Commands::Verify { json } => {
+            let local_node = LocalNode::new(Mode::Client)?;
+            verify(&local_node, json)?;
+        }
+        Commands::Backup { subvols } => {
             let local_node = LocalNode::new(Mode::Client)?;
 
-            let snapshots = local_node
-                .all_snapshots(None)?
-                .into_iter()
-                .chain(local_node.all_backups(None)?);
-
-            let snapshots: Box<dyn Iterator<Item = Snapshot>> = if volumes.is_empty() {
-                Box::new(snapshots)
+            let subvols = if subvols.is_empty() {
+                local_node.config().subvol_names()
             } else {
-                Box::new(
-                    snapshots.filter(|snapshot| volumes.contains(&snapshot.volume().to_string())),
-                )
+                subvols
             };
 
-            let mut volumes: Vec<_> = snapshots.map(|snapshot| snapshot.volume()).collect();
+            for subvol in &subvols {
+                if !local_node.owns_subvol(subvol) {
+                    return Err(LocalNodeError::ForeignSubvolume(subvol.clone()).into());
+                }
+
+                let full = local_node.latest_snapshot_full(subvol.clone())?;
+
+                let mut incrementals: Vec<_> = local_node
+                    .all_snapshots(Some(subvol.clone()))?
+                    .into_iter()
+                    .filter(|snapshot| snapshot.is_incremental() && snapshot.taken() > full.taken())
+                    .collect();
+                incrementals.sort_unstable_by_key(|snapshot| snapshot.taken());
+
+                for snapshot in iter::once(full).chain(incrementals) {
+                    if local_node.backup_exists(&snapshot) {
+                        info!("Skipping {}, already backed up", snapshot);
+                        continue;
+                    }
+
+                    info!("Backing up {}...", snapshot);
+                    let stream =
+                        local_node.send_snapshot(&snapshot, local_node.send_protocol(), true)?;
+                    local_node.backup(stream, &snapshot)?;
+                }
+            }
+        }
+        Commands::ReencryptBackups {
+            subvols,
+            replace_remote,
+        } => {
+            let local_node = LocalNode::new(Mode::Client)?;
+
+            let subvols = if subvols.is_empty() {
+                local_node.config().subvol_names()
+            } else {
+                subvols
+            };
+
+            let old_passphrase = rpassword::prompt_password("Enter old encryption passphrase: ")?;
+
+            let mut reencrypted = 0;
+            let mut all_backups = Vec::new();
+            for subvol in &subvols {
+                if !local_node.owns_subvol(subvol) {
+                    return Err(LocalNodeError::ForeignSubvolume(subvol.clone()).into());
+                }
+
+                let volume = Volume::new_local(&local_node, subvol.clone())?;
+                let mut backups = local_node.all_backups(Some(&volume))?;
+                backups.sort_unstable_by_key(|backup| backup.taken());
+                all_backups.extend(backups.iter().cloned());
+
+                for backup in backups {
+                    if local_node.reencrypt_backup(&backup, &old_passphrase)? {
+                        info!("Re-encrypted {}", backup);
+                        reencrypted += 1;
+                    } else {
+                        info!(
+                            "Skipping {}, already encrypted with the current passphrase",
+                            backup
+                        );
+                    }
+                }
+            }
+
+            info!("Re-encrypted {} backup(s)", reencrypted);
+
+            if replace_remote {
+                for remote_node in &local_node.config().remotes {
+                    let replaced = replace_remote_copies(&local_node, remote_node, &all_backups)?;
+                    info!("Replaced {} copy(s) on {}", replaced, remote_node.address);
+                }
+            } else if reencrypted > 0 {
+                warn!(
+                    "Copies on remote nodes remain encrypted with the old passphrase, \
+                    use --replace-remote to replace them"
+                );
+            }
+        }
+        Commands::ExportBackup { snapshot, path } => {
+            let local_node = LocalNode::new(Mode::Client)?;
+
+            let snapshot = Snapshot::try_from(snapshot.as_str())?;
+            if !local_node.exists(&snapshot) {
+                return Err(LocalNodeError::NoSuchSnapshot(snapshot).into());
+            }
+
+            let mut r = local_node.export(&snapshot, local_node.send_protocol(), true)?;
+
+            match path {
+                Some(path) => {
+                    let path = if path.is_dir() {
+                        path.join(snapshot.to_string())
+                    } else {
+                        path
+                    };
+
+                    info!("Exporting {} to {}...", snapshot, path.display());
+
+                    let mut w = BufWriter::new(File::create(path)?);
+                    io::copy(&mut r, &mut w)?;
+                    w.flush()?;
+                }
+                None => {
+                    let mut w = io::stdout().lock();
+                    io::copy(&mut r, &mut w)?;
+                    w.flush()?;
+                }
+            }
+        }
+        Commands::ImportBackup { path } => {
+            let local_node = LocalNode::new(Mode::Client)?;
+
+            let snapshot = Snapshot::try_from(path.as_path())?;
+
+            info!("Importing {}...", snapshot);
+            local_node.import_backup(BufReader::new(File::open(&path)?), &snapshot)?;
+        }
+        Commands::Restore {
+            no_restore,
+            ignore_fstab,
+            no_safety_snapshot,
+            device,
+            node_name,
+            address,
+            from,
+            subvols,
+            yes,
+            at,
+            as_node,
+            rename_subvol,
+            retries,
+            retry_delay,
+        } => {
+            let relabel = Relabel::new(node_name, as_node, rename_subvol)?;
+            let retry = Retry::new(retries, retry_delay);
+            let files = chain::collect(&from)?;
+
+            let passphrase = rpassword::prompt_password("Enter passphrase: ")?;
+
+            let mut local_node = LocalNode::with_config(
+                Mode::Client,
+                NodeConfig {
+                    device,
+                    mount_root: None,
+                    bind_addr: Vec::new(),
+                    unix_socket: None,
+                    unix_plaintext: None,
+                    rx_bufsize: None,
+                    drain_timeout: None,
+                    max_auth_failures: None,
+                    max_sessions_per_client: None,
+                    max_clients: None,
+                    immutable_snapshots: None,
+                    delete_cooloff: None,
+                    partial_max_age: None,
+                    chunk_size: None,
+                    keepalive_idle: None,
+                    keepalive_interval: None,
+                    stall_timeout: None,
+                    heartbeat_interval: None,
+                    rekey_interval: None,
+                    space_reserve: None,
+                    archive_dir: None,
+                    archive_after: None,
+                    archive_keep: None,
+                    max_clock_skew: None,
+                    max_snapshot_age: None,
+                    send_protocol: None,
+                    compression_level: None,
+                    log_level: None,
+                    log_format: None,
+                    node_name: relabel.as_node().to_string(),
+                    // The restored machine is a new instance. Remote nodes still bound
+                    // to the original machine need to be rebound using `hbak grant --rebind`.
+                    instance_id: Some(system::random_instance_id()),
+                    subvols: subvols
+                        .iter()
+                        .map(|subvol| SubvolConfig::from(relabel.subvol(subvol).to_string()))
+                        .collect(),
+                    subvol_aliases: BTreeMap::default(),
+                    passphrase: Some(passphrase),
+                    passphrase_command: None,
+                    passphrase_prompt: None,
+                    remotes: Vec::default(),
+                    upstreams: Vec::default(),
+                    schedule: BTreeMap::default(),
+                    auth: Vec::default(),
+                },
+            )?;
+
+            // Restoration receives into the snapshot directory.
+            local_node.ensure_snapshot_dir()?;
+
+            // Fail before waiting for any transfers.
+            let available =
+                restorable_subvols(&local_node, &relabel, address.as_ref(), &files, at, &retry)?;
+            if subvols.is_empty() {
+                if available.is_empty() {
+                    return Err(Error::NothingToRestore(relabel.node_name().to_string()));
+                }
+
+                let available: Vec<_> = available.into_iter().collect();
+                if yes {
+                    info!("Restoring subvolume(s) {}", available.join(", "));
+                } else {
+                    warn!("Restore subvolume(s) {}? [y/N]", available.join(", "));
+
+                    let mut answer = String::new();
+                    io::stdin().read_line(&mut answer)?;
+
+                    if !answer.trim().eq_ignore_ascii_case("y") {
+                        info!("Not restoring");
+                        return Ok(());
+                    }
+                }
+
+                local_node.set_subvols(available.into_iter().map(SubvolConfig::from).collect());
+            } else {
+                let unavailable: Vec<_> = subvols
+                    .iter()
+                    .filter(|subvol| !available.contains(relabel.subvol(subvol)))
+                    .map(String::as_str)
+                    .collect();
+                if !unavailable.is_empty() {
+                    return Err(Error::Unrestorable(unavailable.join(", ")));
+                }
+            }
+
+            let _unlocked = local_node.unlock_snapshots()?;
+
+            if let Some(address) = &address {
+                info!("Restoring from {}...", address);
+            } else if !from.is_empty() {
+                info!("Restoring from {} backup file(s)...", files.len());
+                restore_files(&local_node, &relabel, &files, at)?;
+            } else {
+                info!("Restoring locally...");
+            }
+
+            restore(
+                &local_node,
+                &relabel,
+                address.as_ref(),
+                &RestoreOptions {
+                    no_restore,
+                    ignore_fstab,
+                    safety_snapshot: !no_safety_snapshot,
+                    at,
+                    retry,
+                },
+            )?;
+        }
+        Commands::Gc { volumes, dry_run } => {
+            let local_node = LocalNode::new(Mode::Client)?;
+
+            let snapshots = local_node
+                .all_snapshots(None)?
+                .into_iter()
+                .chain(local_node.all_backups(None)?);
+
+            let snapshots: Box<dyn Iterator<Item = Snapshot>> = if volumes.is_empty() {
+                Box::new(snapshots)
+            } else {
+                Box::new(
+                    snapshots.filter(|snapshot| volumes.contains(&snapshot.volume().to_string())),
+                )
+            };
+
+            let mut volumes: Vec<_> = snapshots.map(|snapshot| snapshot.volume()).collect();
 
             // Fully deduplicate the volume Vec. This is needed because multiple snapshots
             // may be of the same volume.
             volumes.sort_unstable();
             volumes.dedup();
 
-            for volume in volumes {
+            let mut deleted = HashSet::new();
+
+            for volume in &volumes {
                 let latest_full = local_node.latest_full(volume.clone())?;
                 let to_delete = local_node
                     .all_snapshots(Some(volume.subvol().to_string()))?
                     .into_iter()
-                    .chain(local_node.all_backups(Some(&volume))?)
+                    .chain(local_node.all_backups(Some(volume))?)
                     .filter(|snapshot| snapshot.taken() < latest_full.taken());
 
-                for snapshot in to_delete {
-                    local_node.delete(&snapshot)?;
+                for snapshot in to_delete {
+                    if dry_run {
+                        out!("Would delete {}", snapshot);
+                        deleted.insert(snapshot);
+                        continue;
+                    }
+
+                    match local_node.delete(&snapshot) {
+                        Ok(_) => {}
+                        Err(LocalNodeError::Cooloff(snapshot)) => {
+                            info!("Keeping {} until its cooloff has elapsed", snapshot)
+                        }
+                        Err(e) => return Err(e.into()),
+                    }
+                }
+            }
+
+            let to_archive = local_node
+                .backups_to_archive()?
+                .into_iter()
+                .filter(|backup| volumes.contains(&backup.volume()) && !deleted.contains(backup));
+
+            for backup in to_archive {
+                if dry_run {
+                    out!("Would archive {}", backup);
+                } else {
+                    local_node.archive_backup(&backup)?;
+                    info!("Archived {}", backup);
+                }
+            }
+        }
+        Commands::Prune {
+            remote,
+            keep_full,
+            keep_days,
+            dry_run,
+            pre_restore: _,
+        } => {
+            let local_node = LocalNode::new(Mode::Client)?;
+            let cutoff = Utc::now().naive_utc() - chrono::Duration::days(keep_days as i64);
+
+            // The remote node is only omitted with `--pre-restore`.
+            let Some(remote) = remote else {
+                prune_safety_snapshots(&local_node, cutoff, dry_run)?;
+                return Ok(());
+            };
+
+            let remote_node = local_node
+                .config()
+                .remotes
+                .iter()
+                .find(|item| item.address == remote)
+                .ok_or(Error::NoSuchRemote(remote.to_string()))?;
+
+            let inventory = connect(&local_node, remote_node)?.list()?;
+            let to_delete = prune_set(local_node.name(), inventory, keep_full as usize, cutoff);
+
+            if dry_run {
+                for snapshot in &to_delete {
+                    out!("Would delete {}", snapshot);
+                }
+            } else if !to_delete.is_empty() {
+                let results = connect(&local_node, remote_node)?.prune(to_delete.clone())?;
+
+                for (snapshot, result) in to_delete.iter().zip(results) {
+                    match result {
+                        Ok(_) => info!("Deleted {}", snapshot),
+                        Err(e) => warn!("Keeping {}: {}", snapshot, e),
+                    }
+                }
+            }
+        }
+        Commands::CleanPartials { max_age } => {
+            let local_node = LocalNode::new(Mode::Client)?;
+
+            let max_age = max_age
+                .map(Duration::from)
+                .unwrap_or(local_node.partial_max_age());
+            let reclaimed = local_node.clean_partials(max_age, &HashSet::new())?;
+
+            out!(
+                "Deleted {} incomplete backup(s), reclaimed {} bytes",
+                reclaimed.files,
+                reclaimed.bytes
+            );
+        }
+        Commands::Quarantine { snapshots } => {
+            let local_node = LocalNode::new(Mode::Client)?;
+
+            for snapshot in snapshots {
+                let snapshot = Snapshot::try_from(snapshot.as_str())?;
+
+                local_node.quarantine(&snapshot)?;
+                info!("Deleted {}", snapshot);
+            }
+        }
+        Commands::MigrateLayout => {
+            let local_node = LocalNode::new(Mode::Client)?;
+
+            let migrated = local_node.migrate_layout()?;
+            info!("Migrated {} backups", migrated);
+        }
+        Commands::List {
+            remote: Some(address),
+            json,
+        } => {
+            let local_node = LocalNode::new(Mode::Client)?;
+
+            let remote_node = local_node
+                .config()
+                .remotes
+                .iter()
+                .find(|item| item.address == address)
+                .ok_or(Error::NoSuchRemote(address.to_string()))?;
+
+            remote_list(&local_node, remote_node, json)?;
+        }
+        Commands::List { remote: None, json } => {
+            let (snapshots, backups) = match AgentClient::connect() {
+                Some(mut agent_client) => (
+                    agent_client.all_snapshots()?,
+                    agent_client.all_backup_tiers()?,
+                ),
+                None => {
+                    let local_node = LocalNode::new(Mode::Client)?;
+                    (
+                        local_node.all_snapshots(None)?,
+                        local_node.all_backup_tiers(None)?,
+                    )
+                }
+            };
+
+            let mut snapshots: Vec<_> = snapshots
+                .into_iter()
+                .map(|snapshot| (snapshot, Tier::Primary))
+                .chain(backups)
+                .collect();
+            snapshots.sort_unstable_by(|(a, _), (b, _)| {
+                a.volume().cmp(&b.volume()).then(a.taken().cmp(&b.taken()))
+            });
+
+            // The configuration may be inaccessible when using the agent.
+            let node_config = NodeConfig::load().ok();
+            let on_disk = |snapshot: &Snapshot| {
+                node_config
+                    .as_ref()
+                    .filter(|node_config| node_config.node_name == snapshot.node_name())
+                    .map(|node_config| node_config.subvol_on_disk(snapshot.subvol()).to_string())
+                    .filter(|subvol| subvol != snapshot.subvol())
+            };
+
+            if json {
+                out!(
+                    "{}",
+                    serde_json::to_string_pretty(
+                        &snapshots
+                            .iter()
+                            .map(|(snapshot, tier)| snapshot_json(
+                                snapshot,
+                                [
+                                    ("tier", tier.to_string().into()),
+                                    ("subvol_on_disk", on_disk(snapshot).into()),
+                                ]
+                            ))
+                            .collect::<Vec<_>>()
+                    )?
+                );
+                return Ok(());
+            }
+
+            for (snapshot, tier) in snapshots {
+                let mut notes = Vec::new();
+                if let Some(subvol) = on_disk(&snapshot) {
+                    notes.push(format!("subvolume {}", subvol));
+                }
+                if let Tier::Archive = tier {
+                    notes.push(tier.to_string());
+                }
+
+                if notes.is_empty() {
+                    out!("{}", snapshot);
+                } else {
+                    out!("{} ({})", snapshot, notes.join(", "));
+                }
+            }
+        }
+        Commands::Status { max_age, json } => {
+            let node_config = NodeConfig::load()?;
+            let sync_state = load_sync_state();
+            let now = Utc::now().naive_utc();
+
+            let addresses: Vec<_> = node_config
+                .remotes
+                .iter()
+                .map(|remote_node| remote_node.address.to_string())
+                .collect();
+            let stale = match max_age {
+                Some(max_age) => {
+                    sync_state.stale_remotes(addresses.iter().map(String::as_str), max_age, now)
+                }
+                None => Vec::new(),
+            };
+
+            let mut results = Vec::new();
+            for address in &addresses {
+                let remote = sync_state.remotes.get(address).cloned().unwrap_or_default();
+                let is_stale = stale.contains(&address.as_str());
+
+                if json {
+                    results.push(status_json(address, &remote, is_stale));
+                } else if is_stale {
+                    out!("{}: {} (STALE)", address, remote.summary(now));
+                } else {
+                    out!("{}: {}", address, remote.summary(now));
+                }
+            }
+
+            if json {
+                out!("{}", serde_json::to_string_pretty(&results)?);
+            }
+
+            if let Some(max_age) = max_age.filter(|_| !stale.is_empty()) {
+                return Err(Error::StaleRemotes(stale.len(), max_age));
+            }
+        }
+        Commands::Doctor { json } => {
+            let (findings, local_node) = doctor::run();
+
+            let mut protection = BTreeMap::new();
+            match local_node {
+                Some(local_node) => {
+                    let layout = local_node.layout();
+                    for dir in [layout.snapshot_dir(), layout.backup_dir()] {
+                        if dir.exists() {
+                            protection
+                                .insert(dir.display().to_string(), system::is_immutable(dir)?);
+                        }
+                    }
+                }
+                None => warn!("Cannot inspect local node protection, see the failed checks"),
+            }
+
+            let mut reports = Vec::new();
+            for path in FailureReport::list(report::CLIENT_DIR)?
+                .into_iter()
+                .chain(FailureReport::list(report::SERVER_DIR)?)
+            {
+                reports.push(FailureReport::load(path)?);
+            }
+
+            reports.sort_unstable_by_key(|report| report.failed);
+
+            if json {
+                out!(
+                    "{}",
+                    serde_json::to_string_pretty(&serde_json::json!({
+                        "checks": findings
+                            .iter()
+                            .map(|finding| serde_json::json!({
+                                "check": finding.check,
+                                "status": finding.status.to_string(),
+                                "message": finding.message,
+                            }))
+                            .collect::<Vec<_>>(),
+                        "protection": protection,
+                        "failures": reports,
+                    }))?
+                );
+            } else {
+                for finding in &findings {
+                    out!(
+                        "[{}] {}: {}",
+                        finding.status,
+                        finding.check,
+                        finding.message
+                    );
+                }
+
+                for (dir, is_immutable) in protection {
+                    out!(
+                        "{}: {}",
+                        dir,
+                        if is_immutable { "immutable" } else { "mutable" }
+                    );
+                }
+
+                for report in reports {
+                    out!(
+                        "{} {} ({}): {}",
+                        report
+                            .failed
+                            .map(|failed| failed.to_string())
+                            .unwrap_or_default(),
+                        report.remote_node.as_deref().unwrap_or("unknown"),
+                        report.address,
+                        report.error.first().map(String::as_str).unwrap_or_default()
+                    );
+                }
+            }
+
+            let failed = findings
+                .iter()
+                .filter(|finding| finding.status == Status::Fail)
+                .count();
+            if failed > 0 {
+                return Err(Error::ChecksFailed(failed));
+            }
+        }
+        Commands::Agent => {
+            let mut agent = Agent::bind()?;
+
+            info!("Listening on {}", agent::SOCKET_PATH);
+
+            loop {
+                if let Err(e) = agent.serve_next() {
+                    warn!("Cannot serve agent client: {}", e);
+                }
+            }
+        }
+        Commands::Config { command } => match command {
+            ConfigCommands::Validate { path } => {
+                let path = path.unwrap_or_else(NodeConfig::path);
+                let node_config = NodeConfig::load_from(&path)?;
+
+                for (node_name, e) in node_config.grant_errors() {
+                    warn!("Grant {}: {}", node_name, e);
+                }
+
+                out!("{} is valid", path.display());
+            }
+            ConfigCommands::RestoreBackup => {
+                NodeConfig::restore_backup()?;
+                info!(
+                    "Restored the previous configuration, the replaced one is kept at {}",
+                    NodeConfig::backup_path().display()
+                );
+            }
+        },
+        Commands::Completions { shell } => completions::generate(shell)?,
+        Commands::CompleteSubvols => completions::print(Candidates::Subvols),
+        Commands::CompleteRemotes => completions::print(Candidates::Remotes),
+        Commands::CompleteNodes => completions::print(Candidates::Nodes),
+    }
+
+    Ok(())
+}
+
+fn main() {
+    output::ignore_sigpipe();
+
+    let result = logic();
+    if let Err(e) = &result {
+        if verbosity::get() == Verbosity::Verbose {
+            verbosity::emit(output::Level::Error, format_args!("{}", e));
+        } else {
+            verbosity::emit(output::Level::Error, format_args!("Error: {}", e));
+        }
+
+        if let Error::HbakNetwork(NetworkError::RemoteError(RemoteError::InstanceConflict)) = e {
+            warn!("Another machine uses the same node name. Re-initialize this node with a new name or run `hbak grant --rebind <node>` on the remote node if this machine replaced the original one");
+        }
+
+        if let Error::HbakNetwork(NetworkError::RemoteError(RemoteError::ImplausibleTimestamp(_))) =
+            e
+        {
+            warn!("Check the system clock. Snapshots with implausible timestamps can be removed using `hbak quarantine <snapshot>`");
+        }
+    }
+
+    let suppressed = output::suppressed();
+    if suppressed > 0 {
+        warn!("{} message(s) could not be written", suppressed);
+    }
+
+    if let Err(e) = result {
+        process::exit(e.exit_code());
+    }
+}
+
+/// Prints the effects of revoking the grant of a remote node.
+/// Returns the node name followed by the label if there is one,
+/// matching [`RemoteNodeAuth::display_name`].
+fn grant_name(node_name: &str, label: Option<&str>) -> String {
+    match label {
+        Some(label) => format!("{} ({})", node_name, label),
+        None => node_name.to_string(),
+    }
+}
+
+fn print_revoke_impact(impact: &RevokeImpact) {
+    let never = || String::from("never");
+
+    match &impact.label {
+        Some(label) => out!("Node {} ({} grant)", impact.node_name, label),
+        None => out!("Node {}", impact.node_name),
+    }
+    out!(
+        "  Last session: {}",
+        impact
+            .last_session
+            .map(|last_session| last_session.to_string())
+            .unwrap_or_else(never)
+    );
+    out!(
+        "  Last push: {}",
+        impact
+            .last_push
+            .map(|last_push| last_push.to_string())
+            .unwrap_or_else(never)
+    );
+
+    for (permission, volumes) in [("Push", &impact.push), ("Pull", &impact.pull)] {
+        for volume in volumes {
+            let others = if volume.is_exclusive() {
+                String::from("no other node")
+            } else {
+                volume.others.join(", ")
+            };
+
+            out!(
+                "  {} {}: {} stored backup(s), latest {}, also granted to {}",
+                permission,
+                volume.volume,
+                volume.backups,
+                volume
+                    .latest
+                    .map(|latest| latest.to_string())
+                    .unwrap_or_else(never),
+                others
+            );
+        }
+    }
+}
+
+/// Reads the hexadecimal verifier and key and the optional instance identifier
+/// from a file in the format written by `export-pass`.
+fn read_pass_file(path: PathBuf) -> Result<(String, String, Option<String>)> {
+    let contents = fs::read_to_string(&path)?;
+
+    let field = |prefix: &str, name: &'static str| {
+        contents
+            .lines()
+            .find_map(|line| line.strip_prefix(prefix))
+            .map(|value| value.trim().to_string())
+            .ok_or(Error::MissingSecret(path.clone(), name))
+    };
+
+    Ok((
+        field("Verifier:", "verifier")?,
+        field("Key:", "key")?,
+        field("Instance:", "instance").ok(),
+    ))
+}
+
+/// Prints the identifier of a new snapshot to stdout
+/// or a notice that the snapshot of the subvolume was skipped.
+fn report_snapshot(subvol: &str, snapshot: Option<Snapshot>, promotion: Option<Promotion>) {
+    match snapshot {
+        Some(snapshot) => {
+            if let Some(promotion) = promotion {
+                info!(
+                    "Took a full snapshot of {} instead of an incremental one, {}",
+                    subvol, promotion
+                );
+            }
+
+            out!("{}", snapshot)
+        }
+        None => info!("Skipping {}, its latest snapshot is too recent", subvol),
+    }
+}
+
+/// Formats a verifier and key for `grant --from-file`
+/// along with the instance identifier to bind to, if any.
+fn format_export(verifier: &[u8], key: &[u8], instance_id: Option<&str>) -> String {
+    let mut export = format!(
+        "Verifier: {}\nKey:      {}\n",
+        hex::encode(verifier),
+        hex::encode(key)
+    );
+    if let Some(instance_id) = instance_id {
+        export.push_str(&format!("Instance: {}\n", instance_id));
+    }
+
+    export
+}
+
+/// Replaces the passphrase stored in the configuration after verifying the current one.
+/// The new credentials are either rotated on all remote nodes
+/// or printed for granting them manually.
+fn change_passphrase(allow_weak: bool, rotate: bool, yes: bool) -> Result<()> {
+    let mut node_config = NodeConfig::load()?;
+    let old_passphrase = node_config
+        .passphrase
+        .clone()
+        .ok_or(Error::PassphraseNotStored)?;
+
+    if rpassword::prompt_password("Enter current encryption passphrase: ")? != old_passphrase {
+        return Err(Error::IncorrectPassphrase);
+    }
+
+    let new_passphrase = prompt_new_passphrase(allow_weak)?;
+    if new_passphrase == old_passphrase {
+        return Err(Error::UnchangedPassphrase);
+    }
+
+    warn!("Existing snapshots and backups remain encrypted with the old passphrase.");
+    warn!("Keep the old passphrase, restoring them is impossible without it.");
+
+    if !yes {
+        warn!("Change the passphrase? [y/N]");
+
+        let mut answer = String::new();
+        io::stdin().read_line(&mut answer)?;
+
+        if !answer.trim().eq_ignore_ascii_case("y") {
+            info!("Not changing the passphrase");
+            return Ok(());
+        }
+    }
+
+    let (verifier, key) = system::hash_passphrase(new_passphrase.clone())?;
+
+    // Rotating authenticates using the old passphrase, so it has to happen first.
+    let mut failed = Vec::new();
+    if rotate {
+        let local_node = LocalNode::new(Mode::Client)?;
+
+        for remote_node in &local_node.config().remotes {
+            let credentials = Credentials {
+                verifier: verifier.clone(),
+                key: key.clone(),
+            };
+
+            match connect(&local_node, remote_node)
+                .and_then(|stream_conn| Ok(stream_conn.rotate_auth(credentials)?))
+            {
+                Ok(_) => info!("Rotated credentials on {}", remote_node.address),
+                Err(e) => {
+                    warn!(
+                        "Unable to rotate credentials on {}: {}",
+                        remote_node.address, e
+                    );
+                    failed.push(remote_node.address.to_string());
+                }
+            }
+        }
+    }
+
+    node_config.passphrase = Some(new_passphrase);
+    node_config.save()?;
+
+    info!("Changed the encryption passphrase");
+
+    let regrant: Vec<_> = if rotate {
+        failed.clone()
+    } else {
+        node_config
+            .remotes
+            .iter()
+            .map(|remote_node| remote_node.address.to_string())
+            .collect()
+    };
+
+    if !regrant.is_empty() {
+        warn!(
+            "Grant {} the credentials below on: {}",
+            node_config.node_name,
+            regrant.join(", ")
+        );
+        output::print(format_args!(
+            "{}",
+            format_export(&verifier, &key, node_config.instance_id.as_deref())
+        ));
+    }
+
+    if failed.is_empty() {
+        Ok(())
+    } else {
+        Err(Error::RotationsFailed(failed.len()))
+    }
+}
+
+/// Prompts for a new passphrase twice, refusing empty or mismatching input.
+/// Passphrases estimated to be weak are refused unless `allow_weak` is set.
+fn prompt_new_passphrase(allow_weak: bool) -> Result<String> {
+    let passphrase = rpassword::prompt_password("Enter new encryption passphrase: ")?;
+    if passphrase.is_empty() {
+        return Err(Error::EmptyPassphrase);
+    }
+
+    let entropy = estimate_entropy(&passphrase);
+    if entropy < MIN_PASSPHRASE_ENTROPY {
+        if !allow_weak {
+            return Err(Error::WeakPassphrase(entropy));
+        }
+
+        warn!(
+            "Passphrase is weak, estimated entropy is only {} bits",
+            entropy
+        );
+    }
+
+    if rpassword::prompt_password("Confirm new encryption passphrase: ")? != passphrase {
+        return Err(Error::PassphraseMismatch);
+    }
+
+    Ok(passphrase)
+}
+
+/// Roughly estimates the entropy of a passphrase in bits
+/// from its length and the character classes it uses.
+/// Repetitions of the previous character don't count towards the length.
+fn estimate_entropy(passphrase: &str) -> u32 {
+    let mut pool = 0;
+    for (class, size) in [
+        (char::is_ascii_lowercase as fn(&char) -> bool, 26),
+        (char::is_ascii_uppercase, 26),
+        (char::is_ascii_digit, 10),
+        (char::is_ascii_punctuation, 33),
+        (|c: &char| c.is_whitespace(), 1),
+        (|c: &char| !c.is_ascii(), 100),
+    ] {
+        if passphrase.chars().any(|c| class(&c)) {
+            pool += size;
+        }
+    }
+
+    let mut len = 0;
+    let mut previous = None;
+    for c in passphrase.chars() {
+        if previous != Some(c) {
+            len += 1;
+        }
+        previous = Some(c);
+    }
+
+    (len as f64 * f64::from(pool).log2()) as u32
+}
+
+/// Decodes a hexadecimal verifier or key, ensuring that it is 32 bytes long.
+fn decode_secret(name: &'static str, hex: &str) -> Result<Vec<u8>> {
+    let secret = hex::decode(hex.trim()).map_err(|e| Error::MalformedSecret(name, e))?;
+
+    if secret.len() != 32 {
+        return Err(Error::SecretLength(name, secret.len()));
+    }
+
+    Ok(secret)
+}
+
+/// Parses an RFC 3339 or `%Y%m%d%H%M%S` (UTC) timestamp.
+fn parse_timestamp(s: &str) -> std::result::Result<NaiveDateTime, chrono::ParseError> {
+    match DateTime::parse_from_rfc3339(s) {
+        Ok(timestamp) => Ok(timestamp.naive_utc()),
+        Err(_) => NaiveDateTime::parse_from_str(s, "%Y%m%d%H%M%S"),
+    }
+}
+
+fn parse_rename(s: &str) -> std::result::Result<(String, String), String> {
+    match s.split_once('=') {
+        Some((old, new)) if !old.is_empty() && !new.is_empty() => {
+            Ok((old.to_string(), new.to_string()))
+        }
+        _ => Err(String::from("expected <old>=<new>")),
+    }
+}
+
+/// Records the specified error in the failure report and writes it to disk.
+/// Records the outcome of a synchronization with the specified remote node
+/// in the [`SyncState`]. Dry runs aren't recorded. An unreadable state file is replaced.
+fn record_sync(address: &RemoteAddress, result: &Result<Option<SyncStats>>) {
+    let now = Utc::now().naive_utc();
+    let mut sync_state = load_sync_state();
+
+    match result {
+        Ok(Some(stats)) => sync_state.record_success(&address.to_string(), now, stats),
+        Ok(None) => return,
+        Err(e) => sync_state.record_failure(&address.to_string(), now, e.to_string()),
+    }
+
+    if let Err(e) = sync_state.save() {
+        warn!("Cannot write sync state: {}", e);
+    }
+}
+
+/// Loads the [`SyncState`], treating an unreadable state file as empty.
+fn load_sync_state() -> SyncState {
+    SyncState::load().unwrap_or_else(|e| {
+        warn!("Ignoring unreadable sync state: {}", e);
+        SyncState::default()
+    })
+}
+
+fn save_report(mut report: FailureReport, e: &Error) {
+    report.fail(e);
+
+    match report.save(report::CLIENT_DIR) {
+        Ok(path) => warn!("Failure report written to {}", path.display()),
+        Err(e) => warn!("Cannot write failure report: {}", e),
+    }
+}
+
+fn connect(local_node: &LocalNode, remote_node: &RemoteNode) -> Result<StreamConn<Idle>> {
+    let auth_conn = AuthConn::connect(&remote_node.address, local_node.keepalive())?
+        .with_plaintext(local_node.unix_plaintext());
+    let stream_conn = auth_conn
+        .secure_stream(
+            local_node.name().to_string(),
+            local_node.config().instance_id.clone(),
+            remote_node.address.to_string(),
+            local_node.passphrase()?,
+        )?
+        .with_stall_timeout(local_node.stall_timeout())
+        .with_heartbeat_interval(local_node.heartbeat_interval())
+        .with_rekey_interval(local_node.rekey_interval());
+
+    info!(
+        "Authentication to and of {} successful",
+        remote_node.address
+    );
+
+    Ok(stream_conn)
+}
+
+/// How often and after which delay connecting to a remote node is retried
+/// after transient failures, see [`is_transient`].
+struct Retry {
+    retries: u32,
+    delay: Duration,
+}
+
+impl Retry {
+    fn new(retries: u32, delay: HumanDuration) -> Self {
+        Self {
+            retries,
+            delay: delay.0,
+        }
+    }
+
+    /// Calls `connect` until it succeeds, fails permanently or the retries
+    /// are used up, doubling the delay after each attempt.
+    fn run<T, F>(&self, address: &RemoteAddress, mut connect: F) -> Result<T>
+    where
+        F: FnMut() -> Result<T>,
+    {
+        let mut remaining = self.retries;
+        let mut delay = self.delay;
+
+        loop {
+            match connect() {
+                Err(e) if remaining > 0 && is_transient(&e) => {
+                    warn!(
+                        "Unable to connect to {}: {}, retrying in {} ({} retry(s) left)",
+                        address,
+                        e,
+                        HumanDuration(delay),
+                        remaining
+                    );
+
+                    thread::sleep(delay);
+                    remaining -= 1;
+                    delay = delay.saturating_mul(2);
+                }
+                result => return result,
+            }
+        }
+    }
+}
+
+/// Reports whether the specified error is a connection failure that may resolve itself,
+/// e.g. because the remote node is rebooting or its name can't be resolved yet.
+fn is_transient(e: &Error) -> bool {
+    let is_transient_io = |e: &io::Error| {
+        matches!(
+            e.kind(),
+            io::ErrorKind::ConnectionRefused
+                | io::ErrorKind::ConnectionReset
+                | io::ErrorKind::ConnectionAborted
+                | io::ErrorKind::NotConnected
+                | io::ErrorKind::TimedOut
+                | io::ErrorKind::WouldBlock
+                | io::ErrorKind::UnexpectedEof
+        )
+    };
+
+    match e {
+        Error::HbakNetwork(NetworkError::NoAddrs | NetworkError::Resolve(_)) => true,
+        Error::HbakNetwork(NetworkError::IoError(e)) => is_transient_io(e),
+        Error::HbakNetwork(NetworkError::Bincode(e)) => match &**e {
+            bincode::ErrorKind::Io(e) => is_transient_io(e),
+            _ => false,
+        },
+        _ => false,
+    }
+}
+
+/// The options of `hbak synchronize` that apply to all remote nodes.
+struct SyncOptions<'a> {
+    push: &'a [String],
+    pull: &'a [String],
+    dry_run: bool,
+    limit_rate: Option<ByteSize>,
+    full_reconcile: bool,
+    retry: Retry,
+}
+
+fn sync(
+    local_node: &LocalNode,
+    remote_node: &RemoteNode,
+    options: &SyncOptions,
+    report: &Mutex<FailureReport>,
+) -> Result<Option<SyncStats>> {
+    let stream_conn = options
+        .retry
+        .run(&remote_node.address, || connect(local_node, remote_node))
+        .inspect_err(|e| {
+            if let Error::HbakNetwork(NetworkError::RemoteError(
+                RemoteError::IncompatibleVersion(version),
+            )) = e
+            {
+                report.lock().unwrap().remote_protocol_version = Some(*version);
+            }
+        })?
+        .with_rate_limit(
+            options
+                .limit_rate
+                .or(remote_node.rate_limit)
+                .map(|rate| rate.0),
+        );
+
+    report.lock().unwrap().authenticated(&stream_conn);
+
+    let push = narrow_specs(&remote_node.push, options.push)?;
+    let pull = narrow_specs(&remote_node.pull, options.pull)?;
+
+    let events = |event: SyncEvent| match event {
+        SyncEvent::Queued(snapshot) => {
+            info!(
+                "Queueing {} for transmission to {}",
+                snapshot, remote_node.address
+            );
+            report.lock().unwrap().queued.push(snapshot.clone());
+        }
+        SyncEvent::Receiving(snapshot) => {
+            info!("Receiving {} from {}", snapshot, remote_node.address);
+            report.lock().unwrap().start_receiving(snapshot);
+        }
+        SyncEvent::Received(snapshot) => {
+            info!("Received {} from {}", snapshot, remote_node.address);
+            report.lock().unwrap().finish_receiving(snapshot);
+        }
+        SyncEvent::Invalid(e) => warn!("Rejecting snapshot from {}: {}", remote_node.address, e),
+        SyncEvent::Accepted(_) | SyncEvent::Rejected(_, _) => {}
+    };
+
+    let sync_session = SyncSession::new(local_node, SyncRole::Initiator, push, pull, events)
+        .with_full_reconcile(options.full_reconcile);
+    let plan = sync_session.initiate(stream_conn)?;
+
+    if options.dry_run {
+        for snapshot in plan.queue() {
+            out!(
+                "{} -> {}: {}",
+                snapshot,
+                remote_node.address,
+                local_node.estimate_send_size(snapshot)?
+            );
+        }
+
+        return Ok(None);
+    }
+
+    let stats = match sync_session.data_sync(plan, &Window::default()) {
+        Ok(stats) => {
+            if let Some(wrap_up) = &stats.wrap_up {
+                info!(
+                    "Remote {} closes its backup window at {}, wrapped up early",
+                    remote_node.address, wrap_up.deadline
+                );
+
+                for snapshot in &wrap_up.skipped {
+                    warn!("Skipped {}, it wouldn't complete in time", snapshot);
                 }
             }
+
+            for (snapshot, e) in stats.rejected() {
+                warn!(
+                    "Skipped {}, rejected by {}: {}",
+                    snapshot, remote_node.address, e
+                );
+            }
+
+            Some(stats)
+        }
+        Err(NetworkError::RemoteError(RemoteError::ShuttingDown)) => None,
+        Err(e) => return Err(e.into()),
+    };
+    let interrupted = stats.is_none();
+
+    let audit = sync_session.audit();
+
+    for snapshot in &audit.unexpected {
+        warn!(
+            "Received unexpected {} from {}, it doesn't fill a gap",
+            snapshot, remote_node.address
+        );
+    }
+    for snapshot in &audit.duplicates {
+        warn!(
+            "Remote {} sent {} more than once",
+            remote_node.address, snapshot
+        );
+    }
+    for snapshot in &audit.missing {
+        warn!(
+            "Reception of {} from {} didn't complete",
+            snapshot, remote_node.address
+        );
+    }
+
+    // Transmissions cut by a shutting down remote node are retried later.
+    if !audit.unexpected.is_empty()
+        || !audit.duplicates.is_empty()
+        || (!audit.missing.is_empty() && !interrupted)
+    {
+        return Err(Error::Degraded(remote_node.address.to_string()));
+    }
+
+    if interrupted {
+        return Err(Error::PartialSync(remote_node.address.to_string()));
+    }
+
+    Ok(stats)
+}
+
+/// Restricts the configured [`VolumeSpec`]s to the ones passed on the command line
+/// that they cover. Returns the configured ones if no restriction is passed.
+fn narrow_specs(specs: &[VolumeSpec], limit: &[String]) -> Result<Vec<VolumeSpec>> {
+    if limit.is_empty() {
+        return Ok(specs.to_vec());
+    }
+
+    Ok(VolumeSpec::try_from_bulk(limit.to_vec())?
+        .into_iter()
+        .filter(|limit| specs.iter().any(|spec| spec.covers(limit)))
+        .collect())
+}
+
+/// Returns the backups of the volumes of the specified node to delete
+/// from the specified inventory of a remote node.
+///
+/// Backups are grouped into chains of a full backup and the incremental backups
+/// taken after it. A chain is kept if its full backup is one of the `keep_full`
+/// most recent ones of its volume or if any of its members was taken after `cutoff`.
+fn prune_set(
+    node_name: &str,
+    inventory: Vec<Snapshot>,
+    keep_full: usize,
+    cutoff: NaiveDateTime,
+) -> Vec<Snapshot> {
+    let mut volumes: BTreeMap<Volume, Vec<Snapshot>> = BTreeMap::new();
+    for snapshot in inventory {
+        if snapshot.node_name() == node_name {
+            volumes.entry(snapshot.volume()).or_default().push(snapshot);
+        }
+    }
+
+    let mut to_delete = Vec::new();
+    for mut snapshots in volumes.into_values() {
+        snapshots.sort_unstable_by_key(|snapshot| snapshot.taken());
+
+        let mut chains: Vec<Vec<Snapshot>> = Vec::new();
+        for snapshot in snapshots {
+            match chains.last_mut() {
+                Some(chain) if snapshot.is_incremental() => chain.push(snapshot),
+                _ => chains.push(vec![snapshot]),
+            }
+        }
+
+        let fulls = chains
+            .iter()
+            .filter(|chain| !chain[0].is_incremental())
+            .count();
+        let mut seen_fulls = 0;
+
+        for chain in chains {
+            if !chain[0].is_incremental() {
+                seen_fulls += 1;
+            }
+
+            let recent_full = !chain[0].is_incremental() && fulls - seen_fulls < keep_full;
+            let recent = chain.iter().any(|snapshot| snapshot.taken() > cutoff);
+
+            if !recent_full && !recent {
+                to_delete.extend(chain);
+            }
+        }
+    }
+
+    to_delete
+}
+
+/// Deletes the safety snapshots taken by `hbak restore` at or before `cutoff`.
+fn prune_safety_snapshots(
+    local_node: &LocalNode,
+    cutoff: NaiveDateTime,
+    dry_run: bool,
+) -> Result<()> {
+    for safety_snapshot in local_node
+        .safety_snapshots()?
+        .into_iter()
+        .filter(|safety_snapshot| safety_snapshot.taken <= cutoff)
+    {
+        if dry_run {
+            out!("Would delete {}", safety_snapshot.path.display());
+        } else {
+            local_node.delete_safety_snapshot(&safety_snapshot)?;
+            info!("Deleted {}", safety_snapshot.path.display());
         }
     }
 
     Ok(())
 }
 
-fn main() {
-    match logic() {
-        Ok(_) => {}
-        Err(e) => eprintln!("Error: {}", e),
+/// Returns the JSON representation of a snapshot extended by the specified fields.
+fn snapshot_json<const N: usize>(
+    snapshot: &Snapshot,
+    fields: [(&str, serde_json::Value); N],
+) -> serde_json::Value {
+    let mut value = serde_json::json!(SnapshotJson::from(snapshot));
+    if let Some(object) = value.as_object_mut() {
+        for (key, field) in fields {
+            object.insert(key.to_string(), field);
+        }
     }
+
+    value
 }
 
-fn sync(
+/// Returns the JSON representation of the synchronization state of a remote node.
+fn status_json(address: &str, remote: &RemoteSyncState, is_stale: bool) -> serde_json::Value {
+    serde_json::json!({
+        "address": address,
+        "last_attempt": remote.last_attempt.map(|time| time.and_utc()),
+        "last_success": remote.last_success.map(|time| time.and_utc()),
+        "last_error": remote.last_error,
+        "bytes_pushed": remote.bytes_pushed,
+        "bytes_pulled": remote.bytes_pulled,
+        "stale": is_stale,
+    })
+}
+
+fn remote_list(local_node: &LocalNode, remote_node: &RemoteNode, json: bool) -> Result<()> {
+    let stream_conn = connect(local_node, remote_node)?;
+    let mut snapshots = stream_conn.list()?;
+
+    let local: HashSet<_> = local_node
+        .all_snapshots(None)?
+        .into_iter()
+        .chain(local_node.all_backups(None)?)
+        .collect();
+
+    snapshots.sort_unstable_by(|a, b| a.volume().cmp(&b.volume()).then(a.taken().cmp(&b.taken())));
+
+    if json {
+        out!(
+            "{}",
+            serde_json::to_string_pretty(
+                &snapshots
+                    .iter()
+                    .map(|snapshot| snapshot_json(
+                        snapshot,
+                        [("remote_only", (!local.contains(snapshot)).into())]
+                    ))
+                    .collect::<Vec<_>>()
+            )?
+        );
+        return Ok(());
+    }
+
+    for snapshot in snapshots {
+        if local.contains(&snapshot) {
+            out!("{}", snapshot);
+        } else {
+            out!("{} (remote only)", snapshot);
+        }
+    }
+
+    Ok(())
+}
+
+/// Replaces the copies of the specified backups of own volumes on the specified
+/// remote node with the local ones, e.g. after re-encrypting them.
+/// Returns the number of replaced copies.
+///
+/// Remote copies are immutable, so each one is deleted before pushing
+/// the local backup again. Backups missing on the remote node within the range
+/// of those it holds are pushed as well, so that interrupted runs can be resumed.
+/// Copies are replaced in chain order and the first failure aborts the operation
+/// because the remote node may be left without the deleted copy.
+fn replace_remote_copies(
     local_node: &LocalNode,
     remote_node: &RemoteNode,
-    push: &[String],
-    pull: &[String],
-) -> Result<()> {
-    let addrs = match remote_node.address.to_socket_addrs() {
-        Ok(addrs) => addrs,
-        Err(_) => (remote_node.address.as_str(), DEFAULT_PORT).to_socket_addrs()?,
-    };
+    backups: &[Snapshot],
+) -> Result<usize> {
+    let address = &remote_node.address;
+
+    let held: HashSet<_> = connect(local_node, remote_node)?
+        .list()?
+        .into_iter()
+        .collect();
 
-    let auth_conn = AuthConn::new_first_success(addrs)?;
-    let stream_conn = auth_conn.secure_stream(
-        local_node.name().to_string(),
-        remote_node.address.to_string(),
-        &local_node.config().passphrase,
-    )?;
+    let mut oldest = HashMap::new();
+    for snapshot in &held {
+        let taken = oldest.entry(snapshot.volume()).or_insert(snapshot.taken());
+        *taken = cmp::min(*taken, snapshot.taken());
+    }
 
-    eprintln!(
-        "Authentication to and of {} successful",
-        remote_node.address
-    );
+    // Backups older than the oldest copy have been pruned on purpose.
+    let to_replace: Vec<_> = backups
+        .iter()
+        .filter(|backup| {
+            oldest
+                .get(&backup.volume())
+                .is_some_and(|oldest| backup.taken() >= *oldest)
+        })
+        .cloned()
+        .collect();
+
+    let mut replaced = 0;
+    for backup in sync::order_queue([to_replace]) {
+        if held.contains(&backup) {
+            let results = connect(local_node, remote_node)?.prune(vec![backup.clone()])?;
+            if let Some(Err(e)) = results.into_iter().next() {
+                warn!("Keeping {} on {}: {}", backup, address, e);
+                continue;
+            }
+        }
 
-    let mut local_sync_info = SyncInfo {
+        let stats = push_backups(local_node, remote_node, vec![backup.clone()])?;
+        match stats.sent.first().map(|transfer| &transfer.outcome) {
+            Some(TransferOutcome::Completed) => {
+                info!("Replaced {} on {}", backup, address);
+                replaced += 1;
+            }
+            // Deleting the copy didn't take effect.
+            Some(TransferOutcome::Rejected(RemoteError::Immutable)) => {
+                warn!("Keeping {} on {}: Not deleted", backup, address);
+            }
+            Some(TransferOutcome::Rejected(e)) => {
+                return Err(Error::ReplaceFailed(
+                    backup,
+                    address.to_string(),
+                    e.to_string(),
+                ));
+            }
+            _ => {
+                return Err(Error::ReplaceFailed(
+                    backup,
+                    address.to_string(),
+                    String::from("Transmission incomplete"),
+                ));
+            }
+        }
+    }
+
+    Ok(replaced)
+}
+
+/// Pushes the specified local backups to the specified remote node as they were stored
+/// rather than sending the snapshots, so that they can replace the remote copies.
+fn push_backups(
+    local_node: &LocalNode,
+    remote_node: &RemoteNode,
+    backups: Vec<Snapshot>,
+) -> Result<SyncStats> {
+    // No volumes are announced so that the remote node has nothing to send.
+    let local_sync_info = SyncInfo {
         volumes: HashMap::new(),
+        patterns: Vec::new(),
+        receive_protocol: local_node.send_support().receive,
+        chunk_size: local_node.chunk_size(),
+        compression: true,
+        held: None,
     };
+    let (stream_conn, _) = connect(local_node, remote_node)?.meta_sync(local_sync_info)?;
 
-    for volume in remote_node
-        .pull
-        .iter()
-        .filter(|volume| volume.node_name() != local_node.name())
-        .filter(|volume| pull.is_empty() || pull.contains(&volume.to_string()))
-    {
-        let latest_snapshots = local_node.latest_snapshots(volume.clone())?;
-        local_sync_info
-            .volumes
-            .insert(volume.clone(), latest_snapshots);
+    let mut tx = Vec::new();
+    for backup in backups {
+        tx.push((local_node.read_backup(&backup)?, backup));
     }
 
-    let (stream_conn, remote_sync_info) = stream_conn.meta_sync(local_sync_info)?;
+    Ok(stream_conn.data_sync(
+        tx,
+        |_| Err::<Empty, _>(RemoteError::AccessDenied),
+        |_, _| Ok(()),
+        |_| {},
+    )?)
+}
 
-    let mut tx = Vec::new();
-    for (volume, latest_snapshots) in remote_sync_info
-        .volumes
-        .into_iter()
-        .filter(|(volume, _)| remote_node.push.contains(volume))
-        .filter(|(volume, _)| push.is_empty() || push.contains(&volume.to_string()))
-    {
-        // Full backup: Remote is out of date.
-        for snapshot in local_node.all_full_after(volume.clone(), latest_snapshots.last_full)? {
-            let r = local_node.export(&snapshot)?;
-            tx.push((r, snapshot));
+/// Prints the state of each volume exchanged with the specified remote node:
+/// The snapshots it is missing of the volumes pushed to it
+/// and the snapshots the local node is missing of the volumes pulled from it.
+fn diff(local_node: &LocalNode, remote_node: &RemoteNode) -> Result<()> {
+    let address = &remote_node.address;
+
+    // Nothing is pulled so that the remote node has nothing to send
+    // and the session can be ended right after the metadata exchange.
+    let sync_session = SyncSession::new(
+        local_node,
+        SyncRole::Initiator,
+        remote_node.push.clone(),
+        Vec::new(),
+        |_: SyncEvent| {},
+    );
+    let plan = sync_session.initiate(connect(local_node, remote_node)?)?;
+
+    let mut volumes: BTreeMap<Volume, Vec<&Snapshot>> = plan
+        .remote_volumes()
+        .keys()
+        .map(|volume| (volume.clone(), Vec::new()))
+        .collect();
+    for snapshot in plan.queue() {
+        volumes.entry(snapshot.volume()).or_default().push(snapshot);
+    }
+
+    for (volume, mut missing) in volumes {
+        missing.sort_unstable_by_key(|snapshot| snapshot.taken());
+
+        let remote_latest = plan
+            .remote_volumes()
+            .get(&volume)
+            .map(|latest| cmp::max(latest.last_full, latest.last_incremental))
+            .unwrap_or(NaiveDateTime::MIN);
+        let local_latest = local_node.latest_snapshots(volume.clone())?;
+        let local_latest = cmp::max(local_latest.last_full, local_latest.last_incremental);
+
+        if missing.is_empty() && local_latest == NaiveDateTime::MIN {
+            out!(
+                "{} push {}: remote is newer, no local snapshots",
+                address,
+                volume
+            );
+        } else if missing.is_empty() && remote_latest > local_latest {
+            out!(
+                "{} push {}: remote is newer by {} day(s)",
+                address,
+                volume,
+                (remote_latest - local_latest).num_days()
+            );
+        } else {
+            out!(
+                "{} push {}: {}",
+                address,
+                volume,
+                lag(&missing, remote_latest)
+            );
+        }
+
+        for snapshot in missing {
+            out!("  {}", snapshot);
         }
+    }
+
+    sync_session.finish(plan)?;
+
+    if remote_node.pull.is_empty() {
+        return Ok(());
+    }
 
-        // Incremental backup: Remote is out of date.
-        for snapshot in
-            local_node.all_incremental_after(volume, latest_snapshots.last_incremental)?
+    let mut volumes: BTreeMap<Volume, Vec<Snapshot>> = BTreeMap::new();
+    for snapshot in connect(local_node, remote_node)?.list()? {
+        let volume = snapshot.volume();
+        if volume.node_name() != local_node.name()
+            && remote_node.pull.iter().any(|spec| spec.matches(&volume))
         {
-            let r = local_node.export(&snapshot)?;
-            tx.push((r, snapshot));
+            volumes.entry(volume).or_default().push(snapshot);
         }
     }
 
-    for (_, snapshot) in &tx {
-        eprintln!(
-            "Queueing {} for transmission to {}",
-            snapshot, remote_node.address
+    for (volume, available) in volumes {
+        let held = local_node.all_backups(Some(&volume))?;
+        let local_latest = held
+            .iter()
+            .map(|backup| backup.taken())
+            .max()
+            .unwrap_or(NaiveDateTime::MIN);
+
+        let mut missing: Vec<_> = available
+            .iter()
+            .filter(|snapshot| snapshot.taken() > local_latest && !held.contains(snapshot))
+            .collect();
+        missing.sort_unstable_by_key(|snapshot| snapshot.taken());
+
+        out!(
+            "{} pull {}: {}",
+            address,
+            volume,
+            lag(&missing, local_latest)
         );
     }
 
-    let rx_setup =
-        |snapshot: &Snapshot| {
-            if !remote_node.pull.iter().any(|volume| {
-                snapshot.is_of_volume(volume) && volume.node_name() != local_node.name()
-            }) {
-                return Err(RemoteError::AccessDenied);
-            }
+    Ok(())
+}
 
-            if snapshot.backup_path(Mode::Client).exists() {
-                return Err(RemoteError::Immutable);
+/// Describes how far the receiving node of a volume is behind
+/// given the snapshots it is missing and the time its latest one was taken.
+fn lag(missing: &[&Snapshot], latest: NaiveDateTime) -> String {
+    match missing.iter().map(|snapshot| snapshot.taken()).max() {
+        None => String::from("up to date"),
+        Some(_) if latest == NaiveDateTime::MIN => {
+            format!("missing all {} snapshot(s)", missing.len())
+        }
+        Some(newest) => format!(
+            "behind by {} snapshot(s) / {} day(s)",
+            missing.len(),
+            (newest - latest).num_days()
+        ),
+    }
+}
+
+fn remote_verify(
+    local_node: &LocalNode,
+    remote_node: &RemoteNode,
+    sample: usize,
+    json: bool,
+) -> Result<()> {
+    let backups: Vec<_> = local_node
+        .all_backups(None)?
+        .into_iter()
+        .filter(|backup| {
+            remote_node
+                .push
+                .iter()
+                .chain(&remote_node.pull)
+                .any(|spec| spec.matches(&backup.volume()))
+        })
+        .collect();
+
+    // The proofs cover the stored bytes, so they have to be compared
+    // with the local copy as stored rather than a fresh export,
+    // which is encrypted using a new nonce.
+    let mut challenges = Vec::new();
+    let mut expected = Vec::new();
+    let mut len = 0;
+    for snapshot in
+        backups.choose_multiple(&mut rand::thread_rng(), cmp::min(sample, MAX_CHALLENGES))
+    {
+        let backup = local_node.read_backup(snapshot)?;
+        len += backup.get_ref().metadata()?.len();
+
+        let challenge = system::random_bytes_secret(32)?;
+        expected.push(system::hash_hmac_reader(&challenge, backup)?);
+
+        challenges.push(Challenge {
+            snapshot: snapshot.clone(),
+            challenge,
+        });
+    }
+
+    if challenges.is_empty() {
+        info!("No local backups to verify against {}", remote_node.address);
+        if json {
+            out!("[]");
+        }
+        return Ok(());
+    }
+
+    // The remote node reads the backups at a limited rate to compute the proofs.
+    let timeout = local_node.stall_timeout() + Duration::from_secs(len / VERIFY_RATE);
+
+    let stream_conn = connect(local_node, remote_node)?;
+    let proofs = stream_conn.verify(challenges.clone(), timeout)?;
+
+    let mut failed = 0;
+    let mut results = Vec::new();
+    for (i, challenge) in challenges.iter().enumerate() {
+        let (status, error) = match proofs.get(i) {
+            Some(Ok(proof)) if *proof == expected[i] => ("ok", None),
+            Some(Ok(_)) => ("mismatch", None),
+            Some(Err(RemoteError::NoSuchBackup)) => ("missing", None),
+            Some(Err(e)) => ("failed", Some(e.to_string())),
+            None => ("failed", Some(String::from("no proof received"))),
+        };
+
+        if status != "ok" {
+            failed += 1;
+        }
+
+        if json {
+            results.push(snapshot_json(
+                &challenge.snapshot,
+                [("status", status.into()), ("error", error.into())],
+            ));
+        } else if status != "ok" || !quiet() {
+            match error {
+                Some(e) => out!("{} {} ({})", challenge.snapshot, status.to_uppercase(), e),
+                None => out!("{} {}", challenge.snapshot, status.to_uppercase()),
             }
+        }
+    }
 
-            let file = File::create(snapshot.streaming_path(Mode::Client))
-                .map_err(|_| RemoteError::RxError)?;
+    if json {
+        out!("{}", serde_json::to_string_pretty(&results)?);
+    }
 
-            eprintln!("Receiving {} from {}", snapshot, remote_node.address);
+    if failed > 0 {
+        return Err(Error::VerificationFailed(failed));
+    }
+
+    Ok(())
+}
 
-            Ok(BufWriter::with_capacity(2 * CHUNKSIZE, file))
+fn verify(local_node: &LocalNode, json: bool) -> Result<()> {
+    let mut backups = local_node.all_backups(None)?;
+    backups.sort_unstable_by_key(|backup| (backup.volume().to_string(), backup.taken()));
+
+    let mut failed = 0;
+    let mut results = Vec::new();
+    for backup in &backups {
+        let (status, error) = match local_node.verify_backup(backup) {
+            Ok(Some(true)) => ("ok", None),
+            Ok(Some(false)) => ("mismatch", None),
+            Ok(None) => ("unverified", None),
+            Err(e) => ("failed", Some(e.to_string())),
         };
 
-    let rx_finish = |snapshot: Snapshot| {
-        fs::rename(
-            snapshot.streaming_path(Mode::Client),
-            snapshot.backup_path(Mode::Client),
-        )
-        .map_err(|_| RemoteError::RxError)?;
+        if status == "mismatch" || status == "failed" {
+            failed += 1;
+        }
 
-        eprintln!("Received {} from {}", snapshot, remote_node.address);
+        if json {
+            results.push(snapshot_json(
+                backup,
+                [("status", status.into()), ("error", error.into())],
+            ));
+        } else if status != "ok" || !quiet() {
+            match error {
+                Some(e) => out!("{} {} ({})", backup, status.to_uppercase(), e),
+                None => out!("{} {}", backup, status.to_uppercase()),
+            }
+        }
+    }
 
-        Ok(())
-    };
+    if json {
+        out!("{}", serde_json::to_string_pretty(&results)?);
+    }
 
-    stream_conn.data_sync(tx, rx_setup, rx_finish)?;
+    if failed > 0 {
+        return Err(Error::VerificationFailed(failed));
+    }
 
     Ok(())
 }
 
-fn restore(
-    local_node: &LocalNode,
-    address: Option<&str>,
+/// The options of `hbak restore` that apply to all subvolumes.
+struct RestoreOptions {
     no_restore: bool,
     ignore_fstab: bool,
+    safety_snapshot: bool,
+    at: Option<NaiveDateTime>,
+    retry: Retry,
+}
+
+fn restore(
+    local_node: &LocalNode,
+    relabel: &Relabel,
+    address: Option<&RemoteAddress>,
+    options: &RestoreOptions,
 ) -> Result<()> {
     // Synchronize with remote node if an address was passed in.
     if let Some(address) = address {
-        let address = match address.parse() {
-            Ok(address) => address,
-            Err(_) => SocketAddr::new(address.parse()?, DEFAULT_PORT),
-        };
-
-        let auth_conn = AuthConn::new(&address)?;
-        let stream_conn = auth_conn.secure_stream(
-            local_node.name().to_string(),
-            address.to_string(),
-            &local_node.config().passphrase,
-        )?;
+        let receive_protocol = local_node.send_support().receive;
+        if receive_protocol < MAX_SEND_PROTOCOL {
+            warn!(
+                "Only btrfs send stream version {} can be received, restoring newer backups requires btrfs-progs 6.0 or later",
+                receive_protocol
+            );
+        }
 
-        eprintln!("Authentication to and of {} successful", address);
+        let stream_conn = options
+            .retry
+            .run(address, || connect_address(local_node, relabel, address))?;
 
         let mut local_sync_info = SyncInfo {
             volumes: HashMap::new(),
+            patterns: Vec::new(),
+            receive_protocol: local_node.send_support().receive,
+            chunk_size: local_node.chunk_size(),
+            compression: true,
+            held: None,
         };
 
         for subvol in &local_node.config().subvols {
-            let volume = Volume::new_local(local_node, subvol.to_string())?;
+            let volume = Volume::new_local(local_node, subvol.name.clone())?;
+            let latest_snapshots = match options.at {
+                Some(at) => local_node.latest_snapshots_at(volume.clone(), at)?,
+                None => local_node.latest_snapshots(volume.clone())?,
+            };
+
+            // The remote node only knows the original identity.
             local_sync_info
                 .volumes
-                .insert(volume.clone(), local_node.latest_snapshots(volume)?);
+                .insert(relabel.original_volume(&volume), latest_snapshots);
         }
 
+        // Incremental backups can only be applied on top of their parent.
+        let chains = RestoreChains::new(&local_sync_info.volumes);
+
         let (stream_conn, _) = stream_conn.meta_sync(local_sync_info)?;
 
         let children = Mutex::new(HashMap::new());
 
-        let rx_setup = |snapshot: &Snapshot| {
-            if !local_node.config().subvols.iter().any(|subvol| {
-                snapshot.subvol() == subvol && snapshot.node_name() == local_node.name()
-            }) {
+        let rx_setup = |target: &Target| {
+            let snapshot = &target.snapshot;
+
+            // Storage paths are derived from the identity chosen by the remote node.
+            if let Err(e) = snapshot.validate() {
+                warn!("Rejecting snapshot from {}: {}", address, e);
+                return Err(RemoteError::AccessDenied);
+            }
+
+            let relabelled = relabel.snapshot(snapshot);
+            if snapshot.node_name() != relabel.node_name()
+                || !local_node.owns_subvol(relabelled.subvol())
+            {
                 return Err(RemoteError::AccessDenied);
             }
 
-            if snapshot.snapshot_path(Mode::Client).exists() {
+            if relabelled.snapshot_path(local_node.layout()).exists() {
                 return Err(RemoteError::Immutable);
             }
 
-            let (child, recovery_stream) =
-                local_node.recover().map_err(|_| RemoteError::RxError)?;
+            // Snapshots taken after restoring must succeed the restored ones.
+            let now = Utc::now().naive_utc();
+            if !local_node.is_plausible(snapshot, now) {
+                warn!(
+                    "Refusing {} from {}: Dated ahead of the local clock",
+                    snapshot, address
+                );
+                return Err(RemoteError::ImplausibleTimestamp(now));
+            }
+
+            if let Err(e) = chains.check(snapshot) {
+                warn!("Refusing {} from {}: {}", snapshot, address, e);
+                return Err(RemoteError::MissingParent);
+            }
+
+            let (child, recovery_stream) = local_node
+                .recover_as(snapshot, &relabelled)
+                .map_err(|_| RemoteError::RxError)?;
             children.lock().unwrap().insert(snapshot.clone(), child);
 
-            eprintln!("Receiving {} from {}", snapshot, address);
+            if relabelled == *snapshot {
+                info!("Receiving {} from {}", snapshot, address);
+            } else {
+                info!("Receiving {} from {} as {}", snapshot, address, relabelled);
+            }
 
             Ok(recovery_stream)
         };
 
-        let rx_finish = |snapshot: Snapshot| {
-            eprintln!("Received {} from {}", snapshot, address);
+        let rx_finish = |snapshot: Snapshot, _: &Integrity| {
+            info!("Received {} from {}", snapshot, address);
 
             let mut child = children
                 .lock()
@@ -568,41 +3093,219 @@ fn restore(
                 .remove(&snapshot)
                 .ok_or(RemoteError::NotStreaming)?;
 
-            if child.wait().map_err(|_| RemoteError::RxError)?.success() {
-                Ok(())
-            } else {
-                Err(RemoteError::RxError)
+            if !child.wait().map_err(|_| RemoteError::RxError)?.success() {
+                return Err(RemoteError::RxError);
             }
+
+            local_node
+                .finish_recovery(&snapshot, &relabel.snapshot(&snapshot))
+                .map_err(|_| RemoteError::RxError)?;
+
+            chains.applied(&snapshot);
+
+            Ok(())
         };
 
-        match stream_conn.data_sync(Vec::<(Empty, Snapshot)>::default(), rx_setup, rx_finish) {
+        // Failed receivers are killed below.
+        let rx_discard = |_| {};
+
+        match stream_conn.data_sync(
+            Vec::<(Empty, Snapshot)>::default(),
+            rx_setup,
+            rx_finish,
+            rx_discard,
+        ) {
             Ok(_) => {}
             Err(e) => {
                 for (snapshot, child) in children.lock().unwrap().iter_mut() {
                     match child.kill() {
                         Ok(_) => {}
-                        Err(e) => eprintln!("Cannot kill failed receiver for {}: {}", snapshot, e),
+                        Err(e) => warn!("Cannot kill failed receiver for {}: {}", snapshot, e),
                     }
                 }
 
+                // The snapshot chain may be incomplete, don't restore from it.
+                if let NetworkError::RemoteError(RemoteError::ShuttingDown) = e {
+                    return Err(Error::PartialRestore(address.to_string()));
+                }
+
                 return Err(e.into());
             }
         }
     }
 
-    if !no_restore {
+    if !options.no_restore {
         for subvol in &local_node.config().subvols {
-            ensure_unmounted(subvol.clone())?;
+            ensure_unmounted(local_node.config(), subvol.name.clone())?;
+
+            info!("Restoring subvolume {}", subvol);
+            let safety_snapshot = local_node.restore(
+                subvol.name.clone(),
+                options.ignore_fstab,
+                options.at,
+                options.safety_snapshot,
+            )?;
 
-            eprintln!("Restoring subvolume {}", subvol);
-            local_node.restore(subvol.clone(), ignore_fstab)?;
+            if let Some(safety_snapshot) = safety_snapshot {
+                info!(
+                    "Kept the previous state of {} at {}",
+                    subvol,
+                    safety_snapshot.path.display()
+                );
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Reports the push and pull permissions that cannot take effect on the local node,
+/// failing if there are any.
+fn check_permissions(
+    node_config: &NodeConfig,
+    push: &[VolumeSpec],
+    pull: &[VolumeSpec],
+) -> Result<()> {
+    let errors = permission_errors(node_config, push, pull);
+    for e in &errors {
+        warn!("{}", e);
+    }
+
+    if errors.is_empty() {
+        Ok(())
+    } else {
+        Err(Error::IneffectivePermissions(errors.len()))
+    }
+}
+
+/// Connects to the node at the specified address for restoration.
+/// Connects to the node to restore from, authenticating
+/// under the node name the backups were made under.
+fn connect_address(
+    local_node: &LocalNode,
+    relabel: &Relabel,
+    address: &RemoteAddress,
+) -> Result<StreamConn<Idle>> {
+    let auth_conn = AuthConn::connect(address, local_node.keepalive())?
+        .with_plaintext(local_node.unix_plaintext());
+    let stream_conn = auth_conn
+        .secure_stream(
+            relabel.node_name().to_string(),
+            local_node.config().instance_id.clone(),
+            address.to_string(),
+            local_node.passphrase()?,
+        )?
+        .with_stall_timeout(local_node.stall_timeout())
+        .with_heartbeat_interval(local_node.heartbeat_interval())
+        .with_rekey_interval(local_node.rekey_interval());
+
+    info!("Authentication to and of {} successful", address);
+
+    Ok(stream_conn)
+}
+
+/// Returns the subvolumes of the node to restore that have a local snapshot
+/// or a full backup on the remote node or in the files, taken at or before `at`
+/// if specified. Subvolumes are named as they are restored.
+fn restorable_subvols(
+    local_node: &LocalNode,
+    relabel: &Relabel,
+    address: Option<&RemoteAddress>,
+    files: &[BackupFile],
+    at: Option<NaiveDateTime>,
+    retry: &Retry,
+) -> Result<BTreeSet<String>> {
+    // Local snapshots are complete subvolumes regardless of their type.
+    let mut candidates = local_node.all_snapshots(None)?;
+    candidates.retain(|snapshot| !relabel.is_renamed(snapshot.subvol()));
+
+    let mut backups: Vec<_> = files.iter().map(|file| file.snapshot.clone()).collect();
+    if let Some(address) = address {
+        backups.extend(
+            retry
+                .run(address, || connect_address(local_node, relabel, address))?
+                .list()?,
+        );
+    }
+    candidates.extend(
+        backups
+            .into_iter()
+            .filter(|backup| !backup.is_incremental() && backup.node_name() == relabel.node_name())
+            .map(|backup| relabel.snapshot(&backup)),
+    );
+
+    Ok(candidates
+        .into_iter()
+        .filter(|snapshot| {
+            snapshot.node_name() == local_node.name() && at.is_none_or(|at| snapshot.taken() <= at)
+        })
+        .map(|snapshot| snapshot.subvol().to_string())
+        .collect())
+}
+
+/// Receives the backup chains of the subvolumes to restore from the provided files.
+fn restore_files(
+    local_node: &LocalNode,
+    relabel: &Relabel,
+    files: &[BackupFile],
+    at: Option<NaiveDateTime>,
+) -> Result<()> {
+    // Refuse ambiguous input before receiving anything.
+    let chains = local_node
+        .config()
+        .subvols
+        .iter()
+        .map(|subvol| {
+            chain::select(
+                files,
+                relabel.node_name(),
+                relabel.original_subvol(&subvol.name),
+                at,
+            )
+        })
+        .collect::<Result<Vec<_>>>()?;
+
+    for file in chains.into_iter().flatten() {
+        let relabelled = relabel.snapshot(&file.snapshot);
+        if relabelled.snapshot_path(local_node.layout()).exists() {
+            info!("Skipping {}, it already exists", relabelled);
+            continue;
         }
+
+        // Snapshots taken after restoring must succeed the restored ones.
+        if !local_node.is_plausible(&file.snapshot, Utc::now().naive_utc()) {
+            return Err(LocalNodeError::PostdatedSnapshot(file.snapshot.clone()).into());
+        }
+
+        info!("Receiving {} from {}", relabelled, file.path.display());
+
+        let (mut child, mut recovery_stream) =
+            local_node.recover_as(&file.snapshot, &relabelled)?;
+        let result = File::open(&file.path)
+            .and_then(|f| io::copy(&mut BufReader::new(f), &mut recovery_stream))
+            .map_err(Error::from)
+            .and_then(|_| Ok(recovery_stream.close()?));
+        drop(recovery_stream);
+
+        if let Err(e) = result {
+            if let Err(e) = child.kill() {
+                warn!("Cannot kill failed receiver for {}: {}", file.snapshot, e);
+            }
+
+            return Err(e);
+        }
+
+        if !child.wait()?.success() {
+            return Err(LocalNodeError::BtrfsCmd.into());
+        }
+
+        local_node.finish_recovery(&file.snapshot, &relabelled)?;
     }
 
     Ok(())
 }
 
-fn ensure_unmounted(subvol: String) -> Result<()> {
+fn ensure_unmounted(node_config: &NodeConfig, subvol: String) -> Result<()> {
     let file = File::open("/proc/self/mounts")?;
     let reader = BufReader::new(file);
 
@@ -614,9 +3317,11 @@ fn ensure_unmounted(subvol: String) -> Result<()> {
             .nth(1)
             .ok_or(Error::NoMountpoint(line.clone()))?;
 
+        // The hbak mounts of the whole file system don't prevent restoration.
         if line.contains(&format!("subvol=/{}", subvol))
-            && mountpoint != "/mnt/hbak"
-            && mountpoint != "/mnt/hbakd"
+            && [Mode::Client, Mode::Server].into_iter().all(|mode| {
+                Path::new(mountpoint) != StorageLayout::from_config(node_config, mode).mountpoint()
+            })
         {
             return Err(Error::Mounted(subvol));
         }
@@ -624,3 +3329,98 @@ fn ensure_unmounted(subvol: String) -> Result<()> {
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use serde_json::json;
+
+    fn snapshot() -> Snapshot {
+        Snapshot::try_from("laptop_home_full_20240102030405").unwrap()
+    }
+
+    fn timestamp(hour: u32) -> NaiveDateTime {
+        NaiveDate::from_ymd_opt(2024, 1, 2)
+            .unwrap()
+            .and_hms_opt(hour, 0, 0)
+            .unwrap()
+    }
+
+    #[test]
+    fn listed_snapshots_have_a_stable_shape() {
+        assert_eq!(
+            snapshot_json(
+                &snapshot(),
+                [
+                    ("tier", Tier::Archive.to_string().into()),
+                    ("subvol_on_disk", Some("@home").into()),
+                ]
+            ),
+            json!({
+                "identifier": "laptop_home_full_20240102030405",
+                "volume": "laptop_home",
+                "node_name": "laptop",
+                "subvol": "home",
+                "is_incremental": false,
+                "taken": "2024-01-02T03:04:05Z",
+                "tier": "archive",
+                "subvol_on_disk": "@home",
+            })
+        );
+    }
+
+    #[test]
+    fn verified_backups_have_a_stable_shape() {
+        let value = snapshot_json(
+            &snapshot(),
+            [("status", "ok".into()), ("error", None::<String>.into())],
+        );
+        assert_eq!(value["status"], json!("ok"));
+        assert_eq!(value["error"], json!(null));
+
+        let value = snapshot_json(&snapshot(), [("remote_only", true.into())]);
+        assert_eq!(value["remote_only"], json!(true));
+        assert_eq!(
+            value["identifier"],
+            json!("laptop_home_full_20240102030405")
+        );
+    }
+
+    #[test]
+    fn remote_status_has_a_stable_shape() {
+        let remote = RemoteSyncState {
+            last_attempt: Some(timestamp(5)),
+            last_success: Some(timestamp(4)),
+            last_error: Some(String::from("Connection refused")),
+            bytes_pushed: 1024,
+            bytes_pulled: 0,
+        };
+
+        assert_eq!(
+            status_json("backup.example.com", &remote, true),
+            json!({
+                "address": "backup.example.com",
+                "last_attempt": "2024-01-02T05:00:00Z",
+                "last_success": "2024-01-02T04:00:00Z",
+                "last_error": "Connection refused",
+                "bytes_pushed": 1024,
+                "bytes_pulled": 0,
+                "stale": true,
+            })
+        );
+
+        assert_eq!(
+            status_json("[::1]:20406", &RemoteSyncState::default(), false),
+            json!({
+                "address": "[::1]:20406",
+                "last_attempt": null,
+                "last_success": null,
+                "last_error": null,
+                "bytes_pushed": 0,
+                "bytes_pulled": 0,
+                "stale": false,
+            })
+        );
+    }
+}
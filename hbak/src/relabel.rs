@@ -0,0 +1,111 @@
+// hbak is a tool for distributed incremental btrfs snapshotting.
+// Copyright (C) 2024  Himbeer <himbeerserverde@gmail.com>
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use crate::error::*;
+
+use hbak_common::proto::{self, Snapshot, Volume};
+use hbak_common::LocalNodeError;
+
+/// A `Relabel` maps the identity backups were made under to the one
+/// they are restored as, e.g. to seed a new node from the backups of an old one
+/// or to restore a subvolume next to the original one for file-level salvage.
+#[derive(Clone, Debug)]
+pub struct Relabel {
+    node_name: String,
+    as_node: String,
+    subvols: Vec<(String, String)>,
+}
+
+impl Relabel {
+    /// Constructs a new `Relabel` from the node name the backups were made under,
+    /// the node name to restore as (defaults to the former)
+    /// and pairs of original and new subvolume names.
+    pub fn new(
+        node_name: String,
+        as_node: Option<String>,
+        subvols: Vec<(String, String)>,
+    ) -> Result<Self> {
+        let as_node = as_node.unwrap_or_else(|| node_name.clone());
+
+        // Node names are encoded in snapshot identifiers just like subvolume names.
+        if !proto::is_valid_name(&as_node) {
+            return Err(Error::InvalidNodeName(as_node));
+        }
+
+        for (i, (old, new)) in subvols.iter().enumerate() {
+            if !proto::is_valid_name(new) {
+                return Err(LocalNodeError::InvalidSubvolumeName(new.clone()).into());
+            }
+
+            if subvols[..i]
+                .iter()
+                .any(|(other_old, other_new)| other_old == old || other_new == new)
+            {
+                return Err(Error::ConflictingRename(old.clone()));
+            }
+        }
+
+        Ok(Self {
+            node_name,
+            as_node,
+            subvols,
+        })
+    }
+
+    /// Returns the node name the backups were made under.
+    /// Remote nodes evaluate permissions against this identity.
+    pub fn node_name(&self) -> &str {
+        &self.node_name
+    }
+
+    /// Returns the node name the backups are restored as.
+    pub fn as_node(&self) -> &str {
+        &self.as_node
+    }
+
+    /// Returns the name the specified original subvolume is restored as.
+    pub fn subvol<'a>(&'a self, subvol: &'a str) -> &'a str {
+        self.subvols
+            .iter()
+            .find(|(old, _)| old == subvol)
+            .map_or(subvol, |(_, new)| new)
+    }
+
+    /// Returns the original name of the specified restored subvolume.
+    pub fn original_subvol<'a>(&'a self, subvol: &'a str) -> &'a str {
+        self.subvols
+            .iter()
+            .find(|(_, new)| new == subvol)
+            .map_or(subvol, |(old, _)| old)
+    }
+
+    /// Reports whether the specified original subvolume is restored
+    /// under a different name. Local snapshots of such subvolumes belong
+    /// to the live subvolume and are never restored from.
+    pub fn is_renamed(&self, subvol: &str) -> bool {
+        self.subvol(subvol) != subvol
+    }
+
+    /// Returns the identity the specified original [`Snapshot`] is restored as.
+    pub fn snapshot(&self, snapshot: &Snapshot) -> Snapshot {
+        snapshot.relabel(&self.as_node, self.subvol(snapshot.subvol()))
+    }
+
+    /// Returns the original identity of the specified restored [`Volume`].
+    pub fn original_volume(&self, volume: &Volume) -> Volume {
+        volume.relabel(&self.node_name, self.original_subvol(volume.subvol()))
+    }
+}
@@ -0,0 +1,144 @@
+// hbak is a tool for distributed incremental btrfs snapshotting.
+// Copyright (C) 2024  Himbeer <himbeerserverde@gmail.com>
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use std::fmt;
+use std::sync::atomic::{AtomicU8, Ordering};
+
+use hbak_common::output::{self, Level};
+
+/// The [`Verbosity`] selected on the command line.
+static VERBOSITY: AtomicU8 = AtomicU8::new(Verbosity::Normal as u8);
+
+/// How much is written to stderr, selected by `--quiet` and `--verbose`.
+/// Results on stdout are never affected.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub enum Verbosity {
+    /// Only warnings and errors are printed.
+    Quiet,
+    /// Informational output, warnings and errors are printed as they are.
+    #[default]
+    Normal,
+    /// Informational output, warnings and errors are printed as log lines.
+    Verbose,
+}
+
+/// Where a message ends up, see [`Verbosity::route`].
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Route {
+    /// The message is discarded.
+    Discard,
+    /// The message is written to stderr as it is.
+    Plain,
+    /// The message is written to stderr as a log line of the specified [`Level`].
+    Log(Level),
+}
+
+impl Verbosity {
+    /// Returns the [`Verbosity`] selected by the `--quiet` and `--verbose` flags.
+    pub fn from_flags(quiet: bool, verbose: bool) -> Self {
+        if verbose {
+            Self::Verbose
+        } else if quiet {
+            Self::Quiet
+        } else {
+            Self::Normal
+        }
+    }
+
+    /// Returns where a message of the specified [`Level`] ends up.
+    pub fn route(self, level: Level) -> Route {
+        match (self, level) {
+            (Self::Verbose, level) => Route::Log(level),
+            (Self::Quiet, Level::Info | Level::Debug) | (Self::Normal, Level::Debug) => {
+                Route::Discard
+            }
+            (Self::Quiet | Self::Normal, Level::Error | Level::Warn | Level::Info) => Route::Plain,
+        }
+    }
+}
+
+/// Sets the [`Verbosity`] of subsequent messages.
+pub fn set(verbosity: Verbosity) {
+    VERBOSITY.store(verbosity as u8, Ordering::Relaxed);
+}
+
+/// Returns the current [`Verbosity`].
+pub fn get() -> Verbosity {
+    match VERBOSITY.load(Ordering::Relaxed) {
+        n if n == Verbosity::Quiet as u8 => Verbosity::Quiet,
+        n if n == Verbosity::Verbose as u8 => Verbosity::Verbose,
+        _ => Verbosity::Normal,
+    }
+}
+
+/// Writes the formatted message to stderr according to the current [`Verbosity`].
+/// Failing to do so never aborts an operation.
+pub fn emit(level: Level, args: fmt::Arguments) {
+    match get().route(level) {
+        Route::Discard => {}
+        Route::Plain => output::eprintln(args),
+        Route::Log(level) => output::log(level, &[], args),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const LEVELS: [Level; 4] = [Level::Error, Level::Warn, Level::Info, Level::Debug];
+
+    #[test]
+    fn flags_select_verbosity() {
+        assert_eq!(Verbosity::from_flags(false, false), Verbosity::Normal);
+        assert_eq!(Verbosity::from_flags(true, false), Verbosity::Quiet);
+        assert_eq!(Verbosity::from_flags(false, true), Verbosity::Verbose);
+    }
+
+    #[test]
+    fn quiet_only_prints_warnings_and_errors() {
+        let routes = LEVELS.map(|level| Verbosity::Quiet.route(level));
+
+        assert_eq!(
+            routes,
+            [Route::Plain, Route::Plain, Route::Discard, Route::Discard]
+        );
+    }
+
+    #[test]
+    fn normal_prints_information_as_is() {
+        let routes = LEVELS.map(|level| Verbosity::Normal.route(level));
+
+        assert_eq!(
+            routes,
+            [Route::Plain, Route::Plain, Route::Plain, Route::Discard]
+        );
+    }
+
+    #[test]
+    fn verbose_logs_everything_at_its_level() {
+        for level in LEVELS {
+            assert_eq!(Verbosity::Verbose.route(level), Route::Log(level));
+        }
+    }
+
+    #[test]
+    fn current_verbosity_round_trips() {
+        for verbosity in [Verbosity::Quiet, Verbosity::Verbose, Verbosity::Normal] {
+            set(verbosity);
+            assert_eq!(get(), verbosity);
+        }
+    }
+}
@@ -0,0 +1,84 @@
+// hbak is a tool for distributed incremental btrfs snapshotting.
+// Copyright (C) 2024  Himbeer <himbeerserverde@gmail.com>
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use std::io;
+use std::process::{Command, Output, Stdio};
+
+/// Runs `hbak` with the specified arguments, writing its stdout
+/// to a pipe whose reading end is closed before it starts.
+fn run_into_closed_pipe(args: &[&str]) -> Output {
+    let (reader, writer) = io::pipe().unwrap();
+    drop(reader);
+
+    Command::new(env!("CARGO_BIN_EXE_hbak"))
+        .args(args)
+        .env("HBAK_CONFIG", "/nonexistent/hbak.conf")
+        .stdin(Stdio::null())
+        .stdout(writer)
+        .stderr(Stdio::piped())
+        .output()
+        .unwrap()
+}
+
+#[test]
+fn completion_scripts_survive_closed_pipe() {
+    for shell in ["bash", "zsh", "fish", "elvish", "powershell"] {
+        let output = run_into_closed_pipe(&["completions", shell]);
+        let stderr = String::from_utf8_lossy(&output.stderr);
+
+        assert_eq!(output.status.code(), Some(0), "{shell}: {stderr}");
+        assert!(!stderr.contains("panicked"), "{shell}: {stderr}");
+        assert!(
+            stderr.contains("1 message(s) could not be written"),
+            "{shell}: {stderr}"
+        );
+    }
+}
+
+#[test]
+fn completion_helpers_survive_closed_pipe() {
+    for helper in [
+        "__complete-subvols",
+        "__complete-remotes",
+        "__complete-nodes",
+    ] {
+        let output = run_into_closed_pipe(&[helper]);
+        let stderr = String::from_utf8_lossy(&output.stderr);
+
+        assert_eq!(output.status.code(), Some(0), "{helper}: {stderr}");
+        assert!(!stderr.contains("panicked"), "{helper}: {stderr}");
+    }
+}
+
+#[test]
+fn help_and_version_survive_closed_pipe() {
+    for args in [&["--help"][..], &["--version"], &["synchronize", "--help"]] {
+        let output = run_into_closed_pipe(args);
+        let stderr = String::from_utf8_lossy(&output.stderr);
+
+        assert_eq!(output.status.code(), Some(0), "{args:?}: {stderr}");
+        assert!(!stderr.contains("panicked"), "{args:?}: {stderr}");
+    }
+}
+
+#[test]
+fn quiet_closed_pipe_is_still_reported() {
+    let output = run_into_closed_pipe(&["--quiet", "completions", "bash"]);
+    let stderr = String::from_utf8_lossy(&output.stderr);
+
+    assert_eq!(output.status.code(), Some(0), "{stderr}");
+    assert!(stderr.contains("could not be written"), "{stderr}");
+}
@@ -0,0 +1,282 @@
+// hbak_common is the main hbak library implementing the protocol shared logic.
+// Copyright (C) 2024  Himbeer <himbeerserverde@gmail.com>
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use crate::config::NodeConfig;
+use crate::proto::{LocalNode, Mode, Promotion, Snapshot, Tier};
+use crate::{AgentError, LocalNodeError};
+
+use std::fs;
+use std::io;
+use std::os::unix::fs::PermissionsExt;
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::Path;
+use std::time::{Duration, SystemTime};
+
+use serde::{Deserialize, Serialize};
+
+/// The Unix socket the agent listens on. Only accessible by root.
+pub const SOCKET_PATH: &str = "/run/hbak-agent.sock";
+
+/// A request to be performed by the agent on behalf of an `hbak` invocation.
+#[derive(Clone, Debug, Eq, PartialEq, Serialize, Deserialize)]
+pub enum AgentRequest {
+    /// List all local snapshots.
+    AllSnapshots,
+    /// List all backups stored on the local node.
+    AllBackups,
+    /// Create a new btrfs snapshot of the specified subvolume
+    /// unless the latest one was taken less than `min_interval` ago.
+    /// The output of the hooks goes to the agent's stderr.
+    SnapshotNow {
+        subvol: String,
+        is_incremental: bool,
+        min_interval: Option<Duration>,
+        run_hooks: bool,
+    },
+    /// List all backups stored on the local node along with their storage tier.
+    AllBackupTiers,
+    /// Determine why the next snapshot of the specified subvolume has to be full.
+    Promotion { subvol: String },
+}
+
+/// The agent's response to an [`AgentRequest`].
+#[derive(Clone, Debug, Eq, PartialEq, Serialize, Deserialize)]
+pub enum AgentResponse {
+    /// The requested snapshots or backups.
+    Snapshots(Vec<Snapshot>),
+    /// The newly created snapshot, `None` if it was skipped.
+    Snapshot(Option<Snapshot>),
+    /// The request failed on the agent.
+    Error(String),
+    /// The requested backups and their storage tiers.
+    BackupTiers(Vec<(Snapshot, Tier)>),
+    /// The reason the next snapshot has to be full, `None` if it doesn't.
+    Promotion(Option<Promotion>),
+}
+
+/// An `Agent` holds the [`LocalNode`] mounted and serves requests
+/// from other `hbak` invocations over a Unix socket,
+/// saving them the cost of mounting and scanning the file system.
+///
+/// The configuration file is reloaded if it has changed since the last request.
+pub struct Agent {
+    listener: UnixListener,
+    local_node: Option<LocalNode>,
+    config_mtime: SystemTime,
+}
+
+impl Agent {
+    /// Mounts the [`LocalNode`] and listens on [`SOCKET_PATH`].
+    /// Fails if another agent is already running.
+    pub fn bind() -> Result<Self, AgentError> {
+        if Path::new(SOCKET_PATH).exists() {
+            if UnixStream::connect(SOCKET_PATH).is_ok() {
+                return Err(AgentError::AlreadyRunning);
+            }
+
+            // Left behind by an agent that didn't exit cleanly.
+            fs::remove_file(SOCKET_PATH)?;
+        }
+
+        let config_mtime = config_mtime()?;
+        let local_node = LocalNode::new(Mode::Client)?;
+
+        // Create the socket without any permissions for other users
+        // so that it is never accessible before its mode is set.
+        // SAFETY: Setting the file mode creation mask has no preconditions.
+        let umask = unsafe { libc::umask(0o077) };
+        let listener = UnixListener::bind(SOCKET_PATH);
+        // SAFETY: See above.
+        unsafe {
+            libc::umask(umask);
+        }
+
+        let listener = listener?;
+        fs::set_permissions(SOCKET_PATH, fs::Permissions::from_mode(0o600))?;
+
+        Ok(Self {
+            listener,
+            local_node: Some(local_node),
+            config_mtime,
+        })
+    }
+
+    /// Accepts a single client and serves its requests until it disconnects.
+    pub fn serve_next(&mut self) -> Result<(), AgentError> {
+        let (stream, _) = self.listener.accept()?;
+
+        loop {
+            let request = match bincode::deserialize_from(&stream) {
+                Ok(request) => request,
+                Err(e) => match *e {
+                    // The client has disconnected.
+                    bincode::ErrorKind::Io(ref e) if e.kind() == io::ErrorKind::UnexpectedEof => {
+                        return Ok(())
+                    }
+                    _ => return Err(e.into()),
+                },
+            };
+
+            let response = match self.handle(request) {
+                Ok(response) => response,
+                Err(e) => AgentResponse::Error(e.to_string()),
+            };
+
+            bincode::serialize_into(&stream, &response)?;
+        }
+    }
+
+    fn handle(&mut self, request: AgentRequest) -> Result<AgentResponse, LocalNodeError> {
+        let local_node = self.local_node()?;
+
+        Ok(match request {
+            AgentRequest::AllSnapshots => AgentResponse::Snapshots(local_node.all_snapshots(None)?),
+            AgentRequest::AllBackups => AgentResponse::Snapshots(local_node.all_backups(None)?),
+            AgentRequest::SnapshotNow {
+                subvol,
+                is_incremental,
+                min_interval,
+                run_hooks,
+            } => {
+                if !local_node.owns_subvol(&subvol) {
+                    return Err(LocalNodeError::ForeignSubvolume(subvol));
+                }
+
+                AgentResponse::Snapshot(local_node.snapshot_now(
+                    subvol,
+                    is_incremental,
+                    min_interval,
+                    run_hooks,
+                )?)
+            }
+            AgentRequest::AllBackupTiers => {
+                AgentResponse::BackupTiers(local_node.all_backup_tiers(None)?)
+            }
+            AgentRequest::Promotion { subvol } => {
+                if !local_node.owns_subvol(&subvol) {
+                    return Err(LocalNodeError::ForeignSubvolume(subvol));
+                }
+
+                AgentResponse::Promotion(local_node.promotion(&subvol)?)
+            }
+        })
+    }
+
+    /// Returns the mounted [`LocalNode`],
+    /// remounting it if the configuration file has changed.
+    fn local_node(&mut self) -> Result<&LocalNode, LocalNodeError> {
+        let mtime = config_mtime()?;
+
+        if mtime != self.config_mtime || self.local_node.is_none() {
+            // Unmount before mounting again.
+            self.local_node = None;
+            self.local_node = Some(LocalNode::new(Mode::Client)?);
+            self.config_mtime = mtime;
+        }
+
+        Ok(self
+            .local_node
+            .as_ref()
+            .expect("LocalNode has just been mounted"))
+    }
+}
+
+impl Drop for Agent {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(SOCKET_PATH);
+    }
+}
+
+/// An `AgentClient` proxies operations through a running [`Agent`].
+pub struct AgentClient {
+    stream: UnixStream,
+}
+
+impl AgentClient {
+    /// Connects to the running [`Agent`]. Returns `None` if no agent is running.
+    pub fn connect() -> Option<Self> {
+        UnixStream::connect(SOCKET_PATH)
+            .ok()
+            .map(|stream| Self { stream })
+    }
+
+    /// Returns all local snapshots.
+    pub fn all_snapshots(&mut self) -> Result<Vec<Snapshot>, AgentError> {
+        match self.call(&AgentRequest::AllSnapshots)? {
+            AgentResponse::Snapshots(snapshots) => Ok(snapshots),
+            _ => Err(AgentError::UnexpectedResponse),
+        }
+    }
+
+    /// Returns all backups stored on the local node.
+    pub fn all_backups(&mut self) -> Result<Vec<Snapshot>, AgentError> {
+        match self.call(&AgentRequest::AllBackups)? {
+            AgentResponse::Snapshots(backups) => Ok(backups),
+            _ => Err(AgentError::UnexpectedResponse),
+        }
+    }
+
+    /// Returns all backups stored on the local node along with their storage tier.
+    pub fn all_backup_tiers(&mut self) -> Result<Vec<(Snapshot, Tier)>, AgentError> {
+        match self.call(&AgentRequest::AllBackupTiers)? {
+            AgentResponse::BackupTiers(backups) => Ok(backups),
+            _ => Err(AgentError::UnexpectedResponse),
+        }
+    }
+
+    /// Creates a new btrfs snapshot of the specified subvolume
+    /// unless the latest one was taken less than `min_interval` ago.
+    /// Returns `None` if the snapshot was skipped.
+    pub fn snapshot_now(
+        &mut self,
+        subvol: String,
+        is_incremental: bool,
+        min_interval: Option<Duration>,
+        run_hooks: bool,
+    ) -> Result<Option<Snapshot>, AgentError> {
+        match self.call(&AgentRequest::SnapshotNow {
+            subvol,
+            is_incremental,
+            min_interval,
+            run_hooks,
+        })? {
+            AgentResponse::Snapshot(snapshot) => Ok(snapshot),
+            _ => Err(AgentError::UnexpectedResponse),
+        }
+    }
+
+    /// Returns why the next snapshot of the specified subvolume has to be full
+    /// even if an incremental snapshot is requested or `None` if it doesn't.
+    pub fn promotion(&mut self, subvol: String) -> Result<Option<Promotion>, AgentError> {
+        match self.call(&AgentRequest::Promotion { subvol })? {
+            AgentResponse::Promotion(promotion) => Ok(promotion),
+            _ => Err(AgentError::UnexpectedResponse),
+        }
+    }
+
+    fn call(&mut self, request: &AgentRequest) -> Result<AgentResponse, AgentError> {
+        bincode::serialize_into(&self.stream, request)?;
+
+        match bincode::deserialize_from(&self.stream)? {
+            AgentResponse::Error(e) => Err(AgentError::Failed(e)),
+            response => Ok(response),
+        }
+    }
+}
+
+fn config_mtime() -> Result<SystemTime, LocalNodeError> {
+    Ok(fs::metadata(NodeConfig::path())?.modified()?)
+}
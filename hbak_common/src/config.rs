@@ -14,15 +14,28 @@
 // You should have received a copy of the GNU Affero General Public License
 // along with this program.  If not, see <https://www.gnu.org/licenses/>.
 
-use crate::proto::Volume;
-use crate::LocalNodeError;
+use crate::conn::{DEFAULT_PORT, MAX_CHUNK_SIZE, MIN_CHUNK_SIZE, MIN_RATE_LIMIT};
+use crate::output::{Level, LogFormat};
+use crate::proto::{is_valid_name, Volume, VolumeSpec, MAX_SEND_PROTOCOL};
+use crate::system;
+use crate::{
+    AddressParseError, ByteSizeParseError, DurationParseError, GrantError, LocalNodeError,
+};
 
-use std::fs::{File, OpenOptions};
-use std::io::{Read, Write};
-use std::net::SocketAddr;
+use std::collections::BTreeMap;
+use std::env;
+use std::fmt;
+use std::fs::{self, File, OpenOptions, Permissions};
+use std::io::{self, Read, Write};
+use std::net::{IpAddr, Ipv6Addr, SocketAddr, SocketAddrV6, ToSocketAddrs};
 use std::os::unix::fs::{OpenOptionsExt, PermissionsExt};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::time::Duration;
 
-use serde::{Deserialize, Serialize};
+use chrono::NaiveTime;
+use ipnet::IpNet;
+use serde::{de, Deserialize, Deserializer, Serialize};
 
 /// A `NodeConfig` contains metadata about a node
 /// such as its name or the nodes it replicates to or stores
@@ -31,13 +44,128 @@ use serde::{Deserialize, Serialize};
 pub struct NodeConfig {
     /// The device file the local btrfs file system is located at.
     pub device: String,
-    /// The network address `hbakd` binds to. The default is `[::]:20406` (dual stack).
-    pub bind_addr: Option<SocketAddr>,
+    /// The directory the mountpoints of the btrfs file system are created in,
+    /// `hbak` for `hbak` and `hbakd` for `hbakd`. The default is `/mnt`.
+    pub mount_root: Option<PathBuf>,
+    /// The network addresses `hbakd` binds to. The default is `[::]:20406` (dual stack).
+    /// Accepts a single address or a list using the syntax of [`RemoteAddress`]
+    /// restricted to IP addresses.
+    #[serde(
+        default,
+        deserialize_with = "deserialize_bind_addrs",
+        skip_serializing_if = "Vec::is_empty"
+    )]
+    pub bind_addr: Vec<SocketAddr>,
+    /// The Unix domain socket `hbakd` additionally listens on for nodes
+    /// on the same machine, e.g. `/run/hbakd.sock`. Disabled by default.
+    pub unix_socket: Option<PathBuf>,
+    /// Skip encrypting sessions over Unix domain sockets. Clients are authenticated
+    /// regardless. `hbakd` only grants this to clients that request it and clients
+    /// only request it when connecting to a `unix:` address, so both nodes
+    /// have to enable it. The default is `false`.
+    pub unix_plaintext: Option<bool>,
+    /// The capacity of the buffer used to write received backups to disk.
+    /// The default is 256 KiB, accepted values range from 1 KiB to 1 GiB.
+    pub rx_bufsize: Option<ByteSize>,
+    /// The time `hbakd` waits for connections to finish on shutdown
+    /// before cutting them. The default is 60 seconds, the maximum is 1 day.
+    pub drain_timeout: Option<HumanDuration>,
+    /// The number of recent failed authentications from a single network address
+    /// after which `hbakd` refuses further connections from it. The default is 10.
+    pub max_auth_failures: Option<u32>,
+    /// The number of concurrent sessions `hbakd` accepts from a single client,
+    /// counted both per node name and per network address. The default is 1.
+    /// Clients connecting via the Unix domain socket share a single address.
+    pub max_sessions_per_client: Option<u32>,
+    /// The number of connections `hbakd` handles at the same time,
+    /// including upstream synchronizations. Further connections wait
+    /// in the backlog of the operating system. The default is 64.
+    pub max_clients: Option<usize>,
+    /// Protect the local snapshots from deletion by marking the snapshot directory
+    /// as immutable between operations and verifying that snapshots are read-only.
+    /// The default is `false`.
+    pub immutable_snapshots: Option<bool>,
+    /// The age after which snapshots and backups may be deleted.
+    /// The default is 7 days if `immutable_snapshots` is enabled and 0 otherwise.
+    pub delete_cooloff: Option<HumanDuration>,
+    /// The time after which incomplete backups left behind
+    /// by failed transmissions are deleted. The default is 1 day, the minimum is 1 minute.
+    pub partial_max_age: Option<HumanDuration>,
+    /// The preferred size of data chunks sent over the network.
+    /// The smaller preference of both nodes is used.
+    /// The default is 4 MiB, accepted values range from 4 KiB to 64 MiB.
+    pub chunk_size: Option<ByteSize>,
+    /// The time a connection may be idle before TCP keepalive probes are sent.
+    /// The default is 60 seconds, accepted values range from 1 second to 32767 seconds.
+    pub keepalive_idle: Option<HumanDuration>,
+    /// The time between TCP keepalive probes.
+    /// The default is 10 seconds, accepted values range from 1 second to 32767 seconds.
+    pub keepalive_interval: Option<HumanDuration>,
+    /// The time without data from the remote node after which a transmission
+    /// is considered stalled and the session is aborted. Incomplete backups
+    /// are kept so that the transmission can resume later.
+    /// The default is 5 minutes, the minimum is 10 seconds.
+    pub stall_timeout: Option<HumanDuration>,
+    /// The time without sending anything after which a heartbeat is sent
+    /// to keep the connection alive while the node has no data to send,
+    /// e.g. through NAT routers that drop idle connections.
+    /// The default is 30 seconds, accepted values range from 1 second to 1 hour.
+    pub heartbeat_interval: Option<HumanDuration>,
+    /// The amount of data sent under a single session key after which
+    /// the next key is derived for the rest of the session.
+    /// The default is 64 GiB, the minimum is 1 MiB.
+    pub rekey_interval: Option<ByteSize>,
+    /// The free space to keep on the file system of the backup directory.
+    /// Received backups are rejected if they would leave less free space
+    /// according to the size reported by the sender, if any. The sender
+    /// skips to its next transmission. The default is 1 GiB.
+    pub space_reserve: Option<ByteSize>,
+    /// The directory older backups are moved to, e.g. on slower bulk storage.
+    /// Archived backups remain restorable and are served from there.
+    /// Archival is disabled by default.
+    pub archive_dir: Option<PathBuf>,
+    /// The age after which backups are archived.
+    /// Requires `archive_dir`. Backups are not archived by age by default.
+    pub archive_after: Option<HumanDuration>,
+    /// The number of most recent backups of each volume that are not archived.
+    /// Requires `archive_dir`. Backups are not archived by count by default.
+    pub archive_keep: Option<usize>,
+    /// The time received snapshots may be dated ahead of the local clock
+    /// before `hbakd` rejects them. The default is 1 hour, the maximum is 30 days.
+    pub max_clock_skew: Option<HumanDuration>,
+    /// The age beyond which `hbakd` rejects received snapshots as implausible.
+    /// Received snapshots are not limited by age by default, the minimum is 1 day.
+    pub max_snapshot_age: Option<HumanDuration>,
+    /// The btrfs send stream version of new backups. Version 2 transfers
+    /// compressed data without decompressing it. Falls back to version 1
+    /// if the kernel or btrfs-progs of this or the remote node don't support it.
+    /// The default is 2, accepted values are 1 and 2.
+    pub send_protocol: Option<u32>,
+    /// Compress new backups of all subvolumes at the specified zstd level
+    /// before encryption unless disabled for individual subvolumes.
+    /// Previous versions cannot restore compressed backups.
+    /// Disabled by default, accepted levels range from 1 to 22.
+    pub compression_level: Option<i32>,
+    /// The most verbose level of messages `hbakd` logs.
+    /// The default is `info`, accepted values are `error`, `warn`, `info` and `debug`.
+    pub log_level: Option<Level>,
+    /// The format of the `hbakd` log. The default is `text`,
+    /// `json` writes one JSON object per line for ingestion by log processors.
+    pub log_format: Option<LogFormat>,
     /// The name of the [`crate::proto::Node`].
     pub node_name: String,
+    /// A random identifier of this installation generated at initialization.
+    /// Remote nodes bind the node name to it to detect cloned machines.
+    /// Nodes initialized by previous versions don't have one.
+    pub instance_id: Option<String>,
     /// The subvolumes owned by the [`crate::proto::Node`], i.e. the subvolumes
     /// that originate from it.
-    pub subvols: Vec<String>,
+    pub subvols: Vec<SubvolConfig>,
+    /// The names the owned subvolumes are identified by in snapshots and backups,
+    /// keyed by their on-disk subvolume name, e.g. to store `@home` as `home`.
+    /// Subvolumes without an entry are identified by their on-disk name.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub subvol_aliases: BTreeMap<String, String>,
     /// The encryption passphrase for the subvolumes owned by this node.
     /// The backups can only be decrypted using this passphrase.
     ///
@@ -46,20 +174,69 @@ pub struct NodeConfig {
     ///
     /// **Remember this passphrase at all costs. Losing it makes it impossible
     /// to recover any of the backups.**
-    pub passphrase: String,
+    ///
+    /// Exactly one of `passphrase`, `passphrase_command` and `passphrase_prompt`
+    /// must be set.
+    pub passphrase: Option<String>,
+    /// A shell command printing the encryption passphrase to stdout,
+    /// e.g. `pass show hbak` or a `systemd-creds` invocation.
+    /// It is only run if an operation needs the passphrase.
+    pub passphrase_command: Option<String>,
+    /// Ask for the encryption passphrase interactively if an operation needs it.
+    /// `hbakd` refuses to start in this mode. The default is `false`.
+    pub passphrase_prompt: Option<bool>,
     /// The remote nodes to interact with by pushing to or pulling from them.
     pub remotes: Vec<RemoteNode>,
+    /// The remote nodes `hbakd` synchronizes with on its own at scheduled times,
+    /// e.g. to forward the backups of its clients to an offsite server.
+    #[serde(default)]
+    pub upstreams: Vec<Upstream>,
+    /// The intervals at which `hbakd` snapshots the owned subvolumes,
+    /// keyed by subvolume name. Subvolumes without an entry are not snapshotted
+    /// automatically.
+    #[serde(default)]
+    pub schedule: BTreeMap<String, SnapshotSchedule>,
     /// The authentication details and privileges of other nodes
     /// for verification when they connect.
     pub auth: Vec<RemoteNodeAuth>,
 }
 
 impl NodeConfig {
+    /// The default location of the configuration file.
     pub const PATH: &'static str = "/etc/hbak.conf";
+    /// The environment variable overriding the location of the configuration file,
+    /// e.g. to operate on a test setup.
+    pub const PATH_VAR: &'static str = "HBAK_CONFIG";
+
+    /// Returns the location of the configuration file of the current machine:
+    /// The value of [`NodeConfig::PATH_VAR`] if set or [`NodeConfig::PATH`].
+    pub fn path() -> PathBuf {
+        env::var_os(Self::PATH_VAR)
+            .filter(|path| !path.is_empty())
+            .map(PathBuf::from)
+            .unwrap_or_else(|| PathBuf::from(Self::PATH))
+    }
+
+    /// Returns the location of the previous version of the configuration file,
+    /// kept by [`NodeConfig::save`].
+    pub fn backup_path() -> PathBuf {
+        with_suffix(&Self::path(), ".bak")
+    }
 
     /// Loads the configuration file of the current machine.
+    /// Parse errors point out the previous version if it exists.
     pub fn load() -> Result<Self, LocalNodeError> {
-        let mut f = File::open(Self::PATH)?;
+        Self::load_from(&Self::path()).map_err(|e| match e {
+            LocalNodeError::TomlDe(e) if Self::backup_path().exists() => {
+                LocalNodeError::CorruptConfig(e, Self::backup_path().display().to_string())
+            }
+            e => e,
+        })
+    }
+
+    /// Loads and validates the configuration file at the specified path.
+    pub fn load_from(path: &Path) -> Result<Self, LocalNodeError> {
+        let mut f = File::open(path)?;
 
         if f.metadata()?.permissions().mode() & 0o7077 > 0 {
             return Err(LocalNodeError::InsecurePerms);
@@ -68,12 +245,258 @@ impl NodeConfig {
         let mut s = String::new();
         f.read_to_string(&mut s)?;
 
-        Ok(toml::from_str(&s)?)
+        let node_config: Self = toml::from_str(&s)?;
+        node_config.validate()?;
+
+        Ok(node_config)
+    }
+
+    /// Ensures that all values are within their accepted ranges.
+    pub fn validate(&self) -> Result<(), LocalNodeError> {
+        check_range(
+            "rx_bufsize",
+            self.rx_bufsize,
+            ByteSize(1024),
+            ByteSize(1024 * 1024 * 1024),
+        )?;
+        check_range(
+            "drain_timeout",
+            self.drain_timeout,
+            HumanDuration::from_secs(0),
+            HumanDuration::from_secs(24 * 60 * 60),
+        )?;
+        check_range(
+            "partial_max_age",
+            self.partial_max_age,
+            HumanDuration::from_secs(60),
+            HumanDuration::from_secs(u64::MAX),
+        )?;
+        check_range(
+            "chunk_size",
+            self.chunk_size,
+            ByteSize(MIN_CHUNK_SIZE as u64),
+            ByteSize(MAX_CHUNK_SIZE as u64),
+        )?;
+        check_range(
+            "keepalive_idle",
+            self.keepalive_idle,
+            HumanDuration::from_secs(1),
+            HumanDuration::from_secs(32767),
+        )?;
+        check_range(
+            "keepalive_interval",
+            self.keepalive_interval,
+            HumanDuration::from_secs(1),
+            HumanDuration::from_secs(32767),
+        )?;
+        check_range(
+            "stall_timeout",
+            self.stall_timeout,
+            HumanDuration::from_secs(10),
+            HumanDuration::from_secs(u64::MAX),
+        )?;
+        check_range(
+            "heartbeat_interval",
+            self.heartbeat_interval,
+            HumanDuration::from_secs(1),
+            HumanDuration::from_secs(60 * 60),
+        )?;
+        check_range(
+            "rekey_interval",
+            self.rekey_interval,
+            ByteSize(1024 * 1024),
+            ByteSize(u64::MAX),
+        )?;
+        check_range(
+            "max_clock_skew",
+            self.max_clock_skew,
+            HumanDuration::from_secs(0),
+            HumanDuration::from_secs(30 * 24 * 60 * 60),
+        )?;
+        check_range(
+            "max_snapshot_age",
+            self.max_snapshot_age,
+            HumanDuration::from_secs(24 * 60 * 60),
+            HumanDuration::from_secs(u64::MAX),
+        )?;
+        check_range("send_protocol", self.send_protocol, 1, MAX_SEND_PROTOCOL)?;
+        check_range("compression_level", self.compression_level, 1, 22)?;
+        check_range(
+            "max_sessions_per_client",
+            self.max_sessions_per_client,
+            1,
+            u32::MAX,
+        )?;
+        check_range("max_clients", self.max_clients, 1, usize::MAX)?;
+
+        if let Some(unix_socket) = &self.unix_socket {
+            if !unix_socket.is_absolute() {
+                return Err(LocalNodeError::InvalidConfig(
+                    "unix_socket",
+                    format!("{} is not an absolute path", unix_socket.display()),
+                ));
+            }
+        }
+
+        for remote_node in self
+            .remotes
+            .iter()
+            .chain(self.upstreams.iter().map(|upstream| &upstream.remote))
+        {
+            check_range(
+                "remotes.rate_limit",
+                remote_node.rate_limit,
+                ByteSize(MIN_RATE_LIMIT),
+                ByteSize(u64::MAX),
+            )?;
+        }
+
+        for (i, auth) in self.auth.iter().enumerate() {
+            check_range(
+                "auth.rate_limit",
+                auth.rate_limit,
+                ByteSize(MIN_RATE_LIMIT),
+                ByteSize(u64::MAX),
+            )?;
+
+            if let Some(label) = &auth.label {
+                if !is_valid_name(label) {
+                    return Err(LocalNodeError::InvalidConfig(
+                        "auth.label",
+                        format!("{:?} of {} is not a valid label", label, auth.node_name),
+                    ));
+                }
+            }
+
+            if self.auth[..i]
+                .iter()
+                .any(|other| other.is(&auth.node_name, auth.label.as_deref()))
+            {
+                return Err(LocalNodeError::InvalidConfig(
+                    "auth",
+                    format!("{} is granted more than once", auth.display_name()),
+                ));
+            }
+        }
+
+        for subvol in &self.subvols {
+            check_range(
+                "subvols.max_incrementals_between_fulls",
+                subvol.max_incrementals_between_fulls,
+                1,
+                usize::MAX,
+            )?;
+        }
+
+        for (subvol, alias) in &self.subvol_aliases {
+            if !is_valid_name(alias) {
+                return Err(LocalNodeError::InvalidConfig(
+                    "subvol_aliases",
+                    format!(
+                        "alias {:?} of {} is not a valid subvolume name",
+                        alias, subvol
+                    ),
+                ));
+            }
+
+            // Aliases must not be ambiguous with any other identity.
+            if self
+                .subvols
+                .iter()
+                .any(|other| other.name != *subvol && self.subvol_alias(&other.name) == alias)
+                || self
+                    .subvol_aliases
+                    .keys()
+                    .any(|other| other != subvol && other == alias)
+            {
+                return Err(LocalNodeError::InvalidConfig(
+                    "subvol_aliases",
+                    format!("alias {} of {} is already used", alias, subvol),
+                ));
+            }
+        }
+
+        let passphrase_sources = [
+            self.passphrase.is_some(),
+            self.passphrase_command.is_some(),
+            self.passphrase_prompt.unwrap_or(false),
+        ];
+        if passphrase_sources
+            .into_iter()
+            .filter(|source| *source)
+            .count()
+            != 1
+        {
+            return Err(LocalNodeError::InvalidConfig(
+                "passphrase",
+                String::from(
+                    "exactly one of passphrase, passphrase_command and passphrase_prompt must be set",
+                ),
+            ));
+        }
+
+        for (subvol, schedule) in &self.schedule {
+            if self.subvol(subvol).is_none() {
+                return Err(LocalNodeError::InvalidConfig(
+                    "schedule",
+                    format!("{} is not an owned subvolume", subvol),
+                ));
+            }
+
+            check_range(
+                "schedule.incremental",
+                schedule.incremental,
+                HumanDuration::from_secs(60),
+                HumanDuration::from_secs(u64::MAX),
+            )?;
+            check_range(
+                "schedule.full",
+                schedule.full,
+                HumanDuration::from_secs(60),
+                HumanDuration::from_secs(u64::MAX),
+            )?;
+        }
+
+        for upstream in &self.upstreams {
+            if upstream.at.is_empty() {
+                return Err(LocalNodeError::InvalidConfig(
+                    "upstreams",
+                    format!("{} has no synchronization times", upstream.remote.address),
+                ));
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Returns the effective encryption passphrase, running the `passphrase_command`
+    /// or asking for it if `passphrase_prompt` is set.
+    /// Asking is refused with [`LocalNodeError::PassphrasePromptUnavailable`]
+    /// unless `interactive` is set.
+    pub fn resolve_passphrase(&self, interactive: bool) -> Result<String, LocalNodeError> {
+        if let Some(passphrase) = &self.passphrase {
+            Ok(passphrase.clone())
+        } else if let Some(passphrase_command) = &self.passphrase_command {
+            system::run_passphrase_command(passphrase_command)
+        } else if !self.passphrase_prompt.unwrap_or(false) {
+            Err(LocalNodeError::InvalidConfig(
+                "passphrase",
+                String::from("no passphrase source configured"),
+            ))
+        } else if interactive {
+            Ok(rpassword::prompt_password("Enter encryption passphrase: ")?)
+        } else {
+            Err(LocalNodeError::PassphrasePromptUnavailable)
+        }
     }
 
     /// Saves the configuration to the configuration file on the current machine.
+    /// The file is replaced atomically so that it is never left incomplete.
     pub fn save(&self) -> Result<(), LocalNodeError> {
         let s = toml::to_string_pretty(self)?;
+        let path = Self::path();
+        let backup_path = Self::backup_path();
+        let tmp_path = with_suffix(&path, ".tmp");
 
         let mut f = OpenOptions::new()
             .create(true)
@@ -82,24 +505,687 @@ impl NodeConfig {
             .append(false)
             .truncate(true)
             .mode(0o0600)
-            .open(Self::PATH)?;
+            .open(&tmp_path)?;
+        // The mode only applies to newly created files.
+        f.set_permissions(Permissions::from_mode(0o600))?;
 
         write!(f, "{}", s)?;
+        f.sync_all()?;
+
+        // Keep the previous version in case the new one turns out to be unusable.
+        if path.exists() {
+            match fs::remove_file(&backup_path) {
+                Ok(_) => {}
+                Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+                Err(e) => return Err(e.into()),
+            }
+
+            fs::hard_link(&path, &backup_path)?;
+        }
+
+        fs::rename(tmp_path, &path)?;
+        sync_parent(&path)?;
+
+        Ok(())
+    }
+
+    /// Swaps the configuration file with its previous version
+    /// after making sure that the latter is valid.
+    /// Running it again undoes the swap.
+    pub fn restore_backup() -> Result<(), LocalNodeError> {
+        let path = Self::path();
+        let backup_path = Self::backup_path();
+
+        if !backup_path.exists() {
+            return Err(LocalNodeError::NoConfigBackup);
+        }
+
+        Self::load_from(&backup_path)?;
+
+        if path.exists() {
+            let swap_path = with_suffix(&path, ".swap");
+
+            fs::rename(&path, &swap_path)?;
+            fs::rename(&backup_path, &path)?;
+            fs::rename(swap_path, &backup_path)?;
+        } else {
+            fs::rename(&backup_path, &path)?;
+        }
+
+        sync_parent(&path)?;
+
+        Ok(())
+    }
+
+    /// Returns the configuration of the owned subvolume
+    /// with the specified on-disk name or alias.
+    pub fn subvol(&self, name: &str) -> Option<&SubvolConfig> {
+        let name = self.subvol_on_disk(name);
+        self.subvols.iter().find(|subvol| subvol.name == name)
+    }
+
+    /// Returns the name the owned subvolume with the specified on-disk name
+    /// or alias is identified by in snapshots and backups, see `subvol_aliases`.
+    pub fn subvol_alias<'a>(&'a self, subvol: &'a str) -> &'a str {
+        self.subvol_aliases
+            .get(subvol)
+            .map(String::as_str)
+            .unwrap_or(subvol)
+    }
+
+    /// Returns the on-disk name of the owned subvolume
+    /// with the specified alias or on-disk name, see `subvol_aliases`.
+    pub fn subvol_on_disk<'a>(&'a self, subvol: &'a str) -> &'a str {
+        self.subvol_aliases
+            .iter()
+            .find(|(_, alias)| *alias == subvol)
+            .map(|(name, _)| name.as_str())
+            .unwrap_or(subvol)
+    }
+
+    /// Returns the push and pull permissions of all granted nodes
+    /// that cannot take effect along with the node names,
+    /// see [`permission_errors`].
+    pub fn grant_errors(&self) -> Vec<(&str, GrantError)> {
+        self.auth
+            .iter()
+            .flat_map(|auth| {
+                permission_errors(self, &auth.push, &auth.pull)
+                    .into_iter()
+                    .map(|e| (auth.node_name.as_str(), e))
+            })
+            .collect()
+    }
+
+    /// Returns the names of the owned subvolumes.
+    pub fn subvol_names(&self) -> Vec<String> {
+        self.subvols
+            .iter()
+            .map(|subvol| subvol.name.clone())
+            .collect()
+    }
+}
+
+/// A `SubvolConfig` describes a subvolume owned by the local node.
+///
+/// The configuration file also accepts the plain subvolume names
+/// written by previous versions.
+#[derive(Clone, Debug, Eq, PartialEq, Serialize, Deserialize)]
+#[serde(from = "RawSubvolConfig")]
+pub struct SubvolConfig {
+    /// The name of the subvolume.
+    pub name: String,
+    /// The shell commands to run before snapshotting the subvolume, e.g. to
+    /// quiesce a database. A failing command aborts the snapshot.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub pre_snapshot: Vec<String>,
+    /// The shell commands to run after snapshotting the subvolume,
+    /// even if the snapshot or a `pre_snapshot` command failed.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub post_snapshot: Vec<String>,
+    /// The number of incremental snapshots after which requesting another one
+    /// takes a full snapshot instead. Unlimited by default.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_incrementals_between_fulls: Option<usize>,
+    /// Whether to compress new backups of the subvolume before encryption,
+    /// e.g. to disable it if the data is compressed on disk already.
+    /// Defaults to whether a `compression_level` is configured.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub compress: Option<bool>,
+}
+
+impl fmt::Display for SubvolConfig {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.name)
+    }
+}
+
+impl From<String> for SubvolConfig {
+    fn from(name: String) -> Self {
+        Self {
+            name,
+            pre_snapshot: Vec::new(),
+            post_snapshot: Vec::new(),
+            max_incrementals_between_fulls: None,
+            compress: None,
+        }
+    }
+}
+
+/// The plain subvolume name used by previous versions or the structured form.
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum RawSubvolConfig {
+    Legacy(String),
+    Structured {
+        name: String,
+        #[serde(default)]
+        pre_snapshot: Vec<String>,
+        #[serde(default)]
+        post_snapshot: Vec<String>,
+        #[serde(default)]
+        max_incrementals_between_fulls: Option<usize>,
+        #[serde(default)]
+        compress: Option<bool>,
+    },
+}
+
+impl From<RawSubvolConfig> for SubvolConfig {
+    fn from(raw: RawSubvolConfig) -> Self {
+        match raw {
+            RawSubvolConfig::Legacy(name) => Self::from(name),
+            RawSubvolConfig::Structured {
+                name,
+                pre_snapshot,
+                post_snapshot,
+                max_incrementals_between_fulls,
+                compress,
+            } => Self {
+                name,
+                pre_snapshot,
+                post_snapshot,
+                max_incrementals_between_fulls,
+                compress,
+            },
+        }
+    }
+}
+
+/// A `RemoteAddress` is the address of a [`RemoteNode`], consisting of
+/// a host name or IP address and an optional port, or the path of the
+/// Unix domain socket of a node on the same machine.
+///
+/// It is parsed from strings like `example.com`, `example.com:20406`, `192.0.2.1`,
+/// `2001:db8::1` or `[2001:db8::1]:20406`. Link-local IPv6 addresses may carry
+/// a scope as in `fe80::1%eth0` or `[fe80::1%2]:20406`. Unix domain sockets
+/// are written as `unix:/run/hbakd.sock`. The configuration file
+/// also accepts this string form as written by previous versions.
+#[derive(Clone, Debug, Eq, Hash, PartialEq, Serialize, Deserialize)]
+#[serde(try_from = "RawRemoteAddress", into = "RawRemoteAddress")]
+pub enum RemoteAddress {
+    /// A network address.
+    Inet {
+        /// The host name or IP address of the node.
+        host: String,
+        /// The port `hbakd` listens on. The default is 20406.
+        port: Option<u16>,
+    },
+    /// The absolute path of the Unix domain socket `hbakd` listens on,
+    /// see [`NodeConfig::unix_socket`].
+    Unix(PathBuf),
+}
+
+impl RemoteAddress {
+    /// Resolves the host name, returning all socket addresses to try in order.
+    /// Fails for Unix domain sockets.
+    pub fn resolve(&self) -> io::Result<Vec<SocketAddr>> {
+        let (host, port) = match self {
+            Self::Inet { host, port } => (host, port),
+            Self::Unix(_) => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "Unix domain sockets have no network address",
+                ))
+            }
+        };
+
+        match self.socket_addr() {
+            Ok(Some(addr)) => Ok(vec![addr]),
+            Ok(None) => Ok((host.as_str(), port.unwrap_or(DEFAULT_PORT))
+                .to_socket_addrs()?
+                .collect()),
+            Err(e) => Err(io::Error::new(io::ErrorKind::InvalidInput, e)),
+        }
+    }
+
+    /// Returns the socket address if the host is an IP address or `None` otherwise.
+    /// The interface name of a scoped IPv6 address is translated to its index.
+    pub fn socket_addr(&self) -> Result<Option<SocketAddr>, AddressParseError> {
+        let (host, port) = match self {
+            Self::Inet { host, port } => (host, port.unwrap_or(DEFAULT_PORT)),
+            Self::Unix(_) => return Ok(None),
+        };
+
+        if let Some((addr, zone)) = host.split_once('%') {
+            let addr: Ipv6Addr = addr
+                .parse()
+                .map_err(|_| AddressParseError::InvalidHost(host.clone()))?;
+            let scope_id = match zone.parse() {
+                Ok(scope_id) => scope_id,
+                Err(_) => system::interface_index(zone)
+                    .ok_or(AddressParseError::NoSuchInterface(zone.to_string()))?,
+            };
+
+            return Ok(Some(SocketAddrV6::new(addr, port, 0, scope_id).into()));
+        }
+
+        Ok(host
+            .parse::<IpAddr>()
+            .ok()
+            .map(|addr| SocketAddr::new(addr, port)))
+    }
+}
+
+impl fmt::Display for RemoteAddress {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::Inet {
+                host,
+                port: Some(port),
+            } if host.contains(':') => write!(f, "[{}]:{}", host, port),
+            Self::Inet {
+                host,
+                port: Some(port),
+            } => write!(f, "{}:{}", host, port),
+            Self::Inet { host, port: None } => write!(f, "{}", host),
+            Self::Unix(path) => write!(f, "unix:{}", path.display()),
+        }
+    }
+}
+
+impl FromStr for RemoteAddress {
+    type Err = AddressParseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if let Some(path) = s.strip_prefix("unix:") {
+            if !path.starts_with('/') {
+                return Err(AddressParseError::RelativeSocketPath(path.to_string()));
+            }
+
+            return Ok(Self::Unix(PathBuf::from(path)));
+        }
+
+        let (host, port) = if let Some(bracketed) = s.strip_prefix('[') {
+            // Bracketed IPv6 address, optionally followed by a port.
+            match bracketed.split_once(']') {
+                Some((host, "")) => (host, None),
+                Some((host, port)) => match port.strip_prefix(':') {
+                    Some(port) => (host, Some(port)),
+                    None => return Err(AddressParseError::InvalidHost(s.to_string())),
+                },
+                None => return Err(AddressParseError::InvalidHost(s.to_string())),
+            }
+        } else if s.matches(':').count() > 1 {
+            // Bare IPv6 address, there is no way to specify a port.
+            (s, None)
+        } else {
+            match s.split_once(':') {
+                Some((host, port)) => (host, Some(port)),
+                None => (s, None),
+            }
+        };
+
+        if host.is_empty() {
+            return Err(AddressParseError::MissingHost);
+        }
+
+        if host.contains(|c: char| c.is_whitespace() || c == '/' || c == '[' || c == ']') {
+            return Err(AddressParseError::InvalidHost(host.to_string()));
+        }
+
+        // Only IPv6 addresses can be scoped.
+        if let Some((addr, zone)) = host.split_once('%') {
+            if zone.is_empty() || addr.parse::<Ipv6Addr>().is_err() {
+                return Err(AddressParseError::InvalidHost(host.to_string()));
+            }
+        }
+
+        Ok(Self::Inet {
+            host: host.to_string(),
+            port: port.map(str::parse).transpose()?,
+        })
+    }
+}
+
+/// The string form of a [`RemoteAddress`] used by previous versions
+/// and by Unix domain sockets or the structured form.
+#[derive(Serialize, Deserialize)]
+#[serde(untagged)]
+enum RawRemoteAddress {
+    Legacy(String),
+    Structured { host: String, port: Option<u16> },
+}
+
+impl TryFrom<RawRemoteAddress> for RemoteAddress {
+    type Error = AddressParseError;
+
+    fn try_from(raw: RawRemoteAddress) -> Result<Self, Self::Error> {
+        match raw {
+            RawRemoteAddress::Legacy(s) => s.parse(),
+            RawRemoteAddress::Structured { host, port } => Ok(Self::Inet { host, port }),
+        }
+    }
+}
+
+impl From<RemoteAddress> for RawRemoteAddress {
+    fn from(address: RemoteAddress) -> Self {
+        match address {
+            RemoteAddress::Inet { host, port } => Self::Structured { host, port },
+            RemoteAddress::Unix(_) => Self::Legacy(address.to_string()),
+        }
+    }
+}
+
+/// Parses a network address to bind to from the syntax of [`RemoteAddress`].
+/// The host has to be an IP address. The default port is used if none is specified.
+pub fn parse_bind_addr(s: &str) -> Result<SocketAddr, AddressParseError> {
+    let address: RemoteAddress = s.parse()?;
+    address
+        .socket_addr()?
+        .ok_or(AddressParseError::NotIpAddress(address.to_string()))
+}
+
+/// The single network address to bind to used by previous versions or a list.
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum RawBindAddrs {
+    Legacy(String),
+    List(Vec<String>),
+}
+
+fn deserialize_bind_addrs<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<Vec<SocketAddr>, D::Error> {
+    let addrs = match RawBindAddrs::deserialize(deserializer)? {
+        RawBindAddrs::Legacy(s) => vec![s],
+        RawBindAddrs::List(addrs) => addrs,
+    };
+
+    addrs
+        .iter()
+        .map(|s| parse_bind_addr(s))
+        .collect::<Result<_, _>>()
+        .map_err(de::Error::custom)
+}
+
+/// Flushes the directory entry changes of the parent directory of the specified path.
+fn sync_parent(path: &Path) -> io::Result<()> {
+    match path.parent() {
+        // Relative paths without a directory component have an empty parent.
+        Some(parent) if parent.as_os_str().is_empty() => File::open(".")?.sync_all(),
+        Some(parent) => File::open(parent)?.sync_all(),
+        None => Ok(()),
+    }
+}
+
+/// Returns the specified path with the suffix appended to its file name.
+fn with_suffix(path: &Path, suffix: &str) -> PathBuf {
+    let mut path = path.as_os_str().to_owned();
+    path.push(suffix);
+    path.into()
+}
+
+/// Fails if the specified configuration value is set and outside of the inclusive range.
+fn check_range<T: fmt::Display + PartialOrd>(
+    field: &'static str,
+    value: Option<T>,
+    min: T,
+    max: T,
+) -> Result<(), LocalNodeError> {
+    match value {
+        Some(value) if value < min => Err(LocalNodeError::InvalidConfig(
+            field,
+            format!("{} is less than the minimum of {}", value, min),
+        )),
+        Some(value) if value > max => Err(LocalNodeError::InvalidConfig(
+            field,
+            format!("{} exceeds the maximum of {}", value, max),
+        )),
+        _ => Ok(()),
+    }
+}
+
+/// A `ByteSize` is an amount of bytes.
+///
+/// It is parsed from strings like `4096`, `4 KiB`, `500GiB` or `2TB`.
+/// Units are case-insensitive, the binary units are powers of 1024
+/// and the decimal units powers of 1000. The configuration file also accepts
+/// plain integers as written by previous versions.
+/// It is formatted using the unit that represents it exactly with the smallest number.
+#[derive(Clone, Copy, Debug, Eq, Hash, Ord, PartialEq, PartialOrd, Serialize, Deserialize)]
+#[serde(try_from = "RawUnitValue", into = "String")]
+pub struct ByteSize(pub u64);
+
+impl ByteSize {
+    const UNITS: [(&'static str, u64); 9] = [
+        ("TiB", 1 << 40),
+        ("GiB", 1 << 30),
+        ("MiB", 1 << 20),
+        ("KiB", 1 << 10),
+        ("TB", 1_000_000_000_000),
+        ("GB", 1_000_000_000),
+        ("MB", 1_000_000),
+        ("KB", 1_000),
+        ("B", 1),
+    ];
+
+    /// Returns the amount of bytes as a `usize`, saturating on overflow.
+    pub fn as_usize(&self) -> usize {
+        usize::try_from(self.0).unwrap_or(usize::MAX)
+    }
+}
+
+impl fmt::Display for ByteSize {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let (unit, factor) = Self::UNITS
+            .into_iter()
+            .filter(|(_, factor)| self.0 >= *factor && self.0.is_multiple_of(*factor))
+            .min_by_key(|(_, factor)| self.0 / factor)
+            .unwrap_or(("B", 1));
+
+        write!(f, "{}{}", self.0 / factor, unit)
+    }
+}
+
+impl FromStr for ByteSize {
+    type Err = ByteSizeParseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        let (number, unit) = s.split_at(s.find(|c: char| !c.is_ascii_digit()).unwrap_or(s.len()));
+        let unit = unit.trim_start();
+
+        let number: u64 = number
+            .parse()
+            .map_err(|_| ByteSizeParseError::Malformed(s.to_string()))?;
+
+        let factor = if unit.is_empty() {
+            1
+        } else {
+            Self::UNITS
+                .into_iter()
+                .find(|(name, _)| name.eq_ignore_ascii_case(unit))
+                .map(|(_, factor)| factor)
+                .ok_or_else(|| ByteSizeParseError::Malformed(s.to_string()))?
+        };
+
+        number
+            .checked_mul(factor)
+            .map(Self)
+            .ok_or_else(|| ByteSizeParseError::Overflow(s.to_string()))
+    }
+}
+
+impl From<ByteSize> for String {
+    fn from(byte_size: ByteSize) -> Self {
+        byte_size.to_string()
+    }
+}
+
+impl TryFrom<RawUnitValue> for ByteSize {
+    type Error = ByteSizeParseError;
+
+    fn try_from(raw: RawUnitValue) -> Result<Self, Self::Error> {
+        match raw {
+            RawUnitValue::Legacy(n) => u64::try_from(n)
+                .map(Self)
+                .map_err(|_| ByteSizeParseError::Malformed(n.to_string())),
+            RawUnitValue::Human(s) => s.parse(),
+        }
+    }
+}
+
+/// A `HumanDuration` is a duration with a resolution of one second.
+///
+/// It is parsed from a number of seconds or a sequence of numbers
+/// followed by units like `90s`, `2h30m` or `90d`. Accepted units are
+/// `s` (seconds), `m` (minutes), `h` (hours), `d` (days) and `w` (weeks).
+/// The configuration file also accepts plain integers of seconds
+/// as written by previous versions. It is formatted in days, hours,
+/// minutes and seconds, omitting zero components.
+#[derive(Clone, Copy, Debug, Eq, Hash, Ord, PartialEq, PartialOrd, Serialize, Deserialize)]
+#[serde(try_from = "RawUnitValue", into = "String")]
+pub struct HumanDuration(pub Duration);
+
+impl HumanDuration {
+    const UNITS: [(char, u64); 5] = [
+        ('w', 7 * 24 * 60 * 60),
+        ('d', 24 * 60 * 60),
+        ('h', 60 * 60),
+        ('m', 60),
+        ('s', 1),
+    ];
+
+    /// Returns a `HumanDuration` of the specified number of seconds.
+    pub const fn from_secs(secs: u64) -> Self {
+        Self(Duration::from_secs(secs))
+    }
+}
+
+impl fmt::Display for HumanDuration {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut secs = self.0.as_secs();
+        if secs == 0 {
+            return write!(f, "0s");
+        }
+
+        // Weeks are accepted but not used for formatting, "90d" reads better than "12w6d".
+        for (unit, factor) in Self::UNITS.into_iter().skip(1) {
+            if secs >= factor {
+                write!(f, "{}{}", secs / factor, unit)?;
+                secs %= factor;
+            }
+        }
+
         Ok(())
     }
 }
 
+impl FromStr for HumanDuration {
+    type Err = DurationParseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        let malformed = || DurationParseError::Malformed(s.to_string());
+        let overflow = || DurationParseError::Overflow(s.to_string());
+
+        if s.is_empty() {
+            return Err(malformed());
+        } else if s.chars().all(|c| c.is_ascii_digit()) {
+            return s.parse().map(Self::from_secs).map_err(|_| overflow());
+        }
+
+        let mut secs: u64 = 0;
+        let mut rest = s;
+        while !rest.is_empty() {
+            let digits = rest
+                .find(|c: char| !c.is_ascii_digit())
+                .unwrap_or(rest.len());
+            let (number, tail) = rest.split_at(digits);
+
+            let mut chars = tail.trim_start().chars();
+            let unit = chars.next().ok_or_else(malformed)?;
+            rest = chars.as_str().trim_start();
+
+            let number: u64 = number.parse().map_err(|_| malformed())?;
+            let factor = Self::UNITS
+                .into_iter()
+                .find(|(name, _)| *name == unit.to_ascii_lowercase())
+                .map(|(_, factor)| factor)
+                .ok_or_else(malformed)?;
+
+            secs = number
+                .checked_mul(factor)
+                .and_then(|component| secs.checked_add(component))
+                .ok_or_else(overflow)?;
+        }
+
+        Ok(Self::from_secs(secs))
+    }
+}
+
+impl From<HumanDuration> for Duration {
+    fn from(human_duration: HumanDuration) -> Self {
+        human_duration.0
+    }
+}
+
+impl From<HumanDuration> for String {
+    fn from(human_duration: HumanDuration) -> Self {
+        human_duration.to_string()
+    }
+}
+
+impl TryFrom<RawUnitValue> for HumanDuration {
+    type Error = DurationParseError;
+
+    fn try_from(raw: RawUnitValue) -> Result<Self, Self::Error> {
+        match raw {
+            RawUnitValue::Legacy(n) => u64::try_from(n)
+                .map(Self::from_secs)
+                .map_err(|_| DurationParseError::Malformed(n.to_string())),
+            RawUnitValue::Human(s) => s.parse(),
+        }
+    }
+}
+
+/// The plain integer form of a [`ByteSize`] or [`HumanDuration`]
+/// used by previous versions or the human-readable form.
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum RawUnitValue {
+    Legacy(i64),
+    Human(String),
+}
+
 /// A `RemoteNode` defines a network node that can be interacted with.
 /// Backups can be pushed to or pulled from a `RemoteNode`.
 #[derive(Clone, Debug, Eq, PartialEq, Serialize, Deserialize)]
 pub struct RemoteNode {
-    /// The network address and port of the node to push to.
-    pub address: String,
+    /// The network address and optional port of the node to push to.
+    pub address: RemoteAddress,
     /// The volumes to push to the remote node.
-    pub push: Vec<Volume>,
+    pub push: Vec<VolumeSpec>,
     /// The volumes to pull from the remote node,
     /// must not include subvolumes owned by the local node.
-    pub pull: Vec<Volume>,
+    pub pull: Vec<VolumeSpec>,
+    /// The maximum rate at which backups are pushed to the remote node
+    /// in bytes per second, e.g. `1MiB`. Unlimited by default, the minimum is 1 KiB.
+    pub rate_limit: Option<ByteSize>,
+}
+
+/// An `Upstream` is a [`RemoteNode`] `hbakd` synchronizes with at scheduled times
+/// using its own mounted file system.
+#[derive(Clone, Debug, Eq, PartialEq, Serialize, Deserialize)]
+pub struct Upstream {
+    /// The remote node to push to or pull from.
+    #[serde(flatten)]
+    pub remote: RemoteNode,
+    /// The times of day (UTC) to synchronize at, e.g. `["02:00"]`.
+    pub at: Vec<NaiveTime>,
+}
+
+/// A `SnapshotSchedule` defines how often `hbakd` snapshots a subvolume.
+/// An initial full snapshot is taken if there is none,
+/// even if only incremental snapshots are scheduled.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq, Serialize, Deserialize)]
+pub struct SnapshotSchedule {
+    /// The time between incremental snapshots, e.g. `1h`. The minimum is 1 minute.
+    /// Incremental snapshots are not scheduled by default.
+    pub incremental: Option<HumanDuration>,
+    /// The time between full snapshots, e.g. `7d`. The minimum is 1 minute.
+    /// Full snapshots are not scheduled by default.
+    pub full: Option<HumanDuration>,
 }
 
 /// A `RemoteNodeAuth` defines authentication and authorization details
@@ -108,13 +1194,117 @@ pub struct RemoteNode {
 pub struct RemoteNodeAuth {
     /// The name of the remote node to apply the details to.
     pub node_name: String,
+    /// Distinguishes multiple entries for the same node name, e.g. `breakglass`.
+    /// The remote node authenticates with any of them and receives
+    /// the privileges of the one matching its passphrase.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub label: Option<String>,
     /// A random value used by the remote node to compute the HMAC shared secret.
     pub verifier: Vec<u8>,
     /// The HMAC hash of verifier and passphrase for mutual authentication.
     pub key: Vec<u8>,
     /// The volumes the remote node is allowed to push.
     /// Must not include subvolumes owned by the local node.
-    pub push: Vec<Volume>,
+    pub push: Vec<VolumeSpec>,
     /// The volumes the remote node is allowed to pull.
-    pub pull: Vec<Volume>,
+    pub pull: Vec<VolumeSpec>,
+    /// The interval within which the remote node is expected to push.
+    /// `hbakd` warns about clients that exceed it.
+    pub push_interval: Option<HumanDuration>,
+    /// The instance identifier the node name is bound to.
+    /// Recorded by `hbakd` at the first contact if not known at grant approval.
+    /// Once bound, connections without an instance identifier are refused.
+    pub instance_id: Option<String>,
+    /// The maximum rate at which `hbakd` transmits backups to the remote node
+    /// in bytes per second, e.g. `1MiB`. Unlimited by default, the minimum is 1 KiB.
+    pub rate_limit: Option<ByteSize>,
+    /// The network address ranges the remote node may connect from,
+    /// e.g. `10.8.0.0/24`. Connections over the Unix domain socket count
+    /// as coming from `::1`. Any source is allowed if empty.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub allowed_sources: Vec<IpNet>,
+}
+
+impl RemoteNodeAuth {
+    /// Reports whether this is the entry with the specified node name and label.
+    pub fn is(&self, node_name: &str, label: Option<&str>) -> bool {
+        self.node_name == node_name && self.label.as_deref() == label
+    }
+
+    /// Returns the node name followed by the label if there is one,
+    /// e.g. `laptop` or `laptop (breakglass)`.
+    pub fn display_name(&self) -> String {
+        match &self.label {
+            Some(label) => format!("{} ({})", self.node_name, label),
+            None => self.node_name.clone(),
+        }
+    }
+
+    /// Reports whether the remote node may connect from the specified address.
+    pub fn allows_source(&self, addr: IpAddr) -> bool {
+        // Dual stack listeners report IPv4 clients as IPv4-mapped IPv6 addresses.
+        let addr = addr.to_canonical();
+
+        self.allowed_sources.is_empty()
+            || self.allowed_sources.iter().any(|net| net.contains(&addr))
+    }
+}
+
+/// Returns the push and pull permissions of a [`RemoteNodeAuth`] that cannot
+/// take effect on the node with the specified configuration:
+/// Pushes of its own volumes, pulls of its own volumes that name
+/// subvolumes it doesn't own, duplicates and volumes whose node
+/// or subvolume name doesn't survive encoding as an identifier.
+pub fn permission_errors(
+    node_config: &NodeConfig,
+    push: &[VolumeSpec],
+    pull: &[VolumeSpec],
+) -> Vec<GrantError> {
+    let mut errors = Vec::new();
+
+    for (i, spec) in push.iter().enumerate() {
+        if push[..i].contains(spec) {
+            errors.push(GrantError::Duplicate(spec.clone()));
+        }
+
+        let own = match spec {
+            VolumeSpec::Volume(volume) => volume.node_name() == node_config.node_name,
+            VolumeSpec::AllOfNode(node_name) => *node_name == node_config.node_name,
+            VolumeSpec::All => false,
+        };
+        if own {
+            errors.push(GrantError::OwnPush(spec.clone()));
+        }
+    }
+
+    for (i, spec) in pull.iter().enumerate() {
+        if pull[..i].contains(spec) {
+            errors.push(GrantError::Duplicate(spec.clone()));
+        }
+
+        if let VolumeSpec::Volume(volume) = spec {
+            if volume.node_name() == node_config.node_name
+                && node_config.subvol(volume.subvol()).is_none()
+            {
+                errors.push(GrantError::ForeignPull(
+                    spec.clone(),
+                    volume.subvol().to_string(),
+                ));
+            }
+        }
+    }
+
+    for volume in push.iter().chain(pull).filter_map(|spec| spec.volume()) {
+        if volume.node_name().is_empty()
+            || volume.subvol().is_empty()
+            || Volume::try_from(volume.to_string().as_str()).ok().as_ref() != Some(volume)
+        {
+            errors.push(GrantError::Unencodable(
+                volume.node_name().to_string(),
+                volume.subvol().to_string(),
+            ));
+        }
+    }
+
+    errors
 }
@@ -14,32 +14,103 @@
 // You should have received a copy of the GNU Affero General Public License
 // along with this program.  If not, see <https://www.gnu.org/licenses/>.
 
-use crate::config::RemoteNodeAuth;
+use crate::config::{HumanDuration, RemoteAddress, RemoteNodeAuth};
+use crate::json;
 use crate::message::*;
 use crate::proto::Snapshot;
-use crate::stream::CHUNKSIZE;
-use crate::system;
-use crate::{NetworkError, RemoteError};
+use crate::stream::RateLimiter;
+use crate::system::{self, SessionKey};
+use crate::{LocalNodeError, NetworkError, RemoteError};
 
-use std::io::{self, BufRead, BufReader, BufWriter, Write};
+use std::collections::VecDeque;
+use std::io::{self, BufRead, BufReader, BufWriter, Read, Write};
 use std::marker::PhantomData;
+use std::mem;
 use std::net::{SocketAddr, TcpStream};
 use std::ops::DerefMut;
-use std::sync::{Arc, Mutex};
+use std::os::unix::net::UnixStream;
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Condvar, Mutex, MutexGuard};
 use std::thread;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
+use bincode::Options;
 use chacha20poly1305::aead::generic_array::GenericArray;
 use chacha20poly1305::aead::stream::{DecryptorBE32, EncryptorBE32};
 use chacha20poly1305::{Key, XChaCha20Poly1305};
-use subtle::ConstantTimeEq;
+use chrono::prelude::*;
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use subtle::{Choice, ConditionallySelectable, ConstantTimeEq};
+
+/// The default amount of data encrypted under a single session key
+/// before the sender switches to the next one.
+pub const DEFAULT_REKEY_INTERVAL: u64 = 64 * 1024 * 1024 * 1024;
+/// The number of messages encrypted under a single session key
+/// after which the sender switches to the next one regardless of their size.
+/// Keeps the 32-bit counter of the STREAM construction far from overflowing.
+const REKEY_MESSAGES: u64 = 1 << 31;
 
 /// Default TCP server port. Not officially reserved.
 /// 406 is the sum of the ASCII codes for `hbak` and an offset to the 20000 port range.
 pub const DEFAULT_PORT: u16 = 20406;
 
+/// The version of the wire protocol. Peers speaking different versions
+/// refuse to communicate.
+pub const PROTOCOL_VERSION: u32 = 26;
+
+/// The version of the software, exchanged during authentication
+/// so that failure reports identify both peers.
+const SOFTWARE_VERSION: &str = env!("CARGO_PKG_VERSION");
+
 /// TCP connect timeout. Connection attempt is aborted if remote doesn't respond.
 pub const CONNECT_TIMEOUT: Duration = Duration::from_secs(30);
+/// The capacity of the buffered reader and writer of a [`StreamConn`] in bytes (256 KiB).
+/// Larger messages such as chunks bypass the buffers, so they only need to hold
+/// the small control messages.
+pub const NET_BUFSIZE: usize = 256 * 1024;
+
+/// The default size of data chunks sent over the network in bytes (4 MiB).
+/// Independent of the chunk size of the encryption stream.
+pub const DEFAULT_CHUNK_SIZE: usize = 4 * 1024 * 1024;
+/// The smallest size of data chunks sent over the network in bytes (4 KiB).
+pub const MIN_CHUNK_SIZE: usize = 4 * 1024;
+/// The largest size of data chunks accepted from the network in bytes (64 MiB).
+/// Bounds the memory a single message can allocate.
+pub const MAX_CHUNK_SIZE: usize = 64 * 1024 * 1024;
+
+/// The largest frame accepted from the network in bytes.
+/// Frames carry a single, possibly encrypted message prefixed with its length
+/// and only exceed [`NET_BUFSIZE`] if they contain a chunk.
+const MAX_FRAME_LEN: usize = NET_BUFSIZE + MAX_CHUNK_SIZE;
+
+/// The lowest rate [`StreamConn::with_rate_limit`] accepts
+/// in bytes per second (1 KiB/s).
+pub const MIN_RATE_LIMIT: u64 = 1024;
+
+/// The transfer rate [`StreamConn::data_sync_until`] assumes when wrapping up
+/// before any data has been sent in bytes per second (1 MiB/s).
+pub const WRAP_UP_RATE: u64 = 1024 * 1024;
+
+/// The maximum number of [`Challenge`]s answered per session.
+/// Bounds the disk I/O a single verification request can cause.
+pub const MAX_CHALLENGES: usize = 16;
+/// The maximum rate at which backups are read to answer [`Challenge`]s
+/// in bytes per second (32 MiB/s).
+pub const VERIFY_RATE: u64 = 32 * 1024 * 1024;
+
+/// The default time a connection may be idle before TCP keepalive probes are sent.
+pub const DEFAULT_KEEPALIVE_IDLE: Duration = Duration::from_secs(60);
+/// The default time between TCP keepalive probes.
+pub const DEFAULT_KEEPALIVE_INTERVAL: Duration = Duration::from_secs(10);
+/// The default time without data from the remote node
+/// after which a transmission is considered stalled.
+pub const DEFAULT_STALL_TIMEOUT: Duration = Duration::from_secs(5 * 60);
+/// The default time without sending anything after which
+/// a heartbeat is sent to the remote node during data synchronization.
+pub const DEFAULT_HEARTBEAT_INTERVAL: Duration = Duration::from_secs(30);
+
 /// TCP read timeout. Used for cancellation of [`StreamConn::data_sync`] receive thread
 /// and `hbakd` TCP accept loop.
 pub const READ_TIMEOUT: Duration = Duration::from_millis(200);
@@ -66,73 +137,567 @@ pub struct Idle;
 /// Timestamp synchronization has succeeded and transmissions are allowed and possibly in progress.
 pub struct Active;
 
+/// The coordination state shared by the worker threads of [`StreamConn::data_sync`].
+#[derive(Debug, Default)]
+struct SyncState {
+    /// The remote node accepted the current transmission.
+    start_streaming: bool,
+    /// The remote node refused the current transmission, but continues the session.
+    rejected: Option<RemoteError>,
+    /// All local transmissions have been completed and announced.
+    local_done: bool,
+    /// The transmit thread has exited.
+    tx_finished: bool,
+    /// The receive thread has exited.
+    rx_finished: bool,
+    /// The worker that exited with an error first, if any.
+    failed: Option<Worker>,
+    /// The local node announced that it stops transmitting early.
+    shutdown_sent: bool,
+    /// The remote node announced that it stops transmitting early.
+    remote_shutdown: bool,
+    /// The local node announced the closing of its [`Window`].
+    closing_sent: bool,
+    /// The deadline announced by the remote node.
+    remote_deadline: Option<NaiveDateTime>,
+}
+
+/// A `Signal` wraps a [`SyncState`] and wakes up all waiting threads
+/// whenever it is modified.
+#[derive(Debug, Default)]
+struct Signal {
+    state: Mutex<SyncState>,
+    cvar: Condvar,
+}
+
+impl Signal {
+    /// Modifies the [`SyncState`] and notifies all waiting threads.
+    fn update<F: FnOnce(&mut SyncState)>(&self, f: F) {
+        f(&mut self.state.lock().unwrap());
+        self.cvar.notify_all();
+    }
+
+    /// Blocks the current thread while the condition holds,
+    /// returning the locked [`SyncState`] once it doesn't.
+    fn wait_while<F>(&self, condition: F) -> MutexGuard<'_, SyncState>
+    where
+        F: FnMut(&mut SyncState) -> bool,
+    {
+        self.cvar
+            .wait_while(self.state.lock().unwrap(), condition)
+            .unwrap()
+    }
+
+    /// Like [`Signal::wait_while`], but gives up after the specified timeout.
+    fn wait_timeout_while<F>(&self, timeout: Duration, condition: F) -> MutexGuard<'_, SyncState>
+    where
+        F: FnMut(&mut SyncState) -> bool,
+    {
+        self.cvar
+            .wait_timeout_while(self.state.lock().unwrap(), timeout, condition)
+            .unwrap()
+            .0
+    }
+}
+
+/// A worker thread of [`StreamConn::data_sync`].
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+enum Worker {
+    Tx,
+    Rx,
+}
+
+/// A `WorkerGuard` marks a [`Worker`] as finished when dropped
+/// so that the supervisor is woken up even if the worker panics.
+/// Workers that exit without calling [`WorkerGuard::succeed`]
+/// are recorded as failed.
+struct WorkerGuard<'a> {
+    signal: &'a Signal,
+    worker: Worker,
+    succeeded: bool,
+}
+
+impl<'a> WorkerGuard<'a> {
+    fn new(signal: &'a Signal, worker: Worker) -> Self {
+        Self {
+            signal,
+            worker,
+            succeeded: false,
+        }
+    }
+
+    /// Marks the [`Worker`] as having completed without error.
+    fn succeed(&mut self) {
+        self.succeeded = true;
+    }
+}
+
+impl Drop for WorkerGuard<'_> {
+    fn drop(&mut self) {
+        self.signal.update(|state| {
+            match self.worker {
+                Worker::Tx => state.tx_finished = true,
+                Worker::Rx => state.rx_finished = true,
+            }
+
+            if !self.succeeded {
+                state.failed.get_or_insert(self.worker);
+            }
+        });
+    }
+}
+
+/// A `Window` is the time available to synchronization sessions.
+/// Closing it asks the remote nodes to wrap up by the deadline,
+/// aborting it ends all transmissions immediately.
+#[derive(Debug, Default)]
+pub struct Window {
+    deadline: Mutex<Option<NaiveDateTime>>,
+    aborted: AtomicBool,
+}
+
+impl Window {
+    /// Schedules the end of the `Window`. Sessions stop starting transmissions
+    /// that aren't expected to complete in time and ask their remote nodes to do the same.
+    pub fn close(&self, deadline: NaiveDateTime) {
+        *self.deadline.lock().unwrap() = Some(deadline);
+    }
+
+    /// Ends the `Window` immediately, aborting all transmissions in progress
+    /// after their current chunk.
+    pub fn abort(&self) {
+        self.aborted.store(true, Ordering::SeqCst);
+    }
+
+    /// Returns the scheduled end of the `Window`, if any.
+    pub fn deadline(&self) -> Option<NaiveDateTime> {
+        *self.deadline.lock().unwrap()
+    }
+
+    /// Reports whether the `Window` has been aborted.
+    pub fn is_aborted(&self) -> bool {
+        self.aborted.load(Ordering::SeqCst)
+    }
+}
+
+/// `Keepalive` settings detect dead peers of idle TCP connections.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct Keepalive {
+    /// The time a connection may be idle before probes are sent.
+    pub idle: Duration,
+    /// The time between probes.
+    pub interval: Duration,
+}
+
+impl Default for Keepalive {
+    fn default() -> Self {
+        Self {
+            idle: DEFAULT_KEEPALIVE_IDLE,
+            interval: DEFAULT_KEEPALIVE_INTERVAL,
+        }
+    }
+}
+
+impl Keepalive {
+    /// Enables TCP keepalive with these settings on the specified [`TcpStream`].
+    pub fn apply(&self, stream: &TcpStream) -> io::Result<()> {
+        system::set_keepalive(stream, self.idle, self.interval)
+    }
+}
+
+/// A `WrapUp` describes how a session was ended early because a [`Window`] was closing.
+#[derive(Clone, Debug, Eq, PartialEq, Serialize)]
+pub struct WrapUp {
+    /// The earliest deadline announced by either node.
+    #[serde(serialize_with = "json::serialize_timestamp")]
+    pub deadline: NaiveDateTime,
+    /// The local transmissions that were skipped because they weren't expected
+    /// to complete before the deadline.
+    #[serde(serialize_with = "json::serialize_snapshots")]
+    pub skipped: Vec<Snapshot>,
+}
+
+/// The outcome of a single transmission in a [`StreamConn`] session.
+#[derive(Clone, Debug, Eq, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TransferOutcome {
+    /// The transmission was completed.
+    Completed,
+    /// The transmission was never started, e.g. because it wasn't expected
+    /// to complete before a deadline.
+    Skipped,
+    /// The transmission was started, but cut short by a shutdown.
+    Aborted,
+    /// The transmission was refused by the remote node without ending the session,
+    /// e.g. because of insufficient disk space.
+    Rejected(RemoteError),
+}
+
+/// `TransferStats` describe a single transmission in a [`StreamConn`] session.
+#[derive(Clone, Debug, Eq, PartialEq, Serialize)]
+pub struct TransferStats {
+    /// The transmitted snapshot.
+    #[serde(serialize_with = "json::serialize_snapshot")]
+    pub snapshot: Snapshot,
+    /// The number of bytes transmitted over the network.
+    pub bytes: u64,
+    /// The time from the start to the end of the transmission.
+    pub elapsed: Duration,
+    /// Whether the transmission was completed.
+    pub outcome: TransferOutcome,
+}
+
+/// `SyncStats` describe the transmissions of a [`StreamConn`] session.
+#[derive(Clone, Debug, Default, Eq, PartialEq, Serialize)]
+pub struct SyncStats {
+    /// The local transmissions in the order they were started in.
+    /// Skipped transmissions are listed last.
+    pub sent: Vec<TransferStats>,
+    /// The remote transmissions in the order they were started in.
+    pub received: Vec<TransferStats>,
+    /// The total duration of the data synchronization phase.
+    pub elapsed: Duration,
+    /// How the session was ended early because a [`Window`] was closing, if it was.
+    pub wrap_up: Option<WrapUp>,
+}
+
+impl SyncStats {
+    /// Returns the local transmissions the remote node refused
+    /// along with the reason.
+    pub fn rejected(&self) -> impl Iterator<Item = (&Snapshot, &RemoteError)> {
+        self.sent
+            .iter()
+            .filter_map(|transfer| match &transfer.outcome {
+                TransferOutcome::Rejected(e) => Some((&transfer.snapshot, e)),
+                _ => None,
+            })
+    }
+
+    /// Returns the number of bytes of the completed local transmissions.
+    pub fn bytes_sent(&self) -> u64 {
+        completed_bytes(&self.sent)
+    }
+
+    /// Returns the number of bytes of the completed remote transmissions.
+    pub fn bytes_received(&self) -> u64 {
+        completed_bytes(&self.received)
+    }
+
+    /// Returns a summary of the completed transmissions in both directions
+    /// using the specified verbs, e.g. `pushed 3 snapshot(s) (1.2 GiB in 4m12s, 4.8 MiB/s)`.
+    pub fn summary(&self, sent: &str, received: &str) -> String {
+        format!(
+            "{}, {}",
+            summarize(sent, &self.sent),
+            summarize(received, &self.received)
+        )
+    }
+}
+
+/// Returns the number of bytes of the completed transmissions of a single direction.
+fn completed_bytes(transfers: &[TransferStats]) -> u64 {
+    transfers
+        .iter()
+        .filter(|transfer| transfer.outcome == TransferOutcome::Completed)
+        .map(|transfer| transfer.bytes)
+        .sum()
+}
+
+/// Summarizes the specified transmissions of a single direction.
+fn summarize(verb: &str, transfers: &[TransferStats]) -> String {
+    let completed: Vec<_> = transfers
+        .iter()
+        .filter(|transfer| transfer.outcome == TransferOutcome::Completed)
+        .collect();
+
+    let mut summary = format!("{} {} snapshot(s)", verb, completed.len());
+
+    if !completed.is_empty() {
+        let bytes: u64 = completed.iter().map(|transfer| transfer.bytes).sum();
+        let elapsed: Duration = completed.iter().map(|transfer| transfer.elapsed).sum();
+
+        summary += &format!(
+            " ({} in {}",
+            format_bytes(bytes),
+            HumanDuration::from_secs(elapsed.as_secs())
+        );
+        if !elapsed.is_zero() {
+            summary += &format!(
+                ", {}/s",
+                format_bytes((bytes as f64 / elapsed.as_secs_f64()) as u64)
+            );
+        }
+        summary += ")";
+    }
+
+    let incomplete = transfers.len() - completed.len();
+    if incomplete > 0 {
+        summary += &format!(", {} incomplete", incomplete);
+    }
+
+    summary
+}
+
+/// Formats the specified number of bytes using the largest fitting binary unit,
+/// e.g. `1.2 GiB`.
+pub(crate) fn format_bytes(bytes: u64) -> String {
+    const UNITS: [&str; 5] = ["KiB", "MiB", "GiB", "TiB", "PiB"];
+
+    let mut value = bytes as f64;
+    let mut unit = None;
+    for next in UNITS {
+        if value < 1024.0 {
+            break;
+        }
+
+        value /= 1024.0;
+        unit = Some(next);
+    }
+
+    match unit {
+        Some(unit) => format!("{:.1} {}", value, unit),
+        None => format!("{} B", bytes),
+    }
+}
+
+/// A `Tally` computes the [`Integrity`] of a transmission as its chunks pass.
+#[derive(Clone, Debug, Default)]
+struct Tally {
+    hasher: Sha256,
+    len: u64,
+    chunks: u64,
+}
+
+impl Tally {
+    fn update(&mut self, chunk: &[u8]) {
+        self.hasher.update(chunk);
+        self.len += chunk.len() as u64;
+        self.chunks += 1;
+    }
+
+    fn finish(&mut self) -> Integrity {
+        Integrity {
+            digest: std::mem::take(&mut self.hasher).finalize().to_vec(),
+            len: self.len,
+        }
+    }
+}
+
+/// A `Transport` is the underlying connection of a session:
+/// A TCP connection or a Unix domain socket connection to a node on the same machine.
+#[derive(Debug)]
+pub enum Transport {
+    Tcp(TcpStream),
+    Unix(UnixStream),
+}
+
+impl Transport {
+    /// Creates a new independently owned handle to the underlying connection.
+    pub fn try_clone(&self) -> io::Result<Self> {
+        match self {
+            Self::Tcp(stream) => Ok(Self::Tcp(stream.try_clone()?)),
+            Self::Unix(stream) => Ok(Self::Unix(stream.try_clone()?)),
+        }
+    }
+
+    /// Sets the read timeout of the underlying connection.
+    pub fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        match self {
+            Self::Tcp(stream) => stream.set_read_timeout(timeout),
+            Self::Unix(stream) => stream.set_read_timeout(timeout),
+        }
+    }
+
+    /// Disables Nagle's algorithm if this is a TCP connection.
+    /// Unix domain sockets don't delay writes in the first place.
+    pub fn set_nodelay(&self, nodelay: bool) -> io::Result<()> {
+        match self {
+            Self::Tcp(stream) => stream.set_nodelay(nodelay),
+            Self::Unix(_) => Ok(()),
+        }
+    }
+
+    /// Reports whether this is a Unix domain socket connection.
+    pub fn is_unix(&self) -> bool {
+        matches!(self, Self::Unix(_))
+    }
+}
+
+impl Read for Transport {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        (&*self).read(buf)
+    }
+}
+
+impl Read for &Transport {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            Transport::Tcp(stream) => (&*stream).read(buf),
+            Transport::Unix(stream) => (&*stream).read(buf),
+        }
+    }
+}
+
+impl Write for Transport {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        (&*self).write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        (&*self).flush()
+    }
+}
+
+impl Write for &Transport {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Transport::Tcp(stream) => (&*stream).write(buf),
+            Transport::Unix(stream) => (&*stream).write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Transport::Tcp(stream) => (&*stream).flush(),
+            Transport::Unix(stream) => (&*stream).flush(),
+        }
+    }
+}
+
+impl From<TcpStream> for Transport {
+    fn from(stream: TcpStream) -> Self {
+        Self::Tcp(stream)
+    }
+}
+
+impl From<UnixStream> for Transport {
+    fn from(stream: UnixStream) -> Self {
+        Self::Unix(stream)
+    }
+}
+
 /// An `AuthConn` attempts mutual authentication between the local node
 /// and a remote [`AuthServ`], transforming into a [`StreamConn`] on success.
 pub struct AuthConn {
-    stream: TcpStream,
+    stream: Transport,
+    plaintext: bool,
 }
 
 impl AuthConn {
-    /// Shorthand for `AuthConn::from(TcpStream::connect_timeout(addr, CONNECT_TIMEOUT)?)`.
+    /// Shorthand for `AuthConn::from(TcpStream::connect_timeout(addr, CONNECT_TIMEOUT)?)`
+    /// with the specified [`Keepalive`] settings applied.
     ///
     /// This is a low-level constructor that should not be used for dual stack connectivity.
     /// Use [`AuthConn::new_first_success`] unless its behavior is unsuitable.
-    pub fn new(addr: &SocketAddr) -> Result<Self, NetworkError> {
-        Ok(TcpStream::connect_timeout(addr, CONNECT_TIMEOUT)?.into())
+    pub fn new(addr: &SocketAddr, keepalive: Keepalive) -> Result<Self, NetworkError> {
+        let stream = TcpStream::connect_timeout(addr, CONNECT_TIMEOUT)?;
+        keepalive.apply(&stream)?;
+
+        Ok(stream.into())
     }
 
     /// Iterates over the passed addresses until a connection succeeds
     /// or there are no more addresses left to try.
+    /// Returns the error of the last attempt if all of them fail.
     ///
     /// This is useful for dual stack connectivity and should replace the low-level
     /// [`AuthConn::new`] constructor in most cases.
-    pub fn new_first_success<A>(addrs: A) -> Result<Self, NetworkError>
+    pub fn new_first_success<A>(addrs: A, keepalive: Keepalive) -> Result<Self, NetworkError>
     where
-        A: Iterator<Item = SocketAddr> + ExactSizeIterator + Clone,
+        A: Iterator<Item = SocketAddr>,
     {
-        for addr in addrs.clone() {
-            match Self::new(&addr) {
+        let mut last_err = NetworkError::NoAddrs;
+        for addr in addrs {
+            match Self::new(&addr, keepalive) {
                 Ok(conn) => return Ok(conn),
-                // Stable version of [`ExactSizeIterator::is_empty`] (tracking issue: #35428).
-                Err(e) if addrs.len() == 0 => return Err(e),
-                _ => {}
+                Err(e) => last_err = e,
+            }
+        }
+
+        Err(last_err)
+    }
+
+    /// Connects to the `hbakd` listening on the specified Unix domain socket.
+    pub fn new_unix<P: AsRef<Path>>(path: P) -> Result<Self, NetworkError> {
+        Ok(UnixStream::connect(path)?.into())
+    }
+
+    /// Connects to the specified [`RemoteAddress`], trying all addresses
+    /// a host name resolves to or using its Unix domain socket.
+    /// The [`Keepalive`] settings only apply to TCP connections.
+    pub fn connect(address: &RemoteAddress, keepalive: Keepalive) -> Result<Self, NetworkError> {
+        match address {
+            RemoteAddress::Unix(path) => Self::new_unix(path),
+            RemoteAddress::Inet { .. } => {
+                let addrs = address.resolve().map_err(NetworkError::Resolve)?;
+                Self::new_first_success(addrs.into_iter(), keepalive)
             }
         }
+    }
 
-        Err(NetworkError::NoAddrs)
+    /// Requests that the session isn't encrypted if `plaintext` is set.
+    /// Authentication is performed regardless. The server only grants this
+    /// on Unix domain sockets if its configuration allows it, the session
+    /// is encrypted otherwise. Disabled by default.
+    pub fn with_plaintext(mut self, plaintext: bool) -> Self {
+        self.plaintext = plaintext;
+        self
     }
 
     /// Performs mutual authentication and encryption of the connection
-    /// using the provided node name and passphrase,
+    /// using the provided node name, instance identifier and passphrase,
     /// returning a [`StreamConn`] on success.
     pub fn secure_stream<P: AsRef<[u8]>>(
         self,
         node_name: String,
+        instance_id: Option<String>,
         remote_node_name: String,
         passphrase: P,
     ) -> Result<StreamConn<Idle>, NetworkError> {
         // Consuming the `AuthConn` guarantees that this function can never be called again.
 
-        let challenge = system::random_bytes(32);
-        let nonce = system::random_bytes(19);
+        let challenge = system::random_bytes_secret(32)?;
+        let nonce = system::random_bytes_secret(32)?;
         let key;
+        let server_nonce;
+        let plaintext;
+        let remote_software;
 
         self.send_message(&CryptoMessage::Hello(Hello {
             node_name,
+            instance_id,
             challenge: challenge.clone(),
             nonce: nonce.clone(),
+            version: PROTOCOL_VERSION,
+            plaintext: self.plaintext && self.stream.is_unix(),
         }))?;
 
         match self.recv_message()? {
             CryptoMessage::ServerAuth(server_auth) => {
                 let server_auth = server_auth?;
 
-                key = system::derive_key(&server_auth.verifier, &passphrase)?;
-                let server_proof = system::hash_hmac(&key, &challenge);
+                let mut matched = None;
+                for candidate in &server_auth.candidates {
+                    let candidate_key = system::derive_key(&candidate.verifier, &passphrase)?;
+                    let server_proof = system::hash_hmac(&candidate_key, &challenge);
+
+                    if candidate.proof.ct_eq(&server_proof).into() {
+                        matched = Some(candidate_key);
+                        break;
+                    }
+                }
+
+                if let Some(candidate_key) = matched {
+                    key = candidate_key;
+                    server_nonce = server_auth.nonce;
+                    plaintext = server_auth.plaintext && self.stream.is_unix();
+                    remote_software = server_auth.software;
 
-                if server_auth.proof.ct_eq(&server_proof).into() {
                     let proof = system::hash_hmac(&key, &server_auth.challenge);
-                    self.send_message(&CryptoMessage::ClientAuth(Ok(ClientAuth { proof })))?;
+                    self.send_message(&CryptoMessage::ClientAuth(Ok(ClientAuth {
+                        proof,
+                        software: SOFTWARE_VERSION.to_string(),
+                    })))?;
                 } else {
                     self.send_message(&CryptoMessage::ClientAuth(Err(RemoteError::AccessDenied)))?;
                     return Err(RemoteError::Unauthorized.into());
@@ -149,12 +714,19 @@ impl AuthConn {
         match self.recv_message()? {
             CryptoMessage::Encrypt(encrypt) => {
                 encrypt?;
-                Ok(StreamConn::try_from_conn(
+
+                let (tx, rx) = system::derive_session_keys(&key, &nonce, &server_nonce);
+
+                let stream_conn = StreamConn::try_from_conn(
                     self.stream,
-                    key,
-                    nonce,
+                    (!plaintext).then_some((tx, rx)),
                     remote_node_name,
-                )?)
+                    None,
+                    remote_software,
+                )?;
+                stream_conn.confirm()?;
+
+                Ok(stream_conn)
             }
             _ => {
                 self.send_message(&CryptoMessage::Error(RemoteError::IllegalTransition))?;
@@ -175,56 +747,108 @@ impl AuthConn {
     }
 }
 
-impl From<TcpStream> for AuthConn {
-    fn from(stream: TcpStream) -> Self {
-        Self { stream }
+impl<T: Into<Transport>> From<T> for AuthConn {
+    fn from(stream: T) -> Self {
+        Self {
+            stream: stream.into(),
+            plaintext: false,
+        }
     }
 }
 
 /// An `AuthServ` attempts mutual authentication between the local node
 /// and a remote [`AuthConn`], transforming into a [`StreamConn`] on success.
 pub struct AuthServ {
-    stream: TcpStream,
+    stream: Transport,
+    allow_plaintext: bool,
 }
 
 impl AuthServ {
+    /// Grants requests of clients to skip encrypting the session
+    /// if `allow_plaintext` is set and the connection is a Unix domain socket.
+    /// Disabled by default.
+    pub fn with_plaintext(mut self, allow_plaintext: bool) -> Self {
+        self.allow_plaintext = allow_plaintext;
+        self
+    }
+
     /// Performs mutual authentication and encryption of the connection
     /// using the provided authentication storage,
     /// returning a [`StreamConn`] on success.
+    ///
+    /// If there are multiple entries for the node name of the client,
+    /// the client proof is checked against all of them and the entry
+    /// whose key it was computed with is returned.
     pub fn secure_stream(
         self,
         auth_storage: impl IntoIterator<Item = RemoteNodeAuth>,
+    ) -> Result<(StreamConn<Idle>, RemoteNodeAuth), NetworkError> {
+        self.secure_stream_delayed(auth_storage, |_| Duration::ZERO)
+    }
+
+    /// Performs mutual authentication and encryption of the connection
+    /// like [`AuthServ::secure_stream`], but waits for the duration returned
+    /// by `delay` for the claimed node name before responding to the client's
+    /// identity proof. This can be used to throttle online guessing.
+    pub fn secure_stream_delayed(
+        self,
+        auth_storage: impl IntoIterator<Item = RemoteNodeAuth>,
+        delay: impl FnOnce(&str) -> Duration,
     ) -> Result<(StreamConn<Idle>, RemoteNodeAuth), NetworkError> {
         // Consuming the `AuthServ` guarantees that this function can never be called again.
 
-        let challenge = system::random_bytes(32);
+        let challenge = system::random_bytes_secret(32)?;
+        let server_nonce = system::random_bytes_secret(32)?;
         let nonce;
-        let key;
-        let remote_node_auth;
+        let mut auths;
         let remote_node_name;
+        let remote_instance_id;
+        let plaintext;
 
-        let client_proof;
+        let client_proofs: Vec<_>;
+        let response_delay;
 
         match self.recv_message()? {
             CryptoMessage::Hello(hello) => {
-                let auth = auth_storage
+                response_delay = delay(&hello.node_name);
+
+                if hello.version != PROTOCOL_VERSION {
+                    self.send_message(&CryptoMessage::ServerAuth(Err(
+                        RemoteError::IncompatibleVersion(PROTOCOL_VERSION),
+                    )))?;
+                    return Err(RemoteError::IncompatibleVersion(hello.version).into());
+                }
+
+                auths = auth_storage
                     .into_iter()
-                    .find(|rna| rna.node_name == hello.node_name);
+                    .filter(|rna| rna.node_name == hello.node_name)
+                    .collect::<Vec<_>>();
 
-                if let Some(auth) = auth {
+                if !auths.is_empty() {
                     nonce = hello.nonce;
-                    key = auth.key.clone();
-                    remote_node_auth = auth;
                     remote_node_name = hello.node_name;
-
-                    client_proof = system::hash_hmac(&key, &challenge);
-
-                    let proof = system::hash_hmac(&key, &hello.challenge);
+                    remote_instance_id = hello.instance_id;
+                    plaintext = hello.plaintext && self.allow_plaintext && self.stream.is_unix();
+
+                    client_proofs = auths
+                        .iter()
+                        .map(|auth| system::hash_hmac(&auth.key, &challenge))
+                        .collect();
+
+                    let candidates = auths
+                        .iter()
+                        .map(|auth| AuthCandidate {
+                            verifier: auth.verifier.clone(),
+                            proof: system::hash_hmac(&auth.key, &hello.challenge),
+                        })
+                        .collect();
 
                     self.send_message(&CryptoMessage::ServerAuth(Ok(ServerAuth {
-                        verifier: remote_node_auth.verifier.clone(),
+                        candidates,
                         challenge,
-                        proof,
+                        nonce: server_nonce.clone(),
+                        plaintext,
+                        software: SOFTWARE_VERSION.to_string(),
                     })))?;
                 } else {
                     self.send_message(&CryptoMessage::ServerAuth(Err(RemoteError::AccessDenied)))?;
@@ -243,12 +867,36 @@ impl AuthServ {
             CryptoMessage::ClientAuth(client_auth) => {
                 let client_auth = client_auth?;
 
-                if client_auth.proof.ct_eq(&client_proof).into() {
+                thread::sleep(response_delay);
+
+                // Compare against every entry so that the timing doesn't reveal
+                // which one matched.
+                let mut matched = Choice::from(0);
+                let mut index = 0u32;
+                for (i, client_proof) in client_proofs.iter().enumerate() {
+                    let is_match = client_auth.proof.ct_eq(client_proof);
+                    index.conditional_assign(&(i as u32), is_match);
+                    matched |= is_match;
+                }
+
+                if matched.into() {
+                    let remote_node_auth = auths.swap_remove(index as usize);
+
                     self.send_message(&CryptoMessage::Encrypt(Ok(())))?;
-                    Ok((
-                        StreamConn::try_from_conn(self.stream, key, nonce, remote_node_name)?,
-                        remote_node_auth,
-                    ))
+
+                    let (rx, tx) =
+                        system::derive_session_keys(&remote_node_auth.key, &nonce, &server_nonce);
+
+                    let stream_conn = StreamConn::try_from_conn(
+                        self.stream,
+                        (!plaintext).then_some((tx, rx)),
+                        remote_node_name,
+                        remote_instance_id,
+                        client_auth.software,
+                    )?;
+                    stream_conn.confirm()?;
+
+                    Ok((stream_conn, remote_node_auth))
                 } else {
                     self.send_message(&CryptoMessage::Encrypt(Err(RemoteError::AccessDenied)))?;
                     Err(RemoteError::Unauthorized.into())
@@ -261,6 +909,23 @@ impl AuthServ {
         }
     }
 
+    /// Refuses the connection with the specified error
+    /// once the client has started the authentication process.
+    pub fn refuse(self, e: RemoteError) -> Result<(), NetworkError> {
+        match self.recv_message()? {
+            CryptoMessage::Hello(_) => {
+                self.send_message(&CryptoMessage::ServerAuth(Err(e)))?;
+                Ok(())
+            }
+            _ => {
+                self.send_message(&CryptoMessage::ServerAuth(Err(
+                    RemoteError::IllegalTransition,
+                )))?;
+                Err(NetworkError::IllegalTransition)
+            }
+        }
+    }
+
     fn send_message(&self, message: &CryptoMessage) -> Result<(), NetworkError> {
         let buf = bincode::serialize(message)?;
         (&self.stream).write_all(&buf)?;
@@ -273,9 +938,84 @@ impl AuthServ {
     }
 }
 
-impl From<TcpStream> for AuthServ {
-    fn from(stream: TcpStream) -> Self {
-        Self { stream }
+impl<T: Into<Transport>> From<T> for AuthServ {
+    fn from(stream: T) -> Self {
+        Self {
+            stream: stream.into(),
+            allow_plaintext: false,
+        }
+    }
+}
+
+/// The length of the authentication tag of an encrypted frame in bytes.
+const TAG_LEN: usize = 16;
+
+/// The encryption state of the transmitting direction of a session.
+struct TxCipher {
+    key: SessionKey,
+    encryptor: EncryptorBE32<XChaCha20Poly1305>,
+    /// The number of messages encrypted under the current key.
+    messages: u64,
+    /// The amount of data encrypted under the current key.
+    bytes: u64,
+}
+
+impl TxCipher {
+    fn new(key: SessionKey) -> Self {
+        Self {
+            encryptor: EncryptorBE32::new(
+                Key::from_slice(&key.key),
+                GenericArray::from_slice(&key.nonce),
+            ),
+            key,
+            messages: 0,
+            bytes: 0,
+        }
+    }
+
+    /// Encrypts the buffer in place, appending the authentication tag.
+    fn encrypt(&mut self, buf: &mut Vec<u8>) -> Result<(), NetworkError> {
+        let len = buf.len();
+
+        // Don't let the tag double the capacity of the reusable buffer.
+        buf.reserve_exact(TAG_LEN);
+        self.encryptor.encrypt_next_in_place(&[], buf)?;
+
+        self.messages += 1;
+        self.bytes += len as u64;
+
+        Ok(())
+    }
+
+    fn rekey(&mut self, salt: &[u8]) {
+        *self = Self::new(system::next_session_key(&self.key, salt));
+    }
+}
+
+/// The decryption state of the receiving direction of a session.
+struct RxCipher {
+    key: SessionKey,
+    decryptor: DecryptorBE32<XChaCha20Poly1305>,
+}
+
+impl RxCipher {
+    fn new(key: SessionKey) -> Self {
+        Self {
+            decryptor: DecryptorBE32::new(
+                Key::from_slice(&key.key),
+                GenericArray::from_slice(&key.nonce),
+            ),
+            key,
+        }
+    }
+
+    /// Decrypts the buffer in place, removing the authentication tag.
+    fn decrypt(&mut self, buf: &mut Vec<u8>) -> Result<(), NetworkError> {
+        Ok(self.decryptor.decrypt_next_in_place(&[], buf)?)
+    }
+
+    fn rekey(&mut self, salt: &[u8]) {
+        *self = Self::new(system::next_session_key(&self.key, salt));
     }
 }
 
@@ -284,11 +1024,27 @@ impl From<TcpStream> for AuthServ {
 /// It is the result of successful authentication and encryption
 /// using an [`AuthConn`] or an [`AuthServ`].
 pub struct StreamConn<P: Phase> {
-    stream_read: Mutex<BufReader<TcpStream>>,
-    stream_write: Mutex<BufWriter<TcpStream>>,
-    encryptor: Mutex<EncryptorBE32<XChaCha20Poly1305>>,
-    decryptor: Mutex<DecryptorBE32<XChaCha20Poly1305>>,
+    stream_read: Mutex<BufReader<Transport>>,
+    stream_write: Mutex<BufWriter<Transport>>,
+    // Frames are assembled and encrypted or decrypted in place
+    // in these buffers, which are reused across messages.
+    tx_buf: Mutex<Vec<u8>>,
+    rx_buf: Mutex<RxBuf>,
+    // Both are `None` if the session is plaintext.
+    tx_cipher: Option<Mutex<TxCipher>>,
+    rx_cipher: Option<Mutex<RxCipher>>,
+    rekey_interval: u64,
     remote_node_name: String,
+    remote_instance_id: Option<String>,
+    remote_software: String,
+    chunk_size: usize,
+    stall_timeout: Duration,
+    heartbeat_interval: Duration,
+    // The time the last frame was sent, used to send heartbeats when idle,
+    // and the time the unanswered heartbeat was sent, if any.
+    last_sent: Mutex<Instant>,
+    ping_sent: Mutex<Option<Instant>>,
+    rate_limit: Option<u64>,
     _phase: PhantomData<P>,
 }
 
@@ -298,118 +1054,698 @@ impl<P: Phase> StreamConn<P> {
         &self.remote_node_name
     }
 
+    /// Returns the instance identifier of the remote node
+    /// if it is a client that has one.
+    pub fn remote_instance_id(&self) -> Option<&str> {
+        self.remote_instance_id.as_deref()
+    }
+
+    /// Returns the software version of the remote node.
+    pub fn remote_software(&self) -> &str {
+        &self.remote_software
+    }
+
+    /// Sends the specified message, switching to the next key of the transmitting
+    /// direction afterwards once the current one has been used for
+    /// the rekey interval, see [`StreamConn::with_rekey_interval`].
+    ///
+    /// The remote node switches to the next key when it receives
+    /// the [`StreamMessage::Rekey`], so the directions are rekeyed
+    /// independently at a message boundary without any coordination.
     fn send_message(&self, message: &StreamMessage) -> Result<(), NetworkError> {
-        let plaintext = bincode::serialize(message)?;
-        let ciphertext = self
-            .encryptor
-            .lock()
-            .unwrap()
-            .encrypt_next(plaintext.as_slice())?;
+        self.send_frame(|buf| bincode::serialize_into(buf, message))
+    }
+
+    /// Sends a [`StreamMessage::Chunk`] containing the specified data
+    /// without copying it into a message first.
+    fn send_chunk(&self, seq: u64, data: &[u8]) -> Result<(), NetworkError> {
+        self.send_frame(|buf| {
+            // The data follows the message instead of being part of it,
+            // see [`decode_message`].
+            bincode::serialize_into(
+                &mut *buf,
+                &StreamMessage::Chunk {
+                    seq,
+                    data: Vec::new(),
+                },
+            )?;
+            buf.extend_from_slice(data);
+
+            Ok(())
+        })
+    }
 
+    /// Sends a frame containing the message written to the reusable buffer
+    /// by `encode`. See [`StreamConn::send_message`] for details.
+    fn send_frame<F>(&self, encode: F) -> Result<(), NetworkError>
+    where
+        F: FnOnce(&mut Vec<u8>) -> bincode::Result<()>,
+    {
+        // Encrypt while holding the writer so that messages are sent
+        // in the order of their STREAM counters.
         let mut w = self.stream_write.lock().unwrap();
-        bincode::serialize_into(w.deref_mut(), &RawMessage(ciphertext))?;
+        let mut buf = self.tx_buf.lock().unwrap();
+
+        buf.clear();
+        encode(&mut buf)?;
+
+        match &self.tx_cipher {
+            Some(tx_cipher) => {
+                let mut tx_cipher = tx_cipher.lock().unwrap();
+
+                tx_cipher.encrypt(&mut buf)?;
+                write_frame(w.deref_mut(), &buf)?;
+
+                if tx_cipher.bytes >= self.rekey_interval || tx_cipher.messages >= REKEY_MESSAGES {
+                    let salt = system::random_bytes_secret(32)?;
+
+                    buf.clear();
+                    bincode::serialize_into(&mut *buf, &StreamMessage::Rekey(salt.clone()))?;
+
+                    tx_cipher.encrypt(&mut buf)?;
+                    write_frame(w.deref_mut(), &buf)?;
+
+                    tx_cipher.rekey(&salt);
+                }
+            }
+            None => write_frame(w.deref_mut(), &buf)?,
+        }
         w.flush()?;
 
+        *self.last_sent.lock().unwrap() = Instant::now();
+
         Ok(())
     }
 
+    /// Receives the next message, answering heartbeats transparently.
+    /// See [`StreamConn::recv_frame`] for details.
     fn recv_message(&self) -> Result<StreamMessage, NetworkError> {
-        let ciphertext: RawMessage =
-            bincode::deserialize_from(self.stream_read.lock().unwrap().deref_mut())?;
-        let plaintext = self
-            .decryptor
-            .lock()
-            .unwrap()
-            .decrypt_next(ciphertext.0.as_slice())?;
+        loop {
+            match self.recv_frame()? {
+                StreamMessage::Ping => self.send_message(&StreamMessage::Pong)?,
+                StreamMessage::Pong => *self.ping_sent.lock().unwrap() = None,
+                message => return Ok(message),
+            }
+        }
+    }
 
-        Ok(bincode::deserialize(&plaintext)?)
+    /// Receives the next frame, switching to the next key
+    /// of the receiving direction if the remote node rekeys.
+    ///
+    /// The data of a [`StreamMessage::Chunk`] takes over the reusable buffer.
+    /// Hand it back using [`StreamConn::recycle`] once it has been processed.
+    fn recv_frame(&self) -> Result<StreamMessage, NetworkError> {
+        let mut r = self.stream_read.lock().unwrap();
+        let mut rx_buf = self.rx_buf.lock().unwrap();
+
+        rx_buf.read_frame(r.deref_mut())?;
+        let buf = &mut rx_buf.buf;
+
+        match &self.rx_cipher {
+            Some(rx_cipher) => {
+                let mut rx_cipher = rx_cipher.lock().unwrap();
+
+                rx_cipher.decrypt(buf)?;
+                match decode_message(buf)? {
+                    StreamMessage::Rekey(salt) => {
+                        rx_cipher.rekey(&salt);
+
+                        drop(rx_cipher);
+                        drop(rx_buf);
+                        drop(r);
+                        self.recv_frame()
+                    }
+                    message => Ok(message),
+                }
+            }
+            None => Ok(decode_message(buf)?),
+        }
     }
-}
+
+    /// Returns the data of a received [`StreamMessage::Chunk`] to the reusable
+    /// buffer so that the next chunk doesn't need to allocate.
+    fn recycle(&self, data: Vec<u8>) {
+        let mut rx_buf = self.rx_buf.lock().unwrap();
+        // The buffer may hold the beginning of the next frame.
+        if !rx_buf.is_partial() && data.capacity() > rx_buf.buf.capacity() {
+            rx_buf.buf = data;
+        }
+    }
+}
+
+/// Writes the specified frame prefixed with its length.
+fn write_frame<W: Write>(w: &mut W, frame: &[u8]) -> io::Result<()> {
+    // Frames are bounded by `MAX_FRAME_LEN` which always fits.
+    w.write_all(&(frame.len() as u32).to_le_bytes())?;
+    w.write_all(frame)
+}
+
+/// An `RxBuf` is the reusable buffer of received frames. It keeps the progress
+/// of a frame whose reception is interrupted by a read timeout
+/// so that the next read resumes it.
+#[derive(Debug, Default)]
+struct RxBuf {
+    buf: Vec<u8>,
+    len: [u8; 4],
+    // The number of bytes of the length prefix and the frame read so far.
+    len_filled: usize,
+    filled: usize,
+}
+
+impl RxBuf {
+    /// Reads the next length-prefixed frame into the buffer,
+    /// growing it only if the frame doesn't fit.
+    ///
+    /// I/O errors are reported like those of `bincode` so that callers
+    /// can handle timeouts and disconnections the same way regardless of framing.
+    fn read_frame<R: Read>(&mut self, r: &mut R) -> bincode::Result<()> {
+        while self.len_filled < self.len.len() {
+            self.len_filled += read_some(r, &mut self.len[self.len_filled..])?;
+        }
+
+        let len = u32::from_le_bytes(self.len) as usize;
+        if len > MAX_FRAME_LEN {
+            return Err(Box::new(bincode::ErrorKind::SizeLimit));
+        }
+
+        if self.filled == 0 {
+            self.buf.resize(len, 0);
+        }
+
+        while self.filled < len {
+            self.filled += read_some(r, &mut self.buf[self.filled..len])?;
+        }
+
+        self.len_filled = 0;
+        self.filled = 0;
+
+        Ok(())
+    }
+
+    /// Reports whether a frame has been read partially.
+    fn is_partial(&self) -> bool {
+        self.len_filled > 0
+    }
+}
+
+/// Reads at least one byte into the buffer, retrying interrupted reads.
+/// Fails with an 'unexpected end of file' error if the reader is exhausted.
+fn read_some<R: Read>(r: &mut R, buf: &mut [u8]) -> io::Result<usize> {
+    loop {
+        match r.read(buf) {
+            Ok(0) => return Err(io::Error::from(io::ErrorKind::UnexpectedEof)),
+            Ok(n) => return Ok(n),
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+/// Deserializes the message contained in a decrypted frame.
+/// The data of a [`StreamMessage::Chunk`] is stored behind the message,
+/// so the buffer is moved into the message instead of copying the data.
+fn decode_message(buf: &mut Vec<u8>) -> bincode::Result<StreamMessage> {
+    let mut rest = buf.as_slice();
+    let message = bincode::options()
+        .with_fixint_encoding()
+        .allow_trailing_bytes()
+        .with_limit(buf.len() as u64)
+        .deserialize_from(&mut rest)?;
+    let header_len = buf.len() - rest.len();
+
+    match message {
+        StreamMessage::Chunk { seq, .. } => {
+            buf.drain(..header_len);
+            Ok(StreamMessage::Chunk {
+                seq,
+                data: mem::take(buf),
+            })
+        }
+        message => Ok(message),
+    }
+}
 
 impl StreamConn<Idle> {
-    /// Constructs a new `StreamConn` from a [`std::net::TcpStream`],
-    /// encryption key and nonce.
+    /// Constructs a new `StreamConn` from a [`Transport`]
+    /// and the session keys of the transmitting and receiving direction.
+    /// The session is plaintext if there are no keys.
     pub(crate) fn try_from_conn(
-        stream: TcpStream,
-        key: Vec<u8>,
-        nonce: Vec<u8>,
+        stream: Transport,
+        keys: Option<(SessionKey, SessionKey)>,
         remote_node_name: String,
+        remote_instance_id: Option<String>,
+        remote_software: String,
     ) -> io::Result<Self> {
         stream.set_read_timeout(Some(READ_TIMEOUT))?;
+        // Messages are already coalesced by the `BufWriter`. Delaying them further
+        // stalls the replication handshake of every single transmission.
+        stream.set_nodelay(true)?;
 
-        let key = Key::from_slice(&key);
-        let nonce = GenericArray::from_slice(&nonce);
+        let (tx_cipher, rx_cipher) = keys
+            .map(|(tx, rx)| (Mutex::new(TxCipher::new(tx)), Mutex::new(RxCipher::new(rx))))
+            .unzip();
 
         Ok(Self {
-            stream_read: Mutex::new(BufReader::with_capacity(2 * CHUNKSIZE, stream.try_clone()?)),
-            stream_write: Mutex::new(BufWriter::with_capacity(2 * CHUNKSIZE, stream)),
-            encryptor: Mutex::new(EncryptorBE32::new(key, nonce)),
-            decryptor: Mutex::new(DecryptorBE32::new(key, nonce)),
+            stream_read: Mutex::new(BufReader::with_capacity(NET_BUFSIZE, stream.try_clone()?)),
+            stream_write: Mutex::new(BufWriter::with_capacity(NET_BUFSIZE, stream)),
+            tx_buf: Mutex::new(Vec::new()),
+            rx_buf: Mutex::new(RxBuf::default()),
+            tx_cipher,
+            rx_cipher,
+            rekey_interval: DEFAULT_REKEY_INTERVAL,
             remote_node_name,
+            remote_instance_id,
+            remote_software,
+            chunk_size: DEFAULT_CHUNK_SIZE,
+            stall_timeout: DEFAULT_STALL_TIMEOUT,
+            heartbeat_interval: DEFAULT_HEARTBEAT_INTERVAL,
+            last_sent: Mutex::new(Instant::now()),
+            ping_sent: Mutex::new(None),
+            rate_limit: None,
             _phase: PhantomData,
         })
     }
 
+    /// Sets the time without data from the remote node after which
+    /// [`StreamConn::data_sync`] considers a reception stalled.
+    /// The default is [`DEFAULT_STALL_TIMEOUT`].
+    pub fn with_stall_timeout(mut self, stall_timeout: Duration) -> Self {
+        self.stall_timeout = stall_timeout;
+        self
+    }
+
+    /// Sets the time without sending anything after which [`StreamConn::data_sync`]
+    /// sends a heartbeat to keep the connection alive. Heartbeats that aren't answered
+    /// within the stall timeout abort the session.
+    /// The default is [`DEFAULT_HEARTBEAT_INTERVAL`].
+    pub fn with_heartbeat_interval(mut self, heartbeat_interval: Duration) -> Self {
+        self.heartbeat_interval = heartbeat_interval;
+        self
+    }
+
+    /// Sets the amount of data sent under a single session key in bytes
+    /// after which the next key is derived and used for the following messages.
+    /// The default is [`DEFAULT_REKEY_INTERVAL`]. Has no effect on plaintext sessions.
+    pub fn with_rekey_interval(mut self, rekey_interval: u64) -> Self {
+        self.rekey_interval = rekey_interval;
+        self
+    }
+
+    /// Limits the rate at which [`StreamConn::data_sync`] transmits backups
+    /// to the specified number of bytes per second, if any. Only data chunks
+    /// count towards the limit, control messages and receptions are unaffected.
+    /// Rates below [`MIN_RATE_LIMIT`] are raised to it. Unlimited by default.
+    pub fn with_rate_limit(mut self, rate_limit: Option<u64>) -> Self {
+        self.rate_limit = rate_limit.map(|rate_limit| rate_limit.max(MIN_RATE_LIMIT));
+        self
+    }
+
+    /// Exchanges key confirmation messages to make sure that both peers
+    /// derived the same session keys.
+    fn confirm(&self) -> Result<(), NetworkError> {
+        self.send_message(&StreamMessage::Confirm)?;
+
+        let message = loop {
+            match self.recv_message() {
+                Err(NetworkError::Bincode(bincode_err)) => match *bincode_err {
+                    bincode::ErrorKind::Io(io_err)
+                        if io_err.kind() == io::ErrorKind::WouldBlock
+                            || io_err.kind() == io::ErrorKind::TimedOut =>
+                    {
+                        continue
+                    }
+                    _ => return Err(bincode_err.into()),
+                },
+                Err(NetworkError::ChaCha20Poly1305(_)) => {
+                    return Err(NetworkError::KeyConfirmation)
+                }
+                result => break result?,
+            }
+        };
+
+        match message {
+            StreamMessage::Confirm => Ok(()),
+            _ => Err(NetworkError::KeyConfirmation),
+        }
+    }
+
     /// Exchanges synchronization information (timestamps), returning an `Active` `StreamConn`
     /// that can send and receive data.
+    ///
+    /// Outgoing data is sent in chunks of the smaller preferred chunk size of both nodes.
     pub fn meta_sync(
         self,
         sync_info: SyncInfo,
     ) -> Result<(StreamConn<Active>, SyncInfo), NetworkError> {
+        let chunk_size = sync_info.chunk_size;
         self.send_message(&StreamMessage::SyncInfo(sync_info))?;
 
         match self.recv_message()? {
-            StreamMessage::SyncInfo(sync_info) => Ok((
-                StreamConn::<Active> {
-                    stream_read: self.stream_read,
-                    stream_write: self.stream_write,
-                    encryptor: self.encryptor,
-                    decryptor: self.decryptor,
-                    remote_node_name: self.remote_node_name,
-                    _phase: PhantomData,
-                },
-                sync_info,
+            StreamMessage::SyncInfo(remote_sync_info) => Ok((
+                self.activate(chunk_size, remote_sync_info.chunk_size),
+                remote_sync_info,
             )),
+            StreamMessage::Error(e) => Err(e.into()),
+            _ => {
+                self.send_message(&StreamMessage::Error(RemoteError::IllegalTransition))?;
+                Err(NetworkError::IllegalTransition)
+            }
+        }
+    }
+
+    /// Requests proofs of possession of the specified backups
+    /// instead of synchronizing, consuming the `StreamConn`.
+    /// Returns the proofs or errors in the order of the challenges.
+    ///
+    /// Fails with [`NetworkError::VerifyTimeout`] if the proofs don't arrive
+    /// within the timeout. Proofs are computed at no more than [`VERIFY_RATE`],
+    /// so the timeout should allow for the combined length of the backups.
+    pub fn verify(
+        self,
+        challenges: Vec<Challenge>,
+        timeout: Duration,
+    ) -> Result<Vec<Result<Vec<u8>, RemoteError>>, NetworkError> {
+        self.send_message(&StreamMessage::Verify(challenges))?;
+
+        // Proofs are rate limited on the remote node and may take a while.
+        let deadline = Instant::now() + timeout;
+        let message = loop {
+            match self.recv_message() {
+                Err(NetworkError::Bincode(bincode_err)) => match *bincode_err {
+                    bincode::ErrorKind::Io(io_err)
+                        if io_err.kind() == io::ErrorKind::WouldBlock
+                            || io_err.kind() == io::ErrorKind::TimedOut =>
+                    {
+                        if Instant::now() >= deadline {
+                            return Err(NetworkError::VerifyTimeout(timeout));
+                        }
+
+                        continue;
+                    }
+                    _ => return Err(bincode_err.into()),
+                },
+                result => break result?,
+            }
+        };
+
+        match message {
+            StreamMessage::Proof(proofs) => Ok(proofs),
+            StreamMessage::Error(e) => Err(e.into()),
+            _ => {
+                self.send_message(&StreamMessage::Error(RemoteError::IllegalTransition))?;
+                Err(NetworkError::IllegalTransition)
+            }
+        }
+    }
+
+    /// Requests the snapshots and backups the remote node holds
+    /// of the volumes the local node may pull or owns
+    /// instead of synchronizing, consuming the `StreamConn`.
+    pub fn list(self) -> Result<Vec<Snapshot>, NetworkError> {
+        self.send_message(&StreamMessage::ListRequest)?;
+
+        match self.recv_message()? {
+            StreamMessage::ListResponse(snapshots) => Ok(snapshots),
+            StreamMessage::Error(e) => Err(e.into()),
+            _ => {
+                self.send_message(&StreamMessage::Error(RemoteError::IllegalTransition))?;
+                Err(NetworkError::IllegalTransition)
+            }
+        }
+    }
+
+    /// Requests the deletion of the specified backups of the local node's
+    /// own volumes from the remote node instead of synchronizing,
+    /// consuming the `StreamConn`.
+    /// Returns the result of each deletion in the order of the request.
+    pub fn prune(
+        self,
+        snapshots: Vec<Snapshot>,
+    ) -> Result<Vec<Result<(), RemoteError>>, NetworkError> {
+        let n = snapshots.len();
+        self.send_message(&StreamMessage::Prune(snapshots))?;
+
+        match self.recv_message()? {
+            StreamMessage::PruneResponse(results) if results.len() == n => Ok(results),
+            StreamMessage::Error(e) => Err(e.into()),
             _ => {
                 self.send_message(&StreamMessage::Error(RemoteError::IllegalTransition))?;
                 Err(NetworkError::IllegalTransition)
             }
         }
     }
+
+    /// Requests the replacement of the authentication credentials
+    /// of the local node on the remote node instead of synchronizing,
+    /// consuming the `StreamConn`. The previous credentials remain valid
+    /// unless the remote node confirms the replacement.
+    pub fn rotate_auth(self, credentials: Credentials) -> Result<(), NetworkError> {
+        self.send_message(&StreamMessage::RotateAuth(credentials))?;
+
+        match self.recv_message()? {
+            StreamMessage::RotateAuthResponse(result) => Ok(result?),
+            StreamMessage::Error(e) => Err(e.into()),
+            _ => {
+                self.send_message(&StreamMessage::Error(RemoteError::IllegalTransition))?;
+                Err(NetworkError::IllegalTransition)
+            }
+        }
+    }
+
+    /// Server counterpart of [`StreamConn::meta_sync`], [`StreamConn::verify`],
+    /// [`StreamConn::list`], [`StreamConn::prune`] and [`StreamConn::rotate_auth`].
+    ///
+    /// Waits for the request of the client. Synchronization requests are answered
+    /// with the synchronization information returned by the `sync_info` closure,
+    /// which is passed the information of the client, returning an `Active` `StreamConn`.
+    /// Verification, list, prune and rotation requests are answered using
+    /// the provided closures and return `None`, ending the session.
+    /// Requests with more than [`MAX_CHALLENGES`] challenges are rejected.
+    pub fn meta_sync_or_verify<S, V, L, P, R>(
+        self,
+        sync_info: S,
+        prove: V,
+        list: L,
+        prune: P,
+        rotate: R,
+    ) -> Result<Option<(StreamConn<Active>, SyncInfo)>, NetworkError>
+    where
+        S: FnOnce(&SyncInfo) -> Result<SyncInfo, NetworkError>,
+        V: Fn(&Challenge) -> Result<Vec<u8>, RemoteError>,
+        L: FnOnce() -> Result<Vec<Snapshot>, RemoteError>,
+        P: FnOnce(Vec<Snapshot>) -> Vec<Result<(), RemoteError>>,
+        R: FnOnce(Credentials) -> Result<(), RemoteError>,
+    {
+        match self.recv_message()? {
+            StreamMessage::SyncInfo(remote_sync_info) => {
+                let sync_info = sync_info(&remote_sync_info)?;
+                let chunk_size = sync_info.chunk_size;
+                self.send_message(&StreamMessage::SyncInfo(sync_info))?;
+
+                Ok(Some((
+                    self.activate(chunk_size, remote_sync_info.chunk_size),
+                    remote_sync_info,
+                )))
+            }
+            StreamMessage::Verify(challenges) => {
+                if challenges.len() > MAX_CHALLENGES {
+                    self.send_message(&StreamMessage::Error(RemoteError::LimitExceeded))?;
+                    return Err(RemoteError::LimitExceeded.into());
+                }
+
+                let proofs = challenges.iter().map(prove).collect();
+                self.send_message(&StreamMessage::Proof(proofs))?;
+
+                Ok(None)
+            }
+            StreamMessage::ListRequest => {
+                match list() {
+                    Ok(snapshots) => self.send_message(&StreamMessage::ListResponse(snapshots))?,
+                    Err(e) => self.send_message(&StreamMessage::Error(e))?,
+                }
+
+                Ok(None)
+            }
+            StreamMessage::Prune(snapshots) => {
+                self.send_message(&StreamMessage::PruneResponse(prune(snapshots)))?;
+                Ok(None)
+            }
+            StreamMessage::RotateAuth(credentials) => {
+                self.send_message(&StreamMessage::RotateAuthResponse(rotate(credentials)))?;
+                Ok(None)
+            }
+            _ => {
+                self.send_message(&StreamMessage::Error(RemoteError::IllegalTransition))?;
+                Err(NetworkError::IllegalTransition)
+            }
+        }
+    }
+
+    /// Refuses the session with the specified error
+    /// once the client has sent its request.
+    pub fn refuse(self, e: RemoteError) -> Result<(), NetworkError> {
+        self.recv_message()?;
+        self.send_message(&StreamMessage::Error(e))
+    }
+
+    fn activate(self, local_chunk_size: usize, remote_chunk_size: usize) -> StreamConn<Active> {
+        StreamConn::<Active> {
+            stream_read: self.stream_read,
+            stream_write: self.stream_write,
+            tx_buf: self.tx_buf,
+            rx_buf: self.rx_buf,
+            tx_cipher: self.tx_cipher,
+            rx_cipher: self.rx_cipher,
+            rekey_interval: self.rekey_interval,
+            remote_node_name: self.remote_node_name,
+            remote_instance_id: self.remote_instance_id,
+            remote_software: self.remote_software,
+            chunk_size: local_chunk_size
+                .min(remote_chunk_size)
+                .clamp(MIN_CHUNK_SIZE, MAX_CHUNK_SIZE),
+            stall_timeout: self.stall_timeout,
+            heartbeat_interval: self.heartbeat_interval,
+            last_sent: self.last_sent,
+            ping_sent: self.ping_sent,
+            rate_limit: self.rate_limit,
+            _phase: PhantomData,
+        }
+    }
 }
 
 impl StreamConn<Active> {
     /// Transmits the passed [`std::io::Read`]s using their associated metadata.
     /// Receives remote transmissions using the provided stream setup closure.
-    pub fn data_sync<B, W, I, S, F>(
+    ///
+    /// Returns the [`SyncStats`] of the session.
+    ///
+    /// Fails with [`RemoteError::ShuttingDown`] if the remote node ends the session early.
+    /// The transmissions completed up to that point remain valid.
+    ///
+    /// Fails with [`NetworkError::Stalled`] if a reception doesn't receive any data
+    /// within the stall timeout, see [`StreamConn::with_stall_timeout`].
+    /// `rx_finish` is not called for the incomplete reception.
+    ///
+    /// While either node has nothing to send, e.g. because `btrfs send` is still
+    /// preparing its output, it sends heartbeats to keep the connection alive,
+    /// see [`StreamConn::with_heartbeat_interval`]. The session fails with
+    /// [`NetworkError::Stalled`] as well if a heartbeat isn't answered within the stall timeout.
+    ///
+    /// If reading a local transmission fails, the remote node is notified
+    /// with [`RemoteError::TxError`] and the session fails. Likewise, if the remote node
+    /// fails to produce its transmission, the incomplete reception
+    /// is passed to `rx_discard` instead of `rx_finish`.
+    ///
+    /// `rx_finish` receives the [`Integrity`] announced by the remote node,
+    /// which has already been checked against the received data.
+    pub fn data_sync<B, W, I, S, F, D>(
         self,
         tx: I,
         rx_setup: S,
         rx_finish: F,
-    ) -> Result<(), NetworkError>
+        rx_discard: D,
+    ) -> Result<SyncStats, NetworkError>
     where
         B: BufRead,
         W: Write + Send,
         I: IntoIterator<Item = (B, Snapshot)> + Send,
-        S: Fn(&Snapshot) -> Result<W, RemoteError> + Sync,
-        F: Fn(Snapshot) -> Result<(), RemoteError> + Sync,
+        S: Fn(&Target) -> Result<W, RemoteError> + Sync,
+        F: Fn(Snapshot, &Integrity) -> Result<(), RemoteError> + Sync,
+        D: Fn(Snapshot) + Sync,
     {
+        self.data_sync_until(
+            tx,
+            rx_setup,
+            rx_finish,
+            rx_discard,
+            &Window::default(),
+            |_| None,
+        )
+    }
+
+    /// Like [`StreamConn::data_sync`], but ends the session early
+    /// when the [`Window`] is closing or aborted.
+    ///
+    /// Once the `Window` is closing, the remote node is notified of the deadline.
+    /// When either node announces a deadline, the remaining local transmissions
+    /// are reordered from smallest to largest according to the `estimate` closure
+    /// and only started if they are expected to complete in time
+    /// at the transfer rate observed so far, or [`WRAP_UP_RATE`] if nothing
+    /// has been sent yet. Transmissions without an estimate are started last
+    /// as long as the deadline hasn't passed. The session then ends gracefully.
+    /// The resulting [`WrapUp`], if any, is part of the returned [`SyncStats`].
+    ///
+    /// Once the `Window` is aborted, no further transmissions are started and the current one
+    /// is aborted after its current chunk. The remote node is notified so that it stops
+    /// transmitting as well, then the session is shut down gracefully.
+    /// Aborted transmissions can be retried in a later session.
+    pub fn data_sync_until<B, W, I, S, F, D, E>(
+        self,
+        tx: I,
+        rx_setup: S,
+        rx_finish: F,
+        rx_discard: D,
+        window: &Window,
+        estimate: E,
+    ) -> Result<SyncStats, NetworkError>
+    where
+        B: BufRead,
+        W: Write + Send,
+        I: IntoIterator<Item = (B, Snapshot)> + Send,
+        S: Fn(&Target) -> Result<W, RemoteError> + Sync,
+        F: Fn(Snapshot, &Integrity) -> Result<(), RemoteError> + Sync,
+        D: Fn(Snapshot) + Sync,
+        E: Fn(&Snapshot) -> Option<u64> + Sync,
+    {
+        let session_started = Instant::now();
+
         let mut stream = None;
-        let start_streaming = Arc::new(Mutex::new(false));
+        let mut next_transfer = 0;
+        let mut received = Vec::new();
+        let signal = Signal::default();
 
         let mut handle = |message| -> Result<bool, NetworkError> {
             match message {
+                StreamMessage::Stream(Err(
+                    e @ (RemoteError::InsufficientSpace(..) | RemoteError::MissingParent),
+                )) => {
+                    signal.update(|state| state.rejected = Some(e));
+                }
                 StreamMessage::Stream(stream) => {
-                    *start_streaming.lock().unwrap() = true;
                     stream?;
+                    signal.update(|state| state.start_streaming = true);
                 }
                 StreamMessage::Replicate(replicate) => {
+                    if replicate.transfer != next_transfer {
+                        self.send_message(&StreamMessage::Error(RemoteError::IntegrityFailure))?;
+                        return Err(NetworkError::SequenceMismatch {
+                            snapshot: replicate.snapshot.to_string(),
+                            unit: "transfer",
+                            expected: next_transfer,
+                            got: replicate.transfer,
+                        });
+                    }
+                    next_transfer += 1;
+
                     if stream.is_none() {
-                        match rx_setup(&replicate.snapshot) {
+                        match rx_setup(&replicate) {
                             Ok(w) => {
-                                stream = Some((w, replicate.snapshot));
+                                stream = Some((
+                                    w,
+                                    replicate.snapshot,
+                                    Tally::default(),
+                                    Instant::now(),
+                                    replicate.transfer,
+                                ));
                                 self.send_message(&StreamMessage::Stream(Ok(())))?;
                             }
+                            // The remote node skips to its next transmission.
+                            Err(
+                                e @ (RemoteError::InsufficientSpace(..)
+                                | RemoteError::MissingParent),
+                            ) => {
+                                self.send_message(&StreamMessage::Stream(Err(e)))?;
+                            }
                             Err(e) => {
                                 self.send_message(&StreamMessage::Stream(Err(e.clone())))?;
                                 return Err(e.into());
@@ -421,10 +1757,30 @@ impl StreamConn<Active> {
                         )))?;
                     }
                 }
-                StreamMessage::Chunk(chunk) => {
+                StreamMessage::Chunk { seq, data: chunk } => {
+                    if chunk.len() > MAX_CHUNK_SIZE {
+                        self.send_message(&StreamMessage::Error(RemoteError::LimitExceeded))?;
+                        return Err(RemoteError::LimitExceeded.into());
+                    }
+
                     if let Some(stream) = &mut stream {
+                        if seq != stream.2.chunks {
+                            self.send_message(&StreamMessage::Error(
+                                RemoteError::IntegrityFailure,
+                            ))?;
+                            return Err(NetworkError::SequenceMismatch {
+                                snapshot: stream.1.to_string(),
+                                unit: "chunk",
+                                expected: stream.2.chunks,
+                                got: seq,
+                            });
+                        }
+
                         match stream.0.write_all(&chunk) {
-                            Ok(_) => {}
+                            Ok(_) => {
+                                stream.2.update(&chunk);
+                                self.recycle(chunk);
+                            }
                             Err(e) => {
                                 self.send_message(&StreamMessage::Error(RemoteError::RxError))?;
                                 return Err(e.into());
@@ -434,20 +1790,73 @@ impl StreamConn<Active> {
                         self.send_message(&StreamMessage::Error(RemoteError::NotStreaming))?;
                     }
                 }
-                StreamMessage::End(end) => {
-                    end?;
+                StreamMessage::End { result: Err(e), .. } => {
+                    if let Some((w, snapshot, _, _, _)) = stream.take() {
+                        drop(w);
+                        rx_discard(snapshot);
+                    }
 
-                    if let Some(current_stream) = stream.take() {
+                    return Err(e.into());
+                }
+                StreamMessage::End {
+                    transfer,
+                    result: Ok(integrity),
+                } => {
+                    if let Some(mut current_stream) = stream.take() {
                         drop(current_stream.0);
 
-                        if let Err(e) = rx_finish(current_stream.1) {
+                        if transfer != current_stream.4 {
+                            self.send_message(&StreamMessage::Error(
+                                RemoteError::IntegrityFailure,
+                            ))?;
+                            return Err(NetworkError::SequenceMismatch {
+                                snapshot: current_stream.1.to_string(),
+                                unit: "transfer",
+                                expected: current_stream.4,
+                                got: transfer,
+                            });
+                        }
+
+                        // Keep the incomplete data for inspection, don't commit it.
+                        if current_stream.2.finish() != integrity {
+                            self.send_message(&StreamMessage::Error(
+                                RemoteError::IntegrityFailure,
+                            ))?;
+                            return Err(RemoteError::IntegrityFailure.into());
+                        }
+
+                        let stats = TransferStats {
+                            snapshot: current_stream.1.clone(),
+                            bytes: current_stream.2.len,
+                            elapsed: current_stream.3.elapsed(),
+                            outcome: TransferOutcome::Completed,
+                        };
+
+                        if let Err(e) = rx_finish(current_stream.1, &integrity) {
                             self.send_message(&StreamMessage::Error(e.clone()))?;
                             return Err(e.into());
                         }
+
+                        received.push(stats);
                     } else {
                         self.send_message(&StreamMessage::Error(RemoteError::NotStreaming))?;
                     }
                 }
+                StreamMessage::Closing { deadline } => {
+                    signal.update(|state| state.remote_deadline = Some(deadline));
+                }
+                StreamMessage::ShuttingDown => {
+                    // Abort the current reception. It can be retried later.
+                    if let Some((_, snapshot, tally, started, _)) = stream.take() {
+                        received.push(TransferStats {
+                            snapshot,
+                            bytes: tally.len,
+                            elapsed: started.elapsed(),
+                            outcome: TransferOutcome::Aborted,
+                        });
+                    }
+                    signal.update(|state| state.remote_shutdown = true);
+                }
                 StreamMessage::Done => return Ok(true),
                 StreamMessage::Error(e) => return Err(e.into()),
                 _ => {
@@ -459,40 +1868,263 @@ impl StreamConn<Active> {
             Ok(false)
         };
 
-        let send_chunk = |r: &mut B| -> Result<bool, NetworkError> {
-            let mut chunk = vec![0; 16 + CHUNKSIZE];
-            let n = r.read(&mut chunk)?;
-            chunk.truncate(n);
+        // Keep chunks small enough to be sent within about a second at the limited rate
+        // so that the remote node doesn't consider the reception stalled.
+        let chunk_size = match self.rate_limit {
+            Some(rate_limit) => self
+                .chunk_size
+                .min(usize::try_from(rate_limit).unwrap_or(usize::MAX))
+                .max(MIN_CHUNK_SIZE),
+            None => self.chunk_size,
+        };
+
+        let transmit_chunk = |r: &mut B,
+                              chunk: &mut [u8],
+                              transfer: u64,
+                              tally: &mut Tally,
+                              limiter: Option<&mut RateLimiter>|
+         -> Result<bool, NetworkError> {
+            let n = match r.read(chunk) {
+                Ok(n) => n,
+                Err(e) => {
+                    self.send_message(&StreamMessage::End {
+                        transfer,
+                        result: Err(RemoteError::TxError),
+                    })?;
+
+                    // Surface the cause of failing btrfs send processes.
+                    return Err(match e.downcast::<LocalNodeError>() {
+                        Ok(e) => e.into(),
+                        Err(e) => e.into(),
+                    });
+                }
+            };
+            let chunk = &chunk[..n];
 
             if !chunk.is_empty() {
-                self.send_message(&StreamMessage::Chunk(chunk))?;
+                if let Some(limiter) = limiter {
+                    limiter.take(chunk.len());
+                }
+
+                let seq = tally.chunks;
+                tally.update(chunk);
+                self.send_chunk(seq, chunk)?;
                 Ok(true)
             } else {
-                self.send_message(&StreamMessage::End(Ok(())))?;
+                self.send_message(&StreamMessage::End {
+                    transfer,
+                    result: Ok(tally.finish()),
+                })?;
                 Ok(false)
             }
         };
 
-        let local_done = Mutex::new(false);
-        thread::scope(|s| {
-            let mut tx = Some(s.spawn(|| -> Result<(), NetworkError> {
-                for (mut r, snapshot) in tx.into_iter() {
-                    self.send_message(&StreamMessage::Replicate(snapshot.into()))?;
+        let should_stop = || window.is_aborted() || signal.state.lock().unwrap().remote_shutdown;
+
+        // The earliest deadline announced by either node.
+        let deadline = || {
+            [
+                window.deadline(),
+                signal.state.lock().unwrap().remote_deadline,
+            ]
+            .into_iter()
+            .flatten()
+            .min()
+        };
+
+        let announce_closing = || -> Result<(), NetworkError> {
+            if let Some(deadline) = window.deadline() {
+                let mut state = signal.state.lock().unwrap();
+                if !state.closing_sent {
+                    state.closing_sent = true;
+                    drop(state);
 
-                    while !*start_streaming.lock().unwrap() {
-                        thread::sleep(READ_TIMEOUT);
+                    self.send_message(&StreamMessage::Closing { deadline })?;
+                }
+            }
+
+            Ok(())
+        };
+
+        let send_heartbeat = || -> Result<(), NetworkError> {
+            if self.ping_sent.lock().unwrap().is_some()
+                || self.last_sent.lock().unwrap().elapsed() < self.heartbeat_interval
+            {
+                return Ok(());
+            }
+
+            self.send_message(&StreamMessage::Ping)?;
+            *self.ping_sent.lock().unwrap() = Some(Instant::now());
+
+            Ok(())
+        };
+
+        let announce_shutdown = || -> Result<(), NetworkError> {
+            let mut state = signal.state.lock().unwrap();
+            if !state.shutdown_sent {
+                state.shutdown_sent = true;
+                drop(state);
+
+                self.send_message(&StreamMessage::ShuttingDown)?;
+            }
+
+            Ok(())
+        };
+
+        let (sent, mut wrap_up) = thread::scope(|s| {
+            let mut tx = Some(s.spawn(|| -> Result<_, NetworkError> {
+                let mut guard = WorkerGuard::new(&signal, Worker::Tx);
+
+                let mut queue: VecDeque<_> = tx.into_iter().collect();
+                let mut transfers = Vec::new();
+                let mut wrap_up: Option<WrapUp> = None;
+
+                let started = Instant::now();
+                let mut sent = 0;
+                let mut limiter = self.rate_limit.map(RateLimiter::new);
+                // Reused for all chunks of all transmissions.
+                let mut chunk = vec![0; chunk_size];
+                let mut next_transfer = 0;
+
+                loop {
+                    if should_stop() {
+                        break;
                     }
-                    *start_streaming.lock().unwrap() = false;
 
-                    while send_chunk(&mut r)? {}
+                    if let Some(deadline) = deadline() {
+                        let wrap_up = wrap_up.get_or_insert_with(|| {
+                            // Complete as many transmissions as possible.
+                            queue.make_contiguous().sort_by_cached_key(|(_, snapshot)| {
+                                estimate(snapshot).unwrap_or(u64::MAX)
+                            });
+
+                            WrapUp {
+                                deadline,
+                                skipped: Vec::new(),
+                            }
+                        });
+                        wrap_up.deadline = deadline;
+
+                        let rate = if sent > 0 {
+                            sent as f64 / started.elapsed().as_secs_f64()
+                        } else {
+                            WRAP_UP_RATE as f64
+                        };
+                        let remaining = (deadline - Utc::now().naive_utc())
+                            .to_std()
+                            .unwrap_or_default()
+                            .as_secs_f64();
+
+                        // The queue is sorted, none of the larger transmissions fit either.
+                        while let Some((_, snapshot)) = queue.front() {
+                            let fits = match estimate(snapshot) {
+                                Some(len) => len as f64 <= rate * remaining,
+                                None => remaining > 0.0,
+                            };
+                            if fits {
+                                break;
+                            }
+
+                            let (_, snapshot) = queue.pop_front().expect("queue is not empty");
+                            transfers.push(TransferStats {
+                                snapshot: snapshot.clone(),
+                                bytes: 0,
+                                elapsed: Duration::ZERO,
+                                outcome: TransferOutcome::Skipped,
+                            });
+                            wrap_up.skipped.push(snapshot);
+                        }
+                    }
+
+                    let Some((mut r, snapshot)) = queue.pop_front() else {
+                        break;
+                    };
+
+                    let transfer = next_transfer;
+                    next_transfer += 1;
+
+                    self.send_message(&StreamMessage::Replicate(Target {
+                        transfer,
+                        expected_len: estimate(&snapshot),
+                        snapshot: snapshot.clone(),
+                    }))?;
+                    let transmission_started = Instant::now();
+
+                    // The receive thread only exits early on error,
+                    // in which case the supervisor reports its error instead of ours.
+                    let mut state = signal.wait_while(|state| {
+                        !state.start_streaming && state.rejected.is_none() && !state.rx_finished
+                    });
+                    if let Some(e) = state.rejected.take() {
+                        drop(state);
+
+                        transfers.push(TransferStats {
+                            snapshot,
+                            bytes: 0,
+                            elapsed: transmission_started.elapsed(),
+                            outcome: TransferOutcome::Rejected(e),
+                        });
+                        continue;
+                    }
+                    if !state.start_streaming {
+                        return Err(NetworkError::IllegalTransition);
+                    }
+                    state.start_streaming = false;
+                    drop(state);
+
+                    let mut tally = Tally::default();
+                    let mut outcome = TransferOutcome::Completed;
+                    while transmit_chunk(
+                        &mut r,
+                        &mut chunk,
+                        transfer,
+                        &mut tally,
+                        limiter.as_mut(),
+                    )? {
+                        if should_stop() {
+                            outcome = TransferOutcome::Aborted;
+                            break;
+                        }
+                    }
+
+                    let aborted = outcome == TransferOutcome::Aborted;
+
+                    sent += tally.len;
+                    transfers.push(TransferStats {
+                        snapshot,
+                        bytes: tally.len,
+                        elapsed: transmission_started.elapsed(),
+                        outcome,
+                    });
+
+                    if aborted {
+                        break;
+                    }
                 }
 
-                Ok(())
+                if should_stop() {
+                    announce_shutdown()?;
+                }
+
+                transfers.extend(queue.into_iter().map(|(_, snapshot)| TransferStats {
+                    snapshot,
+                    bytes: 0,
+                    elapsed: Duration::ZERO,
+                    outcome: TransferOutcome::Skipped,
+                }));
+
+                guard.succeed();
+                Ok((transfers, wrap_up))
             }));
             let mut rx = Some(s.spawn(|| -> Result<(), NetworkError> {
+                let mut guard = WorkerGuard::new(&signal, Worker::Rx);
+
                 let mut remote_done = false;
+                // A reception is in progress and expects data.
+                let mut receiving = false;
+                let mut last_data = Instant::now();
 
-                while !*local_done.lock().unwrap() || !remote_done {
+                while !signal.state.lock().unwrap().local_done || !remote_done {
                     let message = match self.recv_message() {
                         Ok(message) => message,
                         Err(NetworkError::Bincode(bincode_err)) => match *bincode_err {
@@ -500,59 +2132,725 @@ impl StreamConn<Active> {
                                 if io_err.kind() == io::ErrorKind::WouldBlock
                                     || io_err.kind() == io::ErrorKind::TimedOut =>
                             {
-                                continue
+                                if receiving && last_data.elapsed() >= self.stall_timeout {
+                                    return Err(NetworkError::Stalled(self.stall_timeout));
+                                }
+
+                                let ping_sent = *self.ping_sent.lock().unwrap();
+                                if ping_sent.is_some_and(|ping_sent| {
+                                    ping_sent.elapsed() >= self.stall_timeout
+                                }) {
+                                    return Err(NetworkError::Stalled(self.stall_timeout));
+                                }
+
+                                continue;
                             }
                             bincode::ErrorKind::Io(io_err)
                                 if io_err.kind() == io::ErrorKind::UnexpectedEof
-                                    && *local_done.lock().unwrap()
+                                    && signal.state.lock().unwrap().local_done
                                     && remote_done =>
                             {
-                                return Ok(())
+                                guard.succeed();
+                                return Ok(());
                             }
                             _ => return Err(bincode_err.into()),
                         },
                         Err(e) => return Err(e),
                     };
 
+                    match message {
+                        StreamMessage::Replicate(_) | StreamMessage::Chunk { .. } => {
+                            receiving = true;
+                            last_data = Instant::now();
+                        }
+                        StreamMessage::End { .. }
+                        | StreamMessage::ShuttingDown
+                        | StreamMessage::Done => receiving = false,
+                        _ => {}
+                    }
+
                     if handle(message)? {
                         remote_done = true;
                     }
                 }
 
+                guard.succeed();
                 Ok(())
             }));
 
+            let mut sent = Vec::new();
+            let mut wrap_up = None;
+            let mut local_done = false;
             let mut remote_done = false;
-            loop {
-                // Unlock the local_done mutex before sleeping to prevent receive thread deadlock.
-                {
-                    let mut local_done = local_done.lock().unwrap();
-                    if tx.as_ref().map(|tx| tx.is_finished()).unwrap_or(false) && !*local_done {
-                        tx.take()
-                            .expect("tx thread already joined")
-                            .join()
-                            .unwrap()?;
-                        *local_done = true;
-
-                        self.send_message(&StreamMessage::Done)?;
-                    }
-                    if rx.as_ref().map(|rx| rx.is_finished()).unwrap_or(false) && !remote_done {
-                        rx.take()
-                            .expect("rx thread already joined")
-                            .join()
-                            .unwrap()?;
-                        remote_done = true;
-                    }
+            while !local_done || !remote_done {
+                // The transmit thread handles shutdowns while it is running.
+                // Poll the shutdown flag once it has exited.
+                let state = signal.wait_timeout_while(READ_TIMEOUT, |state| {
+                    (!state.tx_finished || local_done) && (!state.rx_finished || remote_done)
+                });
+                let (tx_finished, rx_finished, failed) =
+                    (state.tx_finished, state.rx_finished, state.failed);
+                drop(state);
+
+                // The worker that failed first reports the cause, the other one
+                // usually only fails because it can't continue without it.
+                if rx_finished && !remote_done && failed == Some(Worker::Rx) {
+                    rx.take()
+                        .expect("rx thread already joined")
+                        .join()
+                        .unwrap()?;
+                }
 
-                    if *local_done && remote_done {
-                        break;
-                    }
+                announce_closing()?;
+                send_heartbeat()?;
+
+                if local_done && window.is_aborted() {
+                    announce_shutdown()?;
                 }
 
-                thread::sleep(READ_TIMEOUT);
+                if tx_finished && !local_done {
+                    (sent, wrap_up) = tx
+                        .take()
+                        .expect("tx thread already joined")
+                        .join()
+                        .unwrap()?;
+                    local_done = true;
+                    signal.update(|state| state.local_done = true);
+
+                    self.send_message(&StreamMessage::Done)?;
+                }
+                if rx_finished && !remote_done {
+                    rx.take()
+                        .expect("rx thread already joined")
+                        .join()
+                        .unwrap()?;
+                    remote_done = true;
+                }
             }
 
-            Ok(())
+            Ok::<_, NetworkError>((sent, wrap_up))
+        })?;
+
+        if signal.state.lock().unwrap().remote_shutdown && !window.is_aborted() {
+            return Err(RemoteError::ShuttingDown.into());
+        }
+
+        // The deadline may only have been announced after all local transmissions.
+        if wrap_up.is_none() {
+            wrap_up = deadline().map(|deadline| WrapUp {
+                deadline,
+                skipped: Vec::new(),
+            });
+        }
+
+        Ok(SyncStats {
+            sent,
+            received,
+            elapsed: session_started.elapsed(),
+            wrap_up,
         })
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sync::{self, RestoreChains};
+
+    use std::collections::HashMap;
+    use std::net::{Ipv4Addr, TcpListener};
+
+    /// The number of tiny transmissions of the latency test.
+    const TRANSFERS: u32 = 25;
+
+    /// Returns an authenticated pair of client and server connections
+    /// over an in-memory socket, skipping the expensive key derivation.
+    fn pair() -> (StreamConn<Idle>, StreamConn<Idle>) {
+        let (client, server) = UnixStream::pair().unwrap();
+        authenticated(client.into(), server.into())
+    }
+
+    /// Returns an authenticated pair of client and server connections
+    /// over the loopback interface, skipping the expensive key derivation.
+    fn tcp_pair() -> (StreamConn<Idle>, StreamConn<Idle>) {
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+        let client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (server, _) = listener.accept().unwrap();
+
+        authenticated(client.into(), server.into())
+    }
+
+    fn authenticated(client: Transport, server: Transport) -> (StreamConn<Idle>, StreamConn<Idle>) {
+        let key = system::random_bytes_secret(32).unwrap();
+        let (client_nonce, server_nonce) = (b"client".as_slice(), b"server".as_slice());
+
+        let (tx, rx) = system::derive_session_keys(&key, client_nonce, server_nonce);
+        let client = StreamConn::try_from_conn(
+            client,
+            Some((tx, rx)),
+            String::from("server"),
+            None,
+            SOFTWARE_VERSION.to_string(),
+        )
+        .unwrap();
+
+        let (rx, tx) = system::derive_session_keys(&key, client_nonce, server_nonce);
+        let server = StreamConn::try_from_conn(
+            server,
+            Some((tx, rx)),
+            String::from("client"),
+            None,
+            SOFTWARE_VERSION.to_string(),
+        )
+        .unwrap();
+
+        (client, server)
+    }
+
+    fn sync_info(chunk_size: usize) -> SyncInfo {
+        SyncInfo {
+            volumes: HashMap::new(),
+            patterns: Vec::new(),
+            receive_protocol: 1,
+            chunk_size,
+            compression: false,
+            held: None,
+        }
+    }
+
+    /// Exchanges the synchronization information of both nodes.
+    fn activate(
+        client: StreamConn<Idle>,
+        server: StreamConn<Idle>,
+        client_chunk_size: usize,
+        server_chunk_size: usize,
+    ) -> (StreamConn<Active>, StreamConn<Active>) {
+        thread::scope(|s| {
+            let server = s.spawn(|| {
+                server
+                    .meta_sync_or_verify(
+                        |_| Ok(sync_info(server_chunk_size)),
+                        |_| Err(RemoteError::AccessDenied),
+                        || Err(RemoteError::AccessDenied),
+                        |_| Vec::new(),
+                        |_| Err(RemoteError::AccessDenied),
+                    )
+                    .unwrap()
+                    .expect("client requested synchronization")
+                    .0
+            });
+
+            let (client, _) = client.meta_sync(sync_info(client_chunk_size)).unwrap();
+            (client, server.join().unwrap())
+        })
+    }
+
+    fn snapshot(i: u32) -> Snapshot {
+        Snapshot::try_from(format!("client_subvol_incr_20240101{:06}", i).as_str()).unwrap()
+    }
+
+    #[test]
+    fn tiny_transfers_dont_wait_for_timeouts() {
+        let (client, server) = pair();
+        let (client, server) = activate(client, server, DEFAULT_CHUNK_SIZE, DEFAULT_CHUNK_SIZE);
+
+        let data = b"tiny";
+        let tx: Vec<_> = (0..TRANSFERS).map(|i| (&data[..], snapshot(i))).collect();
+        let received = Mutex::new(0);
+
+        let started = Instant::now();
+        thread::scope(|s| {
+            let server = s.spawn(|| {
+                server.data_sync(
+                    Vec::<(&[u8], Snapshot)>::new(),
+                    |_| Ok(Vec::new()),
+                    |_, _| {
+                        *received.lock().unwrap() += 1;
+                        Ok(())
+                    },
+                    |_| {},
+                )
+            });
+
+            let stats = client
+                .data_sync(tx, |_| Ok(Vec::new()), |_, _| Ok(()), |_| {})
+                .unwrap();
+            assert_eq!(stats.sent.len(), TRANSFERS as usize);
+
+            server.join().unwrap().unwrap();
+        });
+        let elapsed = started.elapsed();
+
+        assert_eq!(*received.lock().unwrap(), TRANSFERS);
+        // Waiting for a read timeout per transmission would take five seconds,
+        // waiting once at the end of the session is fine.
+        assert!(
+            elapsed < READ_TIMEOUT * TRANSFERS / 4,
+            "{} transfers took {:?}",
+            TRANSFERS,
+            elapsed
+        );
+    }
+
+    #[test]
+    fn verification_gives_up_on_silent_peer() {
+        let (client, _server) = pair();
+        let timeout = Duration::from_millis(500);
+
+        let challenge = Challenge {
+            snapshot: snapshot(0),
+            challenge: vec![0; 32],
+        };
+
+        let started = Instant::now();
+        let result = client.verify(vec![challenge], timeout);
+
+        assert!(matches!(result, Err(NetworkError::VerifyTimeout(_))));
+        assert!(started.elapsed() < timeout + 2 * READ_TIMEOUT);
+    }
+
+    #[test]
+    fn reception_error_is_reported_instead_of_transition() {
+        let (client, server) = pair();
+        let (client, server) = activate(client, server, DEFAULT_CHUNK_SIZE, DEFAULT_CHUNK_SIZE);
+
+        thread::scope(|s| {
+            let server = s.spawn(|| {
+                server.data_sync(
+                    Vec::<(&[u8], Snapshot)>::new(),
+                    |_| Err::<Vec<u8>, _>(RemoteError::RxError),
+                    |_, _| Ok(()),
+                    |_| {},
+                )
+            });
+
+            let result = client.data_sync(
+                [(&b"data"[..], snapshot(0))],
+                |_| Ok(Vec::new()),
+                |_, _| Ok(()),
+                |_| {},
+            );
+            assert!(
+                matches!(result, Err(NetworkError::RemoteError(RemoteError::RxError))),
+                "{:?}",
+                result.map(|stats| stats.sent)
+            );
+
+            assert!(server.join().unwrap().is_err());
+        });
+    }
+
+    #[test]
+    fn wrap_up_starts_transmissions_before_any_data_is_sent() {
+        let (client, server) = pair();
+        let (client, server) = activate(client, server, DEFAULT_CHUNK_SIZE, DEFAULT_CHUNK_SIZE);
+
+        let window = Window::default();
+        window.close(Utc::now().naive_utc() + chrono::Duration::seconds(60));
+
+        let data = b"data";
+        let tx: Vec<_> = (0..3).map(|i| (&data[..], snapshot(i))).collect();
+        let estimate = |snapshot: &Snapshot| {
+            if *snapshot == self::snapshot(0) {
+                None
+            } else if *snapshot == self::snapshot(1) {
+                Some(data.len() as u64)
+            } else {
+                // Doesn't fit within a minute at any plausible rate.
+                Some(1 << 50)
+            }
+        };
+
+        thread::scope(|s| {
+            let server = s.spawn(|| {
+                server.data_sync(
+                    Vec::<(&[u8], Snapshot)>::new(),
+                    |_| Ok(Vec::new()),
+                    |_, _| Ok(()),
+                    |_| {},
+                )
+            });
+
+            let stats = client
+                .data_sync_until(
+                    tx,
+                    |_| Ok(Vec::new()),
+                    |_, _| Ok(()),
+                    |_| {},
+                    &window,
+                    estimate,
+                )
+                .unwrap();
+
+            let outcome = |i| {
+                stats
+                    .sent
+                    .iter()
+                    .find(|transfer| transfer.snapshot == snapshot(i))
+                    .map(|transfer| transfer.outcome.clone())
+            };
+            assert_eq!(outcome(0), Some(TransferOutcome::Completed));
+            assert_eq!(outcome(1), Some(TransferOutcome::Completed));
+            assert_eq!(outcome(2), Some(TransferOutcome::Skipped));
+            assert_eq!(stats.wrap_up.unwrap().skipped, vec![snapshot(2)]);
+
+            server.join().unwrap().unwrap();
+        });
+    }
+
+    /// A `Recorder` keeps the data of every write separately.
+    struct Recorder<'a>(&'a Mutex<Vec<Vec<u8>>>);
+
+    impl Write for Recorder<'_> {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().push(buf.to_vec());
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    /// Transmits the specified data from the client to the server or vice versa
+    /// with the specified preferred chunk sizes. Returns the received chunks.
+    fn transmit(
+        data: &[u8],
+        client_chunk_size: usize,
+        server_chunk_size: usize,
+        from_client: bool,
+    ) -> Vec<Vec<u8>> {
+        let (client, server) = pair();
+        let (client, server) = activate(client, server, client_chunk_size, server_chunk_size);
+
+        let chunks = Mutex::new(Vec::new());
+        let tx = |sends| {
+            if sends {
+                vec![(data, snapshot(0))]
+            } else {
+                Vec::new()
+            }
+        };
+
+        thread::scope(|s| {
+            let server = s.spawn(|| {
+                server.data_sync(
+                    tx(!from_client),
+                    |_| Ok(Recorder(&chunks)),
+                    |_, _| Ok(()),
+                    |_| {},
+                )
+            });
+
+            client
+                .data_sync(
+                    tx(from_client),
+                    |_| Ok(Recorder(&chunks)),
+                    |_, _| Ok(()),
+                    |_| {},
+                )
+                .unwrap();
+            server.join().unwrap().unwrap();
+        });
+
+        chunks.into_inner().unwrap()
+    }
+
+    #[test]
+    fn mismatched_chunk_sizes_use_the_smaller_one() {
+        let data: Vec<u8> = (0..10 * MIN_CHUNK_SIZE + 123).map(|i| i as u8).collect();
+
+        for (client_chunk_size, server_chunk_size) in [
+            (MIN_CHUNK_SIZE, 2 * MIN_CHUNK_SIZE),
+            (2 * MIN_CHUNK_SIZE, MIN_CHUNK_SIZE),
+        ] {
+            for from_client in [true, false] {
+                let chunks = transmit(&data, client_chunk_size, server_chunk_size, from_client);
+
+                assert_eq!(chunks.concat(), data);
+                assert_eq!(chunks.len(), 11);
+                assert!(chunks[..10]
+                    .iter()
+                    .all(|chunk| chunk.len() == MIN_CHUNK_SIZE));
+            }
+        }
+    }
+
+    #[test]
+    fn rate_limit_caps_transmissions() {
+        const RATE: u64 = 1024 * 1024;
+
+        let (client, server) = tcp_pair();
+        let (client, server) = activate(
+            client.with_rate_limit(Some(RATE)),
+            server,
+            DEFAULT_CHUNK_SIZE,
+            DEFAULT_CHUNK_SIZE,
+        );
+
+        let data = vec![0x55; 10 * RATE as usize];
+        let chunks = Mutex::new(Vec::new());
+
+        let started = Instant::now();
+        thread::scope(|s| {
+            let server = s.spawn(|| {
+                server.data_sync(
+                    Vec::<(&[u8], Snapshot)>::new(),
+                    |_| Ok(Recorder(&chunks)),
+                    |_, _| Ok(()),
+                    |_| {},
+                )
+            });
+
+            client
+                .data_sync(
+                    [(data.as_slice(), snapshot(0))],
+                    |_| Ok(Vec::new()),
+                    |_, _| Ok(()),
+                    |_| {},
+                )
+                .unwrap();
+            server.join().unwrap().unwrap();
+        });
+        let elapsed = started.elapsed();
+
+        assert_eq!(chunks.into_inner().unwrap().concat(), data);
+        // The bucket starts empty, so the whole transmission is throttled.
+        assert!(
+            elapsed >= Duration::from_millis(9500) && elapsed < Duration::from_secs(13),
+            "10 MiB at 1 MiB/s took {:?}",
+            elapsed
+        );
+    }
+
+    /// Restores the specified queue sent by the server in a new session.
+    /// Returns the snapshots the client applied in the order it applied them.
+    fn restore(queue: &[Snapshot]) -> Vec<Snapshot> {
+        let (client, server) = pair();
+        let (client, server) = activate(client, server, MIN_CHUNK_SIZE, MIN_CHUNK_SIZE);
+
+        let chains = RestoreChains::default();
+        let applied = Mutex::new(Vec::new());
+
+        thread::scope(|s| {
+            let server = s.spawn(|| {
+                server.data_sync(
+                    queue
+                        .iter()
+                        .map(|snapshot| (&b"stream"[..], snapshot.clone())),
+                    |_| Ok(io::sink()),
+                    |_, _| Ok(()),
+                    |_| {},
+                )
+            });
+
+            client
+                .data_sync(
+                    Vec::<(&[u8], Snapshot)>::new(),
+                    |target| {
+                        chains
+                            .check(&target.snapshot)
+                            .map_err(|_| RemoteError::MissingParent)?;
+                        Ok(io::sink())
+                    },
+                    |snapshot, _| {
+                        chains.applied(&snapshot);
+                        applied.lock().unwrap().push(snapshot);
+                        Ok(())
+                    },
+                    |_| {},
+                )
+                .unwrap();
+            server.join().unwrap().unwrap();
+        });
+
+        applied.into_inner().unwrap()
+    }
+
+    #[test]
+    fn restores_refuse_shuffled_chains() {
+        let snapshots = |values: &[&str]| -> Vec<Snapshot> {
+            values
+                .iter()
+                .map(|value| Snapshot::try_from(*value).unwrap())
+                .collect()
+        };
+
+        let shuffled = snapshots(&[
+            "node_subvol_incr_20240103000000",
+            "node_subvol_full_20240101000000",
+            "node_other_incr_20240102000000",
+            "node_subvol_incr_20240102000000",
+            "node_other_full_20240101000000",
+            "node_subvol_incr_20240104000000",
+            "node_subvol_incr_20240101120000",
+        ]);
+
+        // The first incremental snapshot precedes the full one,
+        // later ones miss it as their parent or are sent after their successor.
+        assert_eq!(
+            restore(&shuffled),
+            snapshots(&[
+                "node_subvol_full_20240101000000",
+                "node_subvol_incr_20240102000000",
+                "node_other_full_20240101000000",
+            ])
+        );
+
+        let ordered = sync::order_queue([shuffled.clone()]);
+        assert_eq!(restore(&ordered), ordered);
+    }
+
+    /// Sends a raw `Hello` of the specified protocol version to an [`AuthServ`]
+    /// without any grants. Returns its response and its own outcome.
+    fn hello(version: u32) -> (CryptoMessage, Result<(), NetworkError>) {
+        let (client, server) = UnixStream::pair().unwrap();
+
+        let server =
+            thread::spawn(move || AuthServ::from(server).secure_stream(Vec::new()).map(|_| ()));
+
+        let hello = CryptoMessage::Hello(Hello {
+            node_name: String::from("client"),
+            instance_id: None,
+            challenge: vec![0; 32],
+            nonce: vec![0; 32],
+            version,
+            plaintext: false,
+        });
+        bincode::serialize_into(&client, &hello).unwrap();
+        let response = bincode::deserialize_from(&client).unwrap();
+
+        (response, server.join().unwrap())
+    }
+
+    #[test]
+    fn servers_refuse_other_versions() {
+        for version in [0, 1, PROTOCOL_VERSION - 1, PROTOCOL_VERSION + 1, u32::MAX] {
+            let (response, outcome) = hello(version);
+
+            assert_eq!(
+                response,
+                CryptoMessage::ServerAuth(Err(RemoteError::IncompatibleVersion(PROTOCOL_VERSION)))
+            );
+            assert!(matches!(
+                outcome,
+                Err(NetworkError::RemoteError(RemoteError::IncompatibleVersion(v))) if v == version
+            ));
+        }
+    }
+
+    #[test]
+    fn servers_authenticate_the_same_version() {
+        // The version is accepted, but the client has no grants.
+        let (response, outcome) = hello(PROTOCOL_VERSION);
+
+        assert_eq!(
+            response,
+            CryptoMessage::ServerAuth(Err(RemoteError::AccessDenied))
+        );
+        assert!(matches!(
+            outcome,
+            Err(NetworkError::RemoteError(RemoteError::Unauthorized))
+        ));
+    }
+
+    #[test]
+    fn clients_refuse_other_versions() {
+        for version in [PROTOCOL_VERSION - 1, PROTOCOL_VERSION + 1] {
+            let (client, server) = UnixStream::pair().unwrap();
+
+            let server = thread::spawn(move || {
+                let hello: CryptoMessage = bincode::deserialize_from(&server).unwrap();
+                let response =
+                    CryptoMessage::ServerAuth(Err(RemoteError::IncompatibleVersion(version)));
+                bincode::serialize_into(&server, &response).unwrap();
+
+                hello
+            });
+
+            let outcome = AuthConn::from(client).secure_stream(
+                String::from("client"),
+                None,
+                String::from("server"),
+                "passphrase",
+            );
+
+            assert!(matches!(
+                server.join().unwrap(),
+                CryptoMessage::Hello(Hello { version, .. }) if version == PROTOCOL_VERSION
+            ));
+            assert!(matches!(
+                outcome,
+                Err(NetworkError::RemoteError(RemoteError::IncompatibleVersion(v))) if v == version
+            ));
+        }
+    }
+
+    /// A `Stutter` yields the pieces of its data one read at a time,
+    /// timing out before each piece like a slow peer.
+    struct Stutter {
+        pieces: Vec<Vec<u8>>,
+        timed_out: bool,
+    }
+
+    impl Read for Stutter {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            if self.pieces.is_empty() {
+                return Ok(0);
+            }
+
+            self.timed_out = !self.timed_out;
+            if self.timed_out {
+                return Err(io::Error::from(io::ErrorKind::WouldBlock));
+            }
+
+            let piece = &mut self.pieces[0];
+            let n = piece.len().min(buf.len());
+            buf[..n].copy_from_slice(&piece[..n]);
+            piece.drain(..n);
+            if piece.is_empty() {
+                self.pieces.remove(0);
+            }
+
+            Ok(n)
+        }
+    }
+
+    #[test]
+    fn timeouts_resume_partial_frames() {
+        let frames = [
+            b"short".to_vec(),
+            (0..=255).collect::<Vec<u8>>(),
+            Vec::new(),
+        ];
+
+        let mut data = Vec::new();
+        for frame in &frames {
+            write_frame(&mut data, frame).unwrap();
+        }
+
+        for piece in [1, 3, 7, 100] {
+            let mut r = Stutter {
+                pieces: data.chunks(piece).map(|piece| piece.to_vec()).collect(),
+                timed_out: false,
+            };
+            let mut rx_buf = RxBuf::default();
+
+            for frame in &frames {
+                loop {
+                    match rx_buf.read_frame(&mut r) {
+                        Ok(()) => break,
+                        Err(e) => assert!(
+                            matches!(&*e, bincode::ErrorKind::Io(e) if e.kind() == io::ErrorKind::WouldBlock),
+                            "pieces of {} bytes: {}",
+                            piece,
+                            e
+                        ),
+                    }
+                }
+
+                assert_eq!(&rx_buf.buf, frame, "pieces of {} bytes", piece);
+            }
+        }
+    }
+}
@@ -14,10 +14,15 @@
 // You should have received a copy of the GNU Affero General Public License
 // along with this program.  If not, see <https://www.gnu.org/licenses/>.
 
-use crate::proto::{Snapshot, Volume};
+use crate::conn::format_bytes;
+use crate::proto::{Snapshot, Volume, VolumeSpec};
 
 use std::io;
+use std::path::PathBuf;
+use std::process::ExitStatus;
+use std::time::Duration;
 
+use chrono::NaiveDateTime;
 use serde::{Deserialize, Serialize};
 use thiserror::Error;
 
@@ -44,6 +49,10 @@ pub enum SnapshotParseError {
     /// Accepted values include "full" and "incr".
     #[error("Invalid snapshot type \"{0}\", expected \"full\" or \"incr\"")]
     InvalidType(String),
+    /// The node or subvolume name is empty, contains characters other than
+    /// printable ASCII, a slash or an underscore or is `.` or `..`.
+    #[error("Invalid node or subvolume name \"{0}\" in snapshot identifier")]
+    InvalidName(String),
 
     /// When parsing from a [`std::path::Path`] this error indicates
     /// that [`std::path::Path::file_name`] returned `None`
@@ -76,6 +85,93 @@ pub enum VolumeParseError {
     /// The identifier is missing the subvolume name.
     #[error("Incomplete volume identifier: Missing subvolume")]
     MissingSubvolume,
+    /// The identifier has more than two components,
+    /// i.e. the node or subvolume name contains an underscore.
+    #[error("Invalid volume identifier \"{0}\": Too many components")]
+    TrailingComponents(String),
+    /// The node or subvolume name is empty, contains characters other than
+    /// printable ASCII, a slash or an underscore or is `.` or `..`.
+    #[error("Invalid node or subvolume name \"{0}\" in volume identifier")]
+    InvalidName(String),
+}
+
+/// A `GrantError` indicates a push or pull permission of a `RemoteNodeAuth`
+/// that cannot take effect.
+#[derive(Debug, Error)]
+pub enum GrantError {
+    /// Volumes owned by the local node are never received.
+    #[error("Push of {0} is ineffective: Owned by this node")]
+    OwnPush(VolumeSpec),
+    /// The local node doesn't own the subvolume of a pulled volume of its own.
+    #[error("Pull of {0} is ineffective: Subvolume \"{1}\" is not owned by this node")]
+    ForeignPull(VolumeSpec, String),
+    /// The permission is listed more than once.
+    #[error("Duplicate permission {0}")]
+    Duplicate(VolumeSpec),
+    /// The node or subvolume name is empty or contains an underscore,
+    /// so the volume identifier is ambiguous.
+    #[error("Volume of node \"{0}\" and subvolume \"{1}\" has no valid identifier")]
+    Unencodable(String, String),
+}
+
+/// An `AddressParseError` indicates a failure parsing a `RemoteAddress`.
+#[derive(Debug, Error)]
+pub enum AddressParseError {
+    /// The host name or IP address is empty.
+    #[error("Incomplete address: Missing host")]
+    MissingHost,
+    /// The host name contains characters that are not allowed.
+    #[error("Invalid host \"{0}\"")]
+    InvalidHost(String),
+    /// The host is not an IP address where one is required.
+    #[error("Host \"{0}\" is not an IP address")]
+    NotIpAddress(String),
+    /// The scope of an IPv6 address names a network interface that does not exist.
+    #[error("No such network interface \"{0}\"")]
+    NoSuchInterface(String),
+    /// The port is not a number between 0 and 65535.
+    #[error("Invalid port: {0}")]
+    InvalidPort(#[from] std::num::ParseIntError),
+    /// The path of a Unix domain socket is not absolute.
+    #[error("Unix domain socket path \"{0}\" is not absolute")]
+    RelativeSocketPath(String),
+}
+
+/// A `ByteSizeParseError` indicates a failure parsing a `ByteSize`.
+#[derive(Debug, Error)]
+pub enum ByteSizeParseError {
+    /// The value is not a number optionally followed by a known unit.
+    #[error("Invalid byte size \"{0}\": Expected a number optionally followed by B, KB, MB, GB, TB, KiB, MiB, GiB or TiB such as \"500GiB\"")]
+    Malformed(String),
+    /// The value doesn't fit into 64 bits.
+    #[error("Byte size \"{0}\" is too large")]
+    Overflow(String),
+}
+
+/// A `DurationParseError` indicates a failure parsing a `HumanDuration`.
+#[derive(Debug, Error)]
+pub enum DurationParseError {
+    /// The value is not a sequence of numbers followed by known units.
+    #[error("Invalid duration \"{0}\": Expected a number of seconds or numbers followed by s, m, h, d or w such as \"2h30m\" or \"90d\"")]
+    Malformed(String),
+    /// The value doesn't fit into 64 bits of seconds.
+    #[error("Duration \"{0}\" is too long")]
+    Overflow(String),
+}
+
+/// A `ChainError` indicates an incremental snapshot whose parent can't have been applied,
+/// see `RestoreChains`.
+#[derive(Debug, Error)]
+pub enum ChainError {
+    /// No full snapshot of the subvolume has been applied.
+    #[error("No full backup of subvolume {0} has been received")]
+    NoFull(String),
+    /// A later snapshot has already been applied.
+    #[error("Received after its successor taken at {0}")]
+    OutOfOrder(NaiveDateTime),
+    /// A preceding snapshot that hasn't been applied was refused.
+    #[error("Its predecessor taken at {0} was refused")]
+    Broken(NaiveDateTime),
 }
 
 /// A `LocalNodeError` indicates an error condition on the current node.
@@ -84,6 +180,55 @@ pub enum LocalNodeError {
     /// A btrfs command failed to execute correctly.
     #[error("Btrfs command execution failed")]
     BtrfsCmd,
+    /// A btrfs send process failed mid-stream. Contains its exit status and error output.
+    #[error("Btrfs send failed ({0}): {1}")]
+    BtrfsSend(ExitStatus, String),
+    /// The backup uses a btrfs send stream version that cannot be received locally.
+    #[error("Backup uses btrfs send stream version {0}, but only version {1} can be received, upgrade to btrfs-progs 6.0 or later")]
+    UnsupportedSendProtocol(u32, u32),
+    /// The encrypted stream uses a header version that is not supported.
+    #[error("Encrypted stream uses unsupported header version {0}, upgrade hbak")]
+    UnsupportedStreamHeader(u8),
+    /// The header of the encrypted stream identifies a different snapshot.
+    #[error("Encrypted stream was expected to contain \"{0}\", but its header identifies \"{1}\"")]
+    SnapshotMismatch(Snapshot, String),
+    /// A safety snapshot of the subvolume was already taken at the same time.
+    #[error("Safety snapshot {} already exists", .0.display())]
+    SafetySnapshotExists(PathBuf),
+    /// The encrypted stream doesn't have the structure of a stream
+    /// written by [`crate::stream::SnapshotStream`], e.g. because it is truncated.
+    #[error("Encrypted stream is malformed: {0}")]
+    MalformedStream(String),
+    /// The re-encrypted stream of a backup decrypts to a different number of bytes
+    /// than the original one. The original backup is kept.
+    #[error("Re-encrypted backup \"{0}\" holds {2} bytes instead of {1}, keeping the original")]
+    ReencryptionMismatch(Snapshot, u64, u64),
+    /// The file system of the backup directory doesn't have enough free space
+    /// to receive a backup. Contains the available and required number of bytes.
+    #[error(
+        "Insufficient disk space, {} available, {} required",
+        format_bytes(*.0),
+        format_bytes(*.1)
+    )]
+    InsufficientSpace(u64, u64),
+    /// The passphrase command failed.
+    #[error("Passphrase command failed: {0}")]
+    PassphraseCommand(ExitStatus),
+    /// The passphrase command printed nothing but whitespace.
+    #[error("Passphrase command returned an empty passphrase")]
+    EmptyPassphrase,
+    /// The passphrase needs to be entered interactively, but the process is not interactive.
+    #[error("Passphrase prompt unavailable, configure passphrase or passphrase_command instead")]
+    PassphrasePromptUnavailable,
+    /// A snapshot hook of a subvolume failed.
+    #[error("{0} hook of subvolume {1} failed: {2}")]
+    HookFailed(&'static str, String, ExitStatus),
+    /// A chattr or lsattr command failed to execute correctly.
+    #[error("File attribute command execution failed")]
+    AttrCmd,
+    /// The snapshot or backup is too recent to be deleted.
+    #[error("Snapshot or backup \"{0}\" is within its deletion cooloff")]
+    Cooloff(Snapshot),
     /// A btrfs command did not provide a stdin file.
     #[error("Btrfs command does not have stdin")]
     NoBtrfsInput,
@@ -97,9 +242,22 @@ pub enum LocalNodeError {
     /// No configuration file exists on this node.
     #[error("Local node is not initialized")]
     ConfigUninit,
+    /// `hbakd` is running on this node and may be using the file system.
+    #[error("hbakd is running (PID {0}), stop it first")]
+    DaemonRunning(u32),
     /// The permissions on the configuration file are insecure.
     #[error("Insecure config permissions (limit access to root user!)")]
     InsecurePerms,
+    /// The configuration file cannot be parsed.
+    /// Contains the location of the previous version.
+    #[error("Cannot parse configuration, the previous version is kept at {1} (run `hbak config restore-backup` to restore it): {0}")]
+    CorruptConfig(toml::de::Error, String),
+    /// There is no previous version of the configuration file to restore.
+    #[error("No previous version of the configuration file")]
+    NoConfigBackup,
+    /// A configuration value is outside of its accepted range.
+    #[error("Invalid configuration value for \"{0}\": {1}")]
+    InvalidConfig(&'static str, String),
 
     /// No full backup of the specified volume could be found on this node.
     #[error("No full backups of volume \"{0}\" exist locally")]
@@ -116,9 +274,36 @@ pub enum LocalNodeError {
     /// A snapshot with the same identifier already exists.
     #[error("A snapshot with identifier \"{0}\" already exists")]
     SnapshotExists(Snapshot),
+    /// The snapshot is dated after the current time, so snapshots taken now
+    /// would precede it.
+    #[error("Snapshot \"{0}\" postdates the current time, is the system clock behind?")]
+    PostdatedSnapshot(Snapshot),
+    /// The latest snapshot of the subvolume is writable, e.g. because its reception
+    /// was interrupted, so it cannot be the parent of an incremental snapshot.
+    #[error("Latest snapshot \"{0}\" is incomplete or writable and cannot be a parent, take a full snapshot")]
+    UnusableParent(Snapshot),
+    /// The snapshot or backup does not exist on this node.
+    #[error("Snapshot or backup \"{0}\" does not exist")]
+    NoSuchSnapshot(Snapshot),
+    /// The on-disk name of the first snapshot identifies the second snapshot.
+    /// This happens if the node or subvolume name contains underscores or slashes.
+    #[error("Snapshot \"{0}\" cannot be stored because its name identifies \"{1}\"")]
+    NameCollision(Box<Snapshot>, Box<Snapshot>),
+    /// A snapshot of a subvolume owned by this node cannot be stored as a backup.
+    #[error("Cannot store snapshot \"{0}\" of own subvolume as a backup")]
+    OwnSnapshot(Snapshot),
     /// The snapshot cannot be restored to because it already exists.
     #[error("Cannot restore existing snapshot \"{0}\" from backup")]
     SnapshotNotGone(Snapshot),
+    /// No archive directory is configured on this node.
+    #[error("No archive directory is configured")]
+    NoArchive,
+    /// The archived copy of the backup differs from the original.
+    #[error("Archived copy of backup \"{0}\" does not match the original")]
+    ArchiveMismatch(Snapshot),
+    /// The snapshot or backup cannot be quarantined because its timestamp is plausible.
+    #[error("Snapshot or backup \"{0}\" has a plausible timestamp")]
+    PlausibleTimestamp(Snapshot),
     /// There was a failure parsing a `Snapshot`.
     #[error("Failed to parse snapshot identifier")]
     SnapshotParseError(#[from] SnapshotParseError),
@@ -126,14 +311,28 @@ pub enum LocalNodeError {
     /// The specified subvolume is not owned by this node.
     #[error("Subvolume \"{0}\" is not owned by this node")]
     ForeignSubvolume(String),
+    /// The specified subvolume is still owned by this node.
+    #[error("Subvolume \"{0}\" is still tracked by this node")]
+    TrackedSubvolume(String),
+    /// The specified path exists but is not a btrfs subvolume.
+    #[error("\"{0}\" is not a btrfs subvolume")]
+    NotSubvolume(String),
     /// The specified subvolume does not exist on this node.
     #[error("Subvolume \"{0}\" does not exist")]
     NoSuchSubvolume(String),
+    /// The subvolume name is not a valid name, see [`crate::proto::is_valid_name`].
+    #[error(
+        "Invalid subvolume name \"{0}\", must be printable ASCII without '/', '_' or whitespace"
+    )]
+    InvalidSubvolumeName(String),
 
     /// A `std::io::Error` I/O error occured.
     #[error("IO error: {0}")]
     IoError(#[from] io::Error),
 
+    /// The random number generator of the operating system failed.
+    #[error("Random number generation failure: {0}")]
+    Rand(#[from] rand::Error),
     /// Password-based key derivation using Argon2id failed.
     #[error("Password-based key derivation using Argon2id failed: {0}")]
     Argon2(#[from] argon2::Error),
@@ -146,6 +345,9 @@ pub enum LocalNodeError {
     /// A `toml::de::Error` TOML deserialization error occured.
     #[error("TOML deserialization error: {0}")]
     TomlDe(#[from] toml::de::Error),
+    /// A `serde_json::Error` JSON (de)serialization error occured.
+    #[error("JSON error: {0}")]
+    Json(#[from] serde_json::Error),
 }
 
 /// A `NetworkError` indicates an error condition on a network connection.
@@ -158,6 +360,29 @@ pub enum NetworkError {
     /// Attempt to connect to an empty [`std::net::ToSocketAddrs`].
     #[error("No network addresses to connect to")]
     NoAddrs,
+    /// The host name of a remote node could not be resolved.
+    #[error("Unable to resolve address: {0}")]
+    Resolve(io::Error),
+    /// The peers derived different session keys during the handshake.
+    #[error("Handshake failure: Key confirmation failed")]
+    KeyConfirmation,
+    /// No data has been received from the remote node within the stall timeout
+    /// while a transmission was in progress.
+    #[error("Transmission stalled: No data received for {0:?}")]
+    Stalled(Duration),
+    /// The remote node didn't answer a verification request within the timeout.
+    #[error("Verification timed out: No proofs received within {0:?}")]
+    VerifyTimeout(Duration),
+    /// A transmission from the remote node arrived out of order or incomplete.
+    /// Contains the affected snapshot, whether a chunk sequence number
+    /// or a transfer identifier mismatched and the expected and received values.
+    #[error("Sequence mismatch receiving {snapshot}: Expected {unit} {expected}, got {got}")]
+    SequenceMismatch {
+        snapshot: String,
+        unit: &'static str,
+        expected: u64,
+        got: u64,
+    },
 
     /// Unable to parse a [`Volume`].
     #[error("Unable to parse volume: {0}")]
@@ -181,6 +406,33 @@ pub enum NetworkError {
     ChaCha20Poly1305(#[from] chacha20poly1305::Error),
 }
 
+/// An `AgentError` indicates an error condition communicating with
+/// or running the local agent.
+#[derive(Debug, Error)]
+pub enum AgentError {
+    /// Another agent is already listening on the socket.
+    #[error("Agent is already running")]
+    AlreadyRunning,
+    /// The agent failed to perform the requested operation.
+    #[error("Agent failure: {0}")]
+    Failed(String),
+    /// The agent sent a response that doesn't match the request.
+    #[error("Unexpected response from agent")]
+    UnexpectedResponse,
+
+    /// An error occured on the local node.
+    #[error("Local error: {0}")]
+    LocalError(#[from] LocalNodeError),
+
+    /// A `std::io::Error` I/O error occured.
+    #[error("IO error: {0}")]
+    IoError(#[from] io::Error),
+
+    /// A bincode (de)serialization error occured.
+    #[error("Bincode (de)serialization error: {0}")]
+    Bincode(#[from] Box<bincode::ErrorKind>),
+}
+
 /// A `RemoteError` indicates an error condition on the current session
 /// or the remote node. This is a special case of [`NetworkError`].
 #[derive(Clone, Debug, Eq, PartialEq, Error, Serialize, Deserialize)]
@@ -203,8 +455,9 @@ pub enum RemoteError {
     #[error("Illegal state transition on remote node")]
     IllegalTransition,
 
-    /// Cannot set up multiple concurrent streams in the same direction.
-    #[error("Already streaming in this direction")]
+    /// Cannot set up multiple concurrent streams in the same direction
+    /// or receive a snapshot another session is already receiving.
+    #[error("Already streaming in this direction or snapshot already being received")]
     AlreadyStreaming,
     /// Unsolicited attempt to stream data.
     #[error("Not streaming in this direction")]
@@ -213,4 +466,82 @@ pub enum RemoteError {
     /// This is usually caused by a [`std::io::Error`] on the destination stream.
     #[error("Remote node reception failure")]
     RxError,
+    /// The remote node is unable to continue producing *its* transmission.
+    /// This is usually caused by a failing btrfs send process.
+    /// The incomplete reception is discarded.
+    #[error("Remote node transmission failure")]
+    TxError,
+
+    /// The requested backup does not exist on the remote node.
+    #[error("No such backup on remote node")]
+    NoSuchBackup,
+    /// The remote node is unable to compute a proof of possession of a backup.
+    /// This is usually caused by a [`std::io::Error`] reading the backup.
+    #[error("Remote node proof of possession failure")]
+    ProofError,
+    /// The remote node is shutting down and ended the session early.
+    #[error("Remote node is shutting down")]
+    ShuttingDown,
+    /// The request exceeds the limits of the remote node.
+    #[error("Request exceeds remote node limits")]
+    LimitExceeded,
+    /// The connection was refused because of too many failed authentication attempts.
+    #[error("Too many failed authentication attempts")]
+    TooManyAttempts,
+    /// The remote node speaks the contained, different protocol version.
+    #[error("Incompatible protocol version, remote node speaks version {0}")]
+    IncompatibleVersion(u32),
+    /// The received data doesn't match the digest or length reported by the sender.
+    #[error("Integrity check of received data failed")]
+    IntegrityFailure,
+    /// The node name is bound to a different installation on the remote node.
+    #[error("Node name conflict, bound to a different instance (did you clone a machine?)")]
+    InstanceConflict,
+    /// The snapshot is dated too far into the future or the past.
+    /// Contains the current time of the remote node (UTC).
+    #[error("Implausible snapshot timestamp, remote node time is {0} UTC")]
+    ImplausibleTimestamp(NaiveDateTime),
+    /// The remote node is unable to list its snapshots and backups.
+    /// This is usually caused by a [`std::io::Error`] reading the directories.
+    #[error("Remote node inventory failure")]
+    InventoryError,
+    /// The remote node is unable to delete a backup.
+    /// This is usually caused by a [`std::io::Error`] removing the backup.
+    #[error("Remote node deletion failure")]
+    DeleteError,
+    /// The backup is the last full backup of its volume on the remote node.
+    #[error("Refusing to delete last full backup of volume")]
+    LastFullBackup,
+    /// The backup is too recent to be deleted by the remote node.
+    #[error("Backup deletion cooloff has not elapsed on remote node")]
+    Cooloff,
+    /// The remote node is unable to replace the authentication credentials.
+    /// This is usually caused by malformed credentials
+    /// or a [`std::io::Error`] saving the configuration.
+    #[error("Remote node credential rotation failure")]
+    RotationError,
+    /// The file system of the backup directory on the remote node doesn't have
+    /// enough free space to receive the backup. The transmission is skipped.
+    /// Contains the available and required number of bytes.
+    #[error(
+        "Insufficient disk space on remote node, {} available, {} required",
+        format_bytes(*.0),
+        format_bytes(*.1)
+    )]
+    InsufficientSpace(u64, u64),
+    /// The client already has the maximum number of concurrent sessions
+    /// on the remote node.
+    #[error("Too many concurrent sessions on remote node")]
+    TooManySessions,
+    /// An incremental snapshot was sent before its parent was received completely.
+    /// The transmission is skipped.
+    #[error("Parent of incremental snapshot not received by remote node")]
+    MissingParent,
+    /// The remote node is in maintenance or read-only mode and refuses uploads.
+    /// Downloads keep working. Contains the message of the operator, if any.
+    #[error(
+        "Remote node is in maintenance mode{}",
+        .0.as_ref().map(|message| format!(": {}", message)).unwrap_or_default()
+    )]
+    Maintenance(Option<String>),
 }
@@ -0,0 +1,127 @@
+// hbak_common is the main hbak library implementing the protocol shared logic.
+// Copyright (C) 2024  Himbeer <himbeerserverde@gmail.com>
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use crate::proto::Snapshot;
+
+use chrono::{DateTime, NaiveDateTime, Utc};
+use serde::{Deserialize, Serialize, Serializer};
+
+/// The JSON representation of a [`Snapshot`] for tooling.
+/// Its timestamp is serialized as an RFC 3339 timestamp in UTC.
+#[derive(Clone, Debug, Eq, PartialEq, Serialize, Deserialize)]
+pub struct SnapshotJson {
+    /// The identifier of the snapshot, e.g. `node_subvol_full_20240101000000`.
+    pub identifier: String,
+    /// The volume of the snapshot, e.g. `node_subvol`.
+    pub volume: String,
+    /// The name of the node owning the snapshot.
+    pub node_name: String,
+    /// The name of the subvolume of the snapshot.
+    pub subvol: String,
+    /// Whether the snapshot is incremental.
+    pub is_incremental: bool,
+    /// The creation date of the snapshot.
+    pub taken: DateTime<Utc>,
+}
+
+impl From<&Snapshot> for SnapshotJson {
+    fn from(snapshot: &Snapshot) -> Self {
+        Self {
+            identifier: snapshot.to_string(),
+            volume: snapshot.volume().to_string(),
+            node_name: snapshot.node_name().to_string(),
+            subvol: snapshot.subvol().to_string(),
+            is_incremental: snapshot.is_incremental(),
+            taken: snapshot.taken().and_utc(),
+        }
+    }
+}
+
+/// The version of the [`Manifest`] format written by this version of hbak.
+pub const MANIFEST_VERSION: u32 = 1;
+
+/// A `Manifest` describes a completed backup for auditing and external tooling.
+/// It is stored next to the backup as `<snapshot>.manifest.json`.
+/// Backups written by previous versions don't have one.
+#[derive(Clone, Debug, Eq, PartialEq, Serialize, Deserialize)]
+pub struct Manifest {
+    /// The snapshot stored in the backup.
+    #[serde(flatten)]
+    pub snapshot: SnapshotJson,
+    /// The length of the encrypted stream in bytes.
+    pub size: u64,
+    /// The hex-encoded SHA-256 digest of the encrypted stream.
+    pub sha256: String,
+    /// The time the backup was completed.
+    pub created: DateTime<Utc>,
+    /// The version of the manifest format, see [`MANIFEST_VERSION`].
+    pub format_version: u32,
+    /// The version of hbak that wrote the manifest.
+    pub hbak_version: String,
+}
+
+impl Manifest {
+    /// Constructs a new `Manifest` of the specified backup
+    /// from the length and SHA-256 digest of its encrypted stream.
+    pub fn new(snapshot: &Snapshot, size: u64, sha256: &[u8]) -> Self {
+        Self {
+            snapshot: snapshot.into(),
+            size,
+            sha256: hex(sha256),
+            created: Utc::now(),
+            format_version: MANIFEST_VERSION,
+            hbak_version: env!("CARGO_PKG_VERSION").to_string(),
+        }
+    }
+
+    /// Reports whether an encrypted stream of the specified length
+    /// and SHA-256 digest matches this `Manifest`.
+    pub fn matches(&self, size: u64, sha256: &[u8]) -> bool {
+        size == self.size && hex(sha256) == self.sha256
+    }
+}
+
+/// Returns the lowercase hexadecimal representation of the specified bytes.
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{byte:02x}")).collect()
+}
+
+/// Serializes a UTC [`NaiveDateTime`] as an RFC 3339 timestamp.
+/// Intended for use with `#[serde(serialize_with)]`.
+pub fn serialize_timestamp<S: Serializer>(
+    timestamp: &NaiveDateTime,
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
+    timestamp.and_utc().serialize(serializer)
+}
+
+/// Serializes a [`Snapshot`] as a [`SnapshotJson`].
+/// Intended for use with `#[serde(serialize_with)]`.
+pub fn serialize_snapshot<S: Serializer>(
+    snapshot: &Snapshot,
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
+    SnapshotJson::from(snapshot).serialize(serializer)
+}
+
+/// Serializes [`Snapshot`]s as [`SnapshotJson`]s.
+/// Intended for use with `#[serde(serialize_with)]`.
+pub fn serialize_snapshots<S: Serializer>(
+    snapshots: &[Snapshot],
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
+    serializer.collect_seq(snapshots.iter().map(SnapshotJson::from))
+}
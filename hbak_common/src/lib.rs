@@ -17,9 +17,16 @@
 mod error;
 pub use error::*;
 
+pub mod agent;
 pub mod config;
 pub mod conn;
+pub mod json;
 pub mod message;
+pub mod output;
+pub mod paths;
 pub mod proto;
+pub mod report;
+pub mod state;
 pub mod stream;
+pub mod sync;
 pub mod system;
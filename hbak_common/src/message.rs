@@ -19,12 +19,9 @@ use crate::RemoteError;
 
 use std::collections::HashMap;
 
+use chrono::prelude::*;
 use serde::{Deserialize, Serialize};
 
-/// A network message containing raw data such as an encrypted inner message.
-#[derive(Clone, Debug, Eq, PartialEq, Serialize, Deserialize)]
-pub(crate) struct RawMessage(pub Vec<u8>);
-
 /// A network message to be exchanged between `hbak` and `hbakd`
 /// initializing mutual authentication and encryption.
 ///
@@ -48,19 +45,41 @@ pub enum CryptoMessage {
 pub struct Hello {
     /// The name of the client node.
     pub node_name: String,
+    /// The instance identifier of the client node, if it has one.
+    pub instance_id: Option<String>,
     /// A random challenge for clientbound authentication.
     pub challenge: Vec<u8>,
-    /// A random nonce for transport encryption.
+    /// A random nonce contributing to the session keys.
     pub nonce: Vec<u8>,
+    /// The protocol version of the client.
+    /// The server refuses to authenticate clients speaking a different version.
+    pub version: u32,
+    /// Whether the client requests that the session isn't encrypted.
+    /// Only honored on Unix domain sockets.
+    pub plaintext: bool,
 }
 
 /// Server identity proof and challenge. This message is clientbound.
 #[derive(Clone, Debug, Eq, PartialEq, Serialize, Deserialize)]
 pub struct ServerAuth {
-    /// The verifier needed to compute the shared secret on the client.
-    pub verifier: Vec<u8>,
+    /// One candidate per grant of the client's node name.
+    /// The client authenticates using the one matching its passphrase.
+    pub candidates: Vec<AuthCandidate>,
     /// A random challenge for serverbound authentication.
     pub challenge: Vec<u8>,
+    /// A random nonce contributing to the session keys.
+    pub nonce: Vec<u8>,
+    /// Whether the session isn't encrypted as requested by the client.
+    pub plaintext: bool,
+    /// The software version of the server.
+    pub software: String,
+}
+
+/// The verifier and server identity proof of a single grant. Part of [`ServerAuth`].
+#[derive(Clone, Debug, Eq, PartialEq, Serialize, Deserialize)]
+pub struct AuthCandidate {
+    /// The verifier needed to compute the shared secret on the client.
+    pub verifier: Vec<u8>,
     /// The server's identity proof, HMAC(shared_secret, client_challenge).
     pub proof: Vec<u8>,
 }
@@ -70,6 +89,8 @@ pub struct ServerAuth {
 pub struct ClientAuth {
     /// The client's identity proof, HMAC(shared_secret, server_challenge).
     pub proof: Vec<u8>,
+    /// The software version of the client.
+    pub software: String,
 }
 
 /// A network message to be exchanged between `hbak` and `hbakd`
@@ -84,14 +105,64 @@ pub enum StreamMessage {
     Replicate(Target),
     /// Stream setup successful. Followed by the data.
     Stream(Result<(), RemoteError>),
-    /// Sending a chunk of dynamic size.
-    Chunk(Vec<u8>),
-    /// Transmission completed or failed.
-    End(Result<(), RemoteError>),
+    /// Sending a chunk of dynamic size. The sequence number counts the chunks
+    /// of the current transmission starting from zero.
+    Chunk { seq: u64, data: Vec<u8> },
+    /// Transmission completed or failed. Contains the transfer identifier
+    /// of the transmission, see [`Target::transfer`].
+    End {
+        transfer: u64,
+        result: Result<Integrity, RemoteError>,
+    },
     /// No further transmissions will follow. Used for connection shutdown synchronization.
     Done,
     /// Protocol error independent of the operation or state context.
     Error(RemoteError),
+    /// Request to prove possession of backups instead of synchronizing.
+    /// This message is serverbound.
+    Verify(Vec<Challenge>),
+    /// Proofs of possession in the order of the challenges. This message is clientbound.
+    Proof(Vec<Result<Vec<u8>, RemoteError>>),
+    /// No further transmissions will be started because a node is shutting down.
+    /// Aborts the transmission in progress. Followed by [`StreamMessage::Done`].
+    ShuttingDown,
+    /// Key confirmation, the first message encrypted under the session keys.
+    Confirm,
+    /// The sending node is going to end the session at the deadline.
+    /// Transmissions that aren't expected to complete in time should not be started.
+    Closing { deadline: NaiveDateTime },
+    /// Request to list the snapshots and backups the remote node holds
+    /// of the volumes the sender may pull or owns instead of synchronizing.
+    /// This message is serverbound.
+    ListRequest,
+    /// The snapshots and backups requested by [`StreamMessage::ListRequest`].
+    /// This message is clientbound.
+    ListResponse(Vec<Snapshot>),
+    /// Request to delete backups of the sender's own volumes
+    /// from the remote node instead of synchronizing.
+    /// This message is serverbound.
+    Prune(Vec<Snapshot>),
+    /// The results of the deletions requested by [`StreamMessage::Prune`]
+    /// in the same order.
+    /// This message is clientbound.
+    PruneResponse(Vec<Result<(), RemoteError>>),
+    /// Request to replace the authentication credentials of the sender
+    /// instead of synchronizing. This message is serverbound.
+    RotateAuth(Credentials),
+    /// The result of the replacement requested by [`StreamMessage::RotateAuth`].
+    /// The previous credentials remain valid unless it succeeded.
+    /// This message is clientbound.
+    RotateAuthResponse(Result<(), RemoteError>),
+    /// The sender encrypts all following messages under the next key of its direction,
+    /// derived from the current key and the contained salt.
+    /// Handled transparently by the encryption layer.
+    Rekey(Vec<u8>),
+    /// Heartbeat sent by a node that hasn't sent anything for a while
+    /// to keep the connection alive, e.g. through NAT. The remote node
+    /// answers with [`StreamMessage::Pong`]. Handled transparently when receiving.
+    Ping,
+    /// The answer to a [`StreamMessage::Ping`].
+    Pong,
 }
 
 /// The latest known timestamps of full and incremental snapshots that may be sent.
@@ -100,17 +171,70 @@ pub struct SyncInfo {
     /// A map of accepted volumes and their latest known timestamps
     /// of full and incremental snapshots.
     pub volumes: HashMap<Volume, LatestSnapshots>,
+    /// Wildcard volume specifications of accepted volumes
+    /// that aren't included in `volumes`, assumed to have no known snapshots.
+    pub patterns: Vec<String>,
+    /// The highest btrfs send stream version the node can receive.
+    /// Snapshots are sent using a version both nodes can receive
+    /// so that the backups can be restored on either.
+    pub receive_protocol: u32,
+    /// The preferred size of data chunks sent over the network in bytes.
+    pub chunk_size: usize,
+    /// Whether the node accepts snapshots that are compressed before encryption.
+    /// Compression is only used if the receiving node accepts it.
+    pub compression: bool,
+    /// The snapshots held of each volume in `volumes` if the node requests
+    /// a full reconciliation. Snapshots missing from a list are sent
+    /// instead of only those newer than the latest timestamps,
+    /// filling gaps left by missed transmissions.
+    pub held: Option<HashMap<Volume, Vec<Snapshot>>>,
 }
 
 /// Request to stream a certain snapshot.
 #[derive(Clone, Debug, Eq, PartialEq, Serialize, Deserialize)]
 pub struct Target {
+    /// The identifier of the transmission. Counts the transmissions
+    /// in the same direction of a session starting from zero.
+    pub transfer: u64,
     /// The snapshot to stream to.
     pub snapshot: Snapshot,
+    /// The expected length of the stream in bytes if the sender can estimate it.
+    pub expected_len: Option<u64>,
+}
+
+/// Authentication credentials derived from a passphrase, see [`crate::system::hash_passphrase`].
+#[derive(Clone, Debug, Eq, PartialEq, Serialize, Deserialize)]
+pub struct Credentials {
+    /// The random verifier the key is derived with.
+    pub verifier: Vec<u8>,
+    /// The key derived from the passphrase and the verifier.
+    pub key: Vec<u8>,
+}
+
+/// The digest and length of a completed transmission for verification by the receiver.
+#[derive(Clone, Debug, Eq, PartialEq, Serialize, Deserialize)]
+pub struct Integrity {
+    /// The SHA-256 digest of the transmitted (encrypted) data.
+    pub digest: Vec<u8>,
+    /// The number of transmitted bytes.
+    pub len: u64,
+}
+
+/// Request to prove possession of a backup.
+#[derive(Clone, Debug, Eq, PartialEq, Serialize, Deserialize)]
+pub struct Challenge {
+    /// The backup to prove possession of.
+    pub snapshot: Snapshot,
+    /// A random challenge, the proof is HMAC(challenge, backup).
+    pub challenge: Vec<u8>,
 }
 
 impl From<Snapshot> for Target {
     fn from(snapshot: Snapshot) -> Self {
-        Self { snapshot }
+        Self {
+            transfer: 0,
+            snapshot,
+            expected_len: None,
+        }
     }
 }
@@ -0,0 +1,197 @@
+// hbak_common is the main hbak library implementing the protocol shared logic.
+// Copyright (C) 2024  Himbeer <himbeerserverde@gmail.com>
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use std::fmt;
+use std::io::{self, Write};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, AtomicU8, AtomicUsize, Ordering};
+
+use chrono::{SecondsFormat, Utc};
+use serde::{Deserialize, Serialize};
+
+/// The number of messages that couldn't be written.
+static SUPPRESSED: AtomicUsize = AtomicUsize::new(0);
+/// The most verbose [`Level`] that is logged.
+static LOG_LEVEL: AtomicU8 = AtomicU8::new(Level::Info as u8);
+/// Whether log messages are written as JSON lines.
+static LOG_JSON: AtomicBool = AtomicBool::new(false);
+
+/// The severity of a log message.
+#[derive(Clone, Copy, Debug, Eq, Hash, Ord, PartialEq, PartialOrd, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Level {
+    /// An operation failed.
+    Error,
+    /// Something unexpected happened that doesn't prevent operation.
+    Warn,
+    /// Regular progress information.
+    Info,
+    /// Detailed information for troubleshooting.
+    Debug,
+}
+
+impl fmt::Display for Level {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::Error => write!(f, "error"),
+            Self::Warn => write!(f, "warn"),
+            Self::Info => write!(f, "info"),
+            Self::Debug => write!(f, "debug"),
+        }
+    }
+}
+
+impl FromStr for Level {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "error" => Ok(Self::Error),
+            "warn" => Ok(Self::Warn),
+            "info" => Ok(Self::Info),
+            "debug" => Ok(Self::Debug),
+            _ => Err(format!("invalid log level {:?}", s)),
+        }
+    }
+}
+
+/// The representation of log messages.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    /// One human-readable line per message, e.g.
+    /// `2024-01-01T00:00:00.000Z [info] <node@[::1]:20406> Received ...`.
+    #[default]
+    Text,
+    /// One JSON object per line with the fields `time`, `level`, `message`
+    /// and the context of the message, e.g. `node` and `peer`.
+    Json,
+}
+
+/// Ignores `SIGPIPE` so that writing to a closed pipe fails with an error
+/// instead of terminating the process. The functions of this module
+/// handle such errors without affecting the operation in progress.
+pub fn ignore_sigpipe() {
+    // SAFETY: Ignoring a signal has no preconditions.
+    unsafe {
+        libc::signal(libc::SIGPIPE, libc::SIG_IGN);
+    }
+}
+
+/// Writes the formatted message to stdout.
+pub fn print(args: fmt::Arguments) {
+    write(io::stdout().lock(), args, false);
+}
+
+/// Writes the formatted message followed by a newline to stdout.
+pub fn println(args: fmt::Arguments) {
+    write(io::stdout().lock(), args, true);
+}
+
+/// Writes the formatted message followed by a newline to stderr.
+pub fn eprintln(args: fmt::Arguments) {
+    write(io::stderr().lock(), args, true);
+}
+
+/// Sets the most verbose [`Level`] that is logged. The default is [`Level::Info`].
+pub fn set_log_level(level: Level) {
+    LOG_LEVEL.store(level as u8, Ordering::Relaxed);
+}
+
+/// Sets the [`LogFormat`] of subsequent log messages.
+pub fn set_log_format(format: LogFormat) {
+    LOG_JSON.store(format == LogFormat::Json, Ordering::Relaxed);
+}
+
+/// Reports whether messages of the specified [`Level`] are logged.
+pub fn log_enabled(level: Level) -> bool {
+    level as u8 <= LOG_LEVEL.load(Ordering::Relaxed)
+}
+
+/// Writes the formatted message to stderr as a single log line
+/// with a timestamp, the [`Level`] and the context it applies to,
+/// e.g. the name and address of a remote node. Messages more verbose
+/// than the current log level are discarded.
+pub fn log(level: Level, context: &[(&str, &dyn fmt::Display)], args: fmt::Arguments) {
+    if !log_enabled(level) {
+        return;
+    }
+
+    let time = Utc::now().to_rfc3339_opts(SecondsFormat::Millis, true);
+
+    if LOG_JSON.load(Ordering::Relaxed) {
+        let mut line = format!(
+            "{{\"time\":{},\"level\":{}",
+            json_string(&time),
+            json_string(&level.to_string())
+        );
+        for (key, value) in context {
+            line += &format!(",{}:{}", json_string(key), json_string(&value.to_string()));
+        }
+        line += &format!(",\"message\":{}}}", json_string(&args.to_string()));
+
+        eprintln(format_args!("{}", line));
+    } else if context.is_empty() {
+        eprintln(format_args!("{} [{}] {}", time, level, args));
+    } else {
+        let context = context
+            .iter()
+            .map(|(_, value)| value.to_string())
+            .collect::<Vec<_>>()
+            .join("@");
+
+        eprintln(format_args!("{} [{}] <{}> {}", time, level, context, args));
+    }
+}
+
+/// Returns the number of messages that couldn't be written,
+/// e.g. because the reading end of a pipe was closed
+/// or because the file system holding a log file is full.
+pub fn suppressed() -> usize {
+    SUPPRESSED.load(Ordering::Relaxed)
+}
+
+fn write<W: Write>(mut w: W, args: fmt::Arguments, newline: bool) {
+    let result = w
+        .write_fmt(args)
+        .and_then(|_| if newline { w.write_all(b"\n") } else { Ok(()) })
+        .and_then(|_| w.flush());
+
+    if result.is_err() {
+        SUPPRESSED.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+/// Returns the string as a quoted and escaped JSON string.
+fn json_string(s: &str) -> String {
+    let mut quoted = String::with_capacity(s.len() + 2);
+    quoted.push('"');
+
+    for c in s.chars() {
+        match c {
+            '"' => quoted.push_str("\\\""),
+            '\\' => quoted.push_str("\\\\"),
+            '\n' => quoted.push_str("\\n"),
+            '\r' => quoted.push_str("\\r"),
+            '\t' => quoted.push_str("\\t"),
+            c if c.is_control() => quoted.push_str(&format!("\\u{:04x}", c as u32)),
+            c => quoted.push(c),
+        }
+    }
+
+    quoted.push('"');
+    quoted
+}
@@ -0,0 +1,198 @@
+// hbak_common is the main hbak library implementing the protocol shared logic.
+// Copyright (C) 2024  Himbeer <himbeerserverde@gmail.com>
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use crate::config::NodeConfig;
+use crate::proto::{Mode, Snapshot};
+
+use std::path::{Path, PathBuf};
+
+/// The directory the mountpoints of the btrfs file system are created in by default.
+pub const MOUNT_ROOT: &str = "/mnt";
+
+/// A `StorageLayout` describes where a [`crate::proto::LocalNode`] stores its data.
+/// All paths of snapshots, backups and subvolumes are derived from it.
+///
+/// Snapshot and backup paths are only ever built by appending
+/// the node name, subvolume name and identifier of a [`Snapshot`]
+/// to one of the directories of the layout. Identifiers whose on-disk name
+/// differs from the identifier itself are rejected before any file is created.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct StorageLayout {
+    mountpoint: PathBuf,
+    snapshot_dir: PathBuf,
+    backup_dir: PathBuf,
+    archive_dir: Option<PathBuf>,
+    pre_restore_dir: PathBuf,
+}
+
+impl StorageLayout {
+    /// Returns the default `StorageLayout` of the specified [`Mode`]
+    /// without an archive directory.
+    pub fn new(mode: Mode) -> Self {
+        Self::under(mode, Path::new(MOUNT_ROOT))
+    }
+
+    /// Returns the `StorageLayout` of the specified [`Mode`] whose mountpoint
+    /// is created inside of the specified directory instead of [`MOUNT_ROOT`],
+    /// e.g. to operate on a test file system. It has no archive directory.
+    pub fn under(mode: Mode, root: &Path) -> Self {
+        let mountpoint = root.join(mode.mount_name());
+
+        Self {
+            snapshot_dir: mountpoint.join("snapshots"),
+            backup_dir: mountpoint.join("backups"),
+            pre_restore_dir: mountpoint.join("pre-restore"),
+            mountpoint,
+            archive_dir: None,
+        }
+    }
+
+    /// Returns the `StorageLayout` of the specified [`Mode`]
+    /// including the directories set in the [`NodeConfig`].
+    pub fn from_config(config: &NodeConfig, mode: Mode) -> Self {
+        Self {
+            archive_dir: config.archive_dir.clone(),
+            ..Self::under(
+                mode,
+                config
+                    .mount_root
+                    .as_deref()
+                    .unwrap_or(Path::new(MOUNT_ROOT)),
+            )
+        }
+    }
+
+    /// Stores the snapshots of the subvolumes owned by the local node
+    /// in the specified directory instead of inside of the mountpoint.
+    pub fn with_snapshot_dir(mut self, snapshot_dir: PathBuf) -> Self {
+        self.snapshot_dir = snapshot_dir;
+        self
+    }
+
+    /// Stores the backups of other nodes in the specified directory
+    /// instead of inside of the mountpoint.
+    pub fn with_backup_dir(mut self, backup_dir: PathBuf) -> Self {
+        self.backup_dir = backup_dir;
+        self
+    }
+
+    /// Returns the mountpoint of the btrfs file system.
+    pub fn mountpoint(&self) -> &Path {
+        &self.mountpoint
+    }
+
+    /// Returns the directory containing the snapshots of the subvolumes
+    /// owned by the local node.
+    pub fn snapshot_dir(&self) -> &Path {
+        &self.snapshot_dir
+    }
+
+    /// Returns the directory containing the backups stored for other nodes.
+    pub fn backup_dir(&self) -> &Path {
+        &self.backup_dir
+    }
+
+    /// Returns the directory older backups are moved to, if configured.
+    pub fn archive_dir(&self) -> Option<&Path> {
+        self.archive_dir.as_deref()
+    }
+
+    /// Returns the directory the safety snapshots taken before restoring
+    /// a subvolume are stored in, i.e. `/mnt/hbak/pre-restore`.
+    pub fn pre_restore_dir(&self) -> &Path {
+        &self.pre_restore_dir
+    }
+
+    /// Returns the file the size estimates of snapshots and backups are cached in,
+    /// i.e. `/mnt/hbak/.estimates.json`.
+    pub fn estimate_cache(&self) -> PathBuf {
+        self.mountpoint.join(".estimates.json")
+    }
+
+    /// Returns the location of the specified subvolume,
+    /// i.e. a member of the mountpoint.
+    pub fn subvol_path(&self, subvol: &str) -> PathBuf {
+        self.mountpoint.join(subvol)
+    }
+
+    /// Returns the local storage location of the specified [`Snapshot`],
+    /// i.e. a member of the `/mnt/hbak/snapshots` directory
+    /// of its node's own snapshots.
+    pub fn snapshot_path(&self, snapshot: &Snapshot) -> PathBuf {
+        snapshot.snapshot_path_in(&self.snapshot_dir)
+    }
+
+    /// Returns the hidden directory the specified [`Snapshot`] is received into
+    /// when it is restored under a different identity, i.e. a member
+    /// of the `/mnt/hbak/snapshots` directory prefixed with a dot.
+    pub fn staging_dir(&self, relabelled: &Snapshot) -> PathBuf {
+        self.snapshot_dir.join(format!(".{relabelled}"))
+    }
+
+    /// Returns the directory the backups of the volume of the specified [`Snapshot`]
+    /// are stored in, i.e. `/mnt/hbak/backups/<node>/<subvol>`.
+    pub fn volume_dir(&self, snapshot: &Snapshot) -> PathBuf {
+        snapshot.volume_dir_in(&self.backup_dir)
+    }
+
+    /// Returns the remote storage location of the specified [`Snapshot`],
+    /// i.e. a member of the `/mnt/hbak/backups/<node>/<subvol>` directory
+    /// where other nodes may store it.
+    pub fn backup_path(&self, snapshot: &Snapshot) -> PathBuf {
+        snapshot.backup_path_in(&self.backup_dir)
+    }
+
+    /// Returns the remote storage location of the specified [`Snapshot`]
+    /// in the flat layout used by previous versions,
+    /// i.e. a direct member of the `/mnt/hbak/backups` directory.
+    pub fn legacy_backup_path(&self, snapshot: &Snapshot) -> PathBuf {
+        self.backup_dir.join(snapshot.to_string())
+    }
+
+    /// Returns the temporary remote storage location of the specified [`Snapshot`]
+    /// until its transmission is complete, suffixed with the `.part` file extension.
+    pub fn streaming_path(&self, snapshot: &Snapshot) -> PathBuf {
+        snapshot.streaming_path_in(&self.backup_dir)
+    }
+
+    /// Returns the archived storage location of the specified [`Snapshot`],
+    /// i.e. a member of the `<archive_dir>/<node>/<subvol>` directory,
+    /// or `None` if no archive directory is configured.
+    pub fn archive_path(&self, snapshot: &Snapshot) -> Option<PathBuf> {
+        self.archive_dir
+            .as_ref()
+            .map(|archive_dir| snapshot.backup_path_in(archive_dir))
+    }
+}
+
+/// The file name suffix of the manifest stored alongside each backup.
+pub const MANIFEST_SUFFIX: &str = ".manifest.json";
+
+/// Returns the location of the manifest of the backup at the specified path,
+/// i.e. the path suffixed with `.manifest.json`.
+pub fn manifest_path<P: AsRef<Path>>(backup_path: P) -> PathBuf {
+    let mut path = backup_path.as_ref().as_os_str().to_owned();
+    path.push(MANIFEST_SUFFIX);
+    path.into()
+}
+
+/// Reports whether the file name of the specified path ends in `.manifest.json`.
+pub fn is_manifest(path: &Path) -> bool {
+    path.file_name().is_some_and(|name| {
+        name.as_encoded_bytes()
+            .ends_with(MANIFEST_SUFFIX.as_bytes())
+    })
+}
@@ -14,31 +14,84 @@
 // You should have received a copy of the GNU Affero General Public License
 // along with this program.  If not, see <https://www.gnu.org/licenses/>.
 
-use crate::config::NodeConfig;
-use crate::stream::{RecoveryStream, SnapshotStream, CHUNKSIZE};
-use crate::system::{MOUNTPOINTC, MOUNTPOINTS};
+use crate::config::{NodeConfig, SubvolConfig};
+use crate::conn::{
+    Keepalive, DEFAULT_CHUNK_SIZE, DEFAULT_HEARTBEAT_INTERVAL, DEFAULT_KEEPALIVE_IDLE,
+    DEFAULT_KEEPALIVE_INTERVAL, DEFAULT_REKEY_INTERVAL, DEFAULT_STALL_TIMEOUT, MAX_CHUNK_SIZE,
+    MIN_CHUNK_SIZE,
+};
+use crate::json::Manifest;
+use crate::output::{self, Level};
+use crate::paths::{self, StorageLayout};
+use crate::stream::{
+    self, RecoveryStream, SendStreamCheck, SnapshotStream, StreamSummary, PIPE_BUFSIZE, RX_BUFSIZE,
+};
+use crate::system::{self, SendSupport};
 use crate::{LocalNodeError, SnapshotParseError, VolumeParseError};
 
-use std::cmp::Ordering;
+use std::cmp::{Ordering, Reverse};
+use std::collections::{BTreeMap, HashMap, HashSet};
 use std::ffi::OsStr;
 use std::fs::File;
-use std::io::{self, BufRead, BufReader, BufWriter};
+use std::io::{self, BufRead, BufReader, BufWriter, Read, Write};
 use std::path::{Path, PathBuf};
 use std::process::{Child, ChildStdin, ChildStdout, Command, Stdio};
-use std::{fmt, fs};
+use std::sync::atomic::{self, AtomicU64};
+use std::sync::{Mutex, OnceLock};
+use std::time::Duration;
+use std::{fmt, fs, thread};
 
 use chrono::prelude::*;
 use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
 use sys_mount::{Mount, UnmountDrop, UnmountFlags};
 
-pub const SNAPSHOT_DIR_C: &str = "/mnt/hbak/snapshots";
-pub const SNAPSHOT_DIR_S: &str = "/mnt/hbakd/snapshots";
-pub const BACKUP_DIR_C: &str = "/mnt/hbak/backups";
-pub const BACKUP_DIR_S: &str = "/mnt/hbakd/backups";
+/// The default minimum age of incomplete backups that are cleaned up.
+pub const DEFAULT_PARTIAL_MAX_AGE: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// The minimum age of deletable snapshots and backups
+/// if the snapshot directory is immutable and no explicit cooloff is configured.
+pub const DEFAULT_DELETE_COOLOFF: Duration = Duration::from_secs(7 * 24 * 60 * 60);
+
+/// The default time received snapshots may be dated ahead of the local clock.
+pub const DEFAULT_MAX_CLOCK_SKEW: Duration = Duration::from_secs(60 * 60);
+
+/// The default free space to keep on the file system of the backup directory.
+pub const DEFAULT_SPACE_RESERVE: u64 = 1024 * 1024 * 1024;
+
+/// The highest btrfs send stream version new backups are produced with.
+pub const MAX_SEND_PROTOCOL: u32 = 2;
+
+/// The zstd level of subvolumes that enable compression
+/// if no compression level is configured.
+pub const DEFAULT_COMPRESSION_LEVEL: i32 = 3;
+
+/// A `SafetySnapshot` is a read-only snapshot of a subvolume taken by
+/// [`LocalNode::restore`] before replacing it so that its previous state
+/// can be recovered manually. It is stored in the `/mnt/hbak/pre-restore` directory
+/// under the subvolume name and the time it was taken. Unlike a [`Snapshot`]
+/// it is never sent to other nodes or aged out by garbage collection.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct SafetySnapshot {
+    /// The subvolume the safety snapshot was taken of.
+    pub subvol: String,
+    /// The time the safety snapshot was taken.
+    pub taken: NaiveDateTime,
+    /// The location of the safety snapshot.
+    pub path: PathBuf,
+}
+
+/// The decrypting writer returned by [`LocalNode::recover`]
+/// that feeds a btrfs receive process.
+pub type Receiver<'a> = RecoveryStream<SendStreamCheck<BufWriter<ChildStdin>>, &'a str>;
 
 /// A `Snapshot` uniquely identifies a full or incremental btrfs snapshot
 /// of a node via the node name, subvolume name and creation date.
+///
+/// Deserialization fails if the node or subvolume name is invalid,
+/// see [`Snapshot::validate`].
 #[derive(Clone, Debug, Eq, Hash, PartialEq, Serialize, Deserialize)]
+#[serde(try_from = "RawSnapshot")]
 pub struct Snapshot {
     node_name: String,
     subvol: String,
@@ -46,6 +99,31 @@ pub struct Snapshot {
     taken: NaiveDateTime,
 }
 
+/// The serialized form of a [`Snapshot`] before its names are validated.
+#[derive(Deserialize)]
+struct RawSnapshot {
+    node_name: String,
+    subvol: String,
+    is_incremental: bool,
+    taken: NaiveDateTime,
+}
+
+impl TryFrom<RawSnapshot> for Snapshot {
+    type Error = SnapshotParseError;
+
+    fn try_from(raw: RawSnapshot) -> Result<Self, Self::Error> {
+        let snapshot = Self {
+            node_name: raw.node_name,
+            subvol: raw.subvol,
+            is_incremental: raw.is_incremental,
+            taken: raw.taken,
+        };
+
+        snapshot.validate()?;
+        Ok(snapshot)
+    }
+}
+
 impl Snapshot {
     const TIMESTAMP_FMT: &'static str = "%Y%m%d%H%M%S";
 
@@ -80,29 +158,39 @@ impl Snapshot {
     /// Converts the `Snapshot` to its local storage location,
     /// i.e. a member of the `/mnt/hbak/snapshots` directory
     /// of its node's own snapshots.
-    pub fn snapshot_path(&self, mode: Mode) -> PathBuf {
-        let mut path_buf = PathBuf::new();
-
-        path_buf.push(mode.snapshot_dir());
-        path_buf.push(self.to_string());
-
-        path_buf
+    pub fn snapshot_path(&self, layout: &StorageLayout) -> PathBuf {
+        layout.snapshot_path(self)
     }
 
     /// Converts the `Snapshot` to its remote storage location,
-    /// i.e. a member of the `/mnt/hbak/backups` directory
+    /// i.e. a member of the `/mnt/hbak/backups/<node>/<subvol>` directory
     /// where other nodes may store it.
-    pub fn backup_path(&self, mode: Mode) -> PathBuf {
-        let mut path_buf = PathBuf::new();
+    pub fn backup_path(&self, layout: &StorageLayout) -> PathBuf {
+        layout.backup_path(self)
+    }
+
+    /// Converts the `Snapshot` to its remote storage location
+    /// in the flat layout used by previous versions,
+    /// i.e. a direct member of the `/mnt/hbak/backups` directory.
+    pub fn legacy_backup_path(&self, layout: &StorageLayout) -> PathBuf {
+        layout.legacy_backup_path(self)
+    }
 
-        path_buf.push(mode.backup_dir());
-        path_buf.push(self.to_string());
+    /// Converts the `Snapshot` to its archived storage location,
+    /// i.e. a member of the `<archive_dir>/<node>/<subvol>` directory.
+    /// Returns `None` if no archive directory is configured.
+    pub fn archive_path(&self, layout: &StorageLayout) -> Option<PathBuf> {
+        layout.archive_path(self)
+    }
 
-        path_buf
+    /// Converts the `Snapshot` to the directory its backups are stored in,
+    /// i.e. `/mnt/hbak/backups/<node>/<subvol>`.
+    pub fn volume_dir(&self, layout: &StorageLayout) -> PathBuf {
+        layout.volume_dir(self)
     }
 
     /// Converts the `Snapshot` to its temporary remote storage location,
-    /// i.e. a member of the `/mnt/hbak/backups` directory
+    /// i.e. a member of the `/mnt/hbak/backups/<node>/<subvol>` directory
     /// where other nodes may store it until the transmission is complete.
     ///
     /// It is suffixed with the `.part` file extension and won't be treated
@@ -110,19 +198,73 @@ impl Snapshot {
     /// This behavior allows partial or failed transmissions to be retried
     /// and is used to prevent (malicious) overwriting of existing snapshots
     /// that have fully been written.
-    pub fn streaming_path(&self, mode: Mode) -> PathBuf {
-        let mut path_buf = PathBuf::new();
+    pub fn streaming_path(&self, layout: &StorageLayout) -> PathBuf {
+        layout.streaming_path(self)
+    }
+
+    /// Converts the `Snapshot` to its location inside of the specified
+    /// snapshot directory. Unlike [`Snapshot::snapshot_path`] this doesn't
+    /// require a [`StorageLayout`].
+    pub fn snapshot_path_in(&self, snapshot_dir: &Path) -> PathBuf {
+        snapshot_dir.join(self.to_string())
+    }
 
-        path_buf.push(mode.backup_dir());
-        path_buf.push(format!("{self}.part"));
+    /// Converts the `Snapshot` to the directory its backups are stored in
+    /// inside of the specified backup directory, i.e. `<backup_dir>/<node>/<subvol>`.
+    pub fn volume_dir_in(&self, backup_dir: &Path) -> PathBuf {
+        backup_dir.join(self.node_name()).join(self.subvol())
+    }
+
+    /// Converts the `Snapshot` to its location inside of the specified
+    /// backup directory, i.e. a member of `<backup_dir>/<node>/<subvol>`.
+    pub fn backup_path_in(&self, backup_dir: &Path) -> PathBuf {
+        self.volume_dir_in(backup_dir).join(self.to_string())
+    }
+
+    /// Converts the `Snapshot` to its temporary location inside of the specified
+    /// backup directory until its transmission is complete,
+    /// see [`Snapshot::streaming_path`].
+    pub fn streaming_path_in(&self, backup_dir: &Path) -> PathBuf {
+        self.volume_dir_in(backup_dir)
+            .join(format!("{}.part", self))
+    }
+
+    /// Returns the `Snapshot` identified by the on-disk name of this `Snapshot`.
+    /// It differs from this `Snapshot` (except for sub-second precision)
+    /// if the node or subvolume name contains underscores or slashes.
+    pub fn on_disk_identity(&self) -> Result<Snapshot, SnapshotParseError> {
+        Self::try_from(Path::new(&self.to_string()))
+    }
+
+    /// Ensures that the node and subvolume names are valid, see [`is_valid_name`].
+    /// `Snapshot`s received from remote nodes must be validated
+    /// before any storage path is derived from them.
+    pub fn validate(&self) -> Result<(), SnapshotParseError> {
+        for name in [&self.node_name, &self.subvol] {
+            if !is_valid_name(name) {
+                return Err(SnapshotParseError::InvalidName(name.clone()));
+            }
+        }
 
-        path_buf
+        Ok(())
     }
 
     /// Reports whether this `Snapshot` is a snapshot of the specified [`Volume`].
     pub fn is_of_volume(&self, volume: &Volume) -> bool {
         self.node_name() == volume.node_name() && self.subvol() == volume.subvol()
     }
+
+    /// Returns a `Snapshot` of the same type and creation date
+    /// that is attributed to the specified node and subvolume instead.
+    /// Used to restore backups under a different identity.
+    pub fn relabel(&self, node_name: &str, subvol: &str) -> Self {
+        Self {
+            node_name: node_name.to_string(),
+            subvol: subvol.to_string(),
+            is_incremental: self.is_incremental,
+            taken: self.taken,
+        }
+    }
 }
 
 impl fmt::Display for Snapshot {
@@ -149,7 +291,7 @@ impl TryFrom<&str> for Snapshot {
         let ty = tokens.next().ok_or(SnapshotParseError::MissingType)?;
         let taken = tokens.next().ok_or(SnapshotParseError::MissingTimeTaken)?;
 
-        Ok(Self {
+        let snapshot = Self {
             node_name: node_name.to_string(),
             subvol: subvol.to_string(),
             is_incremental: match ty {
@@ -158,7 +300,10 @@ impl TryFrom<&str> for Snapshot {
                 _ => return Err(SnapshotParseError::InvalidType(ty.to_string())),
             },
             taken: NaiveDateTime::parse_from_str(taken, Self::TIMESTAMP_FMT)?,
-        })
+        };
+
+        snapshot.validate()?;
+        Ok(snapshot)
     }
 }
 
@@ -184,29 +329,80 @@ pub struct LatestSnapshots {
     pub last_full: NaiveDateTime,
     /// Timestamp of the last incremental snapshot.
     pub last_incremental: NaiveDateTime,
+    /// Upper bound of the timestamps of snapshots that may be sent.
+    /// Used for point-in-time recovery.
+    pub not_after: Option<NaiveDateTime>,
 }
 
 impl LatestSnapshots {
     /// Returns a `LatestSnapshots` that signifies that no snapshots exist.
-    /// Only useful when restoring.
+    /// Used when restoring and for volumes only matched by a wildcard.
     pub fn none() -> Self {
         Self {
             last_full: NaiveDateTime::MIN,
             last_incremental: NaiveDateTime::MIN,
+            not_after: None,
+        }
+    }
+
+    /// Returns the timestamps of the latest full and incremental snapshots
+    /// among the specified ones taken at or before `not_after`, if set,
+    /// that prohibit sending any later snapshots.
+    pub fn of(snapshots: &[Snapshot], not_after: Option<NaiveDateTime>) -> Self {
+        let taken = |incremental| {
+            latest_of(snapshots, incremental, not_after)
+                .map(|snapshot| snapshot.taken())
+                .unwrap_or(NaiveDateTime::MIN)
+        };
+
+        Self {
+            last_full: taken(false),
+            last_incremental: taken(true),
+            not_after,
         }
     }
+
+    /// Reports whether a snapshot taken at the specified time may be sent.
+    pub fn permits(&self, taken: NaiveDateTime) -> bool {
+        self.not_after.is_none_or(|not_after| taken <= not_after)
+    }
 }
 
 /// A `Volume` is a unique combination of btrfs subvolume and host name.
+///
+/// Deserialization fails if the node or subvolume name is invalid,
+/// see [`Volume::validate`].
 #[derive(Clone, Debug, Eq, Hash, PartialEq, Serialize, Deserialize)]
+#[serde(try_from = "RawVolume")]
 pub struct Volume {
     node_name: String,
     subvol: String,
 }
 
+/// The serialized form of a [`Volume`] before its names are validated.
+#[derive(Deserialize)]
+struct RawVolume {
+    node_name: String,
+    subvol: String,
+}
+
+impl TryFrom<RawVolume> for Volume {
+    type Error = VolumeParseError;
+
+    fn try_from(raw: RawVolume) -> Result<Self, Self::Error> {
+        let volume = Self {
+            node_name: raw.node_name,
+            subvol: raw.subvol,
+        };
+
+        volume.validate()?;
+        Ok(volume)
+    }
+}
+
 impl Volume {
     /// Constructs a new `Volume` using the name of the provided [`LocalNode`]
-    /// and the specified subvolume name.
+    /// and the specified subvolume name, which is replaced by its alias if any.
     pub fn new_local(local_node: &LocalNode, subvol: String) -> Result<Self, LocalNodeError> {
         if !local_node.owns_subvol(&subvol) {
             return Err(LocalNodeError::ForeignSubvolume(subvol.clone()));
@@ -214,7 +410,7 @@ impl Volume {
 
         Ok(Self {
             node_name: local_node.name().to_string(),
-            subvol,
+            subvol: local_node.config().subvol_alias(&subvol).to_string(),
         })
     }
 
@@ -228,6 +424,25 @@ impl Volume {
         &self.subvol
     }
 
+    /// Ensures that the node and subvolume names are valid, see [`is_valid_name`].
+    pub fn validate(&self) -> Result<(), VolumeParseError> {
+        for name in [&self.node_name, &self.subvol] {
+            if !is_valid_name(name) {
+                return Err(VolumeParseError::InvalidName(name.clone()));
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Returns the `Volume` of the specified node and subvolume, see [`Snapshot::relabel`].
+    pub fn relabel(&self, node_name: &str, subvol: &str) -> Self {
+        Self {
+            node_name: node_name.to_string(),
+            subvol: subvol.to_string(),
+        }
+    }
+
     /// Convenience wrapper for `Vec<String>` to `Vec<Volume>` conversion.
     pub fn try_from_bulk(values: Vec<String>) -> Result<Vec<Self>, VolumeParseError> {
         values
@@ -252,10 +467,17 @@ impl TryFrom<&str> for Volume {
         let node_name = tokens.next().ok_or(VolumeParseError::MissingNodeName)?;
         let subvol = tokens.next().ok_or(VolumeParseError::MissingSubvolume)?;
 
-        Ok(Self {
+        if tokens.next().is_some() {
+            return Err(VolumeParseError::TrailingComponents(value.to_string()));
+        }
+
+        let volume = Self {
             node_name: node_name.to_string(),
             subvol: subvol.to_string(),
-        })
+        };
+
+        volume.validate()?;
+        Ok(volume)
     }
 }
 
@@ -271,6 +493,129 @@ impl PartialOrd<Volume> for Volume {
     }
 }
 
+/// A `VolumeSpec` selects one or more [`Volume`]s in push and pull permissions.
+///
+/// It is written as a volume identifier, `<node>/*` for all volumes of a node
+/// or `*` for all volumes. Configuration files of previous versions
+/// that only contain exact volumes remain valid.
+#[derive(Clone, Debug, Eq, Hash, PartialEq, Serialize, Deserialize)]
+#[serde(try_from = "RawVolumeSpec", into = "RawVolumeSpec")]
+pub enum VolumeSpec {
+    /// A single volume.
+    Volume(Volume),
+    /// All volumes of the specified node.
+    AllOfNode(String),
+    /// All volumes of all nodes.
+    All,
+}
+
+impl VolumeSpec {
+    /// Reports whether the specified [`Volume`] is selected by the `VolumeSpec`.
+    pub fn matches(&self, volume: &Volume) -> bool {
+        match self {
+            Self::Volume(spec) => spec == volume,
+            Self::AllOfNode(node_name) => volume.node_name() == node_name,
+            Self::All => true,
+        }
+    }
+
+    /// Reports whether every [`Volume`] selected by the specified `VolumeSpec`
+    /// is also selected by this `VolumeSpec`.
+    pub fn covers(&self, other: &VolumeSpec) -> bool {
+        match (self, other) {
+            (Self::All, _) => true,
+            (_, Self::All) => false,
+            (Self::AllOfNode(node_name), Self::AllOfNode(other)) => node_name == other,
+            (_, Self::AllOfNode(_)) => false,
+            (spec, Self::Volume(volume)) => spec.matches(volume),
+        }
+    }
+
+    /// Returns the selected [`Volume`] if the `VolumeSpec` is not a wildcard.
+    pub fn volume(&self) -> Option<&Volume> {
+        match self {
+            Self::Volume(volume) => Some(volume),
+            Self::AllOfNode(_) | Self::All => None,
+        }
+    }
+
+    /// Convenience wrapper for `Vec<String>` to `Vec<VolumeSpec>` conversion.
+    pub fn try_from_bulk(values: Vec<String>) -> Result<Vec<Self>, VolumeParseError> {
+        values
+            .into_iter()
+            .map(|value| Self::try_from(value.as_str()))
+            .collect()
+    }
+}
+
+impl fmt::Display for VolumeSpec {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Volume(volume) => write!(f, "{}", volume),
+            Self::AllOfNode(node_name) => write!(f, "{}/*", node_name),
+            Self::All => write!(f, "*"),
+        }
+    }
+}
+
+impl TryFrom<&str> for VolumeSpec {
+    type Error = VolumeParseError;
+
+    fn try_from(value: &str) -> Result<Self, Self::Error> {
+        if value == "*" {
+            return Ok(Self::All);
+        }
+
+        // Also accept the volume identifier separator.
+        match value
+            .strip_suffix("/*")
+            .or_else(|| value.strip_suffix("_*"))
+        {
+            Some("") => Err(VolumeParseError::MissingNodeName),
+            Some(node_name) if !is_valid_name(node_name) => {
+                Err(VolumeParseError::InvalidName(node_name.to_string()))
+            }
+            Some(node_name) => Ok(Self::AllOfNode(node_name.to_string())),
+            None => Ok(Self::Volume(Volume::try_from(value)?)),
+        }
+    }
+}
+
+impl From<Volume> for VolumeSpec {
+    fn from(volume: Volume) -> Self {
+        Self::Volume(volume)
+    }
+}
+
+/// The serialized form of a [`VolumeSpec`]. Exact volumes are stored
+/// as tables like in previous versions, wildcards as strings.
+#[derive(Serialize, Deserialize)]
+#[serde(untagged)]
+enum RawVolumeSpec {
+    Volume(Volume),
+    Pattern(String),
+}
+
+impl TryFrom<RawVolumeSpec> for VolumeSpec {
+    type Error = VolumeParseError;
+
+    fn try_from(raw: RawVolumeSpec) -> Result<Self, Self::Error> {
+        match raw {
+            RawVolumeSpec::Volume(volume) => Ok(Self::Volume(volume)),
+            RawVolumeSpec::Pattern(pattern) => Self::try_from(pattern.as_str()),
+        }
+    }
+}
+
+impl From<VolumeSpec> for RawVolumeSpec {
+    fn from(spec: VolumeSpec) -> Self {
+        match spec {
+            VolumeSpec::Volume(volume) => Self::Volume(volume),
+            spec => Self::Pattern(spec.to_string()),
+        }
+    }
+}
+
 /// A `Node` is a member of a distributed backup network
 /// that can run its own `Volumes` and store those of other `Node`s.
 pub trait Node {
@@ -313,28 +658,69 @@ pub enum Mode {
 }
 
 impl Mode {
-    /// Returns the correct mountpoint for the `Mode`.
-    pub fn mountpoint(&self) -> &'static str {
+    /// Returns the name of the mountpoint of the `Mode`
+    /// inside of the mount root, see [`StorageLayout::under`].
+    pub fn mount_name(&self) -> &'static str {
         match self {
-            Self::Client => MOUNTPOINTC,
-            Self::Server => MOUNTPOINTS,
+            Self::Client => "hbak",
+            Self::Server => "hbakd",
         }
     }
+}
 
-    /// Returns the correct snapshot directory for the `Mode`.
-    pub fn snapshot_dir(&self) -> &'static str {
-        match self {
-            Self::Client => SNAPSHOT_DIR_C,
-            Self::Server => SNAPSHOT_DIR_S,
-        }
+/// A `LocalNodeBuilder` constructs a [`LocalNode`] whose snapshot and backup
+/// directories may differ from those of its [`StorageLayout`],
+/// e.g. to browse backups stored elsewhere on the btrfs file system.
+#[derive(Debug, Default)]
+pub struct LocalNodeBuilder {
+    mode: Option<Mode>,
+    config: Option<NodeConfig>,
+    snapshot_dir: Option<PathBuf>,
+    backup_dir: Option<PathBuf>,
+}
+
+impl LocalNodeBuilder {
+    /// Sets the [`Mode`] the `LocalNode` acts in. Defaults to [`Mode::Client`].
+    pub fn mode(mut self, mode: Mode) -> Self {
+        self.mode = Some(mode);
+        self
     }
 
-    /// Returns the correct backup directory for the `Mode`.
-    pub fn backup_dir(&self) -> &'static str {
-        match self {
-            Self::Client => BACKUP_DIR_C,
-            Self::Server => BACKUP_DIR_S,
+    /// Uses the provided configuration instead of loading it from disk.
+    pub fn config(mut self, config: NodeConfig) -> Self {
+        self.config = Some(config);
+        self
+    }
+
+    /// Stores the snapshots of the owned subvolumes in the specified directory.
+    pub fn snapshot_dir(mut self, snapshot_dir: PathBuf) -> Self {
+        self.snapshot_dir = Some(snapshot_dir);
+        self
+    }
+
+    /// Stores the backups of other nodes in the specified directory.
+    pub fn backup_dir(mut self, backup_dir: PathBuf) -> Self {
+        self.backup_dir = Some(backup_dir);
+        self
+    }
+
+    /// Mounts the btrfs file system and returns the `LocalNode`.
+    pub fn build(self) -> Result<LocalNode, LocalNodeError> {
+        let mode = self.mode.unwrap_or(Mode::Client);
+        let config = match self.config {
+            Some(config) => config,
+            None => NodeConfig::load()?,
+        };
+
+        let mut layout = StorageLayout::from_config(&config, mode);
+        if let Some(snapshot_dir) = self.snapshot_dir {
+            layout = layout.with_snapshot_dir(snapshot_dir);
         }
+        if let Some(backup_dir) = self.backup_dir {
+            layout = layout.with_backup_dir(backup_dir);
+        }
+
+        LocalNode::with_layout(mode, config, layout)
     }
 }
 
@@ -342,15 +728,18 @@ impl Mode {
 pub struct LocalNode {
     config: NodeConfig,
     mode: Mode,
+    layout: StorageLayout,
+    estimates: Mutex<HashMap<Snapshot, SizeEstimate>>,
+    send_support: OnceLock<SendSupport>,
+    passphrase: OnceLock<String>,
     _btrfs: UnmountDrop<Mount>,
 }
 
 impl LocalNode {
     /// Returns a new `LocalNode` representing the local machine.
-    /// Shorthand for `LocalNode::with_config(mode, NodeConfig::load()?)`.
+    /// Shorthand for `LocalNode::builder().mode(mode).build()`.
     pub fn new(mode: Mode) -> Result<Self, LocalNodeError> {
-        let config = NodeConfig::load()?;
-        Self::with_config(mode, config)
+        Self::builder().mode(mode).build()
     }
 
     /// Returns a new `LocalNode` representing the local machine.
@@ -358,74 +747,154 @@ impl LocalNode {
     /// The purpose of this method is to make recovery possible
     /// without requiring tedious pre-initialization by the user.
     pub fn with_config(mode: Mode, config: NodeConfig) -> Result<Self, LocalNodeError> {
-        let device = config.device.clone();
+        Self::builder().mode(mode).config(config).build()
+    }
 
-        fs::create_dir_all(MOUNTPOINTC)?;
-        fs::create_dir_all(MOUNTPOINTS)?;
+    /// Returns a [`LocalNodeBuilder`] to construct a `LocalNode`
+    /// with explicit storage directories.
+    pub fn builder() -> LocalNodeBuilder {
+        LocalNodeBuilder::default()
+    }
+
+    fn with_layout(
+        mode: Mode,
+        config: NodeConfig,
+        layout: StorageLayout,
+    ) -> Result<Self, LocalNodeError> {
+        let device = config.device.clone();
 
-        let mountpoint = mode.mountpoint();
+        // Only create the mountpoint of the role the `LocalNode` is acting in.
+        let mountpoint = layout.mountpoint().to_path_buf();
+        fs::create_dir_all(&mountpoint)?;
 
-        Ok(Self {
+        let local_node = Self {
             config,
             mode,
+            layout,
+            estimates: Mutex::new(HashMap::new()),
+            send_support: OnceLock::new(),
+            passphrase: OnceLock::new(),
             _btrfs: Mount::builder().data("compress=zstd").mount_autodrop(
                 device,
                 mountpoint,
                 UnmountFlags::DETACH,
             )?,
-        })
+        };
+
+        let estimates = local_node.load_estimates();
+        *local_node.estimates.lock().unwrap() = estimates;
+
+        // Restore the protection in case a previous invocation crashed
+        // while it was lifted.
+        if local_node.has_immutable_snapshots() {
+            local_node.protect()?;
+        }
+
+        Ok(local_node)
     }
 
-    /// Returns a reference to the configuration of the `LocalNode`.
-    pub fn config(&self) -> &NodeConfig {
-        &self.config
+    /// Returns the encryption passphrase, resolving it on first use.
+    /// Only [`Mode::Client`] nodes may ask for it interactively.
+    /// See [`NodeConfig::resolve_passphrase`] for details.
+    pub fn passphrase(&self) -> Result<&str, LocalNodeError> {
+        if let Some(passphrase) = self.passphrase.get() {
+            return Ok(passphrase);
+        }
+
+        let passphrase = self.config.resolve_passphrase(self.mode == Mode::Client)?;
+        Ok(self.passphrase.get_or_init(|| passphrase))
     }
 
-    /// Returns the [`Mode`] (network client or server) of the `LocalNode`.
-    pub fn mode(&self) -> Mode {
-        self.mode
+    /// Reports whether the snapshot directory is protected from modification
+    /// between operations.
+    pub fn has_immutable_snapshots(&self) -> bool {
+        self.config().immutable_snapshots.unwrap_or(false)
     }
 
-    /// Reports whether the `LocalNode` is the origin of the specified subvolume.
-    pub fn owns_subvol(&self, subvol: &String) -> bool {
-        self.config().subvols.contains(subvol)
+    /// Returns the minimum age of snapshots and backups that may be deleted.
+    pub fn delete_cooloff(&self) -> Duration {
+        match self.config().delete_cooloff {
+            Some(delete_cooloff) => delete_cooloff.into(),
+            None if self.has_immutable_snapshots() => DEFAULT_DELETE_COOLOFF,
+            None => Duration::ZERO,
+        }
     }
 
-    /// Reports whether the `LocalNode` is the origin of the specified [`Snapshot`]
-    /// by verifying the node name.
-    pub fn owns_backup(&self, backup: &Snapshot) -> bool {
-        backup.node_name() == self.config().node_name
+    /// Verifies that all snapshots are read-only and marks the snapshot directory
+    /// as immutable.
+    pub fn protect(&self) -> Result<(), LocalNodeError> {
+        for snapshot in self.all_snapshots(None)? {
+            self.ensure_read_only(&snapshot)?;
+        }
+
+        system::set_immutable(self.layout.snapshot_dir(), true)
     }
 
-    /// Creates a new btrfs snapshot of the specified subvolume.
-    pub fn snapshot_now(
-        &self,
-        subvol: String,
-        is_incremental: bool,
-    ) -> Result<Snapshot, LocalNodeError> {
-        if !self.owns_subvol(&subvol) {
-            return Err(LocalNodeError::ForeignSubvolume(subvol));
+    /// Replaces the subvolumes owned by the `LocalNode`,
+    /// e.g. once restoration has determined which ones are available.
+    pub fn set_subvols(&mut self, subvols: Vec<SubvolConfig>) {
+        self.config.subvols = subvols;
+    }
+
+    /// Creates the snapshot directory unless it already exists,
+    /// e.g. to recover to a freshly formatted file system.
+    pub fn ensure_snapshot_dir(&self) -> Result<(), LocalNodeError> {
+        system::adopt_subvolume(self.layout.snapshot_dir())?;
+        Ok(())
+    }
+
+    /// Lifts the immutable attribute of the snapshot directory
+    /// until the returned [`Unlocked`] guard is dropped.
+    /// The attribute is restored if it was set before or if the `LocalNode`
+    /// is configured to protect its snapshots.
+    pub fn unlock_snapshots(&self) -> Result<Unlocked, LocalNodeError> {
+        let path = self.layout.snapshot_dir();
+
+        let was_immutable = system::is_immutable(path)?;
+        if was_immutable {
+            system::set_immutable(path, false)?;
         }
 
-        let src = Path::new(self.mode.mountpoint()).join(&subvol);
-        let snapshot = Snapshot {
-            node_name: self.name().to_string(),
-            subvol,
-            is_incremental,
-            taken: Utc::now().naive_utc(),
-        };
-        let dst = snapshot.snapshot_path(self.mode);
+        Ok(Unlocked {
+            path: path.to_path_buf(),
+            relock: was_immutable || self.has_immutable_snapshots(),
+        })
+    }
 
-        if dst.exists() {
-            return Err(LocalNodeError::SnapshotExists(snapshot));
+    /// Reports whether the btrfs read-only property is set on the specified snapshot.
+    /// Snapshots left behind by interrupted receptions are writable.
+    fn is_read_only(&self, snapshot: &Snapshot) -> Result<bool, LocalNodeError> {
+        let output = Command::new("btrfs")
+            .arg("property")
+            .arg("get")
+            .arg("-ts")
+            .arg(snapshot.snapshot_path(&self.layout))
+            .arg("ro")
+            .stdin(Stdio::null())
+            .stderr(Stdio::null())
+            .output()?;
+        if !output.status.success() {
+            return Err(LocalNodeError::BtrfsCmd);
+        }
+
+        Ok(String::from_utf8_lossy(&output.stdout).trim() == "ro=true")
+    }
+
+    /// Ensures that the btrfs read-only property is set on the specified snapshot.
+    fn ensure_read_only(&self, snapshot: &Snapshot) -> Result<(), LocalNodeError> {
+        let path = snapshot.snapshot_path(&self.layout);
+
+        if self.is_read_only(snapshot)? {
+            return Ok(());
         }
 
         if !Command::new("btrfs")
-            .arg("subvolume")
-            .arg("snapshot")
-            .arg("-r")
-            .arg(src)
-            .arg(dst)
+            .arg("property")
+            .arg("set")
+            .arg("-ts")
+            .arg(&path)
+            .arg("ro")
+            .arg("true")
             .stdin(Stdio::null())
             .stdout(Stdio::null())
             .stderr(Stdio::null())
@@ -436,47 +905,271 @@ impl LocalNode {
             return Err(LocalNodeError::BtrfsCmd);
         }
 
-        Ok(snapshot)
+        Ok(())
     }
 
-    /// Returns all snapshots of the specified subvolume or all subvolumes of this node.
-    pub fn all_snapshots(&self, subvol: Option<String>) -> Result<Vec<Snapshot>, LocalNodeError> {
-        match subvol {
-            Some(subvol) if !self.owns_subvol(&subvol) => {
-                return Err(LocalNodeError::ForeignSubvolume(subvol));
-            }
-            _ => {}
-        }
+    /// Returns a reference to the configuration of the `LocalNode`.
+    pub fn config(&self) -> &NodeConfig {
+        &self.config
+    }
 
-        let snapshots = fs::read_dir(self.mode.snapshot_dir())?;
-        let mut all_snapshots = Vec::new();
-        for snapshot in snapshots {
-            let snapshot = Snapshot::try_from(&*snapshot?.path())?;
+    /// Returns the [`Mode`] (network client or server) of the `LocalNode`.
+    pub fn mode(&self) -> Mode {
+        self.mode
+    }
 
-            match &subvol {
-                Some(subvol) if snapshot.subvol() != subvol => {}
-                _ => all_snapshots.push(snapshot),
-            }
+    /// Returns the [`StorageLayout`] of the `LocalNode`.
+    pub fn layout(&self) -> &StorageLayout {
+        &self.layout
+    }
+
+    /// Ensures that the specified subvolume exists on the local btrfs file system.
+    pub fn check_subvol(&self, subvol: &str) -> Result<(), LocalNodeError> {
+        let path = self.subvol_path(subvol);
+
+        if !path.exists() {
+            return Err(LocalNodeError::NoSuchSubvolume(subvol.to_string()));
+        }
+        if !system::is_subvolume(&path)? {
+            return Err(LocalNodeError::NotSubvolume(path.display().to_string()));
         }
 
-        Ok(all_snapshots)
+        Ok(())
     }
 
-    /// Returns the latest full snapshot of the specified subvolume of this node.
-    pub fn latest_snapshot_full(&self, subvol: String) -> Result<Snapshot, LocalNodeError> {
-        self.all_snapshots(Some(subvol.clone()))?
-            .into_iter()
-            .filter(|snapshot| !snapshot.is_incremental())
-            .max_by_key(|snapshot| snapshot.taken())
+    /// Returns the location of the owned subvolume with the specified
+    /// on-disk name or alias, see [`NodeConfig::subvol_on_disk`].
+    pub fn subvol_path(&self, subvol: &str) -> PathBuf {
+        self.layout
+            .subvol_path(self.config().subvol_on_disk(subvol))
+    }
+
+    /// Reports whether the `LocalNode` is the origin of the specified subvolume.
+    pub fn owns_subvol(&self, subvol: &str) -> bool {
+        self.config().subvol(subvol).is_some()
+    }
+
+    /// Reports whether the `LocalNode` is the origin of the specified [`Snapshot`]
+    /// by verifying the node name.
+    pub fn owns_backup(&self, backup: &Snapshot) -> bool {
+        backup.node_name() == self.config().node_name
+    }
+
+    /// Reports whether the specified [`Snapshot`] exists on the `LocalNode`,
+    /// either as a local snapshot or as a backup.
+    pub fn exists(&self, snapshot: &Snapshot) -> bool {
+        if self.owns_backup(snapshot) {
+            snapshot.snapshot_path(&self.layout).exists()
+        } else {
+            self.locate_backup(snapshot).exists()
+        }
+    }
+
+    /// Reports whether a backup of the specified [`Snapshot`] is stored
+    /// on the `LocalNode`, regardless of which node it originates from.
+    pub fn backup_exists(&self, snapshot: &Snapshot) -> bool {
+        self.locate_backup(snapshot).exists()
+    }
+
+    /// Ensures that the on-disk name of the specified [`Snapshot`]
+    /// doesn't identify a different snapshot, preventing silent overwrites
+    /// or interleaving of data of distinct snapshots.
+    fn check_name(&self, snapshot: &Snapshot) -> Result<(), LocalNodeError> {
+        let on_disk = snapshot.on_disk_identity()?;
+
+        if on_disk.node_name() != snapshot.node_name()
+            || on_disk.subvol() != snapshot.subvol()
+            || on_disk.is_incremental() != snapshot.is_incremental()
+        {
+            return Err(LocalNodeError::NameCollision(
+                Box::new(snapshot.clone()),
+                Box::new(on_disk),
+            ));
+        }
+
+        Ok(())
+    }
+
+    /// Returns the storage location of the specified backup,
+    /// falling back to the flat layout used by previous versions
+    /// if the backup has not been migrated yet
+    /// and to the archive directory if the backup has been archived.
+    fn locate_backup(&self, snapshot: &Snapshot) -> PathBuf {
+        let path = snapshot.backup_path(&self.layout);
+        let legacy_path = snapshot.legacy_backup_path(&self.layout);
+
+        if path.exists() {
+            return path;
+        } else if legacy_path.exists() {
+            return legacy_path;
+        }
+
+        match snapshot.archive_path(&self.layout) {
+            Some(archive_path) if archive_path.exists() => archive_path,
+            _ => path,
+        }
+    }
+
+    /// Creates a new btrfs snapshot of the specified subvolume.
+    ///
+    /// Does nothing and returns `None` if the latest snapshot of the subvolume
+    /// was taken less than `min_interval` ago.
+    /// Waits for the next second if a snapshot of the same type
+    /// has already been taken within the current one.
+    ///
+    /// If `run_hooks` is set, the `pre_snapshot` commands of the subvolume
+    /// are run before taking the snapshot and abort it on failure.
+    /// The `post_snapshot` commands are run afterwards in any case.
+    pub fn snapshot_now(
+        &self,
+        subvol: String,
+        is_incremental: bool,
+        min_interval: Option<Duration>,
+        run_hooks: bool,
+    ) -> Result<Option<Snapshot>, LocalNodeError> {
+        if !self.owns_subvol(&subvol) {
+            return Err(LocalNodeError::ForeignSubvolume(subvol));
+        }
+
+        if let Some(min_interval) = min_interval {
+            let now = Utc::now().naive_utc();
+            let latest = self
+                .all_snapshots(Some(subvol.clone()))?
+                .into_iter()
+                .map(|snapshot| snapshot.taken())
+                .max();
+
+            if latest
+                .is_some_and(|latest| (now - latest).to_std().unwrap_or_default() < min_interval)
+            {
+                return Ok(None);
+            }
+        }
+
+        // Disabled hooks are treated like a subvolume without any commands.
+        let hooks = match self.config().subvol(&subvol) {
+            Some(subvol_config) if run_hooks => subvol_config.clone(),
+            _ => SubvolConfig::from(subvol.clone()),
+        };
+
+        let result = system::run_hooks("pre_snapshot", self.name(), &subvol, &hooks.pre_snapshot)
+            .and_then(|_| self.take_snapshot(subvol.clone(), is_incremental));
+        let post = system::run_hooks("post_snapshot", self.name(), &subvol, &hooks.post_snapshot);
+
+        let snapshot = result?;
+        post?;
+
+        Ok(Some(snapshot))
+    }
+
+    /// Creates a new btrfs snapshot of the specified subvolume without running hooks.
+    fn take_snapshot(
+        &self,
+        subvol: String,
+        is_incremental: bool,
+    ) -> Result<Snapshot, LocalNodeError> {
+        let src = self.subvol_path(&subvol);
+        let mut snapshot = Snapshot {
+            node_name: self.name().to_string(),
+            subvol: self.config().subvol_alias(&subvol).to_string(),
+            is_incremental,
+            taken: Utc::now().naive_utc(),
+        };
+        self.check_name(&snapshot)?;
+
+        // Snapshot names have a resolution of one second.
+        if snapshot.snapshot_path(&self.layout).exists() {
+            let nanos = 1_000_000_000_u32.saturating_sub(snapshot.taken.nanosecond());
+            thread::sleep(Duration::from_nanos(nanos.into()));
+
+            snapshot.taken = Utc::now().naive_utc();
+        }
+
+        let dst = snapshot.snapshot_path(&self.layout);
+
+        if dst.exists() {
+            return Err(LocalNodeError::SnapshotExists(snapshot));
+        }
+
+        let latest = predecessor(
+            self.all_snapshots(Some(snapshot.subvol().to_string()))?,
+            &snapshot,
+        )?;
+
+        // The parent has to be complete for btrfs to send the difference.
+        if let Some(latest) = latest.filter(|_| is_incremental) {
+            if !self.is_read_only(&latest)? {
+                return Err(LocalNodeError::UnusableParent(latest));
+            }
+        }
+
+        let _unlocked = self.unlock_snapshots()?;
+
+        if !Command::new("btrfs")
+            .arg("subvolume")
+            .arg("snapshot")
+            .arg("-r")
+            .arg(src)
+            .arg(dst)
+            .stdin(Stdio::null())
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .spawn()?
+            .wait()?
+            .success()
+        {
+            return Err(LocalNodeError::BtrfsCmd);
+        }
+
+        Ok(snapshot)
+    }
+
+    /// Returns all snapshots of the specified subvolume or all subvolumes of this node.
+    /// Entries that aren't snapshot identifiers are skipped with a warning
+    /// unless they are hidden.
+    pub fn all_snapshots(&self, subvol: Option<String>) -> Result<Vec<Snapshot>, LocalNodeError> {
+        match subvol {
+            Some(subvol) if !self.owns_subvol(&subvol) => {
+                return Err(LocalNodeError::ForeignSubvolume(subvol));
+            }
+            _ => {}
+        }
+
+        let mut all_snapshots = read_snapshots(self.layout.snapshot_dir())?;
+        if let Some(subvol) = subvol {
+            let alias = self.config().subvol_alias(&subvol);
+            all_snapshots.retain(|snapshot| snapshot.subvol() == alias);
+        }
+
+        Ok(all_snapshots)
+    }
+
+    /// Returns why the next snapshot of the specified subvolume has to be full
+    /// even if an incremental snapshot is requested or `None` if it doesn't.
+    ///
+    /// Incremental snapshots need a full snapshot to refer to
+    /// and are limited by the `max_incrementals_between_fulls` setting of the subvolume.
+    pub fn promotion(&self, subvol: &str) -> Result<Option<Promotion>, LocalNodeError> {
+        let max_incrementals = self
+            .config()
+            .subvol(subvol)
+            .and_then(|subvol_config| subvol_config.max_incrementals_between_fulls);
+
+        Ok(Promotion::required(
+            &self.all_snapshots(Some(subvol.to_string()))?,
+            max_incrementals,
+        ))
+    }
+
+    /// Returns the latest full snapshot of the specified subvolume of this node.
+    pub fn latest_snapshot_full(&self, subvol: String) -> Result<Snapshot, LocalNodeError> {
+        latest_of(&self.all_snapshots(Some(subvol.clone()))?, false, None)
             .ok_or(LocalNodeError::NoFullSnapshot(subvol))
     }
 
     /// Returns the latest incremental snapshot of the specified subvolume of this node.
     pub fn latest_snapshot_incremental(&self, subvol: String) -> Result<Snapshot, LocalNodeError> {
-        self.all_snapshots(Some(subvol.clone()))?
-            .into_iter()
-            .filter(|snapshot| snapshot.is_incremental())
-            .max_by_key(|snapshot| snapshot.taken())
+        latest_of(&self.all_snapshots(Some(subvol.clone()))?, true, None)
             .ok_or(LocalNodeError::NoIncrementalSnapshot(subvol))
     }
 
@@ -492,6 +1185,22 @@ impl LocalNode {
         .ok_or(LocalNodeError::NoFullSnapshot(subvol))
     }
 
+    /// Returns the latest snapshot, full or incremental, of the specified subvolume
+    /// of this node taken at or before the provided timestamp.
+    pub fn latest_snapshot_before(
+        &self,
+        subvol: String,
+        at: NaiveDateTime,
+    ) -> Result<Snapshot, LocalNodeError> {
+        let latest_full = self.latest_full_before(Volume::new_local(self, subvol.clone())?, at)?;
+
+        Ok(self
+            .incrementals_between(latest_full.volume(), latest_full.taken(), at)?
+            .into_iter()
+            .max_by_key(|snapshot| snapshot.taken())
+            .unwrap_or(latest_full))
+    }
+
     /// Returns all full snapshots of the specified subvolume of this node
     /// taken after the provided timestamp.
     pub fn snapshot_full_after(
@@ -499,11 +1208,7 @@ impl LocalNode {
         subvol: String,
         after: NaiveDateTime,
     ) -> Result<Vec<Snapshot>, LocalNodeError> {
-        Ok(self
-            .all_snapshots(Some(subvol))?
-            .into_iter()
-            .filter(|snapshot| !snapshot.is_incremental() && snapshot.taken() > after)
-            .collect())
+        Ok(taken_after(self.all_snapshots(Some(subvol))?, false, after))
     }
 
     /// Returns all incremental snapshots of the specified subvolume of this node
@@ -513,11 +1218,7 @@ impl LocalNode {
         subvol: String,
         after: NaiveDateTime,
     ) -> Result<Vec<Snapshot>, LocalNodeError> {
-        Ok(self
-            .all_snapshots(Some(subvol))?
-            .into_iter()
-            .filter(|snapshot| snapshot.is_incremental() && snapshot.taken() > after)
-            .collect())
+        Ok(taken_after(self.all_snapshots(Some(subvol))?, true, after))
     }
 
     /// Returns the [`Snapshot`] to use as the parent of another *incremental* [`Snapshot`]
@@ -547,212 +1248,1003 @@ impl LocalNode {
     }
 
     /// Returns a new [`crate::stream::SnapshotStream`]
-    /// wrapping the provided [`Snapshot`].
+    /// wrapping the provided [`Snapshot`] as a btrfs send stream
+    /// of the specified version, see [`LocalNode::send_protocol`].
+    /// The stream is compressed if `compress` is set and compression
+    /// is enabled for the subvolume, see [`LocalNode::compression_level`].
     /// It is an error to call this method on a foreign [`Snapshot`].
     pub fn send_snapshot(
         &self,
         snapshot: &Snapshot,
-    ) -> Result<SnapshotStream<BufReader<ChildStdout>>, LocalNodeError> {
-        let src = snapshot.snapshot_path(self.mode);
+        protocol: u32,
+        compress: bool,
+    ) -> Result<SnapshotStream<BufReader<SendProcess>>, LocalNodeError> {
+        let src = snapshot.snapshot_path(&self.layout);
 
         let mut cmd = Command::new("btrfs");
-        let cmd = cmd.arg("send").arg("--compressed-data");
+        let cmd = cmd.arg("send");
+        // Version 1 is the default, older btrfs-progs don't know the option.
+        let cmd = if protocol >= 2 {
+            cmd.arg("--proto")
+                .arg(protocol.to_string())
+                .arg("--compressed-data")
+        } else {
+            cmd
+        };
         let cmd = if snapshot.is_incremental() {
             cmd.arg("-p")
-                .arg(self.parent_of(snapshot)?.snapshot_path(self.mode))
+                .arg(self.parent_of(snapshot)?.snapshot_path(&self.layout))
         } else {
             cmd
         }
         .arg(src)
         .stdin(Stdio::null())
         .stdout(Stdio::piped())
-        .stderr(Stdio::null())
+        .stderr(Stdio::piped())
         .spawn()?;
 
         SnapshotStream::new(
-            BufReader::with_capacity(
-                2 * CHUNKSIZE,
-                cmd.stdout.ok_or(LocalNodeError::NoBtrfsOutput)?,
-            ),
-            &self.config().passphrase,
+            BufReader::with_capacity(PIPE_BUFSIZE, SendProcess::new(cmd)?),
+            self.passphrase()?,
+            snapshot,
+            compress
+                .then(|| self.compression_level(snapshot.subvol()))
+                .flatten(),
         )
     }
 
-    /// Returns a new [`crate::stream::SnapshotStream`]
-    /// wrapping the latest full snapshot of the specified subvolume.
-    pub fn export_full(
-        &self,
-        subvol: String,
-    ) -> Result<SnapshotStream<BufReader<ChildStdout>>, LocalNodeError> {
-        self.send_snapshot(&self.latest_snapshot_full(subvol)?)
+    /// Estimates the size of a new full snapshot of the specified subvolume
+    /// from its current disk usage.
+    pub fn estimate_subvol_size(&self, subvol: &str) -> Result<SizeEstimate, LocalNodeError> {
+        if !self.owns_subvol(subvol) {
+            return Err(LocalNodeError::ForeignSubvolume(subvol.to_string()));
+        }
+
+        Ok(SizeEstimate {
+            bytes: disk_usage(self.subvol_path(subvol))?,
+            method: EstimateMethod::DiskUsage,
+        })
     }
 
-    /// Returns a new [`io::Read`] wrapping the provided snapshot or backup.
-    /// Performs encryption if exporting a local [`Snapshot`].
-    pub fn export(&self, snapshot: &Snapshot) -> Result<Box<dyn BufRead + Send>, LocalNodeError> {
-        if self.owns_backup(snapshot) {
-            Ok(Box::new(self.send_snapshot(snapshot)?))
+    /// Estimates the amount of data transmitted when pushing the specified
+    /// [`Snapshot`] or backup. Estimates are cached across runs
+    /// since snapshots and backups never change, see [`StorageLayout::estimate_cache`].
+    pub fn estimate_send_size(&self, snapshot: &Snapshot) -> Result<SizeEstimate, LocalNodeError> {
+        if let Some(estimate) = self.estimates.lock().unwrap().get(snapshot) {
+            return Ok(*estimate);
+        }
+
+        let estimate = if !self.owns_backup(snapshot) {
+            SizeEstimate {
+                bytes: fs::metadata(self.locate_backup(snapshot))?.len(),
+                method: EstimateMethod::StoredSize,
+            }
+        } else if snapshot.is_incremental() {
+            SizeEstimate {
+                bytes: self.extent_lengths(snapshot)?,
+                method: EstimateMethod::NoDataSend,
+            }
         } else {
-            Ok(Box::new(BufReader::with_capacity(
-                2 * CHUNKSIZE,
-                File::open(snapshot.backup_path(self.mode))?,
-            )))
+            SizeEstimate {
+                bytes: disk_usage(snapshot.snapshot_path(&self.layout))?,
+                method: EstimateMethod::DiskUsage,
+            }
+        };
+
+        let mut estimates = self.estimates.lock().unwrap();
+        estimates.insert(snapshot.clone(), estimate);
+
+        if let Err(e) = self.save_estimates(&estimates) {
+            output::log(
+                Level::Warn,
+                &[],
+                format_args!("Cannot cache size estimate of {}: {}", snapshot, e),
+            );
         }
+
+        Ok(estimate)
     }
 
-    /// Writes the provided [`crate::stream::SnapshotStream`]
-    /// to the specified local backup.
-    pub fn backup<B: BufRead>(
+    /// Loads the cached size estimates of the snapshots and backups that still exist.
+    /// A missing or unreadable cache is treated as empty.
+    fn load_estimates(&self) -> HashMap<Snapshot, SizeEstimate> {
+        let Ok(json) = fs::read(self.layout.estimate_cache()) else {
+            return HashMap::new();
+        };
+        let Ok(estimates) = serde_json::from_slice::<BTreeMap<String, SizeEstimate>>(&json) else {
+            return HashMap::new();
+        };
+
+        estimates
+            .into_iter()
+            .filter_map(|(snapshot, estimate)| {
+                Some((Snapshot::try_from(snapshot.as_str()).ok()?, estimate))
+            })
+            .filter(|(snapshot, _)| {
+                snapshot.snapshot_path(&self.layout).exists()
+                    || self.locate_backup(snapshot).exists()
+            })
+            .collect()
+    }
+
+    /// Writes the specified size estimates to the cache.
+    /// It is written under a temporary name first so that it is never incomplete.
+    fn save_estimates(
         &self,
-        mut stream: SnapshotStream<B>,
-        snapshot: &Snapshot,
+        estimates: &HashMap<Snapshot, SizeEstimate>,
     ) -> Result<(), LocalNodeError> {
-        let dst = snapshot.backup_path(self.mode);
-        let mut file = BufWriter::with_capacity(2 * CHUNKSIZE, File::create(dst)?);
+        let estimates = estimates
+            .iter()
+            .map(|(snapshot, estimate)| (snapshot.to_string(), estimate))
+            .collect::<BTreeMap<_, _>>();
+
+        let path = self.layout.estimate_cache();
+        let tmp = path.with_extension("json.part");
+
+        fs::write(&tmp, serde_json::to_vec_pretty(&estimates)?)?;
+        fs::rename(tmp, path)?;
 
-        io::copy(&mut stream, &mut file)?;
         Ok(())
     }
 
-    /// Returns all backups that have been synchronized to this node
-    /// of the specified [`Volume`] or all volumes.
-    pub fn all_backups(&self, volume: Option<&Volume>) -> Result<Vec<Snapshot>, LocalNodeError> {
-        let mut all_backups = Vec::new();
+    /// Sums up the lengths of the extents an incremental send stream
+    /// of the specified [`Snapshot`] would contain without transferring any data.
+    fn extent_lengths(&self, snapshot: &Snapshot) -> Result<u64, LocalNodeError> {
+        let mut send = Command::new("btrfs")
+            .arg("send")
+            .arg("--no-data")
+            .arg("-p")
+            .arg(self.parent_of(snapshot)?.snapshot_path(&self.layout))
+            .arg(snapshot.snapshot_path(&self.layout))
+            .stdin(Stdio::null())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::null())
+            .spawn()?;
 
-        let backups = fs::read_dir(self.mode.backup_dir())?;
-        for backup in backups {
-            let backup = backup?;
+        let bytes = send
+            .stdout
+            .take()
+            .ok_or(LocalNodeError::NoBtrfsOutput)
+            .and_then(dump_extent_lengths);
 
-            if backup.path().extension() != Some(OsStr::new("part")) {
-                let snapshot = Snapshot::try_from(&*backup.path())?;
+        // Reap the process on every path, it is stuck writing if the dump ended early.
+        if bytes.is_err() {
+            let _ = send.kill();
+        }
+        let status = send.wait()?;
 
-                match volume {
-                    Some(volume) if !snapshot.is_of_volume(volume) => {}
-                    _ => all_backups.push(snapshot),
-                }
-            }
+        let bytes = bytes?;
+        if !status.success() {
+            return Err(LocalNodeError::BtrfsCmd);
         }
 
-        Ok(all_backups)
+        Ok(bytes)
     }
 
-    /// Returns the latest locally known full backup of the specified [`Volume`].
-    pub fn latest_backup_full(&self, volume: Volume) -> Result<Snapshot, LocalNodeError> {
-        self.all_backups(Some(&volume))?
-            .into_iter()
-            .filter(|backup| !backup.is_incremental())
-            .max_by_key(|backup| backup.taken())
-            .ok_or(LocalNodeError::NoFullBackup(volume))
+    /// Returns the minimum age of incomplete backups that are cleaned up.
+    pub fn partial_max_age(&self) -> Duration {
+        self.config()
+            .partial_max_age
+            .map(Duration::from)
+            .unwrap_or(DEFAULT_PARTIAL_MAX_AGE)
     }
 
-    /// Returns the latest locally known incremental backup of the specified [`Volume`].
-    pub fn latest_backup_incremental(&self, volume: Volume) -> Result<Snapshot, LocalNodeError> {
-        self.all_backups(Some(&volume))?
-            .into_iter()
-            .filter(|backup| backup.is_incremental())
-            .max_by_key(|backup| backup.taken())
-            .ok_or(LocalNodeError::NoIncrementalBackup(volume))
+    /// Returns the preferred size of data chunks sent over the network
+    /// clamped to the range supported by the protocol.
+    pub fn chunk_size(&self) -> usize {
+        self.config()
+            .chunk_size
+            .map(|chunk_size| chunk_size.as_usize())
+            .unwrap_or(DEFAULT_CHUNK_SIZE)
+            .clamp(MIN_CHUNK_SIZE, MAX_CHUNK_SIZE)
     }
 
-    /// Returns all locally known full backups of the specified [`Volume`]
-    /// taken after the provided timestamp.
-    pub fn backup_full_after(
-        &self,
-        volume: Volume,
-        after: NaiveDateTime,
-    ) -> Result<Vec<Snapshot>, LocalNodeError> {
-        Ok(self
-            .all_backups(Some(&volume))?
-            .into_iter()
-            .filter(|backup| !backup.is_incremental() && backup.taken() > after)
-            .collect())
+    /// Returns the TCP keepalive settings of network connections.
+    pub fn keepalive(&self) -> Keepalive {
+        Keepalive {
+            idle: self
+                .config()
+                .keepalive_idle
+                .map(Duration::from)
+                .unwrap_or(DEFAULT_KEEPALIVE_IDLE),
+            interval: self
+                .config()
+                .keepalive_interval
+                .map(Duration::from)
+                .unwrap_or(DEFAULT_KEEPALIVE_INTERVAL),
+        }
     }
 
-    /// Returns all locally known incremental backups of the specified [`Volume`]
-    /// taken after the provided timestamp.
-    pub fn backup_incremental_after(
-        &self,
-        volume: Volume,
-        after: NaiveDateTime,
-    ) -> Result<Vec<Snapshot>, LocalNodeError> {
-        Ok(self
-            .all_backups(Some(&volume))?
-            .into_iter()
-            .filter(|backup| backup.is_incremental() && backup.taken() > after)
-            .collect())
+    /// Reports whether sessions over Unix domain sockets may skip encryption.
+    pub fn unix_plaintext(&self) -> bool {
+        self.config().unix_plaintext.unwrap_or(false)
     }
 
-    /// Returns all locally know full backups or snapshots of the specified volume
-    /// taken after the provided timestamp. Checks the correct location
-    /// depending on whether the `LocalNode` owns the [`Volume`].
-    pub fn all_full_after(
-        &self,
-        volume: Volume,
-        after: NaiveDateTime,
-    ) -> Result<Vec<Snapshot>, LocalNodeError> {
-        if volume.node_name() == self.name() {
-            self.snapshot_full_after(volume.subvol().to_string(), after)
-        } else {
-            self.backup_full_after(volume, after)
-        }
+    /// Returns the time without data from the remote node
+    /// after which a transmission is considered stalled.
+    pub fn stall_timeout(&self) -> Duration {
+        self.config()
+            .stall_timeout
+            .map(Duration::from)
+            .unwrap_or(DEFAULT_STALL_TIMEOUT)
     }
 
-    /// Returns all locally know incremental backups or snapshots of the specified volume
-    /// taken after the provided timestamp. Checks the correct location
-    /// depending on whether the `LocalNode` owns the [`Volume`].
-    pub fn all_incremental_after(
-        &self,
-        volume: Volume,
-        after: NaiveDateTime,
-    ) -> Result<Vec<Snapshot>, LocalNodeError> {
-        if volume.node_name() == self.name() {
-            self.snapshot_incremental_after(volume.subvol().to_string(), after)
-        } else {
-            self.backup_incremental_after(volume, after)
-        }
+    /// Returns the time without sending anything after which
+    /// a heartbeat is sent during data synchronization.
+    pub fn heartbeat_interval(&self) -> Duration {
+        self.config()
+            .heartbeat_interval
+            .map(Duration::from)
+            .unwrap_or(DEFAULT_HEARTBEAT_INTERVAL)
     }
 
-    /// Returns the latest full snapshot or backup of the specified [`Volume`].
-    /// Checks the correct location depending on whether the `LocalNode` owns the [`Volume`].
-    pub fn latest_full(&self, volume: Volume) -> Result<Snapshot, LocalNodeError> {
-        if volume.node_name() == self.name() {
-            self.latest_snapshot_full(volume.subvol().to_string())
-        } else {
-            self.latest_backup_full(volume)
-        }
+    /// Returns the amount of data sent under a single session key in bytes.
+    pub fn rekey_interval(&self) -> u64 {
+        self.config()
+            .rekey_interval
+            .map(|rekey_interval| rekey_interval.0)
+            .unwrap_or(DEFAULT_REKEY_INTERVAL)
     }
 
-    /// Returns the latest incremental snapshot or backup of the specified [`Volume`].
-    /// Checks the correct location depending on whether the `LocalNode` owns the [`Volume`].
-    pub fn latest_incremental(&self, volume: Volume) -> Result<Snapshot, LocalNodeError> {
-        if volume.node_name() == self.name() {
-            self.latest_snapshot_incremental(volume.subvol().to_string())
-        } else {
-            self.latest_backup_incremental(volume)
+    /// Returns the free space to keep on the file system of the backup directory in bytes.
+    pub fn space_reserve(&self) -> u64 {
+        self.config()
+            .space_reserve
+            .map(|space_reserve| space_reserve.0)
+            .unwrap_or(DEFAULT_SPACE_RESERVE)
+    }
+
+    /// Ensures that receiving a backup of the specified expected length, if known,
+    /// leaves at least the configured reserve of free space in the backup directory.
+    pub fn check_space(&self, expected_len: Option<u64>) -> Result<(), LocalNodeError> {
+        let available = system::available_space(self.layout.backup_dir())?;
+        let required = self
+            .space_reserve()
+            .saturating_add(expected_len.unwrap_or_default());
+
+        if available < required {
+            return Err(LocalNodeError::InsufficientSpace(available, required));
         }
+
+        Ok(())
     }
 
-    /// Returns the latest locally known full and incremental backup timestamps
-    /// in the form of a [`LatestSnapshots`] data structure.
+    /// Returns the btrfs send stream versions supported by the local machine.
+    /// They are probed once per `LocalNode`.
+    pub fn send_support(&self) -> SendSupport {
+        *self.send_support.get_or_init(system::probe_send_support)
+    }
+
+    /// Returns the preferred btrfs send stream version of new backups,
+    /// limited to the versions the local machine can both send and receive
+    /// so that it can restore the backups.
+    pub fn send_protocol(&self) -> u32 {
+        self.send_support().preferred(self.config().send_protocol)
+    }
+
+    /// Returns the zstd level new backups of the specified subvolume
+    /// are compressed at, or `None` if they aren't compressed.
+    pub fn compression_level(&self, subvol: &str) -> Option<i32> {
+        let compression_level = self.config().compression_level;
+
+        match self
+            .config()
+            .subvol(subvol)
+            .and_then(|subvol_config| subvol_config.compress)
+        {
+            Some(true) => Some(compression_level.unwrap_or(DEFAULT_COMPRESSION_LEVEL)),
+            Some(false) => None,
+            None => compression_level,
+        }
+    }
+
+    /// Returns the time received snapshots may be dated ahead of the local clock.
+    pub fn max_clock_skew(&self) -> Duration {
+        self.config()
+            .max_clock_skew
+            .map(Duration::from)
+            .unwrap_or(DEFAULT_MAX_CLOCK_SKEW)
+    }
+
+    /// Returns the age beyond which received snapshots are implausible, if limited.
+    pub fn max_snapshot_age(&self) -> Option<Duration> {
+        self.config().max_snapshot_age.map(Duration::from)
+    }
+
+    /// Reports whether the timestamp of the specified [`Snapshot`] is plausible
+    /// at the specified time (UTC), i.e. neither dated further ahead than
+    /// the maximum clock skew nor older than the maximum snapshot age.
+    pub fn is_plausible(&self, snapshot: &Snapshot, now: NaiveDateTime) -> bool {
+        is_plausible(
+            snapshot.taken(),
+            now,
+            self.max_clock_skew(),
+            self.max_snapshot_age(),
+        )
+    }
+
+    /// Deletes the incomplete backups left behind by failed transmissions
+    /// that haven't been modified for at least `max_age`.
+    /// Paths contained in `in_use` are never deleted.
+    pub fn clean_partials(
+        &self,
+        max_age: Duration,
+        in_use: &HashSet<PathBuf>,
+    ) -> Result<Reclaimed, LocalNodeError> {
+        let mut reclaimed = Reclaimed::default();
+
+        for path in read_partials(self.layout.backup_dir())? {
+            if in_use.contains(&path) {
+                continue;
+            }
+
+            let metadata = fs::metadata(&path)?;
+            if metadata.modified()?.elapsed().unwrap_or_default() < max_age {
+                continue;
+            }
+
+            fs::remove_file(&path)?;
+
+            reclaimed.files += 1;
+            reclaimed.bytes += metadata.len();
+        }
+
+        Ok(reclaimed)
+    }
+
+    /// Returns a new [`crate::stream::SnapshotStream`]
+    /// wrapping the latest full snapshot of the specified subvolume.
+    pub fn export_full(
+        &self,
+        subvol: String,
+    ) -> Result<SnapshotStream<BufReader<SendProcess>>, LocalNodeError> {
+        self.send_snapshot(
+            &self.latest_snapshot_full(subvol)?,
+            self.send_protocol(),
+            true,
+        )
+    }
+
+    /// Returns a new [`io::Read`] wrapping the provided snapshot or backup.
+    /// Performs encryption if exporting a local [`Snapshot`],
+    /// which is sent using the specified btrfs send stream version
+    /// and compressed if permitted, see [`LocalNode::send_snapshot`].
+    /// Backups are exported as they were stored.
+    pub fn export(
+        &self,
+        snapshot: &Snapshot,
+        protocol: u32,
+        compress: bool,
+    ) -> Result<Box<dyn BufRead + Send>, LocalNodeError> {
+        if self.owns_backup(snapshot) {
+            Ok(Box::new(self.send_snapshot(snapshot, protocol, compress)?))
+        } else {
+            Ok(Box::new(self.read_backup(snapshot)?))
+        }
+    }
+
+    /// Returns a new [`io::Read`] wrapping the provided backup as it was stored,
+    /// even if it is a backup of an own volume.
+    pub fn read_backup(&self, backup: &Snapshot) -> Result<BufReader<File>, LocalNodeError> {
+        Ok(BufReader::with_capacity(
+            PIPE_BUFSIZE,
+            File::open(self.locate_backup(backup))?,
+        ))
+    }
+
+    /// Writes the provided [`crate::stream::SnapshotStream`]
+    /// to the specified local backup. The backup only becomes visible
+    /// once it has been written completely.
+    ///
+    /// Fails if the backup already exists.
+    pub fn backup<B: BufRead>(
+        &self,
+        mut stream: SnapshotStream<B>,
+        snapshot: &Snapshot,
+    ) -> Result<(), LocalNodeError> {
+        let mut w = self.receive_backup(snapshot)?;
+
+        if let Err(e) = io::copy(&mut stream, &mut w).and_then(|_| w.flush()) {
+            drop(w);
+            self.discard_backup(snapshot)?;
+
+            return Err(e.into());
+        }
+
+        drop(w);
+        self.commit_backup(snapshot)
+    }
+
+    /// Creates the temporary streaming location of the specified backup
+    /// and returns a buffered writer to it. The buffer capacity is taken
+    /// from the configuration. Use [`LocalNode::commit_backup`] once the
+    /// transmission is complete.
+    ///
+    /// Fails if the backup has already been fully received.
+    pub fn receive_backup(&self, snapshot: &Snapshot) -> Result<BufWriter<File>, LocalNodeError> {
+        self.check_name(snapshot)?;
+
+        if self.locate_backup(snapshot).exists() {
+            return Err(LocalNodeError::SnapshotExists(snapshot.clone()));
+        }
+
+        fs::create_dir_all(snapshot.volume_dir(&self.layout))?;
+
+        let file = File::create(snapshot.streaming_path(&self.layout))?;
+
+        Ok(BufWriter::with_capacity(
+            self.config()
+                .rx_bufsize
+                .map(|rx_bufsize| rx_bufsize.as_usize())
+                .unwrap_or(RX_BUFSIZE),
+            file,
+        ))
+    }
+
+    /// Marks the specified backup as fully received by moving it
+    /// from its streaming location to its final location.
+    /// Its [`Manifest`] is written first so that it is never missing
+    /// from a committed backup.
+    ///
+    /// Fails with [`LocalNodeError::MalformedStream`] if the received stream
+    /// isn't well-formed, see [`stream::validate_encrypted_stream`].
+    /// The incomplete backup is kept in that case.
+    pub fn commit_backup(&self, snapshot: &Snapshot) -> Result<(), LocalNodeError> {
+        let summary = stream::validate_encrypted_stream(snapshot.streaming_path(&self.layout))?;
+        self.commit_validated_backup(snapshot, &summary)
+    }
+
+    /// Like [`LocalNode::commit_backup`], but uses the [`StreamSummary`]
+    /// of a previous [`stream::validate_encrypted_stream`] call
+    /// instead of reading the stream again.
+    pub fn commit_validated_backup(
+        &self,
+        snapshot: &Snapshot,
+        summary: &StreamSummary,
+    ) -> Result<(), LocalNodeError> {
+        let streaming_path = snapshot.streaming_path(&self.layout);
+        let backup_path = snapshot.backup_path(&self.layout);
+
+        let manifest = Manifest::new(snapshot, summary.len, &summary.digest);
+        write_manifest(paths::manifest_path(&backup_path), &manifest)?;
+
+        fs::rename(streaming_path, backup_path)?;
+
+        Ok(())
+    }
+
+    /// Returns the [`Manifest`] of the specified backup
+    /// or `None` if it was written by a version that didn't create manifests.
+    pub fn manifest(&self, snapshot: &Snapshot) -> Result<Option<Manifest>, LocalNodeError> {
+        match fs::read(paths::manifest_path(self.locate_backup(snapshot))) {
+            Ok(manifest) => Ok(Some(serde_json::from_slice(&manifest)?)),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// Checks the length and SHA-256 digest of the encrypted stream
+    /// of the specified backup against its [`Manifest`] without decrypting it.
+    /// Returns `None` if the backup doesn't have a manifest.
+    pub fn verify_backup(&self, snapshot: &Snapshot) -> Result<Option<bool>, LocalNodeError> {
+        let Some(manifest) = self.manifest(snapshot)? else {
+            return Ok(None);
+        };
+
+        let path = self.locate_backup(snapshot);
+        let size = fs::metadata(&path)?.len();
+
+        // A length mismatch is conclusive, skip hashing.
+        if size != manifest.size {
+            return Ok(Some(false));
+        }
+
+        Ok(Some(manifest.matches(size, &digest_file(&path)?)))
+    }
+
+    /// Re-encrypts the specified backup of an own volume, which is encrypted
+    /// using `old_passphrase`, with the current passphrase, e.g. after changing it.
+    /// The new stream is written next to the backup and only replaces it once it has
+    /// been verified to decrypt to as many bytes as the original, so the backup
+    /// stays restorable if the operation fails or is interrupted.
+    /// Returns `false` without doing anything if the backup is already encrypted
+    /// with the current passphrase, allowing interrupted runs to be resumed.
+    pub fn reencrypt_backup(
+        &self,
+        snapshot: &Snapshot,
+        old_passphrase: &str,
+    ) -> Result<bool, LocalNodeError> {
+        if !self.owns_backup(snapshot) {
+            return Err(LocalNodeError::ForeignSubvolume(
+                snapshot.subvol().to_string(),
+            ));
+        }
+
+        let path = self.locate_backup(snapshot);
+        if !path.exists() {
+            return Err(LocalNodeError::NoSuchSnapshot(snapshot.clone()));
+        }
+
+        if stream::is_encrypted_with(File::open(&path)?, self.passphrase()?, snapshot)? {
+            return Ok(false);
+        }
+
+        let tmp_path = path.with_file_name(format!(
+            "{}.reencrypt.part",
+            path.file_name()
+                .expect("backup path has a file name")
+                .to_string_lossy()
+        ));
+
+        let result = self.reencrypt_to(snapshot, &path, &tmp_path, old_passphrase);
+        if let Err(e) = result {
+            let _ = fs::remove_file(&tmp_path);
+            return Err(e);
+        }
+
+        // The manifest is written first so that an interruption leaves
+        // the previous backup with a mismatching manifest, which the next run
+        // replaces along with it, rather than the new one with a stale manifest.
+        let manifest = Manifest::new(
+            snapshot,
+            fs::metadata(&tmp_path)?.len(),
+            &digest_file(&tmp_path)?,
+        );
+        write_manifest(paths::manifest_path(&path), &manifest)?;
+
+        fs::rename(&tmp_path, &path)?;
+
+        Ok(true)
+    }
+
+    /// Decrypts the backup at `path` using `old_passphrase` and encrypts it
+    /// with the current passphrase into `tmp_path`, verifying the result.
+    fn reencrypt_to(
+        &self,
+        snapshot: &Snapshot,
+        path: &Path,
+        tmp_path: &Path,
+        old_passphrase: &str,
+    ) -> Result<(), LocalNodeError> {
+        let (pipe_r, pipe_w) = io::pipe()?;
+        let decrypted = AtomicU64::new(0);
+
+        let mut w = BufWriter::with_capacity(PIPE_BUFSIZE, File::create(tmp_path)?);
+
+        thread::scope(|s| {
+            let decrypted = &decrypted;
+            let decrypt = s.spawn(move || {
+                let mut recovery = RecoveryStream::new(
+                    CountingWriter::new(pipe_w, decrypted),
+                    old_passphrase,
+                    snapshot.clone(),
+                );
+
+                io::copy(&mut File::open(path)?, &mut recovery)?;
+                recovery.close()
+            });
+
+            let encrypted = SnapshotStream::new(
+                BufReader::with_capacity(PIPE_BUFSIZE, pipe_r),
+                self.passphrase()?,
+                snapshot,
+                self.compression_level(snapshot.subvol()),
+            )
+            .and_then(|mut stream| Ok(io::copy(&mut stream, &mut w)?));
+
+            // A failed decryption ends the pipe early, which looks like a complete stream.
+            // Dropping the reader above unblocks the decryption if encryption failed.
+            decrypt.join().expect("decryption thread panicked")?;
+            encrypted
+        })?;
+
+        w.into_inner().map_err(|e| e.into_error())?.sync_all()?;
+
+        let verified = AtomicU64::new(0);
+        let mut recovery = RecoveryStream::new(
+            CountingWriter::new(io::sink(), &verified),
+            self.passphrase()?,
+            snapshot.clone(),
+        );
+        io::copy(&mut File::open(tmp_path)?, &mut recovery)?;
+        recovery.close()?;
+        drop(recovery);
+
+        let decrypted = decrypted.load(atomic::Ordering::SeqCst);
+        let verified = verified.load(atomic::Ordering::SeqCst);
+        if verified != decrypted {
+            return Err(LocalNodeError::ReencryptionMismatch(
+                snapshot.clone(),
+                decrypted,
+                verified,
+            ));
+        }
+
+        Ok(())
+    }
+
+    /// Deletes the incomplete backup at the streaming location of the specified backup,
+    /// e.g. because the sender failed to produce it.
+    pub fn discard_backup(&self, snapshot: &Snapshot) -> Result<(), LocalNodeError> {
+        fs::remove_file(snapshot.streaming_path(&self.layout))?;
+        Ok(())
+    }
+
+    /// Stores the encrypted stream read from the provided [`Read`]
+    /// as the specified backup. The backup only becomes visible
+    /// once it has been written completely.
+    ///
+    /// It is an error to import a backup of a subvolume owned by the `LocalNode`
+    /// or to overwrite an existing backup.
+    pub fn import_backup<R: Read>(
+        &self,
+        mut r: R,
+        snapshot: &Snapshot,
+    ) -> Result<(), LocalNodeError> {
+        if self.owns_backup(snapshot) {
+            return Err(LocalNodeError::OwnSnapshot(snapshot.clone()));
+        }
+
+        let mut w = self.receive_backup(snapshot)?;
+        io::copy(&mut r, &mut w)?;
+        w.flush()?;
+        drop(w);
+
+        self.commit_backup(snapshot)
+    }
+
+    /// Returns all backups that have been synchronized to this node
+    /// of the specified [`Volume`] or all volumes.
+    /// Archived backups are included.
+    pub fn all_backups(&self, volume: Option<&Volume>) -> Result<Vec<Snapshot>, LocalNodeError> {
+        let mut backups = read_backups(self.layout.backup_dir(), volume)?;
+
+        if let Some(archive_dir) = self.archive_dir().filter(|dir| dir.exists()) {
+            // Interrupted archivals can leave a backup in both tiers.
+            let known: HashSet<_> = backups.iter().cloned().collect();
+
+            backups.extend(
+                read_backups(archive_dir, volume)?
+                    .into_iter()
+                    .filter(|backup| !known.contains(backup)),
+            );
+        }
+
+        Ok(backups)
+    }
+
+    /// Returns all backups that have been synchronized to this node
+    /// of the specified [`Volume`] or all volumes along with their storage [`Tier`].
+    pub fn all_backup_tiers(
+        &self,
+        volume: Option<&Volume>,
+    ) -> Result<Vec<(Snapshot, Tier)>, LocalNodeError> {
+        Ok(self
+            .all_backups(volume)?
+            .into_iter()
+            .filter_map(|backup| {
+                let tier = self.backup_tier(&backup)?;
+                Some((backup, tier))
+            })
+            .collect())
+    }
+
+    /// Returns the directory older backups are moved to, if configured.
+    pub fn archive_dir(&self) -> Option<&Path> {
+        self.layout.archive_dir()
+    }
+
+    /// Returns the [`Tier`] the specified backup is currently stored in
+    /// or `None` if it doesn't exist.
+    pub fn backup_tier(&self, snapshot: &Snapshot) -> Option<Tier> {
+        if snapshot.backup_path(&self.layout).exists()
+            || snapshot.legacy_backup_path(&self.layout).exists()
+        {
+            Some(Tier::Primary)
+        } else if snapshot
+            .archive_path(&self.layout)
+            .is_some_and(|archive_path| archive_path.exists())
+        {
+            Some(Tier::Archive)
+        } else {
+            None
+        }
+    }
+
+    /// Returns the backups in the backup directory that are due for archival
+    /// according to the configured policy, oldest first.
+    /// Nothing is due if no archive directory is configured.
+    pub fn backups_to_archive(&self) -> Result<Vec<Snapshot>, LocalNodeError> {
+        if self.archive_dir().is_none() {
+            return Ok(Vec::default());
+        }
+
+        let now = Utc::now().naive_utc();
+        let archive_after = self.config().archive_after.map(Duration::from);
+        let archive_keep = self.config().archive_keep;
+
+        let mut volumes: HashMap<Volume, Vec<Snapshot>> = HashMap::new();
+        for backup in read_backups(self.layout.backup_dir(), None)? {
+            volumes.entry(backup.volume()).or_default().push(backup);
+        }
+
+        let mut due = Vec::new();
+        for mut backups in volumes.into_values() {
+            backups.sort_unstable_by_key(|backup| Reverse(backup.taken()));
+
+            for (i, backup) in backups.into_iter().enumerate() {
+                let age = (now - backup.taken()).to_std().unwrap_or_default();
+
+                if archive_after.is_some_and(|archive_after| age >= archive_after)
+                    || archive_keep.is_some_and(|archive_keep| i >= archive_keep)
+                {
+                    due.push(backup);
+                }
+            }
+        }
+
+        due.sort_unstable_by_key(|backup| backup.taken());
+        Ok(due)
+    }
+
+    /// Moves the specified backup from the backup directory to the archive directory.
+    ///
+    /// The copy is written under a temporary name, synced to disk and verified
+    /// before it is committed under its final name. Only then is the original removed,
+    /// so the backup is visible in at least one tier at all times.
+    /// Copies within a single btrfs file system share their extents.
+    pub fn archive_backup(&self, snapshot: &Snapshot) -> Result<(), LocalNodeError> {
+        let dst = snapshot
+            .archive_path(&self.layout)
+            .ok_or(LocalNodeError::NoArchive)?;
+
+        let src = [
+            snapshot.backup_path(&self.layout),
+            snapshot.legacy_backup_path(&self.layout),
+        ]
+        .into_iter()
+        .find(|path| path.exists())
+        .ok_or_else(|| LocalNodeError::NoSuchSnapshot(snapshot.clone()))?;
+
+        let dst_dir = dst.parent().expect("archive path has a volume directory");
+
+        fs::create_dir_all(dst_dir)?;
+
+        // The copy has already been committed by an interrupted archival.
+        if dst.exists() {
+            if digest_file(&src)? != digest_file(&dst)? {
+                return Err(LocalNodeError::ArchiveMismatch(snapshot.clone()));
+            }
+        } else {
+            let tmp = dst.with_file_name(format!("{snapshot}.part"));
+
+            let mut file = File::create(&tmp)?;
+            io::copy(&mut File::open(&src)?, &mut file)?;
+            file.sync_all()?;
+            drop(file);
+
+            if digest_file(&src)? != digest_file(&tmp)? {
+                let _ = fs::remove_file(&tmp);
+                return Err(LocalNodeError::ArchiveMismatch(snapshot.clone()));
+            }
+
+            fs::rename(&tmp, &dst)?;
+            File::open(dst_dir)?.sync_all()?;
+        }
+
+        let src_manifest = paths::manifest_path(&src);
+        if src_manifest.exists() {
+            fs::copy(&src_manifest, paths::manifest_path(&dst))?;
+        }
+
+        fs::remove_file(src)?;
+        let _ = fs::remove_file(src_manifest);
+        Ok(())
+    }
+
+    /// Moves all backups stored in the flat layout used by previous versions
+    /// to their per-node, per-subvolume directories.
+    /// Returns the number of migrated backups.
+    pub fn migrate_layout(&self) -> Result<usize, LocalNodeError> {
+        let mut migrated = 0;
+
+        for entry in fs::read_dir(self.layout.backup_dir())? {
+            let entry = entry?;
+
+            if entry.file_type()?.is_dir() {
+                continue;
+            }
+
+            let path = entry.path();
+            let is_partial = path.extension() == Some(OsStr::new("part"));
+
+            // Leave unrecognized files in place, `hbak doctor` reports them.
+            let Ok(snapshot) = Snapshot::try_from(&*if is_partial {
+                path.with_extension("")
+            } else {
+                path.clone()
+            }) else {
+                continue;
+            };
+
+            fs::create_dir_all(snapshot.volume_dir(&self.layout))?;
+
+            if is_partial {
+                fs::rename(path, snapshot.streaming_path(&self.layout))?;
+            } else {
+                fs::rename(path, snapshot.backup_path(&self.layout))?;
+                migrated += 1;
+            }
+        }
+
+        Ok(migrated)
+    }
+
+    /// Returns the latest locally known full backup of the specified [`Volume`].
+    pub fn latest_backup_full(&self, volume: Volume) -> Result<Snapshot, LocalNodeError> {
+        latest_of(&self.all_backups(Some(&volume))?, false, None)
+            .ok_or(LocalNodeError::NoFullBackup(volume))
+    }
+
+    /// Returns the latest locally known incremental backup of the specified [`Volume`].
+    pub fn latest_backup_incremental(&self, volume: Volume) -> Result<Snapshot, LocalNodeError> {
+        latest_of(&self.all_backups(Some(&volume))?, true, None)
+            .ok_or(LocalNodeError::NoIncrementalBackup(volume))
+    }
+
+    /// Returns all locally known full backups of the specified [`Volume`]
+    /// taken after the provided timestamp.
+    pub fn backup_full_after(
+        &self,
+        volume: Volume,
+        after: NaiveDateTime,
+    ) -> Result<Vec<Snapshot>, LocalNodeError> {
+        Ok(taken_after(self.all_backups(Some(&volume))?, false, after))
+    }
+
+    /// Returns all locally known incremental backups of the specified [`Volume`]
+    /// taken after the provided timestamp.
+    pub fn backup_incremental_after(
+        &self,
+        volume: Volume,
+        after: NaiveDateTime,
+    ) -> Result<Vec<Snapshot>, LocalNodeError> {
+        Ok(taken_after(self.all_backups(Some(&volume))?, true, after))
+    }
+
+    /// Returns all locally know full backups or snapshots of the specified volume
+    /// taken after the provided timestamp. Checks the correct location
+    /// depending on whether the `LocalNode` owns the [`Volume`].
+    pub fn all_full_after(
+        &self,
+        volume: Volume,
+        after: NaiveDateTime,
+    ) -> Result<Vec<Snapshot>, LocalNodeError> {
+        if volume.node_name() == self.name() {
+            self.snapshot_full_after(volume.subvol().to_string(), after)
+        } else {
+            self.backup_full_after(volume, after)
+        }
+    }
+
+    /// Returns all locally know incremental backups or snapshots of the specified volume
+    /// taken after the provided timestamp. Checks the correct location
+    /// depending on whether the `LocalNode` owns the [`Volume`].
+    pub fn all_incremental_after(
+        &self,
+        volume: Volume,
+        after: NaiveDateTime,
+    ) -> Result<Vec<Snapshot>, LocalNodeError> {
+        if volume.node_name() == self.name() {
+            self.snapshot_incremental_after(volume.subvol().to_string(), after)
+        } else {
+            self.backup_incremental_after(volume, after)
+        }
+    }
+
+    /// Returns the latest full snapshot or backup of the specified [`Volume`].
+    /// Checks the correct location depending on whether the `LocalNode` owns the [`Volume`].
+    pub fn latest_full(&self, volume: Volume) -> Result<Snapshot, LocalNodeError> {
+        if volume.node_name() == self.name() {
+            self.latest_snapshot_full(volume.subvol().to_string())
+        } else {
+            self.latest_backup_full(volume)
+        }
+    }
+
+    /// Returns the latest incremental snapshot or backup of the specified [`Volume`].
+    /// Checks the correct location depending on whether the `LocalNode` owns the [`Volume`].
+    pub fn latest_incremental(&self, volume: Volume) -> Result<Snapshot, LocalNodeError> {
+        if volume.node_name() == self.name() {
+            self.latest_snapshot_incremental(volume.subvol().to_string())
+        } else {
+            self.latest_backup_incremental(volume)
+        }
+    }
+
+    /// Returns all snapshots or backups of the specified [`Volume`].
+    /// Checks the correct location depending on whether the `LocalNode` owns the [`Volume`].
+    fn all_of(&self, volume: &Volume) -> Result<Vec<Snapshot>, LocalNodeError> {
+        if volume.node_name() == self.name() {
+            self.all_snapshots(Some(volume.subvol().to_string()))
+        } else {
+            self.all_backups(Some(volume))
+        }
+    }
+
+    /// Returns the latest full snapshot or backup of the specified [`Volume`]
+    /// taken at or before the provided timestamp.
+    /// Checks the correct location depending on whether the `LocalNode` owns the [`Volume`].
+    pub fn latest_full_before(
+        &self,
+        volume: Volume,
+        at: NaiveDateTime,
+    ) -> Result<Snapshot, LocalNodeError> {
+        match latest_of(&self.all_of(&volume)?, false, Some(at)) {
+            Some(snapshot) => Ok(snapshot),
+            None if volume.node_name() == self.name() => {
+                Err(LocalNodeError::NoFullSnapshot(volume.subvol().to_string()))
+            }
+            None => Err(LocalNodeError::NoFullBackup(volume)),
+        }
+    }
+
+    /// Returns the latest incremental snapshot or backup of the specified [`Volume`]
+    /// taken at or before the provided timestamp.
+    /// Checks the correct location depending on whether the `LocalNode` owns the [`Volume`].
+    pub fn latest_incremental_before(
+        &self,
+        volume: Volume,
+        at: NaiveDateTime,
+    ) -> Result<Snapshot, LocalNodeError> {
+        match latest_of(&self.all_of(&volume)?, true, Some(at)) {
+            Some(snapshot) => Ok(snapshot),
+            None if volume.node_name() == self.name() => Err(
+                LocalNodeError::NoIncrementalSnapshot(volume.subvol().to_string()),
+            ),
+            None => Err(LocalNodeError::NoIncrementalBackup(volume)),
+        }
+    }
+
+    /// Returns all incremental snapshots or backups of the specified [`Volume`]
+    /// taken after `after` and at or before `not_after`.
+    /// Checks the correct location depending on whether the `LocalNode` owns the [`Volume`].
+    pub fn incrementals_between(
+        &self,
+        volume: Volume,
+        after: NaiveDateTime,
+        not_after: NaiveDateTime,
+    ) -> Result<Vec<Snapshot>, LocalNodeError> {
+        Ok(self
+            .all_of(&volume)?
+            .into_iter()
+            .filter(|snapshot| {
+                snapshot.is_incremental()
+                    && snapshot.taken() > after
+                    && snapshot.taken() <= not_after
+            })
+            .collect())
+    }
+
+    /// Returns the latest locally known full and incremental backup timestamps
+    /// in the form of a [`LatestSnapshots`] data structure.
     pub fn latest_snapshots(&self, volume: Volume) -> Result<LatestSnapshots, LocalNodeError> {
-        Ok(LatestSnapshots {
-            last_full: match self.latest_full(volume.clone()) {
-                Ok(snapshot) => snapshot.taken(),
-                Err(LocalNodeError::NoFullSnapshot(_)) => NaiveDateTime::MIN,
-                Err(LocalNodeError::NoFullBackup(_)) => NaiveDateTime::MIN,
-                Err(e) => return Err(e),
-            },
-            last_incremental: match self.latest_incremental(volume) {
-                Ok(snapshot) => snapshot.taken(),
-                Err(LocalNodeError::NoIncrementalSnapshot(_)) => NaiveDateTime::MIN,
-                Err(LocalNodeError::NoIncrementalBackup(_)) => NaiveDateTime::MIN,
-                Err(e) => return Err(e),
-            },
-        })
+        Ok(LatestSnapshots::of(&self.all_of(&volume)?, None))
+    }
+
+    /// Returns the latest locally known full and incremental backup timestamps
+    /// at or before the provided timestamp in the form of a [`LatestSnapshots`]
+    /// data structure that prohibits sending any later snapshots.
+    pub fn latest_snapshots_at(
+        &self,
+        volume: Volume,
+        at: NaiveDateTime,
+    ) -> Result<LatestSnapshots, LocalNodeError> {
+        Ok(LatestSnapshots::of(&self.all_of(&volume)?, Some(at)))
     }
 
     /// Returns a `btrfs receive` [`Child`] along with a new [`crate::stream::RecoveryStream`]
-    /// restoring the subvolume written to the stream.
+    /// restoring the specified [`Snapshot`] from the stream written to it.
     ///
     /// # Safety
     ///
@@ -760,10 +2252,35 @@ impl LocalNode {
     /// to ensure that all data is restored. Care needs to be taken
     /// that the `RecoveryStream` is dropped beforehand to prevent a deadlock.
     /// Furthermore the [`Child`] should be killed if any errors occur.
-    pub fn recover(
+    /// If the snapshot directory is immutable, it needs to be unlocked
+    /// using [`LocalNode::unlock_snapshots`] until the [`Child`] has completed.
+    ///
+    /// Streams of a btrfs send stream version the local btrfs-progs
+    /// cannot receive are rejected before any data is passed on,
+    /// as are streams whose header identifies a different snapshot.
+    pub fn recover(&self, snapshot: &Snapshot) -> Result<(Child, Receiver<'_>), LocalNodeError> {
+        self.recover_as(snapshot, snapshot)
+    }
+
+    /// Like [`LocalNode::recover`], but stores the restored [`Snapshot`]
+    /// under the identity of `relabelled` instead, e.g. to clone a node
+    /// or to restore a subvolume next to the original one.
+    /// The snapshot is received into a hidden staging directory
+    /// and only moved into place by [`LocalNode::finish_recovery`]
+    /// once the [`Child`] has completed successfully.
+    pub fn recover_as(
         &self,
-    ) -> Result<(Child, RecoveryStream<BufWriter<ChildStdin>, &str>), LocalNodeError> {
-        let dst = self.mode.snapshot_dir();
+        snapshot: &Snapshot,
+        relabelled: &Snapshot,
+    ) -> Result<(Child, Receiver<'_>), LocalNodeError> {
+        let dst = if relabelled == snapshot {
+            self.layout.snapshot_dir().to_path_buf()
+        } else {
+            let staging_dir = self.layout.staging_dir(relabelled);
+            fs::create_dir_all(&staging_dir)?;
+            staging_dir
+        };
+
         let mut cmd = Command::new("btrfs")
             .arg("receive")
             .arg(dst)
@@ -777,12 +2294,38 @@ impl LocalNode {
         Ok((
             cmd,
             RecoveryStream::new(
-                BufWriter::with_capacity(2 * CHUNKSIZE, child_stdin),
-                &self.config().passphrase,
+                SendStreamCheck::new(
+                    BufWriter::with_capacity(PIPE_BUFSIZE, child_stdin),
+                    self.send_support().receive,
+                ),
+                self.passphrase()?,
+                snapshot.clone(),
             ),
         ))
     }
 
+    /// Moves a [`Snapshot`] restored by [`LocalNode::recover_as`]
+    /// from its staging directory to the location of `relabelled`.
+    /// Does nothing if it isn't relabelled.
+    pub fn finish_recovery(
+        &self,
+        snapshot: &Snapshot,
+        relabelled: &Snapshot,
+    ) -> Result<(), LocalNodeError> {
+        if relabelled == snapshot {
+            return Ok(());
+        }
+
+        let staging_dir = self.layout.staging_dir(relabelled);
+        fs::rename(
+            staging_dir.join(snapshot.to_string()),
+            relabelled.snapshot_path(&self.layout),
+        )?;
+        fs::remove_dir(staging_dir)?;
+
+        Ok(())
+    }
+
     /// Restores the latest full or incremental snapshot, whichever is later,
     /// of the specified subvolume. Only uses locally stored snapshots, remote recovery
     /// with the help of [`LocalNode::recover`] may be necessary.
@@ -791,17 +2334,46 @@ impl LocalNode {
     ///
     /// * `subvol`: The subvolume to restore.
     /// * `ignore_fstab`: If the subvolume already exists, don't keep its `fstab(5)`.
+    /// * `safety_snapshot`: If the subvolume already exists, take a [`SafetySnapshot`]
+    ///   of it before replacing it.
     ///
     /// If the subvolume exists and the `ignore_fstab` argument is not set,
     /// the `/etc/fstab` file is saved to memory before restoring
     /// and written to the restored subvolume afterwards.
     /// This behavior is the most useful to the majority of users
     /// since it automatically handles changed UUIDs from OS reinstalls.
-    pub fn restore(&self, subvol: String, ignore_fstab: bool) -> Result<(), LocalNodeError> {
-        let subvol_path = Path::new(self.mode.mountpoint()).join(&subvol);
+    /// The file is read from the safety snapshot if one is taken.
+    ///
+    /// Returns the safety snapshot, if any.
+    pub fn restore(
+        &self,
+        subvol: String,
+        ignore_fstab: bool,
+        at: Option<NaiveDateTime>,
+        safety_snapshot: bool,
+    ) -> Result<Option<SafetySnapshot>, LocalNodeError> {
+        let subvol_path = self.subvol_path(&subvol);
+
+        // Resolve the snapshot first so that the subvolume is left untouched
+        // if there is nothing to restore.
+        let snapshot = match at {
+            Some(at) => self.latest_snapshot_before(subvol.clone(), at)?,
+            None => self.latest_snapshot(subvol.clone())?,
+        };
+
+        let safety_snapshot = if subvol_path.exists() && safety_snapshot {
+            Some(self.take_safety_snapshot(&subvol)?)
+        } else {
+            None
+        };
 
         let fstab = if subvol_path.exists() && !ignore_fstab {
-            Some(fs::read(subvol_path.join("etc/fstab"))?)
+            let source = match &safety_snapshot {
+                Some(safety_snapshot) => &safety_snapshot.path,
+                None => &subvol_path,
+            };
+
+            Some(fs::read(source.join("etc/fstab"))?)
         } else {
             None
         };
@@ -821,12 +2393,10 @@ impl LocalNode {
             return Err(LocalNodeError::BtrfsCmd);
         }
 
-        let snapshot = self.latest_snapshot(subvol)?;
-
         if !Command::new("btrfs")
             .arg("subvolume")
             .arg("snapshot")
-            .arg(snapshot.snapshot_path(self.mode))
+            .arg(snapshot.snapshot_path(&self.layout))
             .arg(&subvol_path)
             .stdin(Stdio::null())
             .stdout(Stdio::null())
@@ -838,20 +2408,160 @@ impl LocalNode {
             return Err(LocalNodeError::BtrfsCmd);
         }
 
-        if let Some(fstab) = fstab {
-            fs::write(subvol_path.join("etc/fstab"), fstab)?;
+        if let Some(fstab) = fstab {
+            fs::write(subvol_path.join("etc/fstab"), fstab)?;
+        }
+
+        Ok(safety_snapshot)
+    }
+
+    /// Takes a read-only [`SafetySnapshot`] of the specified existing subvolume.
+    fn take_safety_snapshot(&self, subvol: &str) -> Result<SafetySnapshot, LocalNodeError> {
+        // Truncate to the precision of the name.
+        let taken = Utc::now().naive_utc().with_nanosecond(0).unwrap();
+        let path = self.layout.pre_restore_dir().join(format!(
+            "{}_{}",
+            subvol,
+            taken.format(Snapshot::TIMESTAMP_FMT)
+        ));
+
+        fs::create_dir_all(self.layout.pre_restore_dir())?;
+
+        if path.exists() {
+            return Err(LocalNodeError::SafetySnapshotExists(path));
+        }
+
+        if !Command::new("btrfs")
+            .arg("subvolume")
+            .arg("snapshot")
+            .arg("-r")
+            .arg(self.subvol_path(subvol))
+            .arg(&path)
+            .stdin(Stdio::null())
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .spawn()?
+            .wait()?
+            .success()
+        {
+            return Err(LocalNodeError::BtrfsCmd);
+        }
+
+        Ok(SafetySnapshot {
+            subvol: subvol.to_string(),
+            taken,
+            path,
+        })
+    }
+
+    /// Returns the [`SafetySnapshot`]s taken by [`LocalNode::restore`], oldest first.
+    /// Entries that aren't named like safety snapshots are skipped.
+    pub fn safety_snapshots(&self) -> Result<Vec<SafetySnapshot>, LocalNodeError> {
+        let dir = self.layout.pre_restore_dir();
+        if !dir.exists() {
+            return Ok(Vec::new());
+        }
+
+        let mut safety_snapshots = Vec::new();
+        for entry in fs::read_dir(dir)? {
+            let path = entry?.path();
+
+            let parsed = path
+                .file_name()
+                .and_then(|name| name.to_str())
+                .and_then(|name| name.split_once('_'))
+                .and_then(|(subvol, taken)| {
+                    let taken =
+                        NaiveDateTime::parse_from_str(taken, Snapshot::TIMESTAMP_FMT).ok()?;
+                    Some((subvol.to_string(), taken))
+                });
+
+            if let Some((subvol, taken)) = parsed {
+                safety_snapshots.push(SafetySnapshot {
+                    subvol,
+                    taken,
+                    path,
+                });
+            }
+        }
+
+        safety_snapshots.sort_unstable_by_key(|safety_snapshot| safety_snapshot.taken);
+        Ok(safety_snapshots)
+    }
+
+    /// Deletes the specified [`SafetySnapshot`].
+    pub fn delete_safety_snapshot(
+        &self,
+        safety_snapshot: &SafetySnapshot,
+    ) -> Result<(), LocalNodeError> {
+        if !Command::new("btrfs")
+            .arg("subvolume")
+            .arg("delete")
+            .arg(&safety_snapshot.path)
+            .stdin(Stdio::null())
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .spawn()?
+            .wait()?
+            .success()
+        {
+            return Err(LocalNodeError::BtrfsCmd);
+        }
+
+        Ok(())
+    }
+
+    /// Deletes the specified snapshot from the local or remote storage directory.
+    pub fn delete(&self, snapshot: &Snapshot) -> Result<(), LocalNodeError> {
+        let age = Utc::now().naive_utc() - snapshot.taken();
+        if age.to_std().unwrap_or_default() < self.delete_cooloff() {
+            return Err(LocalNodeError::Cooloff(snapshot.clone()));
+        }
+
+        self.remove(snapshot, !self.owns_backup(snapshot))
+    }
+
+    /// Deletes the specified snapshot from the local or remote storage directory
+    /// regardless of its cooloff if its timestamp is implausible.
+    /// Retention never ages out snapshots dated into the future,
+    /// so they need to be removed explicitly.
+    pub fn quarantine(&self, snapshot: &Snapshot) -> Result<(), LocalNodeError> {
+        if !self.exists(snapshot) {
+            return Err(LocalNodeError::NoSuchSnapshot(snapshot.clone()));
+        }
+
+        if self.is_plausible(snapshot, Utc::now().naive_utc()) {
+            return Err(LocalNodeError::PlausibleTimestamp(snapshot.clone()));
         }
 
-        Ok(())
+        self.remove(snapshot, !self.owns_backup(snapshot))
     }
 
-    /// Deletes the specified snapshot from the local or remote storage directory.
-    pub fn delete(&self, snapshot: &Snapshot) -> Result<(), LocalNodeError> {
-        if self.owns_backup(snapshot) {
+    /// Deletes the specified local snapshot, or the backup of it if `backup` is set,
+    /// regardless of its cooloff. Only snapshots of subvolumes
+    /// that are no longer tracked by this node can be deleted this way.
+    pub fn delete_untracked(
+        &self,
+        snapshot: &Snapshot,
+        backup: bool,
+    ) -> Result<(), LocalNodeError> {
+        if self.owns_backup(snapshot) && self.owns_subvol(snapshot.subvol()) {
+            return Err(LocalNodeError::TrackedSubvolume(
+                snapshot.subvol().to_string(),
+            ));
+        }
+
+        self.remove(snapshot, backup)
+    }
+
+    fn remove(&self, snapshot: &Snapshot, backup: bool) -> Result<(), LocalNodeError> {
+        if !backup {
+            let _unlocked = self.unlock_snapshots()?;
+
             if !Command::new("btrfs")
                 .arg("subvolume")
                 .arg("delete")
-                .arg(snapshot.snapshot_path(self.mode))
+                .arg(snapshot.snapshot_path(&self.layout))
                 .stdin(Stdio::null())
                 .stdout(Stdio::null())
                 .stderr(Stdio::null())
@@ -862,15 +2572,390 @@ impl LocalNode {
                 return Err(LocalNodeError::BtrfsCmd);
             }
         } else {
-            fs::remove_file(snapshot.backup_path(self.mode))?;
+            let path = self.locate_backup(snapshot);
+            fs::remove_file(&path)?;
+            let _ = fs::remove_file(paths::manifest_path(path));
+
+            // Left behind by interrupted archivals.
+            if let Some(archive_path) = snapshot.archive_path(&self.layout) {
+                let _ = fs::remove_file(archive_path.with_file_name(format!("{snapshot}.part")));
+                let _ = fs::remove_file(paths::manifest_path(&archive_path));
+                let _ = fs::remove_file(archive_path);
+            }
         }
 
-        let _ = fs::remove_file(snapshot.streaming_path(self.mode));
+        let _ = fs::remove_file(snapshot.streaming_path(&self.layout));
+        let _ = fs::remove_file(
+            snapshot
+                .legacy_backup_path(&self.layout)
+                .with_extension("part"),
+        );
+
+        Ok(())
+    }
+}
+
+/// A `SendProcess` reads the output of a btrfs send process.
+/// Once the output ends, the exit status of the process is checked
+/// so that failures mid-stream aren't mistaken for the end of the stream.
+/// The process is killed if the `SendProcess` is dropped early.
+#[derive(Debug)]
+pub struct SendProcess {
+    child: Option<Child>,
+    stdout: ChildStdout,
+    /// Collects the error output concurrently so that the process can't block on it.
+    stderr: Option<thread::JoinHandle<String>>,
+}
+
+impl SendProcess {
+    fn new(mut child: Child) -> Result<Self, LocalNodeError> {
+        let stdout = child.stdout.take().ok_or(LocalNodeError::NoBtrfsOutput)?;
+        let stderr = child.stderr.take().map(|mut stderr| {
+            thread::spawn(move || {
+                let mut buf = Vec::new();
+                let _ = stderr.read_to_end(&mut buf);
+                String::from_utf8_lossy(&buf).trim().to_string()
+            })
+        });
+
+        Ok(Self {
+            child: Some(child),
+            stdout,
+            stderr,
+        })
+    }
+
+    /// Waits for the process to exit and fails with [`LocalNodeError::BtrfsSend`]
+    /// if it was unsuccessful.
+    fn finish(&mut self) -> Result<(), LocalNodeError> {
+        let Some(mut child) = self.child.take() else {
+            return Ok(());
+        };
+
+        let status = child.wait()?;
+        let stderr = self
+            .stderr
+            .take()
+            .and_then(|stderr| stderr.join().ok())
+            .unwrap_or_default();
+
+        if !status.success() {
+            return Err(LocalNodeError::BtrfsSend(status, stderr));
+        }
 
         Ok(())
     }
 }
 
+impl Read for SendProcess {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.stdout.read(buf)?;
+        if n == 0 && !buf.is_empty() {
+            self.finish().map_err(io::Error::other)?;
+        }
+
+        Ok(n)
+    }
+}
+
+impl Drop for SendProcess {
+    fn drop(&mut self) {
+        if let Some(child) = &mut self.child {
+            let _ = child.kill();
+            let _ = child.wait();
+        }
+    }
+}
+
+/// The method used to obtain a [`SizeEstimate`].
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Serialize, Deserialize)]
+pub enum EstimateMethod {
+    /// The total disk usage as reported by `btrfs filesystem du`.
+    DiskUsage,
+    /// The extent lengths of a metadata-only `btrfs send --no-data` stream.
+    NoDataSend,
+    /// The size of the stored encrypted backup.
+    StoredSize,
+}
+
+impl fmt::Display for EstimateMethod {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::DiskUsage => write!(f, "disk usage"),
+            Self::NoDataSend => write!(f, "metadata-only send"),
+            Self::StoredSize => write!(f, "stored size"),
+        }
+    }
+}
+
+/// A `SizeEstimate` is the expected amount of data
+/// transmitted when sending a snapshot or backup.
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Serialize, Deserialize)]
+pub struct SizeEstimate {
+    /// The estimated number of bytes.
+    pub bytes: u64,
+    /// The method the estimate was obtained with.
+    pub method: EstimateMethod,
+}
+
+impl fmt::Display for SizeEstimate {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "estimated {:.1} MiB ({})",
+            self.bytes as f64 / (1024.0 * 1024.0),
+            self.method
+        )
+    }
+}
+
+/// The amount of storage freed by [`LocalNode::clean_partials`].
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct Reclaimed {
+    /// The number of deleted files.
+    pub files: usize,
+    /// The combined size of the deleted files in bytes.
+    pub bytes: u64,
+}
+
+/// The storage tier a backup is located in.
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Serialize, Deserialize)]
+pub enum Tier {
+    /// The backup directory of the [`LocalNode`].
+    Primary,
+    /// The configured archive directory.
+    Archive,
+}
+
+impl fmt::Display for Tier {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::Primary => write!(f, "primary"),
+            Self::Archive => write!(f, "archive"),
+        }
+    }
+}
+
+/// The reason to take a full snapshot instead of a requested incremental snapshot,
+/// see [`LocalNode::promotion`].
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Serialize, Deserialize)]
+pub enum Promotion {
+    /// No full snapshot exists yet.
+    NoFullSnapshot,
+    /// The specified number of incremental snapshots has been taken
+    /// since the latest full snapshot, reaching the configured maximum.
+    MaxIncrementals(usize),
+}
+
+impl Promotion {
+    /// Returns why the next snapshot of a subvolume with the specified snapshots
+    /// has to be full even if an incremental snapshot is requested or `None` if it doesn't.
+    /// At most `max_incrementals` incremental snapshots may follow the latest full snapshot.
+    pub fn required(snapshots: &[Snapshot], max_incrementals: Option<usize>) -> Option<Self> {
+        let Some(latest_full) = snapshots
+            .iter()
+            .filter(|snapshot| !snapshot.is_incremental())
+            .map(|snapshot| snapshot.taken())
+            .max()
+        else {
+            return Some(Self::NoFullSnapshot);
+        };
+
+        let incrementals = snapshots
+            .iter()
+            .filter(|snapshot| snapshot.is_incremental() && snapshot.taken() > latest_full)
+            .count();
+
+        max_incrementals
+            .filter(|max_incrementals| incrementals >= *max_incrementals)
+            .map(|_| Self::MaxIncrementals(incrementals))
+    }
+
+    /// Reports whether an incremental snapshot can be forced regardless,
+    /// which is impossible without a full snapshot to refer to.
+    pub fn can_be_overridden(&self) -> bool {
+        matches!(self, Self::MaxIncrementals(_))
+    }
+}
+
+impl fmt::Display for Promotion {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::NoFullSnapshot => write!(f, "no full snapshot exists yet"),
+            Self::MaxIncrementals(n) => {
+                write!(
+                    f,
+                    "{} incremental snapshots since the latest full snapshot",
+                    n
+                )
+            }
+        }
+    }
+}
+
+/// Reports whether the specified node or subvolume name can be encoded
+/// in [`Snapshot`] and [`Volume`] identifiers and storage paths:
+/// It must be non-empty printable ASCII without a slash or an underscore
+/// and must not be `.` or `..` so that it can't escape its directory.
+pub fn is_valid_name(name: &str) -> bool {
+    !name.is_empty()
+        && name != "."
+        && name != ".."
+        && name
+            .bytes()
+            .all(|b| b.is_ascii_graphic() && b != b'/' && b != b'_')
+}
+
+/// A `CountingWriter` passes data on to the underlying [`Write`]
+/// and counts the number of bytes written.
+struct CountingWriter<'a, W: Write> {
+    inner: W,
+    count: &'a AtomicU64,
+}
+
+impl<'a, W: Write> CountingWriter<'a, W> {
+    fn new(inner: W, count: &'a AtomicU64) -> Self {
+        Self { inner, count }
+    }
+}
+
+impl<W: Write> Write for CountingWriter<'_, W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = self.inner.write(buf)?;
+        self.count.fetch_add(n as u64, atomic::Ordering::SeqCst);
+
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// Sums up the lengths of the extents of the specified metadata-only send stream
+/// using `btrfs receive --dump`, see [`LocalNode::extent_lengths`].
+fn dump_extent_lengths(send_stream: ChildStdout) -> Result<u64, LocalNodeError> {
+    let mut dump = Command::new("btrfs")
+        .arg("receive")
+        .arg("--dump")
+        .stdin(send_stream)
+        .stdout(Stdio::piped())
+        .stderr(Stdio::null())
+        .spawn()?;
+
+    let bytes = dump
+        .stdout
+        .take()
+        .ok_or(LocalNodeError::NoBtrfsOutput)
+        .and_then(|stdout| {
+            let mut bytes = 0;
+            for line in BufReader::new(stdout).lines() {
+                let line = line?;
+                if !line.starts_with("update_extent") {
+                    continue;
+                }
+
+                if let Some(len) = line
+                    .split_whitespace()
+                    .find_map(|field| field.strip_prefix("len="))
+                {
+                    bytes += len.parse::<u64>().map_err(|_| LocalNodeError::BtrfsCmd)?;
+                }
+            }
+
+            Ok(bytes)
+        });
+
+    // Reap the process on every path, it is stuck writing if parsing failed.
+    if bytes.is_err() {
+        let _ = dump.kill();
+    }
+    let status = dump.wait()?;
+
+    let bytes = bytes?;
+    if !status.success() {
+        return Err(LocalNodeError::BtrfsCmd);
+    }
+
+    Ok(bytes)
+}
+
+/// Writes the specified [`Manifest`] to the specified path.
+/// It is written under a temporary name first so that it is never incomplete.
+fn write_manifest<P: AsRef<Path>>(path: P, manifest: &Manifest) -> Result<(), LocalNodeError> {
+    let path = path.as_ref();
+    let tmp = path.with_file_name(format!(
+        "{}.part",
+        path.file_name()
+            .expect("manifest path has a file name")
+            .to_string_lossy()
+    ));
+
+    fs::write(&tmp, serde_json::to_vec_pretty(manifest)?)?;
+    fs::rename(tmp, path)?;
+
+    Ok(())
+}
+
+/// Returns the SHA-256 digest of the file at the specified path.
+fn digest_file<P: AsRef<Path>>(path: P) -> io::Result<Vec<u8>> {
+    let mut file = File::open(path)?;
+    let mut hasher = Sha256::new();
+    let mut buf = vec![0; RX_BUFSIZE];
+
+    loop {
+        let n = file.read(&mut buf)?;
+        if n == 0 {
+            break;
+        }
+
+        hasher.update(&buf[..n]);
+    }
+
+    Ok(hasher.finalize().to_vec())
+}
+
+/// Returns the total disk usage of the specified path in bytes.
+fn disk_usage<P: AsRef<Path>>(path: P) -> Result<u64, LocalNodeError> {
+    let output = Command::new("btrfs")
+        .arg("filesystem")
+        .arg("du")
+        .arg("-s")
+        .arg("--raw")
+        .arg(path.as_ref())
+        .stdin(Stdio::null())
+        .stderr(Stdio::null())
+        .output()?;
+    if !output.status.success() {
+        return Err(LocalNodeError::BtrfsCmd);
+    }
+
+    // The last line holds the summary, its first column is the total.
+    String::from_utf8_lossy(&output.stdout)
+        .lines()
+        .last()
+        .and_then(|line| line.split_whitespace().next())
+        .and_then(|total| total.parse().ok())
+        .ok_or(LocalNodeError::BtrfsCmd)
+}
+
+/// An `Unlocked` guard lifts the immutable attribute of the snapshot directory
+/// for its lifetime, restoring it when dropped.
+pub struct Unlocked {
+    path: PathBuf,
+    relock: bool,
+}
+
+impl Drop for Unlocked {
+    fn drop(&mut self) {
+        if self.relock {
+            // Failing to restore the protection is not fatal.
+            // The next `LocalNode` with immutable snapshots restores it.
+            let _ = system::set_immutable(&self.path, true);
+        }
+    }
+}
+
 impl Node for LocalNode {
     /// Returns the name of the `LocalNode`.
     fn name(&self) -> &str {
@@ -891,3 +2976,522 @@ impl PartialEq for LocalNode {
 }
 
 impl Eq for LocalNode {}
+
+/// Reports whether the specified timestamp is neither further ahead of `now`
+/// than `max_clock_skew` nor older than `max_age`, if set.
+fn is_plausible(
+    taken: NaiveDateTime,
+    now: NaiveDateTime,
+    max_clock_skew: Duration,
+    max_age: Option<Duration>,
+) -> bool {
+    let ahead = (taken - now).to_std().unwrap_or_default();
+    let age = (now - taken).to_std().unwrap_or_default();
+
+    ahead <= max_clock_skew && max_age.is_none_or(|max| age <= max)
+}
+
+/// Returns the latest of the existing snapshots of a subvolume,
+/// which the specified new snapshot succeeds in its chain.
+///
+/// Chains are ordered by timestamp, so a restored snapshot dated ahead
+/// of the local clock would make the new snapshot precede it.
+/// Fails with [`LocalNodeError::PostdatedSnapshot`] in this case.
+fn predecessor(
+    snapshots: Vec<Snapshot>,
+    snapshot: &Snapshot,
+) -> Result<Option<Snapshot>, LocalNodeError> {
+    // Incremental snapshots succeed full snapshots taken at the same time.
+    let latest = snapshots
+        .into_iter()
+        .max_by_key(|latest| (latest.taken(), latest.is_incremental()));
+
+    match latest {
+        Some(latest) if latest.taken() > snapshot.taken() => {
+            Err(LocalNodeError::PostdatedSnapshot(latest))
+        }
+        latest => Ok(latest),
+    }
+}
+
+/// Returns the latest full or incremental snapshot among the specified ones
+/// taken at or before `not_after`, if set.
+fn latest_of(
+    snapshots: &[Snapshot],
+    incremental: bool,
+    not_after: Option<NaiveDateTime>,
+) -> Option<Snapshot> {
+    snapshots
+        .iter()
+        .filter(|snapshot| snapshot.is_incremental() == incremental)
+        .filter(|snapshot| not_after.is_none_or(|not_after| snapshot.taken() <= not_after))
+        .max_by_key(|snapshot| snapshot.taken())
+        .cloned()
+}
+
+/// Returns the full or incremental snapshots among the specified ones
+/// taken after the provided timestamp.
+fn taken_after(snapshots: Vec<Snapshot>, incremental: bool, after: NaiveDateTime) -> Vec<Snapshot> {
+    snapshots
+        .into_iter()
+        .filter(|snapshot| snapshot.is_incremental() == incremental && snapshot.taken() > after)
+        .collect()
+}
+
+/// Returns all snapshots stored in the specified snapshot directory.
+/// Entries that aren't snapshot identifiers are skipped with a warning
+/// unless they are hidden.
+fn read_snapshots(snapshot_dir: &Path) -> Result<Vec<Snapshot>, LocalNodeError> {
+    let mut snapshots = Vec::new();
+    let mut unrecognized = Vec::new();
+
+    for snapshot in fs::read_dir(snapshot_dir)? {
+        let path = snapshot?.path();
+        if is_hidden(&path) {
+            continue;
+        }
+
+        match Snapshot::try_from(&*path) {
+            Ok(snapshot) => snapshots.push(snapshot),
+            Err(_) => unrecognized.push(path),
+        }
+    }
+
+    warn_unrecognized(snapshot_dir, &unrecognized);
+
+    Ok(snapshots)
+}
+
+/// Returns all backups stored in the specified backup directory
+/// of the specified [`Volume`] or all volumes.
+/// Backups stored in the flat layout used by previous versions are included.
+/// Files that aren't snapshot identifiers are skipped with a warning
+/// unless they are hidden or incomplete backups.
+pub(crate) fn read_backups(
+    backup_dir: &Path,
+    volume: Option<&Volume>,
+) -> Result<Vec<Snapshot>, LocalNodeError> {
+    let mut backups = Vec::new();
+    let mut unrecognized = Vec::new();
+
+    read_backup_dir(backup_dir, volume, &mut backups, &mut unrecognized)?;
+
+    match volume {
+        Some(volume) => {
+            let dir = backup_dir.join(volume.node_name()).join(volume.subvol());
+            if dir.exists() {
+                read_backup_dir(&dir, Some(volume), &mut backups, &mut unrecognized)?;
+            }
+        }
+        None => {
+            for node_dir in fs::read_dir(backup_dir)? {
+                let node_dir = node_dir?;
+                if !node_dir.file_type()?.is_dir() {
+                    continue;
+                }
+
+                for subvol_dir in fs::read_dir(node_dir.path())? {
+                    let subvol_dir = subvol_dir?;
+                    if subvol_dir.file_type()?.is_dir() {
+                        read_backup_dir(&subvol_dir.path(), None, &mut backups, &mut unrecognized)?;
+                    }
+                }
+            }
+        }
+    }
+
+    warn_unrecognized(backup_dir, &unrecognized);
+
+    Ok(backups)
+}
+
+/// Returns the paths of all incomplete backups in the specified backup directory,
+/// including those stored in the flat layout used by previous versions.
+fn read_partials(backup_dir: &Path) -> Result<Vec<PathBuf>, LocalNodeError> {
+    let mut dirs = vec![backup_dir.to_path_buf()];
+
+    for node_dir in fs::read_dir(backup_dir)? {
+        let node_dir = node_dir?;
+        if !node_dir.file_type()?.is_dir() {
+            continue;
+        }
+
+        for subvol_dir in fs::read_dir(node_dir.path())? {
+            let subvol_dir = subvol_dir?;
+            if subvol_dir.file_type()?.is_dir() {
+                dirs.push(subvol_dir.path());
+            }
+        }
+    }
+
+    let mut partials = Vec::new();
+    for dir in dirs {
+        for entry in fs::read_dir(dir)? {
+            let entry = entry?;
+            if entry.file_type()?.is_file() && entry.path().extension() == Some(OsStr::new("part"))
+            {
+                partials.push(entry.path());
+            }
+        }
+    }
+
+    Ok(partials)
+}
+
+fn read_backup_dir(
+    dir: &Path,
+    volume: Option<&Volume>,
+    backups: &mut Vec<Snapshot>,
+    unrecognized: &mut Vec<PathBuf>,
+) -> Result<(), LocalNodeError> {
+    for backup in fs::read_dir(dir)? {
+        let backup = backup?;
+        let path = backup.path();
+
+        if backup.file_type()?.is_dir()
+            || path.extension() == Some(OsStr::new("part"))
+            || paths::is_manifest(&path)
+            || is_hidden(&path)
+        {
+            continue;
+        }
+
+        let Ok(snapshot) = Snapshot::try_from(&*path) else {
+            unrecognized.push(path);
+            continue;
+        };
+
+        match volume {
+            Some(volume) if !snapshot.is_of_volume(volume) => {}
+            _ => backups.push(snapshot),
+        }
+    }
+
+    Ok(())
+}
+
+/// Reports whether the file name of the specified path starts with a dot.
+fn is_hidden(path: &Path) -> bool {
+    path.file_name()
+        .is_some_and(|name| name.as_encoded_bytes().starts_with(b"."))
+}
+
+/// Logs a single warning about the entries of the specified directory
+/// that aren't snapshot identifiers. `hbak doctor` lists them as well.
+pub(crate) fn warn_unrecognized(dir: &Path, unrecognized: &[PathBuf]) {
+    if unrecognized.is_empty() {
+        return;
+    }
+
+    output::log(
+        Level::Warn,
+        &[],
+        format_args!(
+            "Ignoring unrecognized entries in {}: {}",
+            dir.display(),
+            unrecognized
+                .iter()
+                .map(|path| path.display().to_string())
+                .collect::<Vec<_>>()
+                .join(", ")
+        ),
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::{env, process};
+
+    /// Returns an empty temporary directory for the specified test.
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = env::temp_dir().join(format!("hbak-proto-{}-{}", process::id(), name));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+
+        dir
+    }
+
+    fn snapshot(s: &str) -> Snapshot {
+        Snapshot::try_from(s).unwrap()
+    }
+
+    fn sorted(mut snapshots: Vec<Snapshot>) -> Vec<Snapshot> {
+        snapshots.sort_by_key(|snapshot| snapshot.to_string());
+        snapshots
+    }
+
+    #[test]
+    fn unrecognized_snapshots_are_skipped() {
+        let dir = temp_dir("unrecognized_snapshots_are_skipped");
+
+        let snapshots = [
+            snapshot("node_home_full_20240101000000"),
+            snapshot("node_home_incr_20240102000000"),
+            snapshot("node_root_full_20240101000000"),
+        ];
+        for snapshot in &snapshots {
+            fs::create_dir(dir.join(snapshot.to_string())).unwrap();
+        }
+
+        fs::create_dir(dir.join("scratch")).unwrap();
+        fs::create_dir(dir.join(".trash")).unwrap();
+        File::create(dir.join("notes.txt")).unwrap();
+        File::create(dir.join(".node_home_full_20240103000000.swp")).unwrap();
+
+        assert_eq!(sorted(read_snapshots(&dir).unwrap()), snapshots);
+    }
+
+    #[test]
+    fn unrecognized_backups_are_skipped() {
+        let dir = temp_dir("unrecognized_backups_are_skipped");
+
+        let nested = [
+            snapshot("node_home_full_20240101000000"),
+            snapshot("node_home_incr_20240102000000"),
+            snapshot("other_home_full_20240101000000"),
+        ];
+        for backup in &nested {
+            let path = backup.backup_path_in(&dir);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            File::create(&path).unwrap();
+            File::create(paths::manifest_path(&path)).unwrap();
+        }
+
+        // Backups stored in the flat layout used by previous versions.
+        let legacy = snapshot("node_root_full_20231231000000");
+        File::create(dir.join(legacy.to_string())).unwrap();
+
+        let volume_dir = nested[0].volume_dir_in(&dir);
+        File::create(snapshot("node_home_incr_20240103000000").streaming_path_in(&dir)).unwrap();
+        File::create(volume_dir.join(".node_home_incr_20240102000000.swp")).unwrap();
+        File::create(volume_dir.join("node_home_incr_20240102000000~")).unwrap();
+        fs::create_dir(volume_dir.join("node_home_full_20240104000000")).unwrap();
+        File::create(dir.join("README")).unwrap();
+
+        let mut all = nested.to_vec();
+        all.push(legacy);
+        assert_eq!(sorted(read_backups(&dir, None).unwrap()), sorted(all));
+
+        assert_eq!(
+            sorted(read_backups(&dir, Some(&nested[0].volume())).unwrap()),
+            nested[..2]
+        );
+    }
+
+    /// Returns a temporary backup directory holding empty backups
+    /// with the specified identifiers.
+    fn backup_dir(name: &str, backups: &[&str]) -> PathBuf {
+        let dir = temp_dir(name);
+        for backup in backups {
+            let path = snapshot(backup).backup_path_in(&dir);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            File::create(path).unwrap();
+        }
+
+        dir
+    }
+
+    fn volume(s: &str) -> Volume {
+        Volume::try_from(s).unwrap()
+    }
+
+    fn taken(s: &str) -> NaiveDateTime {
+        NaiveDateTime::parse_from_str(s, "%Y%m%d%H%M%S").unwrap()
+    }
+
+    const MIXED: &[&str] = &[
+        "node_home_full_20240101000000",
+        "node_home_incr_20240102000000",
+        "node_home_incr_20240103000000",
+        "node_home_full_20240104000000",
+        "node_home_incr_20240105000000",
+        "node_root_full_20240106000000",
+        "other_home_incr_20240107000000",
+        "other_home_full_20240101000000",
+    ];
+
+    #[test]
+    fn latest_backups_are_selected_by_type() {
+        let dir = backup_dir("latest_backups_are_selected_by_type", MIXED);
+        let home = read_backups(&dir, Some(&volume("node_home"))).unwrap();
+
+        assert_eq!(
+            latest_of(&home, false, None),
+            Some(snapshot("node_home_full_20240104000000"))
+        );
+        assert_eq!(
+            latest_of(&home, true, None),
+            Some(snapshot("node_home_incr_20240105000000"))
+        );
+
+        assert_eq!(
+            latest_of(&home, false, Some(taken("20240103000000"))),
+            Some(snapshot("node_home_full_20240101000000"))
+        );
+        assert_eq!(
+            latest_of(&home, true, Some(taken("20240103000000"))),
+            Some(snapshot("node_home_incr_20240103000000"))
+        );
+        assert_eq!(latest_of(&home, true, Some(taken("20240101235959"))), None);
+    }
+
+    #[test]
+    fn latest_incremental_backup_requires_incrementals() {
+        let dir = backup_dir("latest_incremental_backup_requires_incrementals", MIXED);
+        let root = read_backups(&dir, Some(&volume("node_root"))).unwrap();
+
+        assert_eq!(
+            latest_of(&root, false, None),
+            Some(snapshot("node_root_full_20240106000000"))
+        );
+        assert_eq!(latest_of(&root, true, None), None);
+    }
+
+    #[test]
+    fn backups_after_are_selected_by_type() {
+        let dir = backup_dir("backups_after_are_selected_by_type", MIXED);
+        let home = read_backups(&dir, Some(&volume("node_home"))).unwrap();
+
+        assert_eq!(
+            sorted(taken_after(home.clone(), false, taken("20240101000000"))),
+            [snapshot("node_home_full_20240104000000")]
+        );
+        assert_eq!(
+            sorted(taken_after(home.clone(), true, taken("20240102000000"))),
+            [
+                snapshot("node_home_incr_20240103000000"),
+                snapshot("node_home_incr_20240105000000"),
+            ]
+        );
+        assert!(taken_after(home, true, taken("20240105000000")).is_empty());
+    }
+
+    #[test]
+    fn latest_snapshots_cover_each_volume() {
+        let dir = backup_dir("latest_snapshots_cover_each_volume", MIXED);
+        let latest =
+            |name, at| LatestSnapshots::of(&read_backups(&dir, Some(&volume(name))).unwrap(), at);
+
+        assert_eq!(
+            latest("node_home", None),
+            LatestSnapshots {
+                last_full: taken("20240104000000"),
+                last_incremental: taken("20240105000000"),
+                not_after: None,
+            }
+        );
+        assert_eq!(
+            latest("node_root", None),
+            LatestSnapshots {
+                last_full: taken("20240106000000"),
+                last_incremental: NaiveDateTime::MIN,
+                not_after: None,
+            }
+        );
+        assert_eq!(
+            latest("other_home", None),
+            LatestSnapshots {
+                last_full: taken("20240101000000"),
+                last_incremental: taken("20240107000000"),
+                not_after: None,
+            }
+        );
+
+        let at = taken("20240102000000");
+        assert_eq!(
+            latest("node_home", Some(at)),
+            LatestSnapshots {
+                last_full: taken("20240101000000"),
+                last_incremental: taken("20240102000000"),
+                not_after: Some(at),
+            }
+        );
+        assert_eq!(
+            latest("node_root", Some(at)),
+            LatestSnapshots {
+                not_after: Some(at),
+                ..LatestSnapshots::none()
+            }
+        );
+    }
+
+    #[test]
+    fn empty_backup_dirs_select_nothing() {
+        let dir = backup_dir("empty_backup_dirs_select_nothing", &[]);
+        let backups = read_backups(&dir, Some(&volume("node_home"))).unwrap();
+
+        assert!(backups.is_empty());
+        assert_eq!(latest_of(&backups, false, None), None);
+        assert_eq!(latest_of(&backups, true, None), None);
+        assert!(taken_after(backups.clone(), false, NaiveDateTime::MIN).is_empty());
+        assert_eq!(LatestSnapshots::of(&backups, None), LatestSnapshots::none());
+    }
+
+    #[test]
+    fn plausible_timestamps_are_bounded_inclusively() {
+        const SKEW: Duration = Duration::from_secs(300);
+        const AGE: Duration = Duration::from_secs(86400);
+
+        let now = taken("20240102000000");
+        let plausible = |taken, max_age| is_plausible(taken, now, SKEW, max_age);
+
+        assert!(plausible(now, Some(AGE)));
+        assert!(plausible(taken("20240102000500"), Some(AGE)));
+        assert!(!plausible(taken("20240102000501"), Some(AGE)));
+        assert!(plausible(taken("20240101000000"), Some(AGE)));
+        assert!(!plausible(taken("20231231235959"), Some(AGE)));
+
+        // Without a maximum age, only the clock skew matters.
+        assert!(plausible(NaiveDateTime::MIN, None));
+        assert!(!plausible(NaiveDateTime::MAX, None));
+    }
+
+    #[test]
+    fn new_snapshots_succeed_restored_ones() {
+        let restored = vec![
+            snapshot("node_home_full_20240101000000"),
+            snapshot("node_home_incr_20240102000000"),
+        ];
+
+        // Restored snapshots have no sub-second precision.
+        let mut same_second = snapshot("node_home_incr_20240102000000");
+        same_second.taken += chrono::Duration::milliseconds(500);
+
+        for new in [
+            snapshot("node_home_incr_20240103000000"),
+            snapshot("node_home_full_20240102000000"),
+            same_second,
+        ] {
+            assert_eq!(
+                predecessor(restored.clone(), &new).unwrap(),
+                Some(snapshot("node_home_incr_20240102000000"))
+            );
+        }
+
+        assert!(matches!(
+            predecessor(restored, &snapshot("node_home_incr_20240101235959")),
+            Err(LocalNodeError::PostdatedSnapshot(latest))
+                if latest == snapshot("node_home_incr_20240102000000")
+        ));
+    }
+
+    #[test]
+    fn incremental_snapshots_succeed_simultaneous_full_ones() {
+        let restored = vec![
+            snapshot("node_home_incr_20240102000000"),
+            snapshot("node_home_full_20240102000000"),
+            snapshot("node_home_full_20240101000000"),
+        ];
+
+        assert_eq!(
+            predecessor(restored, &snapshot("node_home_incr_20240103000000")).unwrap(),
+            Some(snapshot("node_home_incr_20240102000000"))
+        );
+        assert_eq!(
+            predecessor(Vec::new(), &snapshot("node_home_full_20240103000000")).unwrap(),
+            None
+        );
+    }
+}
@@ -0,0 +1,169 @@
+// hbak_common is the main hbak library implementing the protocol shared logic.
+// Copyright (C) 2024  Himbeer <himbeerserverde@gmail.com>
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use crate::conn::{Phase, StreamConn, PROTOCOL_VERSION};
+use crate::proto::Snapshot;
+use crate::LocalNodeError;
+
+use std::error::Error;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use chrono::prelude::*;
+use serde::{Deserialize, Serialize};
+
+/// The directory `hbak` writes its failure reports to.
+pub const CLIENT_DIR: &str = "/var/lib/hbak/failures";
+/// The directory `hbakd` writes its failure reports to.
+pub const SERVER_DIR: &str = "/var/lib/hbakd/failures";
+
+/// The number of failure reports to keep per directory. Older reports are deleted.
+pub const MAX_REPORTS: usize = 32;
+
+/// A `FailureReport` records the state of a synchronization session
+/// at the time it failed for post-mortem analysis.
+#[derive(Clone, Debug, Eq, PartialEq, Serialize, Deserialize)]
+pub struct FailureReport {
+    /// The time the session started.
+    pub started: NaiveDateTime,
+    /// The time the session failed.
+    pub failed: Option<NaiveDateTime>,
+    /// The version of the reporting program.
+    pub version: String,
+    /// The protocol version of the reporting program.
+    pub protocol_version: u32,
+    /// The version of the remote program, if authenticated.
+    pub remote_version: Option<String>,
+    /// The protocol version of the remote node, if known.
+    /// It only differs from `protocol_version` if authentication failed because of it.
+    pub remote_protocol_version: Option<u32>,
+    /// The name of the local node.
+    pub local_node: String,
+    /// The name of the remote node, if authenticated.
+    pub remote_node: Option<String>,
+    /// The network address of the remote node.
+    pub address: String,
+    /// The snapshots queued for transmission.
+    pub queued: Vec<Snapshot>,
+    /// The snapshots whose reception had started but not completed.
+    pub receiving: Vec<Snapshot>,
+    /// The snapshots that were received completely.
+    pub received: Vec<Snapshot>,
+    /// The error that ended the session followed by its sources.
+    pub error: Vec<String>,
+}
+
+impl FailureReport {
+    /// Starts recording a session with the specified remote node.
+    pub fn new(version: &str, local_node: String, address: String) -> Self {
+        Self {
+            started: Utc::now().naive_utc(),
+            failed: None,
+            version: version.to_string(),
+            protocol_version: PROTOCOL_VERSION,
+            remote_version: None,
+            remote_protocol_version: None,
+            local_node,
+            remote_node: None,
+            address,
+            queued: Vec::default(),
+            receiving: Vec::default(),
+            received: Vec::default(),
+            error: Vec::default(),
+        }
+    }
+
+    /// Records the name and versions of the remote node once it is authenticated.
+    pub fn authenticated<P: Phase>(&mut self, stream_conn: &StreamConn<P>) {
+        self.remote_node = Some(stream_conn.remote_node_name().to_string());
+        self.remote_version = Some(stream_conn.remote_software().to_string());
+        self.remote_protocol_version = Some(PROTOCOL_VERSION);
+    }
+
+    /// Marks the reception of the specified snapshot as started.
+    pub fn start_receiving(&mut self, snapshot: &Snapshot) {
+        self.receiving.push(snapshot.clone());
+    }
+
+    /// Marks the reception of the specified snapshot as completed.
+    pub fn finish_receiving(&mut self, snapshot: &Snapshot) {
+        self.receiving.retain(|item| item != snapshot);
+        self.received.push(snapshot.clone());
+    }
+
+    /// Records the specified error and its sources as the cause of the failure.
+    pub fn fail(&mut self, e: &dyn Error) {
+        self.failed = Some(Utc::now().naive_utc());
+
+        let mut source = Some(e);
+        while let Some(e) = source {
+            self.error.push(e.to_string());
+            source = e.source();
+        }
+    }
+
+    /// Writes the report to the specified directory, deleting the oldest reports
+    /// in excess of [`MAX_REPORTS`]. Returns the path of the report.
+    pub fn save<P: AsRef<Path>>(&self, dir: P) -> Result<PathBuf, LocalNodeError> {
+        let dir = dir.as_ref();
+        fs::create_dir_all(dir)?;
+
+        let path = dir.join(format!(
+            "{}_{}.toml",
+            self.failed
+                .unwrap_or(self.started)
+                .format("%Y%m%d%H%M%S%.f"),
+            self.remote_node.as_deref().unwrap_or("unknown")
+        ));
+        fs::write(&path, toml::to_string_pretty(self)?)?;
+
+        let mut reports = Self::list(dir)?;
+        while reports.len() > MAX_REPORTS {
+            fs::remove_file(reports.remove(0))?;
+        }
+
+        Ok(path)
+    }
+
+    /// Loads the report at the specified path.
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self, LocalNodeError> {
+        Ok(toml::from_str(&fs::read_to_string(path)?)?)
+    }
+
+    /// Returns the paths of all reports in the specified directory, oldest first.
+    /// A missing directory contains no reports.
+    pub fn list<P: AsRef<Path>>(dir: P) -> Result<Vec<PathBuf>, LocalNodeError> {
+        let dir = dir.as_ref();
+        if !dir.exists() {
+            return Ok(Vec::default());
+        }
+
+        let mut reports = Vec::new();
+        for entry in fs::read_dir(dir)? {
+            let path = entry?.path();
+            if path
+                .extension()
+                .is_some_and(|extension| extension == "toml")
+            {
+                reports.push(path);
+            }
+        }
+
+        // File names start with the time of failure.
+        reports.sort_unstable();
+        Ok(reports)
+    }
+}
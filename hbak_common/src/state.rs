@@ -0,0 +1,468 @@
+// hbak_common is the main hbak library implementing the protocol shared logic.
+// Copyright (C) 2024  Himbeer <himbeerserverde@gmail.com>
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use crate::config::{HumanDuration, RemoteNodeAuth};
+use crate::conn::{self, SyncStats};
+use crate::proto::{Snapshot, VolumeSpec};
+use crate::LocalNodeError;
+
+use std::collections::BTreeMap;
+use std::env;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use chrono::prelude::*;
+use serde::{Deserialize, Serialize};
+
+/// A `ServerState` contains the information `hbakd` tracks about its clients
+/// and upstreams. It is persisted across restarts.
+#[derive(Clone, Debug, Default, Eq, PartialEq, Serialize, Deserialize)]
+pub struct ServerState {
+    /// The tracked clients by node name.
+    pub clients: BTreeMap<String, ClientState>,
+    /// The tracked upstreams by network address.
+    #[serde(default)]
+    pub upstreams: BTreeMap<String, UpstreamState>,
+}
+
+impl ServerState {
+    pub const PATH: &'static str = "/var/lib/hbakd/state.toml";
+
+    /// Loads the state file of the current machine.
+    /// Returns an empty `ServerState` if it doesn't exist yet.
+    pub fn load() -> Result<Self, LocalNodeError> {
+        match fs::read_to_string(Self::PATH) {
+            Ok(s) => Ok(toml::from_str(&s)?),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(Self::default()),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// Saves the state to the state file on the current machine.
+    pub fn save(&self) -> Result<(), LocalNodeError> {
+        if let Some(parent) = Path::new(Self::PATH).parent() {
+            fs::create_dir_all(parent)?;
+        }
+
+        fs::write(Self::PATH, toml::to_string_pretty(self)?)?;
+        Ok(())
+    }
+
+    /// Records a successful authentication of the specified client.
+    pub fn record_session(&mut self, node_name: &str, now: NaiveDateTime) {
+        self.clients
+            .entry(node_name.to_string())
+            .or_default()
+            .last_session = Some(now);
+    }
+
+    /// Records the complete reception of a [`Snapshot`] from the specified client.
+    pub fn record_received(&mut self, node_name: &str, snapshot: Snapshot, now: NaiveDateTime) {
+        self.clients
+            .entry(node_name.to_string())
+            .or_default()
+            .last_received
+            .insert(
+                snapshot.volume().to_string(),
+                ReceivedSnapshot {
+                    snapshot,
+                    received: now,
+                },
+            );
+    }
+
+    /// Records a successful synchronization with the specified upstream
+    /// and the time of the next one.
+    pub fn record_upstream_success(
+        &mut self,
+        address: &str,
+        now: NaiveDateTime,
+        next_attempt: NaiveDateTime,
+    ) {
+        let upstream = self.upstreams.entry(address.to_string()).or_default();
+
+        upstream.last_attempt = Some(now);
+        upstream.last_success = Some(now);
+        upstream.last_error = None;
+        upstream.failures = 0;
+        upstream.next_attempt = Some(next_attempt);
+    }
+
+    /// Records a failed synchronization with the specified upstream
+    /// and the time of the retry.
+    pub fn record_upstream_failure(
+        &mut self,
+        address: &str,
+        now: NaiveDateTime,
+        error: String,
+        next_attempt: NaiveDateTime,
+    ) {
+        let upstream = self.upstreams.entry(address.to_string()).or_default();
+
+        upstream.last_attempt = Some(now);
+        upstream.last_error = Some(error);
+        upstream.failures += 1;
+        upstream.next_attempt = Some(next_attempt);
+    }
+
+    /// Returns the [`Staleness`] of all clients that have an expected push interval
+    /// and haven't pushed within it.
+    pub fn stale_clients<'a>(
+        &self,
+        auth: impl IntoIterator<Item = &'a RemoteNodeAuth>,
+        now: NaiveDateTime,
+    ) -> Vec<Staleness> {
+        let mut seen = Vec::new();
+
+        auth.into_iter()
+            .filter_map(|auth| {
+                let push_interval = auth.push_interval?;
+
+                // Only the first grant of a node name with an interval applies.
+                if seen.contains(&&auth.node_name) {
+                    return None;
+                }
+                seen.push(&auth.node_name);
+
+                let last_push = self
+                    .clients
+                    .get(&auth.node_name)
+                    .and_then(|client| client.last_push());
+
+                let is_stale = match last_push {
+                    Some(last_push) => {
+                        now.signed_duration_since(last_push)
+                            .to_std()
+                            .unwrap_or_default()
+                            > push_interval.into()
+                    }
+                    None => true,
+                };
+
+                is_stale.then(|| Staleness {
+                    node_name: auth.node_name.clone(),
+                    last_push,
+                    push_interval,
+                })
+            })
+            .collect()
+    }
+}
+
+/// A `ClientState` contains the information `hbakd` tracks about a single client.
+#[derive(Clone, Debug, Default, Eq, PartialEq, Serialize, Deserialize)]
+pub struct ClientState {
+    /// The time of the last successful authentication.
+    pub last_session: Option<NaiveDateTime>,
+    /// The last completely received snapshot of each volume by volume identifier.
+    pub last_received: BTreeMap<String, ReceivedSnapshot>,
+}
+
+impl ClientState {
+    /// Returns the time of the last completed push of any volume.
+    pub fn last_push(&self) -> Option<NaiveDateTime> {
+        self.last_received
+            .values()
+            .map(|received| received.received)
+            .max()
+    }
+}
+
+/// An `UpstreamState` contains the information `hbakd` tracks about a single upstream.
+#[derive(Clone, Debug, Default, Eq, PartialEq, Serialize, Deserialize)]
+pub struct UpstreamState {
+    /// The time of the last synchronization attempt.
+    pub last_attempt: Option<NaiveDateTime>,
+    /// The time of the last successful synchronization.
+    pub last_success: Option<NaiveDateTime>,
+    /// The error of the last attempt if it failed.
+    pub last_error: Option<String>,
+    /// The number of consecutive failed attempts.
+    pub failures: u32,
+    /// The time of the next scheduled attempt.
+    pub next_attempt: Option<NaiveDateTime>,
+}
+
+/// A `SyncState` contains the outcome of the latest synchronizations of `hbak`
+/// with each remote node. It is kept separate from the configuration file
+/// and persisted across runs.
+#[derive(Clone, Debug, Default, Eq, PartialEq, Serialize, Deserialize)]
+pub struct SyncState {
+    /// The tracked remote nodes by network address.
+    #[serde(default)]
+    pub remotes: BTreeMap<String, RemoteSyncState>,
+}
+
+impl SyncState {
+    pub const PATH: &'static str = "/var/lib/hbak/state.toml";
+    /// The environment variable overriding the location of the state file.
+    pub const PATH_VAR: &'static str = "HBAK_STATE";
+
+    /// Returns the location of the state file of the current machine:
+    /// The value of [`SyncState::PATH_VAR`] if set or [`SyncState::PATH`].
+    pub fn path() -> PathBuf {
+        env::var_os(Self::PATH_VAR)
+            .filter(|path| !path.is_empty())
+            .map(PathBuf::from)
+            .unwrap_or_else(|| PathBuf::from(Self::PATH))
+    }
+
+    /// Loads the state file of the current machine.
+    /// Returns an empty `SyncState` if it doesn't exist yet.
+    pub fn load() -> Result<Self, LocalNodeError> {
+        match fs::read_to_string(Self::path()) {
+            Ok(s) => Ok(toml::from_str(&s)?),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(Self::default()),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// Saves the state to the state file on the current machine.
+    /// The file is replaced atomically so that it is never left incomplete.
+    pub fn save(&self) -> Result<(), LocalNodeError> {
+        let path = Self::path();
+        if let Some(parent) = path
+            .parent()
+            .filter(|parent| !parent.as_os_str().is_empty())
+        {
+            fs::create_dir_all(parent)?;
+        }
+
+        let mut tmp_path = path.clone().into_os_string();
+        tmp_path.push(".tmp");
+
+        fs::write(&tmp_path, toml::to_string_pretty(self)?)?;
+        fs::rename(tmp_path, path)?;
+
+        Ok(())
+    }
+
+    /// Records a successful synchronization with the specified remote node
+    /// and the amount of data transferred.
+    pub fn record_success(&mut self, address: &str, now: NaiveDateTime, stats: &SyncStats) {
+        let remote = self.remotes.entry(address.to_string()).or_default();
+
+        remote.last_attempt = Some(now);
+        remote.last_success = Some(now);
+        remote.last_error = None;
+        remote.bytes_pushed = stats.bytes_sent();
+        remote.bytes_pulled = stats.bytes_received();
+    }
+
+    /// Records a failed synchronization with the specified remote node.
+    pub fn record_failure(&mut self, address: &str, now: NaiveDateTime, error: String) {
+        let remote = self.remotes.entry(address.to_string()).or_default();
+
+        remote.last_attempt = Some(now);
+        remote.last_error = Some(error);
+    }
+
+    /// Returns the addresses of the specified remote nodes that haven't synchronized
+    /// successfully within `max_age`, including the ones that never have.
+    pub fn stale_remotes<'a>(
+        &self,
+        addresses: impl IntoIterator<Item = &'a str>,
+        max_age: HumanDuration,
+        now: NaiveDateTime,
+    ) -> Vec<&'a str> {
+        addresses
+            .into_iter()
+            .filter(|address| {
+                match self
+                    .remotes
+                    .get(*address)
+                    .and_then(|remote| remote.last_success)
+                {
+                    Some(last_success) => {
+                        now.signed_duration_since(last_success)
+                            .to_std()
+                            .unwrap_or_default()
+                            > max_age.into()
+                    }
+                    None => true,
+                }
+            })
+            .collect()
+    }
+}
+
+/// A `RemoteSyncState` contains the information `hbak` tracks
+/// about the synchronizations with a single remote node.
+#[derive(Clone, Debug, Default, Eq, PartialEq, Serialize, Deserialize)]
+pub struct RemoteSyncState {
+    /// The time of the last synchronization attempt.
+    pub last_attempt: Option<NaiveDateTime>,
+    /// The time of the last successful synchronization.
+    pub last_success: Option<NaiveDateTime>,
+    /// The error of the last attempt if it failed.
+    pub last_error: Option<String>,
+    /// The number of bytes pushed by the last successful synchronization.
+    #[serde(default)]
+    pub bytes_pushed: u64,
+    /// The number of bytes pulled by the last successful synchronization.
+    #[serde(default)]
+    pub bytes_pulled: u64,
+}
+
+impl RemoteSyncState {
+    /// Returns a human-readable summary relative to the specified time, e.g.
+    /// `last successful sync 1d2h ago (pushed 1.2 GiB, pulled 0 B), last attempt failed: ...`.
+    pub fn summary(&self, now: NaiveDateTime) -> String {
+        let mut summary = match self.last_success {
+            Some(last_success) => format!(
+                "last successful sync {} ago (pushed {}, pulled {})",
+                HumanDuration::from_secs(
+                    now.signed_duration_since(last_success).num_seconds().max(0) as u64
+                ),
+                conn::format_bytes(self.bytes_pushed),
+                conn::format_bytes(self.bytes_pulled)
+            ),
+            None => String::from("never synchronized successfully"),
+        };
+
+        if let Some(last_error) = &self.last_error {
+            summary += &format!(", last attempt failed: {last_error}");
+        }
+
+        summary
+    }
+}
+
+/// A `ReceivedSnapshot` describes a completely received [`Snapshot`].
+#[derive(Clone, Debug, Eq, PartialEq, Serialize, Deserialize)]
+pub struct ReceivedSnapshot {
+    /// The received snapshot.
+    pub snapshot: Snapshot,
+    /// The time the transmission was completed.
+    pub received: NaiveDateTime,
+}
+
+/// A `Staleness` describes a client that hasn't pushed within its expected interval.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Staleness {
+    /// The name of the client node.
+    pub node_name: String,
+    /// The time of the last completed push, `None` if the client has never pushed.
+    pub last_push: Option<NaiveDateTime>,
+    /// The expected push interval.
+    pub push_interval: HumanDuration,
+}
+
+/// A `RevokeImpact` summarizes what revoking the grant of a client affects.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct RevokeImpact {
+    /// The name of the client node.
+    pub node_name: String,
+    /// The label of the revoked grant, `None` if all grants of the client are revoked.
+    pub label: Option<String>,
+    /// The time of the last successful authentication, `None` if unknown.
+    pub last_session: Option<NaiveDateTime>,
+    /// The time of the last completed push, `None` if unknown.
+    pub last_push: Option<NaiveDateTime>,
+    /// The volumes the client is allowed to push.
+    pub push: Vec<VolumeImpact>,
+    /// The volumes the client is allowed to pull.
+    pub pull: Vec<VolumeImpact>,
+}
+
+impl RevokeImpact {
+    /// Cross-references the grants of the specified client with the other grants,
+    /// the stored backups and the [`ServerState`]. If `label` is set, only the grant
+    /// with that label is considered revoked, all grants of the client otherwise.
+    /// Returns `None` if the client has no such grant.
+    pub fn analyze(
+        node_name: &str,
+        label: Option<&str>,
+        auth: &[RemoteNodeAuth],
+        backups: &[Snapshot],
+        server_state: &ServerState,
+    ) -> Option<Self> {
+        let (revoked, others): (Vec<_>, Vec<_>) = auth.iter().partition(|auth| match label {
+            Some(label) => auth.is(node_name, Some(label)),
+            None => auth.node_name == node_name,
+        });
+
+        if revoked.is_empty() {
+            return None;
+        }
+
+        let union = |permits: fn(&RemoteNodeAuth) -> &[VolumeSpec]| {
+            let mut volumes: Vec<VolumeSpec> = Vec::new();
+            for spec in revoked.iter().flat_map(|auth| permits(auth)) {
+                if !volumes.contains(spec) {
+                    volumes.push(spec.clone());
+                }
+            }
+
+            volumes
+        };
+
+        let impact = |volumes: &[VolumeSpec], permits: fn(&RemoteNodeAuth) -> &[VolumeSpec]| {
+            volumes
+                .iter()
+                .map(|volume| {
+                    let stored: Vec<_> = backups
+                        .iter()
+                        .filter(|backup| volume.matches(&backup.volume()))
+                        .collect();
+
+                    VolumeImpact {
+                        volume: volume.clone(),
+                        backups: stored.len(),
+                        latest: stored.iter().map(|backup| backup.taken()).max(),
+                        others: others
+                            .iter()
+                            .filter(|auth| permits(auth).iter().any(|spec| spec.covers(volume)))
+                            .map(|auth| auth.display_name())
+                            .collect(),
+                    }
+                })
+                .collect()
+        };
+
+        let client = server_state.clients.get(node_name);
+
+        Some(Self {
+            node_name: node_name.to_string(),
+            label: label.map(String::from),
+            last_session: client.and_then(|client| client.last_session),
+            last_push: client.and_then(|client| client.last_push()),
+            push: impact(&union(|auth| &auth.push), |auth| &auth.push),
+            pull: impact(&union(|auth| &auth.pull), |auth| &auth.pull),
+        })
+    }
+}
+
+/// A `VolumeImpact` describes a single volume covered by the grant of a client.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct VolumeImpact {
+    /// The volume or wildcard.
+    pub volume: VolumeSpec,
+    /// The number of backups of the volume stored on the local node.
+    pub backups: usize,
+    /// The time the latest stored backup of the volume was taken.
+    pub latest: Option<NaiveDateTime>,
+    /// The other clients granted the same permission on the volume.
+    pub others: Vec<String>,
+}
+
+impl VolumeImpact {
+    /// Reports whether no other client is granted the same permission on the volume.
+    pub fn is_exclusive(&self) -> bool {
+        self.others.is_empty()
+    }
+}
@@ -14,51 +14,131 @@
 // You should have received a copy of the GNU Affero General Public License
 // along with this program.  If not, see <https://www.gnu.org/licenses/>.
 
+use crate::proto::Snapshot;
 use crate::system;
 use crate::LocalNodeError;
 
-use std::collections::VecDeque;
-use std::io::{self, BufRead, Read, Write};
+use std::cmp;
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::path::Path;
+use std::thread;
+use std::time::{Duration, Instant};
 
 use chacha20::XChaCha20;
 use chacha20poly1305::aead::generic_array::GenericArray;
 use chacha20poly1305::aead::stream::{DecryptorBE32, EncryptorBE32};
-use chacha20poly1305::aead::OsRng;
+use chacha20poly1305::aead::{Aead, KeyInit, OsRng, Payload};
 use chacha20poly1305::consts::U19;
-use chacha20poly1305::{AeadCore, ChaChaPoly1305, Key, XChaCha20Poly1305};
+use chacha20poly1305::{AeadCore, ChaChaPoly1305, Key, XChaCha20Poly1305, XNonce};
+use sha2::{Digest, Sha256};
+use zstd::stream::raw::{Decoder, InBuffer, Operation, OutBuffer};
+use zstd::stream::read::Encoder;
+use zstd::zstd_safe::DCtx;
 
-/// The size of data chunks to encrypt or decrypt at a time in bytes (4096 KiB).
+/// The size of data chunks to encrypt or decrypt at a time in bytes (4 MiB).
+/// Part of the on-disk format of backups, independent of the network chunk size.
 pub const CHUNKSIZE: usize = 4096 * 1024;
+/// The default capacity of the buffer used to write received backups to disk
+/// in bytes (256 KiB).
+pub const RX_BUFSIZE: usize = 256 * 1024;
+/// The capacity of the buffers around btrfs send and receive processes
+/// and exported backup files in bytes (256 KiB). Reads and writes
+/// of whole chunks bypass them.
+pub const PIPE_BUFSIZE: usize = 256 * 1024;
+
+/// The length of the nonce at the start of every encrypted stream in bytes.
+const NONCE_LEN: usize = 19;
+/// The length of the authentication tag of every encrypted chunk in bytes.
+const TAG_LEN: usize = 16;
+/// The magic bytes following the nonce of streams that carry a header.
+const STREAM_HEADER_MAGIC: &[u8] = b"hbakhdr\0";
+/// The version of the stream header format written by [`SnapshotStream`].
+/// Every chunk authenticates the header as associated data
+/// so that the header cannot be stripped from the stream.
+const STREAM_HEADER_VERSION: u8 = 3;
+/// The version of the stream header of streams whose data is zstd-compressed
+/// before encryption. The header format is identical to [`STREAM_HEADER_VERSION`].
+const STREAM_HEADER_VERSION_ZSTD: u8 = 4;
+/// The version of the stream header of older streams whose chunks
+/// don't authenticate the header. They are still readable.
+const STREAM_HEADER_VERSION_UNBOUND: u8 = 1;
+/// The version of the stream header of older zstd-compressed streams
+/// whose chunks don't authenticate the header. They are still readable.
+const STREAM_HEADER_VERSION_UNBOUND_ZSTD: u8 = 2;
+/// The length of the unencrypted part of the stream header, the magic bytes
+/// followed by the version and the length of the encrypted part.
+const STREAM_HEADER_PREFIX_LEN: usize = STREAM_HEADER_MAGIC.len() + 1 + 2;
 
 /// A `SnapshotStream` is a wrapper around a btrfs stream
 /// that maps the stream to an encrypted version
-/// preceeded by a randomly generated nonce.
+/// preceeded by a randomly generated nonce and a header.
+/// The header authenticates the identifier of the [`Snapshot`]
+/// so that the stream cannot be restored as a different snapshot.
+/// The btrfs stream may be compressed before encryption,
+/// which is recorded in the header.
 pub struct SnapshotStream<B: BufRead> {
-    inner: B,
+    inner: Plaintext<B>,
     // The purpose of the `Option` is to allow `cipher` to be moved
     // when calling `encrypt_last` on it with just a mutable reference
     // to the `SnapshotStream` (so that `SnapshotStream::read_data`
     // can be called multiple times).
     cipher: Option<EncryptorBE32<XChaCha20Poly1305>>,
+    // The header authenticated by every chunk, see [`STREAM_HEADER_VERSION`].
+    aad: Vec<u8>,
     buf: Vec<u8>,
+    // The number of bytes of `buf` that have already been consumed.
+    pos: usize,
 }
 
 impl<B: BufRead> SnapshotStream<B> {
-    pub(crate) fn new<P: AsRef<[u8]>>(inner: B, passphrase: P) -> Result<Self, LocalNodeError> {
+    /// Wraps the provided btrfs stream, compressing it at the specified
+    /// zstd compression level if any.
+    pub(crate) fn new<P: AsRef<[u8]>>(
+        inner: B,
+        passphrase: P,
+        snapshot: &Snapshot,
+        compression_level: Option<i32>,
+    ) -> Result<Self, LocalNodeError> {
+        let (inner, version) = match compression_level {
+            Some(level) => (
+                Plaintext::Zstd(BufReader::with_capacity(
+                    CHUNKSIZE,
+                    Encoder::with_buffer(inner, level)?,
+                )),
+                STREAM_HEADER_VERSION_ZSTD,
+            ),
+            None => (Plaintext::Raw(inner), STREAM_HEADER_VERSION),
+        };
+
         let nonce = ChaChaPoly1305::<XChaCha20, U19>::generate_nonce(&mut OsRng);
         let mut key_array = [0; 32];
         system::hash_argon2id(&mut key_array, &nonce, passphrase)?;
         let key = Key::from_slice(&key_array);
         let cipher = EncryptorBE32::new(key, &nonce);
 
+        let header = XChaCha20Poly1305::new(key).encrypt(
+            &header_nonce(&nonce),
+            Payload {
+                msg: snapshot.to_string().as_bytes(),
+                aad: &header_aad(version),
+            },
+        )?;
+
         // Accomodate authentication tag (16 bytes).
         let mut buf = Vec::with_capacity(16 + CHUNKSIZE);
         buf.extend(nonce);
+        buf.extend(STREAM_HEADER_MAGIC);
+        buf.push(version);
+        buf.extend((header.len() as u16).to_be_bytes());
+        buf.extend(header);
 
         Ok(Self {
             inner,
             cipher: Some(cipher),
+            aad: buf[NONCE_LEN..].to_vec(),
             buf,
+            pos: 0,
         })
     }
 }
@@ -76,8 +156,12 @@ impl<B: BufRead> Read for SnapshotStream<B> {
 
 impl<B: BufRead> BufRead for SnapshotStream<B> {
     fn fill_buf(&mut self) -> io::Result<&[u8]> {
-        // Stable version of [`BufRead::has_data_left`] (tracking issue: #86423).
-        if self.buf.is_empty() && self.inner.fill_buf().map(|b| !b.is_empty())? {
+        // The last chunk is always produced, even if it is empty,
+        // so that truncation at a chunk boundary is detected.
+        if self.pos == self.buf.len() && self.cipher.is_some() {
+            self.buf.clear();
+            self.pos = 0;
+
             let mut chunk = Vec::with_capacity(CHUNKSIZE);
             self.inner
                 .by_ref()
@@ -90,7 +174,10 @@ impl<B: BufRead> BufRead for SnapshotStream<B> {
                     self.cipher
                         .as_mut()
                         .unwrap()
-                        .encrypt_next(chunk.as_slice())
+                        .encrypt_next(Payload {
+                            msg: chunk.as_slice(),
+                            aad: &self.aad,
+                        })
                         .map_err(io::Error::other)?,
                 );
             } else {
@@ -98,50 +185,102 @@ impl<B: BufRead> BufRead for SnapshotStream<B> {
                     self.cipher
                         .take()
                         .unwrap()
-                        .encrypt_last(chunk.as_slice())
+                        .encrypt_last(Payload {
+                            msg: chunk.as_slice(),
+                            aad: &self.aad,
+                        })
                         .map_err(io::Error::other)?,
                 );
             }
         }
 
-        Ok(&self.buf)
+        Ok(&self.buf[self.pos..])
     }
 
     fn consume(&mut self, amt: usize) {
-        // It's okay to panic if amt > self.buf.len()
+        // It's okay to panic if amt exceeds the remaining data
         // since [`BufRead::consume`] requires the caller to pass in
-        // amt <= self.buf.len() and silently clamping amt is probably bad
+        // amt <= self.fill_buf()?.len() and silently clamping amt is probably bad
         // behavior.
-        self.buf.drain(..amt);
+        assert!(self.pos + amt <= self.buf.len());
+        self.pos += amt;
+    }
+}
+
+/// The data of a [`SnapshotStream`] before encryption.
+enum Plaintext<B: BufRead> {
+    Raw(B),
+    Zstd(BufReader<Encoder<'static, B>>),
+}
+
+impl<B: BufRead> Read for Plaintext<B> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            Self::Raw(inner) => inner.read(buf),
+            Self::Zstd(inner) => inner.read(buf),
+        }
+    }
+}
+
+impl<B: BufRead> BufRead for Plaintext<B> {
+    fn fill_buf(&mut self) -> io::Result<&[u8]> {
+        match self {
+            Self::Raw(inner) => inner.fill_buf(),
+            Self::Zstd(inner) => inner.fill_buf(),
+        }
+    }
+
+    fn consume(&mut self, amt: usize) {
+        match self {
+            Self::Raw(inner) => inner.consume(amt),
+            Self::Zstd(inner) => inner.consume(amt),
+        }
     }
 }
 
 /// A `RecoveryStream` is a wrapper around an encrypted btrfs snapshot
-/// that maps the stream to a decrypted version without the nonce.
+/// that maps the stream to a decrypted version without the nonce and header.
+///
+/// The header has to identify the expected [`Snapshot`], otherwise the stream
+/// is rejected before any data is passed on. Streams created before the header
+/// was introduced don't have one and are decrypted without this check.
+/// Current streams authenticate the header in every chunk, so removing
+/// the header from them fails decryption instead of skipping the check.
+/// Compressed streams are decompressed after decryption.
 ///
 /// Dropping a `RecoveryStream` flushes the last chunk to the underlying [`Write`]
 /// ignoring any errors. You should handle errors where applicable
 /// by calling [`RecoveryStream::close`] manually before dropping the stream.
 pub struct RecoveryStream<W: Write, P: AsRef<[u8]>> {
-    inner: W,
+    inner: Decompressor<W>,
     passphrase: P,
+    snapshot: Snapshot,
     closed: bool,
     // The purpose of the `Option` is to allow `cipher` to be moved
     // when calling `encrypt_last` on it with just a mutable reference
     // to the `RecoveryStream` (so that `RecoveryStream::read_data`
     // can be called multiple times).
     cipher: Option<DecryptorBE32<XChaCha20Poly1305>>,
-    buf: VecDeque<u8>,
+    // The header authenticated by every chunk, empty for older streams.
+    aad: Vec<u8>,
+    buf: Vec<u8>,
 }
 
 impl<W: Write, P: AsRef<[u8]>> RecoveryStream<W, P> {
-    pub(crate) fn new(inner: W, passphrase: P) -> Self {
+    pub(crate) fn new(inner: W, passphrase: P, snapshot: Snapshot) -> Self {
         Self {
-            inner,
+            inner: Decompressor {
+                inner,
+                decoder: None,
+                buf: Vec::new(),
+                remaining: 0,
+            },
             passphrase,
+            snapshot,
             closed: false,
             cipher: None,
-            buf: VecDeque::with_capacity(16 + CHUNKSIZE), // Accomodate authentication tag (16 bytes).
+            aad: Vec::new(),
+            buf: Vec::with_capacity(2 * (16 + CHUNKSIZE)), // Accomodate authentication tag (16 bytes).
         }
     }
 
@@ -164,19 +303,29 @@ impl<W: Write, P: AsRef<[u8]>> RecoveryStream<W, P> {
 
         self.closed = true;
 
-        self.buf.make_contiguous();
-
-        // Read the authentication tag (16 bytes) too, otherwise decryption fails.
-        let mut chunk = vec![0; 16 + CHUNKSIZE];
-        let n = self.buf.read(&mut chunk)?;
-        chunk.truncate(n);
-
-        if let Some(cipher) = self.cipher.take() {
-            let plain = cipher.decrypt_last(chunk.as_slice())?;
-            self.inner.write_all(&plain)?;
+        // The buffer holds at most one chunk including its authentication tag.
+        match self.cipher.take() {
+            // Every stream ends with a last chunk, even if it holds no data.
+            Some(_) if self.buf.is_empty() => {
+                return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into());
+            }
+            Some(cipher) => {
+                let plain = cipher.decrypt_last(Payload {
+                    msg: self.buf.as_slice(),
+                    aad: &self.aad,
+                })?;
+                self.inner.write_all(&plain)?;
+                self.inner.finish()?;
+            }
+            // The header is incomplete.
+            None if self.buf.len() > NONCE_LEN => {
+                return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into());
+            }
+            // Uninitialized cipher is okay, nothing needs to be written.
+            None => {}
         }
 
-        // Uninitialized cipher is okay, nothing needs to be written.
+        self.buf.clear();
         Ok(())
     }
 }
@@ -187,33 +336,106 @@ impl<W: Write, P: AsRef<[u8]>> Write for RecoveryStream<W, P> {
             return Err(io::Error::from(io::ErrorKind::BrokenPipe));
         }
 
-        for byte in buf {
-            if let Some(cipher) = &mut self.cipher {
-                // Read the authentication tag (16 bytes) too, otherwise decryption fails.
-                if self.buf.len() >= 16 + CHUNKSIZE {
-                    let mut chunk = vec![0; 16 + CHUNKSIZE];
-                    self.buf.read_exact(&mut chunk)?;
+        self.buf.extend_from_slice(buf);
+
+        // The first chunk of streams without a header is longer than
+        // the unencrypted part of the header due to its authentication tag,
+        // so the presence of a header can be detected once enough data is buffered.
+        let mut start = 0;
+        if self.cipher.is_none() {
+            if self.buf.len() < NONCE_LEN + STREAM_HEADER_PREFIX_LEN {
+                return Ok(buf.len());
+            }
+
+            let prefix = &self.buf[NONCE_LEN..NONCE_LEN + STREAM_HEADER_PREFIX_LEN];
+            let header = match prefix.strip_prefix(STREAM_HEADER_MAGIC) {
+                Some(&[version, len_hi, len_lo]) => {
+                    if !is_supported(version) {
+                        return Err(io::Error::other(LocalNodeError::UnsupportedStreamHeader(
+                            version,
+                        )));
+                    }
+
+                    let end = NONCE_LEN
+                        + STREAM_HEADER_PREFIX_LEN
+                        + u16::from_be_bytes([len_hi, len_lo]) as usize;
+                    if self.buf.len() < end {
+                        return Ok(buf.len());
+                    }
 
-                    let plain = cipher
-                        .decrypt_next(chunk.as_slice())
-                        .map_err(io::Error::other)?;
-                    self.inner.write_all(&plain)?;
+                    Some((version, NONCE_LEN + STREAM_HEADER_PREFIX_LEN..end))
                 }
-            } else if self.buf.len() >= 19 {
-                let mut nonce_buf = [0; 19];
-                self.buf.read_exact(&mut nonce_buf)?;
+                _ => None,
+            };
 
-                let nonce = GenericArray::from_slice(&nonce_buf);
-                let mut key_array = [0; 32];
-                system::hash_argon2id(&mut key_array, nonce, &self.passphrase)
+            let nonce = GenericArray::from_slice(&self.buf[..NONCE_LEN]);
+            let mut key_array = [0; 32];
+            system::hash_argon2id(&mut key_array, nonce, &self.passphrase)
+                .map_err(io::Error::other)?;
+            let key = Key::from_slice(&key_array);
+
+            start = NONCE_LEN;
+            if let Some((version, range)) = header {
+                let identifier = XChaCha20Poly1305::new(key)
+                    .decrypt(
+                        &header_nonce(nonce),
+                        Payload {
+                            msg: &self.buf[range.clone()],
+                            aad: &header_aad(version),
+                        },
+                    )
                     .map_err(io::Error::other)?;
-                let key = Key::from_slice(&key_array);
-                self.cipher = Some(DecryptorBE32::new(key, nonce));
+
+                let identifier = String::from_utf8_lossy(&identifier);
+                if identifier != self.snapshot.to_string() {
+                    return Err(io::Error::other(LocalNodeError::SnapshotMismatch(
+                        self.snapshot.clone(),
+                        identifier.into_owned(),
+                    )));
+                }
+
+                if version == STREAM_HEADER_VERSION || version == STREAM_HEADER_VERSION_ZSTD {
+                    self.aad = self.buf[NONCE_LEN..range.end].to_vec();
+                }
+
+                if version == STREAM_HEADER_VERSION_ZSTD
+                    || version == STREAM_HEADER_VERSION_UNBOUND_ZSTD
+                {
+                    self.inner.decoder = Some(Decoder::new()?);
+                    self.inner.buf = vec![0; DCtx::out_size()];
+                }
+
+                start = range.end;
             }
 
-            self.buf.push_back(*byte);
+            self.cipher = Some(DecryptorBE32::new(key, nonce));
+        }
+
+        let cipher = self
+            .cipher
+            .as_mut()
+            .expect("cipher has just been initialized");
+
+        // Read the authentication tag (16 bytes) too, otherwise decryption fails.
+        // Keep the last complete chunk until more data follows
+        // because it has to be decrypted differently by `RecoveryStream::close`.
+        while self.buf.len() - start > 16 + CHUNKSIZE {
+            let end = start + 16 + CHUNKSIZE;
+
+            let plain = cipher
+                .decrypt_next(Payload {
+                    msg: &self.buf[start..end],
+                    aad: &self.aad,
+                })
+                .map_err(io::Error::other)?;
+            self.inner.write_all(&plain)?;
+
+            start = end;
         }
 
+        // Move the remaining partial chunk to the front once per call.
+        self.buf.drain(..start);
+
         Ok(buf.len())
     }
 
@@ -227,3 +449,692 @@ impl<W: Write, P: AsRef<[u8]>> Drop for RecoveryStream<W, P> {
         self.close().ok();
     }
 }
+
+/// Reports whether the stream read from the provided [`Read`] has a header
+/// identifying the specified [`Snapshot`] that is encrypted using the passphrase.
+/// Streams without a header never match. Only the header is read.
+pub fn is_encrypted_with<R: Read, P: AsRef<[u8]>>(
+    mut r: R,
+    passphrase: P,
+    snapshot: &Snapshot,
+) -> Result<bool, LocalNodeError> {
+    let mut prefix = [0; NONCE_LEN + STREAM_HEADER_PREFIX_LEN];
+    match r.read_exact(&mut prefix) {
+        Ok(_) => {}
+        Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(false),
+        Err(e) => return Err(e.into()),
+    }
+
+    let Some(&[version, len_hi, len_lo]) = prefix[NONCE_LEN..].strip_prefix(STREAM_HEADER_MAGIC)
+    else {
+        return Ok(false);
+    };
+
+    let mut header = vec![0; u16::from_be_bytes([len_hi, len_lo]) as usize];
+    match r.read_exact(&mut header) {
+        Ok(_) => {}
+        Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(false),
+        Err(e) => return Err(e.into()),
+    }
+
+    let nonce = &prefix[..NONCE_LEN];
+    let mut key_array = [0; 32];
+    system::hash_argon2id(&mut key_array, nonce, passphrase)?;
+    let key = Key::from_slice(&key_array);
+
+    let identifier = XChaCha20Poly1305::new(key).decrypt(
+        &header_nonce(nonce),
+        Payload {
+            msg: &header,
+            aad: &header_aad(version),
+        },
+    );
+
+    Ok(identifier.is_ok_and(|identifier| identifier == snapshot.to_string().as_bytes()))
+}
+
+/// A `StreamSummary` describes a well-formed encrypted stream,
+/// see [`validate_encrypted_stream`].
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct StreamSummary {
+    /// The version of the stream header, `None` if the stream doesn't have one.
+    pub header_version: Option<u8>,
+    /// The number of encrypted chunks following the nonce and header.
+    pub chunks: u64,
+    /// The length of the stream in bytes.
+    pub len: u64,
+    /// The SHA-256 digest of the stream.
+    pub digest: Vec<u8>,
+}
+
+/// Checks the structure of the encrypted stream at the specified path
+/// without decrypting it: It has to start with a nonce, optionally followed
+/// by a complete header of a supported version. The remaining data has to split
+/// into at least one chunk of the fixed encrypted chunk size, the last of which
+/// may be shorter but has to hold at least an authentication tag.
+///
+/// This doesn't require the passphrase, so it can be used on backups of other nodes.
+/// Returns the length and SHA-256 digest of the stream along with its structure.
+pub fn validate_encrypted_stream<P: AsRef<Path>>(path: P) -> Result<StreamSummary, LocalNodeError> {
+    let malformed = |reason: String| Err(LocalNodeError::MalformedStream(reason));
+
+    let mut r = DigestReader::new(BufReader::with_capacity(PIPE_BUFSIZE, File::open(path)?));
+
+    // Short streams are reported below rather than failing with an I/O error.
+    let mut prefix = Vec::with_capacity(NONCE_LEN + STREAM_HEADER_PREFIX_LEN);
+    r.by_ref()
+        .take((NONCE_LEN + STREAM_HEADER_PREFIX_LEN) as u64)
+        .read_to_end(&mut prefix)?;
+
+    if prefix.len() < NONCE_LEN {
+        return malformed(format!(
+            "{} bytes long, too short for the nonce",
+            prefix.len()
+        ));
+    }
+
+    let (header_version, mut data_len) = match prefix[NONCE_LEN..].strip_prefix(STREAM_HEADER_MAGIC)
+    {
+        Some(&[version, len_hi, len_lo]) => {
+            if !is_supported(version) {
+                return Err(LocalNodeError::UnsupportedStreamHeader(version));
+            }
+
+            let header_len = u16::from_be_bytes([len_hi, len_lo]) as u64;
+            if header_len < TAG_LEN as u64 {
+                return malformed(format!(
+                    "header of {} bytes is too short for an authentication tag",
+                    header_len
+                ));
+            }
+
+            if io::copy(&mut r.by_ref().take(header_len), &mut io::sink())? < header_len {
+                return malformed(String::from("header is truncated"));
+            }
+
+            (Some(version), 0)
+        }
+        // Streams without a header continue with the first chunk.
+        _ => (None, (prefix.len() - NONCE_LEN) as u64),
+    };
+
+    data_len += io::copy(&mut r, &mut io::sink())?;
+
+    // All chunks but the last one are complete. Even streams without any data
+    // end with a last chunk holding its authentication tag.
+    let chunk_len = (TAG_LEN + CHUNKSIZE) as u64;
+    let chunks = data_len.div_ceil(chunk_len);
+    if chunks == 0 {
+        return malformed(String::from("no chunks, the last chunk is missing"));
+    }
+
+    let last_len = data_len - (chunks - 1) * chunk_len;
+    if last_len < TAG_LEN as u64 {
+        return malformed(format!(
+            "last chunk of {} bytes is too short for an authentication tag",
+            last_len
+        ));
+    }
+
+    Ok(StreamSummary {
+        header_version,
+        chunks,
+        len: r.len,
+        digest: r.hasher.finalize().to_vec(),
+    })
+}
+
+/// A `DigestReader` computes the SHA-256 digest and length
+/// of the data read from the underlying [`Read`].
+struct DigestReader<R: Read> {
+    inner: R,
+    hasher: Sha256,
+    len: u64,
+}
+
+impl<R: Read> DigestReader<R> {
+    fn new(inner: R) -> Self {
+        Self {
+            inner,
+            hasher: Sha256::new(),
+            len: 0,
+        }
+    }
+}
+
+impl<R: Read> Read for DigestReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.hasher.update(&buf[..n]);
+        self.len += n as u64;
+
+        Ok(n)
+    }
+}
+
+/// A `Decompressor` passes the decrypted data of a [`RecoveryStream`] on,
+/// decompressing it first if the stream is compressed.
+struct Decompressor<W: Write> {
+    inner: W,
+    decoder: Option<Decoder<'static>>,
+    buf: Vec<u8>,
+    // The hint returned by the last decompression step,
+    // zero if the compressed data ended on a frame boundary.
+    remaining: usize,
+}
+
+impl<W: Write> Decompressor<W> {
+    fn write_all(&mut self, data: &[u8]) -> io::Result<()> {
+        let Some(decoder) = &mut self.decoder else {
+            return self.inner.write_all(data);
+        };
+
+        let mut input = InBuffer::around(data);
+        loop {
+            let mut output = OutBuffer::around(self.buf.as_mut_slice());
+            self.remaining = decoder.run(&mut input, &mut output)?;
+
+            let n = output.pos();
+            self.inner.write_all(&self.buf[..n])?;
+
+            // A full output buffer may leave decompressed data behind
+            // unless the frame is complete.
+            if input.pos() == data.len() && (n < self.buf.len() || self.remaining == 0) {
+                return Ok(());
+            }
+        }
+    }
+
+    /// Ensures that the compressed data isn't truncated.
+    fn finish(&mut self) -> io::Result<()> {
+        if self.decoder.is_some() && self.remaining > 0 {
+            return Err(io::Error::from(io::ErrorKind::UnexpectedEof));
+        }
+
+        Ok(())
+    }
+}
+
+/// Returns the nonce of the stream header derived from the nonce of the stream.
+/// The STREAM construction appends a 32-bit counter and a last block flag of 0 or 1
+/// to the nonce of the stream, so a flag of 2 never collides with the nonce of a chunk.
+fn header_nonce(nonce: &[u8]) -> XNonce {
+    let mut header_nonce = XNonce::default();
+    header_nonce[..NONCE_LEN].copy_from_slice(nonce);
+    header_nonce[NONCE_LEN..NONCE_LEN + 4].fill(0xff);
+    header_nonce[NONCE_LEN + 4] = 2;
+
+    header_nonce
+}
+
+/// Returns the associated data authenticated along with the stream header,
+/// the magic bytes followed by the version.
+fn header_aad(version: u8) -> Vec<u8> {
+    let mut aad = STREAM_HEADER_MAGIC.to_vec();
+    aad.push(version);
+
+    aad
+}
+
+/// Reports whether streams with the specified header version can be read.
+fn is_supported(version: u8) -> bool {
+    [
+        STREAM_HEADER_VERSION,
+        STREAM_HEADER_VERSION_ZSTD,
+        STREAM_HEADER_VERSION_UNBOUND,
+        STREAM_HEADER_VERSION_UNBOUND_ZSTD,
+    ]
+    .contains(&version)
+}
+
+/// The magic bytes at the start of every btrfs send stream.
+const SEND_STREAM_MAGIC: &[u8] = b"btrfs-stream\0";
+/// The length of the btrfs send stream header, the magic bytes followed by the version.
+const SEND_STREAM_HEADER_LEN: usize = SEND_STREAM_MAGIC.len() + 4;
+
+/// A `SendStreamCheck` passes a decrypted btrfs send stream through
+/// after verifying that the version recorded in its header can be received.
+/// Data that isn't a btrfs send stream is passed through unchanged.
+pub struct SendStreamCheck<W: Write> {
+    inner: W,
+    max_version: u32,
+    header: Option<Vec<u8>>,
+}
+
+impl<W: Write> SendStreamCheck<W> {
+    pub(crate) fn new(inner: W, max_version: u32) -> Self {
+        Self {
+            inner,
+            max_version,
+            header: Some(Vec::with_capacity(SEND_STREAM_HEADER_LEN)),
+        }
+    }
+}
+
+impl<W: Write> Write for SendStreamCheck<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let Some(header) = &mut self.header else {
+            return self.inner.write(buf);
+        };
+
+        let n = cmp::min(buf.len(), SEND_STREAM_HEADER_LEN - header.len());
+        header.extend_from_slice(&buf[..n]);
+
+        if header.len() == SEND_STREAM_HEADER_LEN {
+            if let Some(version) = header.strip_prefix(SEND_STREAM_MAGIC) {
+                let version = u32::from_le_bytes(
+                    version
+                        .try_into()
+                        .expect("header is followed by four bytes"),
+                );
+
+                if version > self.max_version {
+                    return Err(io::Error::other(LocalNodeError::UnsupportedSendProtocol(
+                        version,
+                        self.max_version,
+                    )));
+                }
+            }
+
+            self.inner.write_all(header)?;
+            self.header = None;
+        }
+
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// A `ThrottledReader` limits the rate at which data is read from the wrapped [`Read`]
+/// by sleeping whenever it gets ahead of the configured rate.
+pub struct ThrottledReader<R: Read> {
+    inner: R,
+    rate: u64,
+    start: Instant,
+    total: u64,
+}
+
+impl<R: Read> ThrottledReader<R> {
+    /// Wraps the provided [`Read`], limiting it to `rate` bytes per second.
+    pub fn new(inner: R, rate: u64) -> Self {
+        Self {
+            inner,
+            rate,
+            start: Instant::now(),
+            total: 0,
+        }
+    }
+}
+
+impl<R: Read> Read for ThrottledReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.total += n as u64;
+
+        let due = Duration::from_secs_f64(self.total as f64 / self.rate as f64);
+        let elapsed = self.start.elapsed();
+        if due > elapsed {
+            thread::sleep(due - elapsed);
+        }
+
+        Ok(n)
+    }
+}
+
+/// A `RateLimiter` is a token bucket limiting the rate at which data is sent.
+/// Unused capacity accumulates up to one second worth of data,
+/// allowing short bursts after idle periods.
+pub struct RateLimiter {
+    rate: u64,
+    tokens: f64,
+    last: Instant,
+}
+
+impl RateLimiter {
+    /// Constructs a new `RateLimiter` allowing `rate` bytes per second
+    /// that starts with an empty bucket.
+    pub fn new(rate: u64) -> Self {
+        Self {
+            rate: rate.max(1),
+            tokens: 0.0,
+            last: Instant::now(),
+        }
+    }
+
+    /// Takes the tokens for `n` bytes from the bucket,
+    /// sleeping until the bucket has refilled if it runs short.
+    pub fn take(&mut self, n: usize) {
+        let now = Instant::now();
+        let rate = self.rate as f64;
+
+        self.tokens = (self.tokens + (now - self.last).as_secs_f64() * rate).min(rate) - n as f64;
+        self.last = now;
+
+        // The debt is paid off by the time spent sleeping.
+        if self.tokens < 0.0 {
+            thread::sleep(Duration::from_secs_f64(-self.tokens / rate));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::{env, fs, process};
+
+    const KEY: [u8; 32] = [7; 32];
+    const NONCE: [u8; NONCE_LEN] = [3; NONCE_LEN];
+
+    /// Returns `len` bytes of data that differ between chunks.
+    fn data(len: usize) -> Vec<u8> {
+        (0..len).map(|i| (i % 251) as u8).collect()
+    }
+
+    /// Returns a `SnapshotStream` over the provided data
+    /// with a fixed key and without nonce or header, skipping key derivation.
+    fn snapshot_stream(data: &[u8]) -> SnapshotStream<&[u8]> {
+        SnapshotStream {
+            inner: Plaintext::Raw(data),
+            cipher: Some(EncryptorBE32::new(
+                Key::from_slice(&KEY),
+                GenericArray::from_slice(&NONCE),
+            )),
+            aad: Vec::new(),
+            buf: Vec::new(),
+            pos: 0,
+        }
+    }
+
+    /// Returns a `RecoveryStream` writing to the provided [`Write`] that expects
+    /// the chunks produced by [`snapshot_stream`], skipping key derivation.
+    fn recovery_stream<W: Write>(inner: W) -> RecoveryStream<W, &'static str> {
+        let mut recovery = RecoveryStream::new(
+            inner,
+            "",
+            Snapshot::try_from("node_subvol_full_20240101000000").unwrap(),
+        );
+        recovery.cipher = Some(DecryptorBE32::new(
+            Key::from_slice(&KEY),
+            GenericArray::from_slice(&NONCE),
+        ));
+
+        recovery
+    }
+
+    /// Copies `r` to `w` in pieces of the specified size.
+    fn copy_in_pieces<R: Read, W: Write>(mut r: R, mut w: W, piece: usize) {
+        let mut buf = vec![0; piece];
+        loop {
+            let n = r.read(&mut buf).unwrap();
+            if n == 0 {
+                break;
+            }
+
+            w.write_all(&buf[..n]).unwrap();
+        }
+    }
+
+    #[test]
+    fn streams_round_trip_in_pieces_of_any_size() {
+        let plain = data(CHUNKSIZE + CHUNKSIZE / 2);
+
+        for piece in [1, 7919, TAG_LEN + CHUNKSIZE, 2 * CHUNKSIZE] {
+            // Single bytes only for the start of the first chunk to keep the test fast.
+            let plain = if piece == 1 { &plain[..1024] } else { &plain };
+
+            let mut encrypted = Vec::new();
+            copy_in_pieces(snapshot_stream(plain), &mut encrypted, piece);
+
+            let mut decrypted = Vec::new();
+            let mut recovery = recovery_stream(&mut decrypted);
+            copy_in_pieces(encrypted.as_slice(), &mut recovery, piece);
+            recovery.close().unwrap();
+            drop(recovery);
+
+            assert_eq!(decrypted, plain, "pieces of {} bytes", piece);
+        }
+    }
+
+    /// Returns the start of a btrfs send stream of the specified version.
+    fn send_stream(version: u32) -> Vec<u8> {
+        let mut stream = SEND_STREAM_MAGIC.to_vec();
+        stream.extend(version.to_le_bytes());
+        stream.extend(b"commands");
+
+        stream
+    }
+
+    #[test]
+    fn send_streams_of_receivable_versions_pass() {
+        for (version, max_version) in [(1, 1), (1, 2), (2, 2)] {
+            let mut received = Vec::new();
+            let mut check = SendStreamCheck::new(&mut received, max_version);
+
+            // The header may be split across writes.
+            for byte in send_stream(version) {
+                check.write_all(&[byte]).unwrap();
+            }
+
+            assert_eq!(received, send_stream(version));
+        }
+    }
+
+    #[test]
+    fn send_streams_of_newer_versions_are_rejected() {
+        let mut received = Vec::new();
+        let mut check = SendStreamCheck::new(&mut received, 1);
+
+        let e = check.write_all(&send_stream(2)).unwrap_err();
+        assert!(matches!(
+            e.into_inner().unwrap().downcast_ref(),
+            Some(LocalNodeError::UnsupportedSendProtocol(2, 1))
+        ));
+        assert!(received.is_empty());
+    }
+
+    #[test]
+    fn other_data_passes_unchanged() {
+        let data = b"not a btrfs send stream at all";
+
+        let mut received = Vec::new();
+        SendStreamCheck::new(&mut received, 1)
+            .write_all(data)
+            .unwrap();
+
+        assert_eq!(received, data);
+    }
+
+    /// Returns the shortest time out of three runs of the provided function.
+    fn fastest<F: FnMut()>(mut f: F) -> Duration {
+        (0..3)
+            .map(|_| {
+                let start = Instant::now();
+                f();
+                start.elapsed()
+            })
+            .min()
+            .unwrap()
+    }
+
+    #[test]
+    #[cfg_attr(
+        debug_assertions,
+        ignore = "the cipher dominates without optimizations, run using `cargo test --release`"
+    )]
+    fn streams_keep_up_with_the_cipher() {
+        let plain = data(16 * CHUNKSIZE + 1);
+        let mut encrypted = Vec::with_capacity(plain.len() + 17 * TAG_LEN);
+
+        // Encrypting and decrypting the chunks directly is the upper bound.
+        let encrypt = fastest(|| {
+            encrypted.clear();
+
+            let mut cipher = EncryptorBE32::<XChaCha20Poly1305>::new(
+                Key::from_slice(&KEY),
+                GenericArray::from_slice(&NONCE),
+            );
+            let (chunks, last) = plain.split_at(16 * CHUNKSIZE);
+            for chunk in chunks.chunks(CHUNKSIZE) {
+                encrypted.extend(cipher.encrypt_next(chunk).unwrap());
+            }
+            encrypted.extend(cipher.encrypt_last(last).unwrap());
+        });
+        let decrypt = fastest(|| {
+            let mut cipher = DecryptorBE32::<XChaCha20Poly1305>::new(
+                Key::from_slice(&KEY),
+                GenericArray::from_slice(&NONCE),
+            );
+            let (chunks, last) = encrypted.split_at(16 * (TAG_LEN + CHUNKSIZE));
+            for chunk in chunks.chunks(TAG_LEN + CHUNKSIZE) {
+                io::sink()
+                    .write_all(&cipher.decrypt_next(chunk).unwrap())
+                    .unwrap();
+            }
+            io::sink()
+                .write_all(&cipher.decrypt_last(last).unwrap())
+                .unwrap();
+        });
+
+        // Network reads and pipe writes are much smaller than a chunk.
+        let snapshot = fastest(|| {
+            encrypted.clear();
+            copy_in_pieces(snapshot_stream(&plain), &mut encrypted, 64 * 1024);
+        });
+        let recovery = fastest(|| {
+            let mut recovery = recovery_stream(io::sink());
+            copy_in_pieces(encrypted.as_slice(), &mut recovery, 64 * 1024);
+            recovery.close().unwrap();
+        });
+
+        let slack = Duration::from_millis(20);
+        assert!(
+            snapshot < encrypt * 3 / 2 + slack,
+            "encryption took {:?}, the cipher alone {:?}",
+            snapshot,
+            encrypt
+        );
+        assert!(
+            recovery < decrypt * 3 / 2 + slack,
+            "decryption took {:?}, the cipher alone {:?}",
+            recovery,
+            decrypt
+        );
+    }
+
+    #[test]
+    fn empty_streams_end_with_a_last_chunk() {
+        let mut encrypted = Vec::new();
+        copy_in_pieces(snapshot_stream(&[]), &mut encrypted, CHUNKSIZE);
+        assert_eq!(encrypted.len(), TAG_LEN);
+
+        let mut decrypted = Vec::new();
+        let mut recovery = recovery_stream(&mut decrypted);
+        recovery.write_all(&encrypted).unwrap();
+        recovery.close().unwrap();
+        drop(recovery);
+        assert!(decrypted.is_empty());
+
+        // Streams truncated at a chunk boundary lack the last chunk.
+        let mut recovery = recovery_stream(io::sink());
+        assert!(recovery.close().is_err());
+    }
+
+    /// Writes the specified encrypted stream to a temporary file
+    /// and validates its structure.
+    fn validate(name: &str, stream: &[u8]) -> Result<StreamSummary, LocalNodeError> {
+        let path = env::temp_dir().join(format!("hbak-stream-{}-{}", process::id(), name));
+        fs::write(&path, stream).unwrap();
+
+        let summary = validate_encrypted_stream(&path);
+        fs::remove_file(&path).unwrap();
+
+        summary
+    }
+
+    /// Returns the nonce and a header of the specified length
+    /// preceding the chunks of an encrypted stream.
+    fn prefix(header_len: u16) -> Vec<u8> {
+        let mut prefix = NONCE.to_vec();
+        prefix.extend(STREAM_HEADER_MAGIC);
+        prefix.push(STREAM_HEADER_VERSION);
+        prefix.extend(header_len.to_be_bytes());
+        prefix.extend(vec![0; header_len as usize]);
+        prefix
+    }
+
+    #[test]
+    fn streams_without_chunks_are_malformed() {
+        for (name, stream) in [
+            ("nonce", NONCE.to_vec()),
+            ("header", prefix(TAG_LEN as u16 + 8)),
+        ] {
+            assert!(
+                matches!(
+                    validate(name, &stream),
+                    Err(LocalNodeError::MalformedStream(_))
+                ),
+                "stream of the {} alone",
+                name
+            );
+        }
+    }
+
+    #[test]
+    fn streams_end_with_a_complete_tag() {
+        let header = prefix(TAG_LEN as u16 + 8);
+
+        for (last_len, valid) in [(TAG_LEN - 1, false), (TAG_LEN, true), (TAG_LEN + 1, true)] {
+            for full_chunks in [0, 2] {
+                let mut stream = header.clone();
+                stream.extend(vec![0; full_chunks * (TAG_LEN + CHUNKSIZE) + last_len]);
+
+                let result = validate("tag", &stream);
+                assert_eq!(
+                    result.is_ok(),
+                    valid,
+                    "last chunk of {} bytes after {} chunks",
+                    last_len,
+                    full_chunks
+                );
+                if let Ok(summary) = result {
+                    assert_eq!(summary.header_version, Some(STREAM_HEADER_VERSION));
+                    assert_eq!(summary.chunks, full_chunks as u64 + 1);
+                    assert_eq!(summary.len, stream.len() as u64);
+                }
+            }
+        }
+
+        // A complete last chunk needs no further chunk.
+        let mut stream = header;
+        stream.extend(vec![0; TAG_LEN + CHUNKSIZE]);
+        assert_eq!(validate("complete", &stream).unwrap().chunks, 1);
+    }
+
+    #[test]
+    fn chunks_are_bound_to_the_header() {
+        let plain = data(1024);
+        let header = prefix(TAG_LEN as u16 + 8).split_off(NONCE_LEN);
+
+        let mut snapshot = snapshot_stream(&plain);
+        snapshot.aad = header.clone();
+        let mut encrypted = Vec::new();
+        copy_in_pieces(snapshot, &mut encrypted, CHUNKSIZE);
+
+        let mut decrypted = Vec::new();
+        let mut recovery = recovery_stream(&mut decrypted);
+        recovery.aad = header;
+        recovery.write_all(&encrypted).unwrap();
+        recovery.close().unwrap();
+        drop(recovery);
+        assert_eq!(decrypted, plain);
+
+        // Stripping the header makes the chunks look like an older stream.
+        let mut recovery = recovery_stream(io::sink());
+        recovery.write_all(&encrypted).unwrap();
+        assert!(recovery.close().is_err());
+    }
+}
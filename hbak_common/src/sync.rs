@@ -0,0 +1,900 @@
+// hbak_common is the main hbak library implementing the protocol shared logic.
+// Copyright (C) 2024  Himbeer <himbeerserverde@gmail.com>
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use crate::conn::{Active, Idle, StreamConn, SyncStats, Window};
+use crate::message::{Challenge, Credentials, Integrity, SyncInfo, Target};
+use crate::proto::{LatestSnapshots, LocalNode, Node, Snapshot, Volume, VolumeSpec};
+use crate::stream;
+use crate::system;
+use crate::{ChainError, LocalNodeError, NetworkError, RemoteError, SnapshotParseError};
+
+use std::cmp;
+use std::collections::{HashMap, HashSet};
+use std::path::{Path, PathBuf};
+use std::sync::{LazyLock, Mutex};
+
+use chrono::prelude::*;
+
+/// The role of the [`LocalNode`] in a [`SyncSession`].
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Role {
+    /// The local node has connected to the remote node (`hbak`).
+    Initiator,
+    /// The remote node has connected to the local node (`hbakd`).
+    /// Remote nodes may restore their own volumes
+    /// and received snapshots must have plausible timestamps.
+    Responder,
+}
+
+/// A `SyncEvent` notifies the frontend of the progress of a [`SyncSession`].
+#[derive(Debug)]
+pub enum SyncEvent<'a> {
+    /// The snapshot is queued for transmission.
+    Queued(&'a Snapshot),
+    /// The remote node is about to transmit the snapshot
+    /// and its incomplete backup is about to be created.
+    Accepted(&'a Snapshot),
+    /// The transmission of the snapshot by the remote node was refused.
+    Rejected(&'a Snapshot, &'a RemoteError),
+    /// The remote node offered a snapshot with an invalid node or subvolume name.
+    /// Its transmission was refused.
+    Invalid(&'a SnapshotParseError),
+    /// The snapshot is being received.
+    Receiving(&'a Snapshot),
+    /// The snapshot has been received completely.
+    Received(&'a Snapshot),
+}
+
+/// A `SyncSession` holds the push and pull decision logic shared by all frontends.
+/// It builds the [`SyncInfo`], enforces the permissions of the remote node
+/// and constructs the transmission queue.
+///
+/// The events of the session are reported to the provided callback.
+pub struct SyncSession<'a, E> {
+    local_node: &'a LocalNode,
+    role: Role,
+    push: Vec<VolumeSpec>,
+    pull: Vec<VolumeSpec>,
+    events: E,
+    full_reconcile: bool,
+    reception_gate: Option<&'a (dyn Fn() -> Result<(), RemoteError> + Sync)>,
+    /// The latest local snapshots announced to the remote node.
+    announced: Mutex<HashMap<Volume, LatestSnapshots>>,
+    /// The local snapshots announced to the remote node in a full reconciliation.
+    announced_held: Mutex<Option<HashMap<Volume, Vec<Snapshot>>>>,
+    /// The transmissions the remote node started, including refused ones.
+    offered: Mutex<Vec<Snapshot>>,
+    /// The transmissions that were received completely.
+    received: Mutex<Vec<Snapshot>>,
+    /// The streaming paths the session is receiving into.
+    claims: StreamingClaims,
+}
+
+/// The streaming paths any [`SyncSession`] of this process is receiving into.
+/// Concurrent receptions of the same snapshot would interleave their writes.
+static STREAMING: LazyLock<Mutex<HashSet<PathBuf>>> = LazyLock::new(Mutex::default);
+
+/// `StreamingClaims` are the streaming paths claimed by a single session.
+/// The remaining claims are released when it is dropped.
+#[derive(Debug, Default)]
+struct StreamingClaims {
+    paths: Mutex<Vec<PathBuf>>,
+}
+
+impl StreamingClaims {
+    /// Claims the specified streaming path unless another session already did.
+    /// Returns whether the claim succeeded.
+    fn claim(&self, path: PathBuf) -> bool {
+        if !STREAMING.lock().unwrap().insert(path.clone()) {
+            return false;
+        }
+
+        self.paths.lock().unwrap().push(path);
+        true
+    }
+
+    /// Releases the claim of the specified streaming path if the session holds it.
+    fn release(&self, path: &Path) {
+        let mut paths = self.paths.lock().unwrap();
+        if let Some(i) = paths.iter().position(|claimed| claimed == path) {
+            paths.swap_remove(i);
+            STREAMING.lock().unwrap().remove(path);
+        }
+    }
+}
+
+impl Drop for StreamingClaims {
+    fn drop(&mut self) {
+        let mut streaming = STREAMING.lock().unwrap();
+        for path in self.paths.get_mut().unwrap().drain(..) {
+            streaming.remove(&path);
+        }
+    }
+}
+
+/// Concatenates the transmission chains of the individual volumes into a queue.
+/// Each chain is sorted so that every parent is sent before its children
+/// because receivers may apply incremental snapshots to their parent right away.
+/// Snapshots queued more than once, e.g. restored volumes of a remote node
+/// that is also granted to pull them, are only sent the first time
+/// as the receiver refuses to overwrite complete backups.
+pub fn order_queue<I: IntoIterator<Item = Vec<Snapshot>>>(chains: I) -> Vec<Snapshot> {
+    let mut seen = HashSet::new();
+    let mut queue = Vec::new();
+
+    for mut chain in chains {
+        chain.sort_by_key(|snapshot| (snapshot.taken(), snapshot.is_incremental()));
+        queue.extend(
+            chain
+                .into_iter()
+                .filter(|snapshot| seen.insert(snapshot.clone())),
+        );
+    }
+
+    queue
+}
+
+/// `RestoreChains` track the snapshots of each [`Volume`] that have been applied
+/// while restoring them, so that incremental snapshots are only accepted
+/// if their parent, the latest snapshot taken before them, has been applied.
+///
+/// The parent of an incremental snapshot can't have been applied if no full snapshot
+/// has been applied, if a later snapshot has already been applied
+/// or if a snapshot taken after the latest applied one was refused.
+#[derive(Debug, Default)]
+pub struct RestoreChains {
+    chains: Mutex<HashMap<Volume, Chain>>,
+}
+
+/// The applied and refused snapshots of a single [`Volume`], see [`RestoreChains`].
+#[derive(Debug)]
+struct Chain {
+    last_full: NaiveDateTime,
+    latest: NaiveDateTime,
+    refused: Vec<NaiveDateTime>,
+}
+
+impl Chain {
+    fn new(latest_snapshots: &LatestSnapshots) -> Self {
+        Self {
+            last_full: latest_snapshots.last_full,
+            latest: cmp::max(
+                latest_snapshots.last_full,
+                latest_snapshots.last_incremental,
+            ),
+            refused: Vec::new(),
+        }
+    }
+}
+
+impl RestoreChains {
+    /// Constructs new `RestoreChains` starting at the specified latest local snapshots.
+    pub fn new<'a, I>(volumes: I) -> Self
+    where
+        I: IntoIterator<Item = (&'a Volume, &'a LatestSnapshots)>,
+    {
+        Self {
+            chains: Mutex::new(
+                volumes
+                    .into_iter()
+                    .map(|(volume, latest_snapshots)| {
+                        (volume.clone(), Chain::new(latest_snapshots))
+                    })
+                    .collect(),
+            ),
+        }
+    }
+
+    /// Checks whether the specified snapshot can be applied.
+    /// Refused snapshots break the chain of the incremental snapshots following them.
+    pub fn check(&self, snapshot: &Snapshot) -> Result<(), ChainError> {
+        let mut chains = self.chains.lock().unwrap();
+        let chain = chains
+            .entry(snapshot.volume())
+            .or_insert_with(|| Chain::new(&LatestSnapshots::none()));
+
+        if !snapshot.is_incremental() {
+            return Ok(());
+        }
+
+        let result = if chain.last_full == NaiveDateTime::MIN {
+            Err(ChainError::NoFull(snapshot.subvol().to_string()))
+        } else if chain.latest >= snapshot.taken() {
+            Err(ChainError::OutOfOrder(chain.latest))
+        } else if let Some(refused) = chain
+            .refused
+            .iter()
+            .filter(|&&refused| refused > chain.latest && refused < snapshot.taken())
+            .max()
+        {
+            Err(ChainError::Broken(*refused))
+        } else {
+            Ok(())
+        };
+
+        if result.is_err() {
+            chain.refused.push(snapshot.taken());
+        }
+
+        result
+    }
+
+    /// Records that the specified snapshot has been applied completely.
+    pub fn applied(&self, snapshot: &Snapshot) {
+        let mut chains = self.chains.lock().unwrap();
+        let chain = chains
+            .entry(snapshot.volume())
+            .or_insert_with(|| Chain::new(&LatestSnapshots::none()));
+
+        if !snapshot.is_incremental() {
+            chain.last_full = cmp::max(chain.last_full, snapshot.taken());
+        }
+        chain.latest = cmp::max(chain.latest, snapshot.taken());
+    }
+}
+
+/// A `SyncPlan` is the outcome of the metadata synchronization of a [`SyncSession`].
+/// It can be inspected before transferring any data.
+pub struct SyncPlan {
+    stream_conn: StreamConn<Active>,
+    queue: Vec<Snapshot>,
+    remote_volumes: HashMap<Volume, LatestSnapshots>,
+    send_protocol: u32,
+    compression: bool,
+}
+
+impl SyncPlan {
+    /// Returns the snapshots that will be sent to the remote node.
+    pub fn queue(&self) -> &[Snapshot] {
+        &self.queue
+    }
+
+    /// Returns the latest snapshots the remote node announced
+    /// of the volumes it may receive.
+    pub fn remote_volumes(&self) -> &HashMap<Volume, LatestSnapshots> {
+        &self.remote_volumes
+    }
+
+    /// Returns the btrfs send stream version local snapshots are sent with.
+    pub fn send_protocol(&self) -> u32 {
+        self.send_protocol
+    }
+}
+
+impl<'a, E> SyncSession<'a, E>
+where
+    E: Fn(SyncEvent) + Sync,
+{
+    /// Constructs a new `SyncSession` that may send the volumes matched by `push`
+    /// and receive the volumes matched by `pull`.
+    /// Volumes owned by the local node are never received.
+    pub fn new(
+        local_node: &'a LocalNode,
+        role: Role,
+        push: Vec<VolumeSpec>,
+        pull: Vec<VolumeSpec>,
+        events: E,
+    ) -> Self {
+        Self {
+            local_node,
+            role,
+            push,
+            pull,
+            events,
+            full_reconcile: false,
+            reception_gate: None,
+            announced: Mutex::default(),
+            announced_held: Mutex::default(),
+            offered: Mutex::default(),
+            received: Mutex::default(),
+            claims: StreamingClaims::default(),
+        }
+    }
+
+    /// Requests a full reconciliation as the [`Role::Initiator`]: Both nodes
+    /// announce all snapshots they hold of the volumes they receive, and each sends
+    /// the snapshots the other is missing rather than only those newer than
+    /// its latest ones. This catches up on transmissions missed in the past
+    /// at the cost of a larger metadata exchange. Responders reconcile fully
+    /// whenever the initiator requests it.
+    pub fn with_full_reconcile(mut self, full_reconcile: bool) -> Self {
+        self.full_reconcile = full_reconcile;
+        self
+    }
+
+    /// Consults the `gate` before receiving each snapshot sent by the remote node,
+    /// refusing the transmission with the returned error, e.g. during maintenance.
+    /// The refusal is reported as [`SyncEvent::Rejected`]
+    /// and the remote node skips to its next transmission.
+    pub fn with_reception_gate(
+        mut self,
+        gate: &'a (dyn Fn() -> Result<(), RemoteError> + Sync),
+    ) -> Self {
+        self.reception_gate = Some(gate);
+        self
+    }
+
+    /// Exchanges metadata with the remote node as the [`Role::Initiator`].
+    pub fn initiate(&self, stream_conn: StreamConn<Idle>) -> Result<SyncPlan, NetworkError> {
+        let remote_node_name = stream_conn.remote_node_name().to_string();
+        let (stream_conn, remote_sync_info) =
+            stream_conn.meta_sync(self.local_sync_info(self.full_reconcile)?)?;
+
+        Ok(SyncPlan {
+            send_protocol: self.send_protocol(&remote_sync_info),
+            compression: remote_sync_info.compression,
+            remote_volumes: remote_sync_info.volumes.clone(),
+            queue: self.queue(&remote_node_name, remote_sync_info)?,
+            stream_conn,
+        })
+    }
+
+    /// Exchanges metadata with the remote node as the [`Role::Responder`],
+    /// proves possession of a backup using the `prove` closure,
+    /// lists the available snapshots using the `list` closure,
+    /// deletes backups using the `prune` closure
+    /// or replaces the credentials of the remote node using the `rotate` closure.
+    /// Returns `None` if the remote node only requested proofs, a listing,
+    /// deletions or a credential rotation.
+    pub fn respond<V, L, P, R>(
+        &self,
+        stream_conn: StreamConn<Idle>,
+        prove: V,
+        list: L,
+        prune: P,
+        rotate: R,
+    ) -> Result<Option<SyncPlan>, NetworkError>
+    where
+        V: Fn(&Challenge) -> Result<Vec<u8>, RemoteError>,
+        L: FnOnce() -> Result<Vec<Snapshot>, RemoteError>,
+        P: FnOnce(Vec<Snapshot>) -> Vec<Result<(), RemoteError>>,
+        R: FnOnce(Credentials) -> Result<(), RemoteError>,
+    {
+        let remote_node_name = stream_conn.remote_node_name().to_string();
+        let (stream_conn, remote_sync_info) = match stream_conn.meta_sync_or_verify(
+            |remote_sync_info| Ok(self.local_sync_info(remote_sync_info.held.is_some())?),
+            prove,
+            list,
+            prune,
+            rotate,
+        )? {
+            Some(result) => result,
+            None => return Ok(None),
+        };
+
+        Ok(Some(SyncPlan {
+            send_protocol: self.send_protocol(&remote_sync_info),
+            compression: remote_sync_info.compression,
+            remote_volumes: remote_sync_info.volumes.clone(),
+            queue: self.queue(&remote_node_name, remote_sync_info)?,
+            stream_conn,
+        }))
+    }
+
+    /// Ends the session without transmitting the queued snapshots,
+    /// e.g. after inspecting the [`SyncPlan`]. Snapshots the remote node
+    /// sends are still received, so nothing should be pulled to end it right away.
+    pub fn finish(&self, mut plan: SyncPlan) -> Result<SyncStats, NetworkError> {
+        plan.queue.clear();
+        self.data_sync(plan, &Window::default())
+    }
+
+    /// Transmits the queued snapshots and receives the snapshots
+    /// sent by the remote node until the `Window` closes.
+    /// See [`StreamConn::data_sync_until`] for details.
+    pub fn data_sync(&self, plan: SyncPlan, window: &Window) -> Result<SyncStats, NetworkError> {
+        let local_node = self.local_node;
+
+        let mut tx = Vec::new();
+        for snapshot in plan.queue {
+            let r = local_node.export(&snapshot, plan.send_protocol, plan.compression)?;
+            (self.events)(SyncEvent::Queued(&snapshot));
+            tx.push((r, snapshot));
+        }
+
+        let rx_setup = |target: &Target| {
+            let snapshot = &target.snapshot;
+
+            // Storage paths are derived from the identity chosen by the remote node.
+            if let Err(e) = snapshot.validate() {
+                (self.events)(SyncEvent::Invalid(&e));
+                return Err(RemoteError::AccessDenied);
+            }
+
+            self.offered.lock().unwrap().push(snapshot.clone());
+
+            if snapshot.node_name() == local_node.name() || !self.pulls(&snapshot.volume()) {
+                return Err(RemoteError::AccessDenied);
+            }
+
+            if let Some(Err(e)) = self.reception_gate.map(|gate| gate()) {
+                (self.events)(SyncEvent::Rejected(snapshot, &e));
+                return Err(e);
+            }
+
+            if self.role == Role::Responder {
+                let now = Utc::now().naive_utc();
+                if !local_node.is_plausible(snapshot, now) {
+                    let e = RemoteError::ImplausibleTimestamp(now);
+                    (self.events)(SyncEvent::Rejected(snapshot, &e));
+                    return Err(e);
+                }
+            }
+
+            if let Err(e) = local_node.check_space(target.expected_len) {
+                let e = match e {
+                    LocalNodeError::InsufficientSpace(available, required) => {
+                        RemoteError::InsufficientSpace(available, required)
+                    }
+                    _ => RemoteError::RxError,
+                };
+                (self.events)(SyncEvent::Rejected(snapshot, &e));
+                return Err(e);
+            }
+
+            let streaming_path = snapshot.streaming_path(local_node.layout());
+            if !self.claims.claim(streaming_path.clone()) {
+                let e = RemoteError::AlreadyStreaming;
+                (self.events)(SyncEvent::Rejected(snapshot, &e));
+                return Err(e);
+            }
+
+            (self.events)(SyncEvent::Accepted(snapshot));
+
+            let w = local_node.receive_backup(snapshot).map_err(|e| {
+                self.claims.release(&streaming_path);
+                match e {
+                    LocalNodeError::SnapshotExists(_) => RemoteError::Immutable,
+                    _ => RemoteError::RxError,
+                }
+            })?;
+
+            (self.events)(SyncEvent::Receiving(snapshot));
+
+            Ok(w)
+        };
+
+        // The data of other nodes can't be decrypted without their passphrase,
+        // but its structure is checked so that broken backups aren't committed.
+        let rx_finish = |snapshot: Snapshot, integrity: &Integrity| {
+            let streaming_path = snapshot.streaming_path(local_node.layout());
+            let committed = stream::validate_encrypted_stream(&streaming_path)
+                .map_err(|e| match e {
+                    LocalNodeError::MalformedStream(_)
+                    | LocalNodeError::UnsupportedStreamHeader(_) => RemoteError::IntegrityFailure,
+                    _ => RemoteError::RxError,
+                })
+                .and_then(|summary| {
+                    // The file may differ from what was received if writing it failed.
+                    if summary.len != integrity.len || summary.digest != integrity.digest {
+                        return Err(RemoteError::IntegrityFailure);
+                    }
+
+                    local_node
+                        .commit_validated_backup(&snapshot, &summary)
+                        .map_err(|_| RemoteError::RxError)
+                });
+            self.claims.release(&streaming_path);
+
+            if let Err(e) = committed {
+                (self.events)(SyncEvent::Rejected(&snapshot, &e));
+                return Err(e);
+            }
+
+            (self.events)(SyncEvent::Received(&snapshot));
+            self.received.lock().unwrap().push(snapshot);
+
+            Ok(())
+        };
+
+        let rx_discard = |snapshot: Snapshot| {
+            let _ = local_node.discard_backup(&snapshot);
+            self.claims
+                .release(&snapshot.streaming_path(local_node.layout()));
+        };
+
+        let estimate = |snapshot: &Snapshot| {
+            local_node
+                .estimate_send_size(snapshot)
+                .ok()
+                .map(|estimate| estimate.bytes)
+        };
+
+        plan.stream_conn
+            .data_sync_until(tx, rx_setup, rx_finish, rx_discard, window, estimate)
+    }
+
+    /// Returns the latest local snapshots of the volumes the remote node may push,
+    /// along with all of them if `full_reconcile` is set.
+    /// Wildcards are resolved against the locally stored backups
+    /// and announced as patterns to cover volumes that aren't known yet.
+    fn local_sync_info(&self, full_reconcile: bool) -> Result<SyncInfo, LocalNodeError> {
+        let local_node = self.local_node;
+
+        let mut candidates: HashSet<_> = self
+            .pull
+            .iter()
+            .filter_map(|spec| spec.volume())
+            .cloned()
+            .collect();
+        if self.pull.iter().any(|spec| spec.volume().is_none()) {
+            candidates.extend(
+                local_node
+                    .all_backups(None)?
+                    .into_iter()
+                    .map(|backup| backup.volume())
+                    .filter(|volume| self.pulls(volume)),
+            );
+        }
+
+        let mut volumes = HashMap::new();
+        for volume in candidates
+            .into_iter()
+            .filter(|volume| volume.node_name() != local_node.name())
+        {
+            let latest_snapshots = local_node.latest_snapshots(volume.clone())?;
+            volumes.insert(volume, latest_snapshots);
+        }
+
+        let held = if full_reconcile {
+            let mut held = HashMap::new();
+            for volume in volumes.keys() {
+                held.insert(volume.clone(), local_node.all_backups(Some(volume))?);
+            }
+
+            Some(held)
+        } else {
+            None
+        };
+
+        *self.announced.lock().unwrap() = volumes.clone();
+        *self.announced_held.lock().unwrap() = held.clone();
+
+        Ok(SyncInfo {
+            volumes,
+            patterns: self.wildcards().map(|spec| spec.to_string()).collect(),
+            receive_protocol: local_node.send_support().receive,
+            chunk_size: local_node.chunk_size(),
+            compression: true,
+            held,
+        })
+    }
+
+    /// Returns the btrfs send stream version both nodes can receive,
+    /// preferring the one configured locally.
+    fn send_protocol(&self, remote_sync_info: &SyncInfo) -> u32 {
+        system::negotiate_send_protocol(
+            self.local_node.send_protocol(),
+            remote_sync_info.receive_protocol,
+        )
+    }
+
+    /// Reports whether the remote node may push the specified [`Volume`].
+    fn pulls(&self, volume: &Volume) -> bool {
+        self.pull.iter().any(|spec| spec.matches(volume))
+    }
+
+    /// Reports whether the specified [`Volume`] may be sent to the remote node.
+    fn pushes(&self, volume: &Volume) -> bool {
+        self.push.iter().any(|spec| spec.matches(volume))
+    }
+
+    /// Returns the pull permissions that match more than a single [`Volume`].
+    fn wildcards(&self) -> impl Iterator<Item = &VolumeSpec> {
+        self.pull.iter().filter(|spec| spec.volume().is_none())
+    }
+
+    /// Compares the transmissions of the remote node with the gaps
+    /// announced to it. Can be called at any time, even after a failed session.
+    pub fn audit(&self) -> SyncAudit {
+        let wildcards: Vec<_> = self.wildcards().cloned().collect();
+
+        SyncAudit::new(
+            self.local_node.name(),
+            &self.announced.lock().unwrap(),
+            self.announced_held.lock().unwrap().as_ref(),
+            &wildcards,
+            &self.offered.lock().unwrap(),
+            &self.received.lock().unwrap(),
+        )
+    }
+
+    /// Returns the snapshots the remote node is missing
+    /// out of the volumes it is allowed to receive.
+    fn queue(
+        &self,
+        remote_node_name: &str,
+        remote_sync_info: SyncInfo,
+    ) -> Result<Vec<Snapshot>, LocalNodeError> {
+        let local_node = self.local_node;
+
+        let mut volumes = remote_sync_info.volumes;
+        let held = remote_sync_info.held.unwrap_or_default();
+
+        // Volumes matched by a pattern that the remote node doesn't know yet.
+        // Patterns never cover the own volumes of the remote node.
+        let patterns: Vec<_> = remote_sync_info
+            .patterns
+            .iter()
+            .filter_map(|pattern| VolumeSpec::try_from(pattern.as_str()).ok())
+            .collect();
+        if !patterns.is_empty() {
+            let mut candidates = local_node
+                .all_backups(None)?
+                .into_iter()
+                .map(|backup| backup.volume())
+                .collect::<HashSet<_>>();
+            for subvol in &local_node.config().subvols {
+                candidates.insert(Volume::new_local(local_node, subvol.name.clone())?);
+            }
+
+            for volume in candidates {
+                if volume.node_name() != remote_node_name
+                    && patterns.iter().any(|pattern| pattern.matches(&volume))
+                {
+                    volumes.entry(volume).or_insert_with(LatestSnapshots::none);
+                }
+            }
+        }
+
+        let mut chains = Vec::new();
+        for (volume, latest_snapshots) in volumes {
+            let is_restore = self.role == Role::Responder && volume.node_name() == remote_node_name;
+
+            if !is_restore && !self.pushes(&volume) {
+                continue;
+            }
+
+            // Full reconciliation: Send everything the remote node is missing.
+            chains.push(match held.get(&volume).filter(|_| !is_restore) {
+                Some(held) => self.missing(volume, held, &latest_snapshots)?,
+                None => self.newer(volume, &latest_snapshots, is_restore)?,
+            });
+        }
+
+        Ok(order_queue(chains))
+    }
+
+    /// Returns the local snapshots of the specified [`Volume`] that are newer
+    /// than the latest ones of the remote node, or the chain to restore it from.
+    fn newer(
+        &self,
+        volume: Volume,
+        latest_snapshots: &LatestSnapshots,
+        is_restore: bool,
+    ) -> Result<Vec<Snapshot>, LocalNodeError> {
+        let local_node = self.local_node;
+        let mut queue = Vec::new();
+
+        // Full backup: Either restoring or remote is out of date.
+        if is_restore {
+            let snapshot = self.latest_backup_full(&volume, latest_snapshots)?;
+
+            if snapshot.taken() > latest_snapshots.last_full {
+                queue.push(snapshot);
+            }
+        } else {
+            queue.extend(
+                local_node
+                    .all_full_after(volume.clone(), latest_snapshots.last_full)?
+                    .into_iter()
+                    .filter(|snapshot| latest_snapshots.permits(snapshot.taken())),
+            );
+        }
+
+        // Incremental backup: Either restoring or remote is out of date.
+        let incr = if is_restore {
+            local_node.backup_incremental_after(
+                volume.clone(),
+                cmp::max(
+                    cmp::max(
+                        latest_snapshots.last_full,
+                        self.latest_backup_full(&volume, latest_snapshots)?.taken(),
+                    ),
+                    latest_snapshots.last_incremental,
+                ),
+            )?
+        } else {
+            local_node.all_incremental_after(volume, latest_snapshots.last_incremental)?
+        };
+
+        // Snapshots taken after the point in time to restore to must never be sent.
+        queue.extend(
+            incr.into_iter()
+                .filter(|snapshot| latest_snapshots.permits(snapshot.taken())),
+        );
+
+        Ok(queue)
+    }
+
+    /// Returns the local snapshots of the specified [`Volume`] that aren't `held`
+    /// by the remote node. Snapshots older than the oldest one
+    /// it holds are left out so that backups it has pruned aren't sent again.
+    fn missing(
+        &self,
+        volume: Volume,
+        held: &[Snapshot],
+        latest_snapshots: &LatestSnapshots,
+    ) -> Result<Vec<Snapshot>, LocalNodeError> {
+        let oldest = held
+            .iter()
+            .map(|snapshot| snapshot.taken())
+            .min()
+            .unwrap_or(NaiveDateTime::MIN);
+        let held: HashSet<_> = held.iter().collect();
+
+        let mut missing = self.local_node.all_full_after(volume.clone(), oldest)?;
+        missing.extend(self.local_node.all_incremental_after(volume, oldest)?);
+        missing.retain(|snapshot| {
+            !held.contains(snapshot) && latest_snapshots.permits(snapshot.taken())
+        });
+
+        Ok(missing)
+    }
+
+    /// Returns the full backup to restore the specified [`Volume`] from,
+    /// respecting the point in time to restore to.
+    fn latest_backup_full(
+        &self,
+        volume: &Volume,
+        latest_snapshots: &LatestSnapshots,
+    ) -> Result<Snapshot, LocalNodeError> {
+        match latest_snapshots.not_after {
+            Some(not_after) => self
+                .local_node
+                .latest_full_before(volume.clone(), not_after),
+            None => self.local_node.latest_backup_full(volume.clone()),
+        }
+    }
+}
+
+/// A `SyncAudit` lists the deviations of the transmissions of a remote node
+/// from the snapshots the local node expected to receive.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct SyncAudit {
+    /// Snapshots of volumes that weren't requested or that aren't newer
+    /// than the latest local snapshot of the same type.
+    pub unexpected: Vec<Snapshot>,
+    /// Snapshots the remote node started to transmit more than once.
+    pub duplicates: Vec<Snapshot>,
+    /// Expected snapshots whose transmission was started but never completed.
+    pub missing: Vec<Snapshot>,
+}
+
+impl SyncAudit {
+    /// Compares the `offered` and `received` snapshots with the latest local snapshots
+    /// that were `announced` to the remote node. Snapshots of other volumes
+    /// are expected if they match one of the announced `wildcards`
+    /// and aren't owned by the local node. If all snapshots `held` locally
+    /// were announced, snapshots missing from them are expected
+    /// unless they are older than the oldest one.
+    pub fn new(
+        local_node_name: &str,
+        announced: &HashMap<Volume, LatestSnapshots>,
+        held: Option<&HashMap<Volume, Vec<Snapshot>>>,
+        wildcards: &[VolumeSpec],
+        offered: &[Snapshot],
+        received: &[Snapshot],
+    ) -> Self {
+        let mut audit = Self::default();
+
+        let mut seen = HashSet::new();
+        for snapshot in offered {
+            if !seen.insert(snapshot) {
+                if !audit.duplicates.contains(snapshot) {
+                    audit.duplicates.push(snapshot.clone());
+                }
+
+                continue;
+            }
+
+            let volume = snapshot.volume();
+            let latest_snapshots = announced.get(&volume).cloned().or_else(|| {
+                (volume.node_name() != local_node_name
+                    && wildcards.iter().any(|spec| spec.matches(&volume)))
+                .then(LatestSnapshots::none)
+            });
+
+            let held = held.and_then(|held| held.get(&volume));
+
+            let is_expected = latest_snapshots.as_ref().is_some_and(|latest_snapshots| {
+                if let Some(held) = held {
+                    let oldest = held.iter().map(|snapshot| snapshot.taken()).min();
+
+                    return !held.contains(snapshot)
+                        && oldest.is_none_or(|oldest| snapshot.taken() > oldest)
+                        && latest_snapshots.permits(snapshot.taken());
+                }
+
+                let latest = if snapshot.is_incremental() {
+                    latest_snapshots.last_incremental
+                } else {
+                    latest_snapshots.last_full
+                };
+
+                snapshot.taken() > latest && latest_snapshots.permits(snapshot.taken())
+            });
+
+            if !is_expected {
+                audit.unexpected.push(snapshot.clone());
+            } else if !received.contains(snapshot) {
+                audit.missing.push(snapshot.clone());
+            }
+        }
+
+        audit
+    }
+
+    /// Reports whether the remote node deviated from the expectations in any way.
+    pub fn is_degraded(&self) -> bool {
+        !self.unexpected.is_empty() || !self.duplicates.is_empty() || !self.missing.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::sync::Barrier;
+    use std::thread;
+
+    #[test]
+    fn streaming_paths_are_claimed_once() {
+        const ROUNDS: usize = 100;
+
+        let path = Path::new("/nonexistent/streaming_paths_are_claimed_once.part");
+        let barrier = Barrier::new(2);
+
+        for _ in 0..ROUNDS {
+            let (first, second) = (StreamingClaims::default(), StreamingClaims::default());
+
+            let (a, b) = thread::scope(|s| {
+                let a = s.spawn(|| {
+                    barrier.wait();
+                    first.claim(path.to_path_buf())
+                });
+                let b = s.spawn(|| {
+                    barrier.wait();
+                    second.claim(path.to_path_buf())
+                });
+
+                (a.join().unwrap(), b.join().unwrap())
+            });
+            assert!(
+                a ^ b,
+                "both or neither of the racing sessions claimed the path"
+            );
+
+            let (winner, loser) = if a {
+                (&first, &second)
+            } else {
+                (&second, &first)
+            };
+
+            // Releasing a path the session doesn't hold is a no-op.
+            loser.release(path);
+            assert!(!loser.claim(path.to_path_buf()));
+
+            winner.release(path);
+            assert!(loser.claim(path.to_path_buf()));
+
+            // Dropping the session releases its remaining claims.
+            drop(first);
+            drop(second);
+            assert!(!STREAMING.lock().unwrap().contains(path));
+        }
+    }
+}
@@ -14,146 +14,650 @@
 // You should have received a copy of the GNU Affero General Public License
 // along with this program.  If not, see <https://www.gnu.org/licenses/>.
 
-use crate::config::NodeConfig;
-use crate::proto::{BACKUP_DIR_C, SNAPSHOT_DIR_C};
+use crate::config::{NodeConfig, SubvolConfig};
+use crate::output;
+use crate::paths::{StorageLayout, MOUNT_ROOT};
+use crate::proto::{self, Mode, Snapshot, MAX_SEND_PROTOCOL};
 use crate::LocalNodeError;
 
+use std::cmp;
+use std::collections::BTreeMap;
+use std::ffi::CString;
 use std::fs;
-use std::io::BufRead;
-use std::net::SocketAddr;
-use std::path::Path;
+use std::io::{self, BufRead, BufReader, Read};
+use std::mem;
+use std::net::{SocketAddr, TcpStream};
+use std::os::fd::AsRawFd;
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
 use std::process::{Command, Stdio};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use std::time::Duration;
 
 use argon2::Argon2;
+use hkdf::Hkdf;
 use hmac::{Hmac, Mac};
-use rand::Rng;
+use rand::rngs::OsRng;
+use rand::{Rng, RngCore};
 use sha2::Sha256;
 use sys_mount::{Mount, UnmountFlags};
 
-pub const MOUNTPOINTC: &str = "/mnt/hbak";
-pub const MOUNTPOINTS: &str = "/mnt/hbakd";
+/// The file `hbakd` records its process ID in when running as a daemon.
+pub const PIDFILE: &str = "/run/hbakd.pid";
+
+/// Set by the `SIGHUP` handler installed by [`catch_sighup`].
+static SIGHUP_RECEIVED: AtomicBool = AtomicBool::new(false);
+
+/// The file the kernel reports the highest btrfs send stream version it can produce in.
+const SEND_STREAM_VERSION_PATH: &str = "/sys/fs/btrfs/features/send_stream_version";
+/// The btrfs-progs release that introduced btrfs send stream version 2.
+const PROGS_SEND_STREAM_V2: (u32, u32) = (6, 0);
+
+/// The btrfs send stream versions the local machine supports.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct SendSupport {
+    /// The highest version that can be sent, limited by the kernel and btrfs-progs.
+    pub send: u32,
+    /// The highest version that can be received, limited by btrfs-progs.
+    pub receive: u32,
+}
+
+impl SendSupport {
+    /// Determines the supported versions from the output of `btrfs --version`
+    /// and the highest version reported by the kernel, `None` if unavailable.
+    /// Version 1 is assumed if either cannot be determined.
+    fn from_versions(progs: Option<&str>, kernel: Option<&str>) -> Self {
+        let progs = match progs.and_then(parse_progs_version) {
+            Some(version) if version >= PROGS_SEND_STREAM_V2 => 2,
+            _ => 1,
+        };
+
+        let kernel = kernel
+            .and_then(|version| version.trim().parse().ok())
+            .unwrap_or(1);
+
+        Self {
+            send: cmp::min(kernel, progs),
+            receive: progs,
+        }
+    }
+
+    /// Returns the preferred btrfs send stream version of new backups,
+    /// the configured one or [`MAX_SEND_PROTOCOL`] limited to the versions
+    /// that can be both sent and received so that the backups can be restored.
+    pub fn preferred(&self, configured: Option<u32>) -> u32 {
+        configured
+            .unwrap_or(MAX_SEND_PROTOCOL)
+            .min(self.send)
+            .min(self.receive)
+            .max(1)
+    }
+}
+
+/// Returns the btrfs send stream version to send to a remote node
+/// that can receive up to the specified version, the preferred version if possible.
+pub fn negotiate_send_protocol(preferred: u32, remote_receive: u32) -> u32 {
+    preferred.min(remote_receive).max(1)
+}
+
+/// The existing data found by [`init`] on a previously initialized btrfs file system.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct Adopted {
+    /// The number of existing snapshots.
+    pub snapshots: usize,
+    /// The number of existing backups.
+    pub backups: usize,
+    /// The subvolumes owned by the node according to the existing snapshots.
+    pub subvols: Vec<String>,
+}
+
+/// The subvolumes [`deinit`] deleted and kept,
+/// relative to the top level of the btrfs file system.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct Cleaned {
+    /// The deleted subvolumes.
+    pub deleted: Vec<PathBuf>,
+    /// The subvolumes left in place.
+    pub kept: Vec<PathBuf>,
+}
 
 /// Initializes the configuration file and local btrfs subvolumes.
+///
+/// Existing snapshot and backup subvolumes are adopted unless `wipe` is set,
+/// in which case they are deleted and recreated. The subvolumes owned by the node
+/// are restored from the names of the existing snapshots.
+/// Only the mountpoint of the specified [`Mode`] is created, the mountpoint
+/// of the other role is created on demand if the node ever acts in it.
 pub fn init(
+    mode: Mode,
     config_only: bool,
+    wipe: bool,
     device: String,
-    bind_addr: Option<SocketAddr>,
+    bind_addr: Vec<SocketAddr>,
     node_name: String,
     passphrase: String,
-) -> Result<(), LocalNodeError> {
-    if Path::new(NodeConfig::PATH).exists() {
+) -> Result<Adopted, LocalNodeError> {
+    if NodeConfig::path().exists() {
         return Err(LocalNodeError::ConfigExists);
     }
 
+    let adopted = if !config_only {
+        init_storage(&StorageLayout::new(mode), &device, &node_name, wipe)?
+    } else {
+        Adopted::default()
+    };
+
     let node_config = NodeConfig {
         device,
+        mount_root: None,
         bind_addr,
+        unix_socket: None,
+        unix_plaintext: None,
+        rx_bufsize: None,
+        drain_timeout: None,
+        max_auth_failures: None,
+        max_sessions_per_client: None,
+        max_clients: None,
+        immutable_snapshots: None,
+        delete_cooloff: None,
+        partial_max_age: None,
+        chunk_size: None,
+        keepalive_idle: None,
+        keepalive_interval: None,
+        stall_timeout: None,
+        heartbeat_interval: None,
+        rekey_interval: None,
+        space_reserve: None,
+        archive_dir: None,
+        archive_after: None,
+        archive_keep: None,
+        max_clock_skew: None,
+        max_snapshot_age: None,
+        send_protocol: None,
+        compression_level: None,
+        log_level: None,
+        log_format: None,
         node_name,
-        subvols: Vec::default(),
-        passphrase,
+        instance_id: Some(random_instance_id()),
+        subvols: adopted
+            .subvols
+            .iter()
+            .cloned()
+            .map(SubvolConfig::from)
+            .collect(),
+        subvol_aliases: BTreeMap::default(),
+        passphrase: Some(passphrase),
+        passphrase_command: None,
+        passphrase_prompt: None,
         remotes: Vec::default(),
+        upstreams: Vec::default(),
+        schedule: BTreeMap::default(),
         auth: Vec::default(),
     };
 
     node_config.save()?;
 
-    if !config_only {
-        init_btrfs(&node_config.device)?;
-    }
-
-    Ok(())
+    Ok(adopted)
 }
 
-fn init_btrfs(device: &str) -> Result<(), LocalNodeError> {
-    fs::create_dir_all(MOUNTPOINTC)?;
-    fs::create_dir_all(MOUNTPOINTS)?;
+/// Mounts the btrfs file system at the mountpoint of the specified [`StorageLayout`]
+/// and creates its snapshot and backup subvolumes, see [`init`].
+/// The configuration file is left untouched.
+pub fn init_storage(
+    layout: &StorageLayout,
+    device: &str,
+    node_name: &str,
+    wipe: bool,
+) -> Result<Adopted, LocalNodeError> {
+    fs::create_dir_all(layout.mountpoint())?;
 
     let _btrfs = Mount::builder().data("compress=zstd").mount_autodrop(
         device,
-        MOUNTPOINTC,
+        layout.mountpoint(),
         UnmountFlags::DETACH,
     )?;
 
-    if !Command::new("btrfs")
+    if wipe {
+        delete_btrfs(layout)?;
+    }
+
+    let mut adopted = Adopted::default();
+
+    if adopt_subvolume(layout.snapshot_dir())? {
+        let mut unrecognized = Vec::new();
+
+        for entry in fs::read_dir(layout.snapshot_dir())? {
+            let path = entry?.path();
+            let Ok(snapshot) = Snapshot::try_from(&*path) else {
+                unrecognized.push(path);
+                continue;
+            };
+
+            if snapshot.node_name() == node_name
+                && !adopted
+                    .subvols
+                    .iter()
+                    .any(|subvol| subvol == snapshot.subvol())
+            {
+                adopted.subvols.push(snapshot.subvol().to_string());
+            }
+
+            adopted.snapshots += 1;
+        }
+
+        proto::warn_unrecognized(layout.snapshot_dir(), &unrecognized);
+    }
+
+    if adopt_subvolume(layout.backup_dir())? {
+        adopted.backups = proto::read_backups(layout.backup_dir(), None)?.len();
+    }
+
+    Ok(adopted)
+}
+
+/// Creates the specified btrfs subvolume unless it already exists.
+/// Returns whether an existing subvolume was adopted.
+pub(crate) fn adopt_subvolume(path: &Path) -> Result<bool, LocalNodeError> {
+    if !path.exists() {
+        if !Command::new("btrfs")
+            .arg("subvolume")
+            .arg("create")
+            .arg(path)
+            .stdin(Stdio::null())
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .spawn()?
+            .wait()?
+            .success()
+        {
+            return Err(LocalNodeError::BtrfsCmd);
+        }
+
+        return Ok(false);
+    }
+
+    if !is_subvolume(path)? {
+        return Err(LocalNodeError::NotSubvolume(path.display().to_string()));
+    }
+
+    Ok(true)
+}
+
+/// Installs a `SIGHUP` handler that records the signal for [`take_sighup`]
+/// instead of terminating the process, replacing any previous handler.
+pub fn catch_sighup() {
+    // SAFETY: The handler only stores to an atomic, which is async-signal-safe.
+    unsafe {
+        libc::signal(
+            libc::SIGHUP,
+            handle_sighup as extern "C" fn(libc::c_int) as libc::sighandler_t,
+        );
+    }
+}
+
+extern "C" fn handle_sighup(_: libc::c_int) {
+    SIGHUP_RECEIVED.store(true, Ordering::SeqCst);
+}
+
+/// Reports whether `SIGHUP` was received since the last call.
+pub fn take_sighup() -> bool {
+    SIGHUP_RECEIVED.swap(false, Ordering::SeqCst)
+}
+
+/// Enables TCP keepalive on the specified [`TcpStream`], sending the first probe
+/// after the connection has been idle for `idle` and further probes every `interval`.
+pub fn set_keepalive(stream: &TcpStream, idle: Duration, interval: Duration) -> io::Result<()> {
+    let fd = stream.as_raw_fd();
+
+    for (level, name, value) in [
+        (libc::SOL_SOCKET, libc::SO_KEEPALIVE, 1),
+        (libc::IPPROTO_TCP, libc::TCP_KEEPIDLE, idle.as_secs()),
+        (libc::IPPROTO_TCP, libc::TCP_KEEPINTVL, interval.as_secs()),
+    ] {
+        let value = libc::c_int::try_from(value).unwrap_or(libc::c_int::MAX);
+
+        // SAFETY: The file descriptor is valid for the lifetime of the stream
+        // and the option value is a `c_int` as expected by these options.
+        let ret = unsafe {
+            libc::setsockopt(
+                fd,
+                level,
+                name,
+                &value as *const libc::c_int as *const libc::c_void,
+                mem::size_of::<libc::c_int>() as libc::socklen_t,
+            )
+        };
+
+        if ret != 0 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+
+    Ok(())
+}
+
+/// Returns the index of the network interface with the specified name
+/// or `None` if it does not exist.
+pub fn interface_index(name: &str) -> Option<u32> {
+    let name = CString::new(name).ok()?;
+
+    // SAFETY: The name is a valid nul-terminated string that outlives the call.
+    match unsafe { libc::if_nametoindex(name.as_ptr()) } {
+        0 => None,
+        index => Some(index),
+    }
+}
+
+/// Returns the number of bytes available to unprivileged users
+/// on the file system the specified path is located on.
+pub fn available_space<P: AsRef<Path>>(path: P) -> io::Result<u64> {
+    let path = CString::new(path.as_ref().as_os_str().as_bytes())?;
+    let mut stat = mem::MaybeUninit::<libc::statvfs>::uninit();
+
+    // SAFETY: The path is a valid nul-terminated string that outlives the call
+    // and the buffer is large enough to hold a `statvfs` struct.
+    if unsafe { libc::statvfs(path.as_ptr(), stat.as_mut_ptr()) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    // SAFETY: `statvfs` initializes the struct on success.
+    let stat = unsafe { stat.assume_init() };
+
+    Ok(stat.f_bavail.saturating_mul(stat.f_frsize))
+}
+
+/// Reports whether the specified path is a btrfs subvolume.
+pub fn is_subvolume<P: AsRef<Path>>(path: P) -> Result<bool, LocalNodeError> {
+    Ok(Command::new("btrfs")
         .arg("subvolume")
-        .arg("create")
-        .arg(SNAPSHOT_DIR_C)
+        .arg("show")
+        .arg(path.as_ref())
         .stdin(Stdio::null())
         .stdout(Stdio::null())
         .stderr(Stdio::null())
         .spawn()?
         .wait()?
-        .success()
-    {
-        return Err(LocalNodeError::BtrfsCmd);
-    }
+        .success())
+}
 
-    if !Command::new("btrfs")
-        .arg("subvolume")
-        .arg("create")
-        .arg(BACKUP_DIR_C)
+/// Probes the btrfs send stream versions supported by the kernel and btrfs-progs.
+/// Version 1 is assumed if either cannot be determined.
+pub fn probe_send_support() -> SendSupport {
+    SendSupport::from_versions(
+        btrfs_version_output().as_deref(),
+        fs::read_to_string(SEND_STREAM_VERSION_PATH).ok().as_deref(),
+    )
+}
+
+/// Returns the output of `btrfs --version`.
+fn btrfs_version_output() -> Option<String> {
+    let output = Command::new("btrfs")
+        .arg("--version")
         .stdin(Stdio::null())
-        .stdout(Stdio::null())
         .stderr(Stdio::null())
-        .spawn()?
-        .wait()?
-        .success()
-    {
-        return Err(LocalNodeError::BtrfsCmd);
+        .output()
+        .ok()?;
+
+    if !output.status.success() {
+        return None;
+    }
+
+    Some(String::from_utf8_lossy(&output.stdout).into_owned())
+}
+
+/// Parses the major and minor version from the output of `btrfs --version`,
+/// e.g. `btrfs-progs v6.6.3`.
+fn parse_progs_version(s: &str) -> Option<(u32, u32)> {
+    let version = s
+        .split_whitespace()
+        .find_map(|word| word.strip_prefix('v'))?;
+    let mut numbers = version.split(|c: char| !c.is_ascii_digit());
+
+    let major = numbers.next()?.parse().ok()?;
+    let minor = numbers
+        .next()
+        .and_then(|minor| minor.parse().ok())
+        .unwrap_or(0);
+
+    Some((major, minor))
+}
+
+/// Runs the snapshot hook commands of the specified stage in order using `sh -c`,
+/// stopping at the first failure. The node and subvolume names are passed
+/// in the `HBAK_NODE_NAME` and `HBAK_SUBVOL` environment variables.
+///
+/// The output of the commands is written to stderr line by line,
+/// prefixed with the stage and subvolume name.
+pub(crate) fn run_hooks(
+    stage: &'static str,
+    node_name: &str,
+    subvol: &str,
+    commands: &[String],
+) -> Result<(), LocalNodeError> {
+    let prefix = format!("[{} {}]", stage, subvol);
+
+    for command in commands {
+        let mut child = Command::new("sh")
+            .arg("-c")
+            .arg(command)
+            .env("HBAK_NODE_NAME", node_name)
+            .env("HBAK_SUBVOL", subvol)
+            .stdin(Stdio::null())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()?;
+
+        let stdout = child.stdout.take().expect("stdout is piped");
+        let stderr = child.stderr.take().expect("stderr is piped");
+
+        thread::scope(|s| {
+            s.spawn(|| forward_output(stdout, &prefix));
+            forward_output(stderr, &prefix);
+        });
+
+        let status = child.wait()?;
+        if !status.success() {
+            return Err(LocalNodeError::HookFailed(
+                stage,
+                subvol.to_string(),
+                status,
+            ));
+        }
     }
 
     Ok(())
 }
 
-/// Deinitializes the configuration file, optionally deleting the btrfs subvolumes.
-pub fn deinit(remove_backups: bool) -> Result<(), LocalNodeError> {
-    if !Path::new(NodeConfig::PATH).exists() {
+/// Runs the specified passphrase command using `sh -c`
+/// and returns its output without the trailing line break.
+/// The command may interact with the user through stdin and stderr.
+pub(crate) fn run_passphrase_command(command: &str) -> Result<String, LocalNodeError> {
+    let output = Command::new("sh")
+        .arg("-c")
+        .arg(command)
+        .stdin(Stdio::inherit())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::inherit())
+        .output()?;
+
+    if !output.status.success() {
+        return Err(LocalNodeError::PassphraseCommand(output.status));
+    }
+
+    let passphrase =
+        String::from_utf8(output.stdout).map_err(|_| LocalNodeError::EmptyPassphrase)?;
+    let passphrase = passphrase
+        .strip_suffix('\n')
+        .map(|passphrase| passphrase.strip_suffix('\r').unwrap_or(passphrase))
+        .unwrap_or(&passphrase);
+
+    if passphrase.trim().is_empty() {
+        return Err(LocalNodeError::EmptyPassphrase);
+    }
+
+    Ok(passphrase.to_string())
+}
+
+/// Writes the lines read from the hook output to stderr with the specified prefix.
+fn forward_output<R: Read>(r: R, prefix: &str) {
+    for line in BufReader::new(r).lines().map_while(Result::ok) {
+        output::eprintln(format_args!("{} {}", prefix, line));
+    }
+}
+
+/// Deinitializes the configuration file, optionally deleting the snapshot
+/// and backup subvolumes. The subvolumes owned by the node are always kept.
+/// Refuses to proceed while `hbakd` is running, see [`hbakd_pid`].
+pub fn deinit(remove_backups: bool) -> Result<Cleaned, LocalNodeError> {
+    if !NodeConfig::path().exists() {
         return Err(LocalNodeError::ConfigUninit);
     }
 
-    if remove_backups {
-        deinit_btrfs()?;
+    if let Some(pid) = hbakd_pid() {
+        return Err(LocalNodeError::DaemonRunning(pid));
     }
 
-    fs::remove_file(NodeConfig::PATH)?;
+    // Unparseable configurations can still be removed.
+    let node_config = if remove_backups {
+        Some(NodeConfig::load()?)
+    } else {
+        NodeConfig::load().ok()
+    };
 
-    fs::remove_dir(MOUNTPOINTC)?;
-    fs::remove_dir(MOUNTPOINTS)?;
+    let mut cleaned = Cleaned::default();
+    if let Some(node_config) = &node_config {
+        cleaned.kept.extend(
+            node_config
+                .subvols
+                .iter()
+                .map(|subvol| PathBuf::from(&subvol.name)),
+        );
+    }
 
-    Ok(())
+    match &node_config {
+        Some(node_config) if remove_backups => cleaned.deleted = deinit_btrfs(node_config)?,
+        _ => cleaned
+            .kept
+            .extend([PathBuf::from("snapshots"), PathBuf::from("backups")]),
+    }
+
+    let mount_root = node_config
+        .and_then(|node_config| node_config.mount_root)
+        .unwrap_or_else(|| PathBuf::from(MOUNT_ROOT));
+
+    fs::remove_file(NodeConfig::path())?;
+    match fs::remove_file(NodeConfig::backup_path()) {
+        Ok(_) => {}
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+        Err(e) => return Err(e.into()),
+    }
+
+    // Single-role nodes only have one of the mountpoints.
+    for mode in [Mode::Client, Mode::Server] {
+        match fs::remove_dir(StorageLayout::under(mode, &mount_root).mountpoint()) {
+            Ok(_) => {}
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+            Err(e) => return Err(e.into()),
+        }
+    }
+
+    Ok(cleaned)
 }
 
-fn deinit_btrfs() -> Result<(), LocalNodeError> {
-    fs::create_dir_all(MOUNTPOINTC)?;
-    fs::create_dir_all(MOUNTPOINTS)?;
+/// Returns the process ID of the running `hbakd`, if any.
+/// The process recorded in the [`PIDFILE`] is checked first,
+/// instances that don't write it such as those managed by systemd
+/// are found by their name.
+pub fn hbakd_pid() -> Option<u32> {
+    let recorded = fs::read_to_string(PIDFILE)
+        .ok()
+        .and_then(|pid| pid.trim().parse().ok());
+    if let Some(pid) = recorded.filter(|pid| is_hbakd(*pid)) {
+        return Some(pid);
+    }
 
-    let node_config = NodeConfig::load()?;
+    fs::read_dir("/proc")
+        .ok()?
+        .filter_map(|entry| entry.ok()?.file_name().to_str()?.parse().ok())
+        .find(|pid| is_hbakd(*pid))
+}
+
+/// Reports whether the process with the specified ID is alive and named `hbakd`.
+/// Stale PIDs may have been reused by unrelated processes.
+fn is_hbakd(pid: u32) -> bool {
+    fs::read_to_string(format!("/proc/{}/comm", pid)).is_ok_and(|comm| comm.trim_end() == "hbakd")
+}
+
+fn deinit_btrfs(node_config: &NodeConfig) -> Result<Vec<PathBuf>, LocalNodeError> {
+    let mount_root = node_config
+        .mount_root
+        .as_deref()
+        .unwrap_or(Path::new(MOUNT_ROOT));
+    // The mountpoint is only created if it is missing entirely, it is removed afterwards.
+    let mode = initialized_mode(mount_root).unwrap_or(Mode::Client);
+    let layout = StorageLayout::from_config(node_config, mode);
+
+    fs::create_dir_all(layout.mountpoint())?;
 
     let _btrfs = Mount::builder().data("compress=zstd").mount_autodrop(
-        node_config.device,
-        MOUNTPOINTC,
+        &node_config.device,
+        layout.mountpoint(),
         UnmountFlags::DETACH,
     )?;
 
-    if !Command::new("btrfs")
-        .arg("subvolume")
-        .arg("delete")
-        .arg(BACKUP_DIR_C)
-        .stdin(Stdio::null())
-        .stdout(Stdio::null())
-        .stderr(Stdio::null())
-        .spawn()?
-        .wait()?
-        .success()
-    {
-        return Err(LocalNodeError::BtrfsCmd);
+    delete_btrfs(&layout)
+}
+
+/// Returns the [`Mode`] whose mountpoint exists inside of the specified mount root.
+/// Dual-role nodes mount the same btrfs file system at both mountpoints,
+/// the client mountpoint is used in that case. Returns `None` if neither exists.
+fn initialized_mode(mount_root: &Path) -> Option<Mode> {
+    [Mode::Client, Mode::Server].into_iter().find(|mode| {
+        StorageLayout::under(*mode, mount_root)
+            .mountpoint()
+            .is_dir()
+    })
+}
+
+/// Deletes the snapshot and backup subvolumes including all snapshots
+/// from the btrfs file system mounted at the mountpoint of the specified [`StorageLayout`]
+/// if they exist. Returns the deleted subvolumes relative to the mountpoint.
+fn delete_btrfs(layout: &StorageLayout) -> Result<Vec<PathBuf>, LocalNodeError> {
+    let relative = |path: &Path| {
+        path.strip_prefix(layout.mountpoint())
+            .unwrap_or(path)
+            .to_path_buf()
+    };
+    let mut deleted = Vec::new();
+
+    if layout.backup_dir().exists() {
+        if !Command::new("btrfs")
+            .arg("subvolume")
+            .arg("delete")
+            .arg(layout.backup_dir())
+            .stdin(Stdio::null())
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .spawn()?
+            .wait()?
+            .success()
+        {
+            return Err(LocalNodeError::BtrfsCmd);
+        }
+
+        deleted.push(relative(layout.backup_dir()));
+    }
+
+    if !layout.snapshot_dir().exists() {
+        return Ok(deleted);
     }
 
     let output = Command::new("btrfs")
         .arg("subvolume")
         .arg("list")
         .arg("-o")
-        .arg(SNAPSHOT_DIR_C)
+        .arg(layout.snapshot_dir())
         .stdin(Stdio::null())
         .output()?;
     if !output.status.success() {
@@ -161,7 +665,7 @@ fn deinit_btrfs() -> Result<(), LocalNodeError> {
     }
 
     let subvols = output.stdout.lines().map(|line| match line {
-        Ok(line) => Ok(Path::new(MOUNTPOINTC).join(
+        Ok(line) => Ok(layout.mountpoint().join(
             line.split_whitespace()
                 .next_back()
                 .expect("String splitting yields at least one item"),
@@ -170,10 +674,12 @@ fn deinit_btrfs() -> Result<(), LocalNodeError> {
     });
 
     for subvol in subvols {
+        let subvol = subvol?;
+
         if !Command::new("btrfs")
             .arg("subvolume")
             .arg("delete")
-            .arg(subvol?)
+            .arg(&subvol)
             .stdin(Stdio::null())
             .stdout(Stdio::null())
             .stderr(Stdio::null())
@@ -183,12 +689,14 @@ fn deinit_btrfs() -> Result<(), LocalNodeError> {
         {
             return Err(LocalNodeError::BtrfsCmd);
         }
+
+        deleted.push(relative(&subvol));
     }
 
     if !Command::new("btrfs")
         .arg("subvolume")
         .arg("delete")
-        .arg(SNAPSHOT_DIR_C)
+        .arg(layout.snapshot_dir())
         .stdin(Stdio::null())
         .stdout(Stdio::null())
         .stderr(Stdio::null())
@@ -199,11 +707,51 @@ fn deinit_btrfs() -> Result<(), LocalNodeError> {
         return Err(LocalNodeError::BtrfsCmd);
     }
 
+    deleted.push(relative(layout.snapshot_dir()));
+
+    Ok(deleted)
+}
+
+/// Reports whether the immutable attribute is set on the specified path.
+pub fn is_immutable<P: AsRef<Path>>(path: P) -> Result<bool, LocalNodeError> {
+    let output = Command::new("lsattr")
+        .arg("-d")
+        .arg(path.as_ref())
+        .stdin(Stdio::null())
+        .stderr(Stdio::null())
+        .output()?;
+    if !output.status.success() {
+        return Err(LocalNodeError::AttrCmd);
+    }
+
+    let output = String::from_utf8_lossy(&output.stdout);
+    let attrs = output.split_whitespace().next().unwrap_or_default();
+
+    Ok(attrs.contains('i'))
+}
+
+/// Sets or clears the immutable attribute on the specified path.
+pub fn set_immutable<P: AsRef<Path>>(path: P, immutable: bool) -> Result<(), LocalNodeError> {
+    if !Command::new("chattr")
+        .arg(if immutable { "+i" } else { "-i" })
+        .arg(path.as_ref())
+        .stdin(Stdio::null())
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .spawn()?
+        .wait()?
+        .success()
+    {
+        return Err(LocalNodeError::AttrCmd);
+    }
+
     Ok(())
 }
 
 /// Provides a `Vec<u8>` of `n` random bytes. Uses the thread-local generator
 /// of the `rand` crate.
+///
+/// Use [`random_bytes_secret`] for keys, verifiers and other security material.
 pub fn random_bytes(n: usize) -> Vec<u8> {
     rand::thread_rng()
         .sample_iter(&rand::distributions::Standard)
@@ -211,6 +759,35 @@ pub fn random_bytes(n: usize) -> Vec<u8> {
         .collect()
 }
 
+/// Generates a random instance identifier in the UUID version 4 format.
+pub fn random_instance_id() -> String {
+    let mut bytes = random_bytes(16);
+
+    // Version 4, variant 1.
+    bytes[6] = (bytes[6] & 0x0f) | 0x40;
+    bytes[8] = (bytes[8] & 0x3f) | 0x80;
+
+    let hex: String = bytes.iter().map(|byte| format!("{byte:02x}")).collect();
+    format!(
+        "{}-{}-{}-{}-{}",
+        &hex[..8],
+        &hex[8..12],
+        &hex[12..16],
+        &hex[16..20],
+        &hex[20..]
+    )
+}
+
+/// Provides a `Vec<u8>` of `n` random bytes suitable for security material.
+/// Uses the random number generator of the operating system
+/// and fails if it is unable to provide enough entropy.
+pub fn random_bytes_secret(n: usize) -> Result<Vec<u8>, LocalNodeError> {
+    let mut buf = vec![0; n];
+    OsRng.try_fill_bytes(&mut buf)?;
+
+    Ok(buf)
+}
+
 /// Performs an HMAC-SHA256 hash computation.
 pub fn hash_hmac(secret: &[u8], data: &[u8]) -> Vec<u8> {
     let mut mac: Hmac<Sha256> =
@@ -221,6 +798,79 @@ pub fn hash_hmac(secret: &[u8], data: &[u8]) -> Vec<u8> {
     hmac.into_bytes().to_vec()
 }
 
+/// A `SessionKey` is the key and nonce protecting a single direction of a session.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct SessionKey {
+    /// The XChaCha20Poly1305 key.
+    pub key: Vec<u8>,
+    /// The nonce prefix of the STREAM construction.
+    pub nonce: Vec<u8>,
+}
+
+/// Derives the directional session keys and nonces from the shared secret
+/// and the nonces contributed by both peers using HKDF-SHA256.
+/// Returns the key and nonce for client to server traffic
+/// followed by the key and nonce for server to client traffic.
+pub fn derive_session_keys(
+    key: &[u8],
+    client_nonce: &[u8],
+    server_nonce: &[u8],
+) -> (SessionKey, SessionKey) {
+    let salt = [client_nonce, server_nonce].concat();
+    let hkdf = Hkdf::<Sha256>::new(Some(&salt), key);
+
+    let expand = |info: &[u8]| {
+        let mut okm = [0; 32 + 19];
+        hkdf.expand(info, &mut okm)
+            .expect("HKDF output length is valid for SHA-256");
+
+        SessionKey {
+            key: okm[..32].to_vec(),
+            nonce: okm[32..].to_vec(),
+        }
+    };
+
+    (
+        expand(b"hbak client to server"),
+        expand(b"hbak server to client"),
+    )
+}
+
+/// Derives the next [`SessionKey`] of a direction from the current one
+/// and the salt announced by the sender when rekeying using HKDF-SHA256.
+pub fn next_session_key(current: &SessionKey, salt: &[u8]) -> SessionKey {
+    let hkdf = Hkdf::<Sha256>::new(Some(salt), &current.key);
+
+    let mut okm = [0; 32 + 19];
+    hkdf.expand(b"hbak rekey", &mut okm)
+        .expect("HKDF output length is valid for SHA-256");
+
+    SessionKey {
+        key: okm[..32].to_vec(),
+        nonce: okm[32..].to_vec(),
+    }
+}
+
+/// Performs an HMAC-SHA256 hash computation over all data read from the provided [`Read`].
+pub fn hash_hmac_reader<R: Read>(secret: &[u8], mut r: R) -> io::Result<Vec<u8>> {
+    let mut mac: Hmac<Sha256> =
+        Hmac::new_from_slice(secret).expect("HMAC can take key of any size");
+
+    let mut buf = vec![0; 64 * 1024];
+    loop {
+        match r.read(&mut buf) {
+            Ok(0) => break,
+            Ok(n) => mac.update(&buf[..n]),
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+
+    let hmac = mac.finalize();
+
+    Ok(hmac.into_bytes().to_vec())
+}
+
 /// Performs an Argon2id hash computation.
 pub fn hash_argon2id<P: AsRef<[u8]>>(
     okm: &mut [u8],
@@ -246,7 +896,7 @@ pub fn hash_argon2id<P: AsRef<[u8]>>(
 pub fn hash_passphrase<P: AsRef<[u8]>>(
     passphrase: P,
 ) -> Result<(Vec<u8>, Vec<u8>), LocalNodeError> {
-    let verifier = random_bytes(32);
+    let verifier = random_bytes_secret(32)?;
     let key = derive_key(&verifier, passphrase)?;
 
     Ok((verifier, key))
@@ -264,3 +914,136 @@ pub fn derive_key<P: AsRef<[u8]>>(
     let key = hash_hmac(&key_array, verifier);
     Ok(key)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::env;
+    use std::process;
+
+    /// Creates an empty mount root with the mountpoints of the specified [`Mode`]s.
+    fn mount_root(name: &str, modes: &[Mode]) -> PathBuf {
+        let dir = env::temp_dir().join(format!("hbak-layout-{}-{}", process::id(), name));
+        let _ = fs::remove_dir_all(&dir);
+
+        fs::create_dir_all(&dir).unwrap();
+        for mode in modes {
+            fs::create_dir(StorageLayout::under(*mode, &dir).mountpoint()).unwrap();
+        }
+
+        dir
+    }
+
+    #[test]
+    fn client_only_nodes_use_the_client_mountpoint() {
+        let dir = mount_root("client", &[Mode::Client]);
+        assert_eq!(initialized_mode(&dir), Some(Mode::Client));
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn server_only_nodes_use_the_server_mountpoint() {
+        let dir = mount_root("server", &[Mode::Server]);
+        assert_eq!(initialized_mode(&dir), Some(Mode::Server));
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn dual_role_nodes_use_the_client_mountpoint() {
+        let dir = mount_root("dual", &[Mode::Server, Mode::Client]);
+        assert_eq!(initialized_mode(&dir), Some(Mode::Client));
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn uninitialized_nodes_have_no_mountpoint() {
+        let dir = mount_root("none", &[]);
+        assert_eq!(initialized_mode(&dir), None);
+
+        // A file of the same name is not a mountpoint.
+        fs::write(StorageLayout::under(Mode::Server, &dir).mountpoint(), b"").unwrap();
+        assert_eq!(initialized_mode(&dir), None);
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn progs_versions_are_parsed_from_real_outputs() {
+        for (output, version) in [
+            ("btrfs-progs v6.6.3\n", Some((6, 6))),
+            ("btrfs-progs v6.0\n", Some((6, 0))),
+            ("btrfs-progs v5.16.2 \n", Some((5, 16))),
+            ("btrfs-progs v4.4\n", Some((4, 4))),
+            (
+                "btrfs-progs v6.10\n-EXPERIMENTAL -INJECT -STATIC +LZO +ZSTD\n",
+                Some((6, 10)),
+            ),
+            ("btrfs-progs v7\n", Some((7, 0))),
+            ("", None),
+            ("btrfs-progs\n", None),
+            ("btrfs-progs version unknown\n", None),
+        ] {
+            assert_eq!(parse_progs_version(output), version, "{:?}", output);
+        }
+    }
+
+    #[test]
+    fn send_support_is_limited_by_kernel_and_progs() {
+        for (progs, kernel, send, receive) in [
+            (Some("btrfs-progs v6.6.3\n"), Some("2\n"), 2, 2),
+            (Some("btrfs-progs v6.0\n"), Some("2\n"), 2, 2),
+            // Old kernels lack the send stream version file or only support version 1.
+            (Some("btrfs-progs v6.6.3\n"), None, 1, 2),
+            (Some("btrfs-progs v6.6.3\n"), Some("1\n"), 1, 2),
+            (Some("btrfs-progs v5.19.1\n"), Some("2\n"), 1, 1),
+            // The version of btrfs-progs is unknown if the command fails.
+            (None, Some("2\n"), 1, 1),
+            (Some("garbage"), Some("garbage"), 1, 1),
+            (None, None, 1, 1),
+        ] {
+            assert_eq!(
+                SendSupport::from_versions(progs, kernel),
+                SendSupport { send, receive },
+                "{:?} {:?}",
+                progs,
+                kernel
+            );
+        }
+    }
+
+    #[test]
+    fn preferred_send_protocol_can_be_restored() {
+        let full = SendSupport {
+            send: 2,
+            receive: 2,
+        };
+        let send_only = SendSupport {
+            send: 2,
+            receive: 1,
+        };
+        let receive_only = SendSupport {
+            send: 1,
+            receive: 2,
+        };
+
+        assert_eq!(full.preferred(None), MAX_SEND_PROTOCOL);
+        assert_eq!(full.preferred(Some(1)), 1);
+        assert_eq!(send_only.preferred(None), 1);
+        assert_eq!(receive_only.preferred(Some(2)), 1);
+
+        // Out of range configurations are clamped to the supported versions.
+        assert_eq!(full.preferred(Some(0)), 1);
+        assert_eq!(full.preferred(Some(3)), 2);
+    }
+
+    #[test]
+    fn send_protocol_falls_back_to_what_the_remote_receives() {
+        assert_eq!(negotiate_send_protocol(2, 2), 2);
+        assert_eq!(negotiate_send_protocol(2, 1), 1);
+        assert_eq!(negotiate_send_protocol(1, 2), 1);
+
+        // Invalid versions fall back to version 1.
+        assert_eq!(negotiate_send_protocol(2, 0), 1);
+    }
+}
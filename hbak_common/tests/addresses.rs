@@ -0,0 +1,244 @@
+// hbak_common is the main hbak library implementing the protocol shared logic.
+// Copyright (C) 2024  Himbeer <himbeerserverde@gmail.com>
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use hbak_common::config::{parse_bind_addr, RemoteAddress};
+use hbak_common::conn::DEFAULT_PORT;
+use hbak_common::system;
+use hbak_common::AddressParseError;
+
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV6};
+use std::path::PathBuf;
+
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+struct Remote {
+    address: RemoteAddress,
+}
+
+fn inet(host: &str, port: Option<u16>) -> RemoteAddress {
+    RemoteAddress::Inet {
+        host: host.to_string(),
+        port,
+    }
+}
+
+fn parse(s: &str) -> RemoteAddress {
+    s.parse().unwrap()
+}
+
+#[test]
+fn addresses_parse_into_host_and_port() {
+    for (s, host, port) in [
+        ("backup.example.com", "backup.example.com", None),
+        ("backup.example.com:2000", "backup.example.com", Some(2000)),
+        ("localhost", "localhost", None),
+        ("192.0.2.1", "192.0.2.1", None),
+        ("192.0.2.1:20406", "192.0.2.1", Some(20406)),
+        ("[2001:db8::1]:20406", "2001:db8::1", Some(20406)),
+        ("[2001:db8::1]", "2001:db8::1", None),
+        ("[::1]:0", "::1", Some(0)),
+        ("2001:db8::1", "2001:db8::1", None),
+        ("::1", "::1", None),
+        ("::ffff:192.0.2.1", "::ffff:192.0.2.1", None),
+        ("fe80::1%eth0", "fe80::1%eth0", None),
+        ("fe80::1%2", "fe80::1%2", None),
+        ("[fe80::1%2]:20406", "fe80::1%2", Some(20406)),
+    ] {
+        assert_eq!(parse(s), inet(host, port), "{}", s);
+    }
+}
+
+#[test]
+fn unix_socket_addresses_require_absolute_paths() {
+    assert_eq!(
+        parse("unix:/run/hbakd.sock"),
+        RemoteAddress::Unix(PathBuf::from("/run/hbakd.sock"))
+    );
+    assert!(matches!(
+        "unix:run/hbakd.sock".parse::<RemoteAddress>(),
+        Err(AddressParseError::RelativeSocketPath(path)) if path == "run/hbakd.sock"
+    ));
+}
+
+#[test]
+fn malformed_addresses_are_rejected() {
+    for s in ["", ":20406", "[]:20406", "[]"] {
+        assert!(
+            matches!(
+                s.parse::<RemoteAddress>(),
+                Err(AddressParseError::MissingHost)
+            ),
+            "{}",
+            s
+        );
+    }
+
+    for s in [
+        "[2001:db8::1",
+        "[2001:db8::1]20406",
+        "[[::1]]:20406",
+        "backup example.com",
+        "backup/example.com:20406",
+        "192.0.2.1%eth0",
+        "backup.example.com%eth0",
+        "fe80::1%",
+        "[fe80::1%]:20406",
+    ] {
+        assert!(
+            matches!(
+                s.parse::<RemoteAddress>(),
+                Err(AddressParseError::InvalidHost(_))
+            ),
+            "{}",
+            s
+        );
+    }
+
+    for s in [
+        "backup.example.com:",
+        "[::1]:",
+        "192.0.2.1:65536",
+        "[::1]:http",
+        "[2001:db8::1]:20406]",
+    ] {
+        assert!(
+            matches!(
+                s.parse::<RemoteAddress>(),
+                Err(AddressParseError::InvalidPort(_))
+            ),
+            "{}",
+            s
+        );
+    }
+}
+
+#[test]
+fn addresses_round_trip_through_their_display() {
+    for s in [
+        "backup.example.com",
+        "backup.example.com:2000",
+        "192.0.2.1:20406",
+        "[2001:db8::1]:20406",
+        "2001:db8::1",
+        "[fe80::1%eth0]:20406",
+        "fe80::1%2",
+        "unix:/run/hbakd.sock",
+    ] {
+        assert_eq!(parse(s).to_string(), s);
+        assert_eq!(parse(&parse(s).to_string()), parse(s));
+    }
+
+    // Brackets are only needed to separate a port.
+    assert_eq!(parse("[2001:db8::1]").to_string(), "2001:db8::1");
+}
+
+#[test]
+fn addresses_round_trip_through_the_configuration_file() {
+    for s in [
+        "backup.example.com",
+        "[2001:db8::1]:20406",
+        "[fe80::1%eth0]:20406",
+        "unix:/run/hbakd.sock",
+    ] {
+        let remote = Remote { address: parse(s) };
+        let serialized = toml::to_string(&remote).unwrap();
+        assert_eq!(toml::from_str::<Remote>(&serialized).unwrap(), remote);
+    }
+
+    // Previous versions wrote the string form.
+    let remote: Remote = toml::from_str(r#"address = "[2001:db8::1]:2000""#).unwrap();
+    assert_eq!(remote.address, inet("2001:db8::1", Some(2000)));
+    assert!(toml::from_str::<Remote>(r#"address = "[2001:db8::1""#).is_err());
+}
+
+#[test]
+fn ip_addresses_have_a_socket_address() {
+    let v6 = |addr: &str, port, scope_id| {
+        Some(SocketAddr::from(SocketAddrV6::new(
+            addr.parse::<Ipv6Addr>().unwrap(),
+            port,
+            0,
+            scope_id,
+        )))
+    };
+
+    for (s, addr) in [
+        (
+            "192.0.2.1",
+            Some(SocketAddr::from((
+                Ipv4Addr::new(192, 0, 2, 1),
+                DEFAULT_PORT,
+            ))),
+        ),
+        (
+            "192.0.2.1:2000",
+            Some(SocketAddr::from((Ipv4Addr::new(192, 0, 2, 1), 2000))),
+        ),
+        ("[2001:db8::1]:2000", v6("2001:db8::1", 2000, 0)),
+        ("2001:db8::1", v6("2001:db8::1", DEFAULT_PORT, 0)),
+        ("[fe80::1%7]:2000", v6("fe80::1", 2000, 7)),
+        ("backup.example.com:2000", None),
+        ("unix:/run/hbakd.sock", None),
+    ] {
+        assert_eq!(parse(s).socket_addr().unwrap(), addr, "{}", s);
+    }
+}
+
+#[test]
+fn scopes_name_network_interfaces() {
+    // The loopback interface exists on every Linux machine.
+    let index = system::interface_index("lo").unwrap();
+    assert_eq!(
+        parse("fe80::1%lo").socket_addr().unwrap(),
+        Some(SocketAddrV6::new("fe80::1".parse().unwrap(), DEFAULT_PORT, 0, index).into())
+    );
+
+    assert!(matches!(
+        parse("fe80::1%hbak-missing0").socket_addr(),
+        Err(AddressParseError::NoSuchInterface(zone)) if zone == "hbak-missing0"
+    ));
+}
+
+#[test]
+fn ip_addresses_resolve_without_lookups() {
+    assert_eq!(
+        parse("[2001:db8::1]:2000").resolve().unwrap(),
+        [SocketAddr::from(([0x2001, 0xdb8, 0, 0, 0, 0, 0, 1], 2000))]
+    );
+    assert!(parse("unix:/run/hbakd.sock").resolve().is_err());
+}
+
+#[test]
+fn bind_addresses_have_to_be_ip_addresses() {
+    assert_eq!(
+        parse_bind_addr("[::]").unwrap(),
+        SocketAddr::from((Ipv6Addr::UNSPECIFIED, DEFAULT_PORT))
+    );
+    assert_eq!(
+        parse_bind_addr("0.0.0.0:2000").unwrap(),
+        SocketAddr::from((Ipv4Addr::UNSPECIFIED, 2000))
+    );
+
+    assert!(matches!(
+        parse_bind_addr("backup.example.com"),
+        Err(AddressParseError::NotIpAddress(_))
+    ));
+    assert!(matches!(
+        parse_bind_addr("unix:/run/hbakd.sock"),
+        Err(AddressParseError::NotIpAddress(_))
+    ));
+}
@@ -0,0 +1,272 @@
+// hbak_common is the main hbak library implementing the protocol shared logic.
+// Copyright (C) 2024  Himbeer <himbeerserverde@gmail.com>
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+// These tests need root privileges, btrfs-progs and loop device support.
+// Run them using `cargo test -p hbak_common --test e2e -- --ignored`.
+
+use hbak_common::config::{NodeConfig, RemoteNodeAuth, SubvolConfig};
+use hbak_common::conn::{AuthConn, AuthServ, Window};
+use hbak_common::message::{Challenge, Credentials};
+use hbak_common::paths::StorageLayout;
+use hbak_common::proto::{LocalNode, Mode, Snapshot, VolumeSpec};
+use hbak_common::sync::{Role, SyncSession};
+use hbak_common::system;
+use hbak_common::RemoteError;
+
+use std::env;
+use std::fs::{self, File, Permissions};
+use std::io;
+use std::net::TcpListener;
+use std::os::unix::fs::PermissionsExt;
+use std::path::PathBuf;
+use std::process::{self, Command};
+use std::thread;
+use std::time::Duration;
+
+/// The size of the btrfs images in bytes, slightly above the minimum of mkfs.btrfs.
+const IMAGE_LEN: u64 = 256 * 1024 * 1024;
+const PASSPHRASE: &str = "correct horse battery staple";
+const SUBVOL: &str = "data";
+
+/// A `Fixture` is a btrfs image attached to a loop device
+/// along with a temporary directory for its mountpoints and configuration.
+/// Both are cleaned up when it is dropped, so it has to outlive
+/// the [`LocalNode`]s mounting it.
+struct Fixture {
+    dir: PathBuf,
+    device: String,
+}
+
+impl Fixture {
+    fn new(name: &str) -> Self {
+        let dir = env::temp_dir().join(format!("hbak-e2e-{}-{}", process::id(), name));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+
+        let image = dir.join("btrfs.img");
+        File::create(&image).unwrap().set_len(IMAGE_LEN).unwrap();
+        run(Command::new("mkfs.btrfs").arg("-q").arg(&image));
+
+        let output = Command::new("losetup")
+            .arg("--find")
+            .arg("--show")
+            .arg(&image)
+            .output()
+            .unwrap();
+        assert!(output.status.success(), "losetup failed");
+
+        Self {
+            dir,
+            device: String::from_utf8(output.stdout).unwrap().trim().to_string(),
+        }
+    }
+
+    /// Writes a configuration file rooted at the temporary directory
+    /// and loads it the way the binaries do.
+    fn config(&self, node_name: &str) -> NodeConfig {
+        let path = self.dir.join("hbak.conf");
+        fs::write(
+            &path,
+            format!(
+                "device = {:?}\nmount_root = {:?}\nnode_name = {:?}\npassphrase = {:?}\n\
+                 subvols = []\nremotes = []\nauth = []\n",
+                self.device,
+                self.dir.join("mnt"),
+                node_name,
+                PASSPHRASE
+            ),
+        )
+        .unwrap();
+        fs::set_permissions(&path, Permissions::from_mode(0o600)).unwrap();
+
+        NodeConfig::load_from(&path).unwrap()
+    }
+
+    /// Initializes the storage of a node and mounts it.
+    fn init(&self, mode: Mode, node_name: &str) -> LocalNode {
+        let config = self.config(node_name);
+        let layout = StorageLayout::from_config(&config, mode);
+
+        system::init_storage(&layout, &self.device, node_name, false).unwrap();
+
+        let mut local_node = LocalNode::with_config(mode, config).unwrap();
+        local_node.set_subvols(vec![SubvolConfig::from(SUBVOL.to_string())]);
+
+        local_node
+    }
+}
+
+impl Drop for Fixture {
+    fn drop(&mut self) {
+        let _ = Command::new("losetup").arg("-d").arg(&self.device).status();
+        let _ = fs::remove_dir_all(&self.dir);
+    }
+}
+
+fn run(cmd: &mut Command) {
+    assert!(cmd.status().unwrap().success(), "{:?} failed", cmd);
+}
+
+/// Creates the tracked subvolume containing a file with the specified content.
+fn create_subvol(local_node: &LocalNode, content: &str) {
+    let path = local_node.layout().subvol_path(SUBVOL);
+
+    run(Command::new("btrfs")
+        .arg("subvolume")
+        .arg("create")
+        .arg(&path));
+    fs::write(path.join("file"), content).unwrap();
+}
+
+/// Takes a snapshot of the tracked subvolume.
+fn snapshot(local_node: &LocalNode, is_incremental: bool) -> Snapshot {
+    // Snapshot identifiers have a resolution of one second.
+    thread::sleep(Duration::from_millis(1100));
+
+    local_node
+        .snapshot_now(SUBVOL.to_string(), is_incremental, None, false)
+        .unwrap()
+        .unwrap()
+}
+
+/// Receives the specified backup stored on `storage` into `local_node`.
+fn recover(local_node: &LocalNode, storage: &LocalNode, snapshot: &Snapshot) {
+    let (mut child, mut recovery_stream) = local_node.recover(snapshot).unwrap();
+
+    let mut export = storage
+        .export(snapshot, storage.send_protocol(), false)
+        .unwrap();
+    io::copy(&mut export, &mut recovery_stream).unwrap();
+    recovery_stream.close().unwrap();
+    drop(recovery_stream);
+
+    assert!(child.wait().unwrap().success(), "btrfs receive failed");
+}
+
+#[test]
+#[ignore = "requires root, btrfs-progs and loop devices"]
+fn snapshot_export_import_restore() {
+    let client_fixture = Fixture::new("client");
+    let server_fixture = Fixture::new("server");
+    let restored_fixture = Fixture::new("restored");
+
+    let client = client_fixture.init(Mode::Client, "alice");
+    let server = server_fixture.init(Mode::Server, "bob");
+    let restored = restored_fixture.init(Mode::Client, "alice");
+
+    create_subvol(&client, "full");
+    let full = snapshot(&client, false);
+
+    fs::write(
+        client.layout().subvol_path(SUBVOL).join("file"),
+        "incremental",
+    )
+    .unwrap();
+    let incremental = snapshot(&client, true);
+
+    for snapshot in [&full, &incremental] {
+        let stream = client
+            .send_snapshot(snapshot, client.send_protocol(), true)
+            .unwrap();
+        server.backup(stream, snapshot).unwrap();
+    }
+
+    assert_eq!(server.all_backups(None).unwrap().len(), 2);
+
+    for snapshot in [&full, &incremental] {
+        recover(&restored, &server, snapshot);
+    }
+
+    restored
+        .restore(SUBVOL.to_string(), true, None, true)
+        .unwrap();
+
+    let content = fs::read_to_string(restored.layout().subvol_path(SUBVOL).join("file")).unwrap();
+    assert_eq!(content, "incremental");
+}
+
+#[test]
+#[ignore = "requires root, btrfs-progs and loop devices"]
+fn push_over_localhost() {
+    let client_fixture = Fixture::new("push-client");
+    let server_fixture = Fixture::new("push-server");
+
+    let client = client_fixture.init(Mode::Client, "alice");
+    let server = server_fixture.init(Mode::Server, "bob");
+
+    create_subvol(&client, "pushed");
+    let full = snapshot(&client, false);
+    let incremental = snapshot(&client, true);
+
+    let spec = VolumeSpec::try_from("alice/*").unwrap();
+    let (verifier, key) = system::hash_passphrase(PASSPHRASE).unwrap();
+    let auth = RemoteNodeAuth {
+        node_name: String::from("alice"),
+        label: None,
+        verifier,
+        key,
+        push: vec![spec.clone()],
+        pull: Vec::new(),
+        push_interval: None,
+        instance_id: None,
+        rate_limit: None,
+        allowed_sources: Vec::new(),
+    };
+
+    // Pick a free port.
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+
+    thread::scope(|s| {
+        let server_thread = s.spawn(|| {
+            let (stream, _) = listener.accept().unwrap();
+            let (stream_conn, auth) = AuthServ::from(stream).secure_stream([auth]).unwrap();
+
+            // Permissions are mirrored on the server, see `hbakd`.
+            let sync_session =
+                SyncSession::new(&server, Role::Responder, auth.pull, auth.push, |_| {});
+            let plan = sync_session
+                .respond(
+                    stream_conn,
+                    |_: &Challenge| Err(RemoteError::AccessDenied),
+                    || Err(RemoteError::AccessDenied),
+                    |_: Vec<Snapshot>| Vec::new(),
+                    |_: Credentials| Err(RemoteError::AccessDenied),
+                )
+                .unwrap()
+                .expect("client requested synchronization");
+
+            sync_session.data_sync(plan, &Window::default()).unwrap();
+        });
+
+        let stream_conn = AuthConn::new(&addr, client.keepalive())
+            .unwrap()
+            .secure_stream(String::from("alice"), None, String::from("bob"), PASSPHRASE)
+            .unwrap();
+
+        let sync_session =
+            SyncSession::new(&client, Role::Initiator, vec![spec], Vec::new(), |_| {});
+        let plan = sync_session.initiate(stream_conn).unwrap();
+        assert_eq!(plan.queue().len(), 2);
+
+        sync_session.data_sync(plan, &Window::default()).unwrap();
+        server_thread.join().unwrap();
+    });
+
+    let mut backups = server.all_backups(None).unwrap();
+    backups.sort_unstable_by_key(Snapshot::taken);
+    assert_eq!(backups, [full, incremental]);
+}
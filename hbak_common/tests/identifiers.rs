@@ -0,0 +1,185 @@
+// hbak_common is the main hbak library implementing the protocol shared logic.
+// Copyright (C) 2024  Himbeer <himbeerserverde@gmail.com>
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use hbak_common::paths::StorageLayout;
+use hbak_common::proto::{Mode, Snapshot, Volume, VolumeSpec};
+
+use std::path::{Component, Path};
+
+use proptest::prelude::*;
+
+const FULL: &str = "node_subvol_full_20240101000000";
+
+/// Node and subvolume names as they occur in practice,
+/// mixed with separators and path components that must never reach the file system.
+fn name() -> impl Strategy<Value = String> {
+    prop_oneof![
+        "[a-z][a-z0-9.-]{0,11}",
+        "[a-z0-9._/ -]{0,6}",
+        Just(String::from(".")),
+        Just(String::from("..")),
+    ]
+}
+
+/// Snapshots with arbitrary names, types and capture times between 2000 and 2099.
+fn snapshot() -> impl Strategy<Value = Snapshot> {
+    (
+        name(),
+        name(),
+        any::<bool>(),
+        2000..2100u32,
+        0..366 * 24 * 60 * 60u32,
+    )
+        .prop_map(|(node_name, subvol, is_incremental, year, secs)| {
+            let ty = if is_incremental { "incr" } else { "full" };
+            let taken = format!(
+                "{year}{:02}{:02}{:02}{:02}{:02}",
+                secs / (31 * 24 * 60 * 60) % 12 + 1,
+                secs / (24 * 60 * 60) % 28 + 1,
+                secs / (60 * 60) % 24,
+                secs / 60 % 60,
+                secs % 60,
+            );
+
+            Snapshot::try_from(format!("node_subvol_{ty}_{taken}").as_str())
+                .unwrap()
+                .relabel(&node_name, &subvol)
+        })
+}
+
+proptest! {
+    #[test]
+    fn valid_snapshots_round_trip_through_their_on_disk_name(snapshot in snapshot()) {
+        let round_trips = snapshot.on_disk_identity().ok().as_ref() == Some(&snapshot);
+        prop_assert_eq!(snapshot.validate().is_ok(), round_trips);
+    }
+
+    #[test]
+    fn on_disk_names_identify_a_single_valid_snapshot(snapshot in snapshot()) {
+        // Splitting the names at any other separator yields a distinct snapshot
+        // with the same on-disk name, at most one of them may be valid.
+        let names = format!("{}_{}", snapshot.node_name(), snapshot.subvol());
+        for (i, _) in names.match_indices('_') {
+            let other = snapshot.relabel(&names[..i], &names[i + 1..]);
+            if other != snapshot {
+                prop_assert_eq!(other.to_string(), snapshot.to_string());
+                prop_assert!(snapshot.validate().is_err() || other.validate().is_err());
+            }
+        }
+    }
+
+    #[test]
+    fn distinct_valid_snapshots_never_share_a_path(a in snapshot(), b in snapshot()) {
+        if a == b || a.validate().is_err() || b.validate().is_err() {
+            return Ok(());
+        }
+
+        let layout = StorageLayout::under(Mode::Server, Path::new("/mnt"));
+        prop_assert_ne!(a.to_string(), b.to_string());
+        prop_assert_ne!(a.backup_path(&layout), b.backup_path(&layout));
+        prop_assert_ne!(a.streaming_path(&layout), b.streaming_path(&layout));
+    }
+
+    #[test]
+    fn volumes_accept_exactly_the_valid_names(node_name in name(), subvol in name()) {
+        let volume = Volume::try_from(format!("{node_name}_{subvol}").as_str());
+        let valid = hbak_common::proto::is_valid_name(&node_name)
+            && hbak_common::proto::is_valid_name(&subvol);
+
+        prop_assert_eq!(volume.is_ok(), valid);
+    }
+}
+
+#[test]
+fn volume_rejects_empty_components() {
+    for value in ["_", "node_", "_subvol", ""] {
+        assert!(Volume::try_from(value).is_err(), "accepted {value:?}");
+    }
+
+    assert!(Volume::try_from("node_subvol").is_ok());
+    assert!(VolumeSpec::try_from("../*").is_err());
+}
+
+#[test]
+fn snapshot_rejects_invalid_names() {
+    for value in [
+        "node__full_20240101000000",
+        "node_.._full_20240101000000",
+        "._subvol_full_20240101000000",
+        "node_sub\0vol_full_20240101000000",
+        "node_sub vol_full_20240101000000",
+        "node_süb_full_20240101000000",
+    ] {
+        assert!(Snapshot::try_from(value).is_err(), "accepted {value:?}");
+    }
+
+    assert!(Snapshot::try_from("node.example_sub-vol_full_20240101000000").is_ok());
+}
+
+#[test]
+fn validate_rejects_path_traversal() {
+    let snapshot = Snapshot::try_from(FULL).unwrap();
+    assert!(snapshot.validate().is_ok());
+
+    // Deserialized snapshots bypass parsing, relabelling constructs them just the same.
+    for (node_name, subvol) in [
+        ("node", "../../etc"),
+        ("node", ".."),
+        ("..", "subvol"),
+        ("node", "a/b"),
+        ("node", ""),
+        ("node", "sub\0vol"),
+    ] {
+        let snapshot = snapshot.relabel(node_name, subvol);
+        assert!(snapshot.validate().is_err(), "accepted {snapshot:?}");
+        assert!(snapshot.volume().validate().is_err());
+    }
+}
+
+#[test]
+fn deserialization_rejects_path_traversal() {
+    let snapshot = Snapshot::try_from(FULL).unwrap();
+
+    let valid = bincode::serialize(&snapshot).unwrap();
+    assert_eq!(bincode::deserialize::<Snapshot>(&valid).unwrap(), snapshot);
+
+    for (node_name, subvol) in [("node", "../../etc"), ("..", "subvol"), ("node", "a/b")] {
+        let snapshot = snapshot.relabel(node_name, subvol);
+
+        let serialized = bincode::serialize(&snapshot).unwrap();
+        assert!(bincode::deserialize::<Snapshot>(&serialized).is_err());
+
+        let serialized = bincode::serialize(&snapshot.volume()).unwrap();
+        assert!(bincode::deserialize::<Volume>(&serialized).is_err());
+    }
+}
+
+#[test]
+fn valid_snapshots_stay_inside_backup_dir() {
+    let layout = StorageLayout::under(Mode::Server, Path::new("/mnt"));
+    let snapshot = Snapshot::try_from(FULL).unwrap();
+    snapshot.validate().unwrap();
+
+    for path in [
+        snapshot.backup_path(&layout),
+        snapshot.streaming_path(&layout),
+    ] {
+        assert!(path.starts_with(layout.backup_dir()));
+        assert!(!path
+            .components()
+            .any(|component| component == Component::ParentDir));
+    }
+}
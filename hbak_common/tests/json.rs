@@ -0,0 +1,200 @@
+// hbak_common is the main hbak library implementing the protocol shared logic.
+// Copyright (C) 2024  Himbeer <himbeerserverde@gmail.com>
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use hbak_common::conn::{SyncStats, TransferOutcome, TransferStats, WrapUp};
+use hbak_common::json::{Manifest, SnapshotJson, MANIFEST_VERSION};
+use hbak_common::proto::Snapshot;
+use hbak_common::RemoteError;
+
+use std::time::Duration;
+
+use chrono::{NaiveDate, TimeZone, Utc};
+use serde_json::json;
+
+fn snapshot(s: &str) -> Snapshot {
+    Snapshot::try_from(s).unwrap()
+}
+
+fn snapshot_json() -> serde_json::Value {
+    json!({
+        "identifier": "laptop_home_incr_20240102030405",
+        "volume": "laptop_home",
+        "node_name": "laptop",
+        "subvol": "home",
+        "is_incremental": true,
+        "taken": "2024-01-02T03:04:05Z",
+    })
+}
+
+fn transfer(identifier: &str, bytes: u64, outcome: TransferOutcome) -> TransferStats {
+    TransferStats {
+        snapshot: snapshot(identifier),
+        bytes,
+        elapsed: Duration::from_millis(1500),
+        outcome,
+    }
+}
+
+#[test]
+fn snapshots_have_a_stable_shape() {
+    let snapshot = snapshot("laptop_home_incr_20240102030405");
+
+    assert_eq!(
+        serde_json::to_value(SnapshotJson::from(&snapshot)).unwrap(),
+        snapshot_json()
+    );
+}
+
+#[test]
+fn snapshot_timestamps_are_explicitly_utc() {
+    let snapshot = snapshot("laptop_home_full_20241231235959");
+    let serialized = serde_json::to_string(&SnapshotJson::from(&snapshot)).unwrap();
+
+    assert!(serialized.contains(r#""taken":"2024-12-31T23:59:59Z""#));
+
+    let deserialized: SnapshotJson = serde_json::from_str(&serialized).unwrap();
+    assert_eq!(
+        deserialized.taken.naive_utc(),
+        NaiveDate::from_ymd_opt(2024, 12, 31)
+            .unwrap()
+            .and_hms_opt(23, 59, 59)
+            .unwrap()
+    );
+}
+
+#[test]
+fn manifests_have_a_stable_shape() {
+    let manifest = Manifest {
+        created: Utc.with_ymd_and_hms(2024, 1, 2, 4, 0, 0).unwrap(),
+        hbak_version: String::from("1.2.3"),
+        ..Manifest::new(
+            &snapshot("laptop_home_incr_20240102030405"),
+            4096,
+            &[0xde, 0xad, 0xbe, 0xef],
+        )
+    };
+
+    let mut expected = snapshot_json();
+    let object = expected.as_object_mut().unwrap();
+    object.insert(String::from("size"), json!(4096));
+    object.insert(String::from("sha256"), json!("deadbeef"));
+    object.insert(String::from("created"), json!("2024-01-02T04:00:00Z"));
+    object.insert(String::from("format_version"), json!(MANIFEST_VERSION));
+    object.insert(String::from("hbak_version"), json!("1.2.3"));
+
+    assert_eq!(serde_json::to_value(&manifest).unwrap(), expected);
+    assert_eq!(
+        serde_json::from_value::<Manifest>(expected).unwrap(),
+        manifest
+    );
+    assert!(manifest.matches(4096, &[0xde, 0xad, 0xbe, 0xef]));
+}
+
+#[test]
+fn sync_stats_have_a_stable_shape() {
+    let stats = SyncStats {
+        sent: vec![
+            transfer(
+                "laptop_home_incr_20240102030405",
+                1024,
+                TransferOutcome::Completed,
+            ),
+            transfer(
+                "laptop_root_full_20240102030405",
+                0,
+                TransferOutcome::Rejected(RemoteError::InsufficientSpace(10, 5)),
+            ),
+            transfer(
+                "laptop_var_full_20240102030405",
+                0,
+                TransferOutcome::Skipped,
+            ),
+        ],
+        received: vec![transfer(
+            "desktop_home_full_20240101000000",
+            512,
+            TransferOutcome::Aborted,
+        )],
+        elapsed: Duration::from_secs(3),
+        wrap_up: Some(WrapUp {
+            deadline: NaiveDate::from_ymd_opt(2024, 1, 2)
+                .unwrap()
+                .and_hms_opt(6, 0, 0)
+                .unwrap(),
+            skipped: vec![snapshot("laptop_var_full_20240102030405")],
+        }),
+    };
+
+    let value = serde_json::to_value(&stats).unwrap();
+    assert_eq!(value["sent"][0]["snapshot"], snapshot_json());
+    assert_eq!(value["sent"][0]["bytes"], json!(1024));
+    assert_eq!(
+        value["sent"][0]["elapsed"],
+        json!({"secs": 1, "nanos": 500_000_000})
+    );
+    assert_eq!(value["sent"][0]["outcome"], json!("completed"));
+    assert_eq!(
+        value["sent"][1]["outcome"],
+        json!({"rejected": serde_json::to_value(RemoteError::InsufficientSpace(10, 5)).unwrap()})
+    );
+    assert_eq!(value["sent"][2]["outcome"], json!("skipped"));
+    assert_eq!(value["received"][0]["outcome"], json!("aborted"));
+    assert_eq!(
+        value["received"][0]["snapshot"]["identifier"],
+        json!("desktop_home_full_20240101000000")
+    );
+    assert_eq!(value["elapsed"], json!({"secs": 3, "nanos": 0}));
+    assert_eq!(
+        value["wrap_up"],
+        json!({
+            "deadline": "2024-01-02T06:00:00Z",
+            "skipped": [{
+                "identifier": "laptop_var_full_20240102030405",
+                "volume": "laptop_var",
+                "node_name": "laptop",
+                "subvol": "var",
+                "is_incremental": false,
+                "taken": "2024-01-02T03:04:05Z",
+            }],
+        })
+    );
+
+    let keys = |value: &serde_json::Value| {
+        let mut keys: Vec<_> = value.as_object().unwrap().keys().cloned().collect();
+        keys.sort();
+        keys
+    };
+    assert_eq!(keys(&value), ["elapsed", "received", "sent", "wrap_up"]);
+    assert_eq!(
+        keys(&value["sent"][0]),
+        ["bytes", "elapsed", "outcome", "snapshot"]
+    );
+}
+
+#[test]
+fn sync_stats_without_wrap_up_have_a_null_field() {
+    let value = serde_json::to_value(SyncStats::default()).unwrap();
+
+    assert_eq!(
+        value,
+        json!({
+            "sent": [],
+            "received": [],
+            "elapsed": {"secs": 0, "nanos": 0},
+            "wrap_up": null,
+        })
+    );
+}
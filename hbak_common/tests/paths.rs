@@ -0,0 +1,175 @@
+// hbak_common is the main hbak library implementing the protocol shared logic.
+// Copyright (C) 2024  Himbeer <himbeerserverde@gmail.com>
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use hbak_common::config::NodeConfig;
+use hbak_common::paths::StorageLayout;
+use hbak_common::proto::{Mode, Snapshot};
+
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process;
+
+use proptest::prelude::*;
+
+/// Node and subvolume names as they occur in practice,
+/// mixed with separators and path components that must never reach the file system.
+fn name() -> impl Strategy<Value = String> {
+    prop_oneof![
+        "[a-z][a-z0-9.-]{0,11}",
+        "[a-z0-9._/ -]{0,6}",
+        Just(String::from(".")),
+        Just(String::from("..")),
+        Just(String::from("../..")),
+    ]
+}
+
+/// Snapshots with arbitrary names of both types.
+fn snapshot() -> impl Strategy<Value = Snapshot> {
+    (name(), name(), any::<bool>()).prop_map(|(node_name, subvol, is_incremental)| {
+        let ty = if is_incremental { "incr" } else { "full" };
+        Snapshot::try_from(format!("node_subvol_{ty}_20240101000000").as_str())
+            .unwrap()
+            .relabel(&node_name, &subvol)
+    })
+}
+
+/// Creates an empty directory to build layouts in.
+fn root(name: &str) -> PathBuf {
+    let dir = env::temp_dir().join(format!("hbak-paths-{}-{}", process::id(), name));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+
+    // Symbolic links in the temporary directory are resolved by canonicalization.
+    dir.canonicalize().unwrap()
+}
+
+/// Returns the layouts of both modes under the specified root,
+/// once with the default directories and once with all of them relocated.
+fn layouts(root: &Path) -> Vec<StorageLayout> {
+    let config: NodeConfig = toml::from_str(&format!(
+        r#"
+            device = "/dev/null"
+            node_name = "node"
+            mount_root = "{0}/mnt"
+            archive_dir = "{0}/archive"
+            subvols = []
+            remotes = []
+            auth = []
+        "#,
+        root.display()
+    ))
+    .unwrap();
+
+    [Mode::Client, Mode::Server]
+        .into_iter()
+        .flat_map(|mode| {
+            [
+                StorageLayout::under(mode, &root.join("mnt")),
+                StorageLayout::from_config(&config, mode)
+                    .with_snapshot_dir(root.join("snapshots"))
+                    .with_backup_dir(root.join("backups")),
+            ]
+        })
+        .collect()
+}
+
+/// Creates the directory at the specified path and returns its canonical path,
+/// resolving any `.` or `..` components as the file system does.
+fn create(path: &Path) -> PathBuf {
+    fs::create_dir_all(path).unwrap();
+    path.canonicalize().unwrap()
+}
+
+/// Reports whether the path canonicalizes to a location strictly inside of the directory.
+fn is_inside(path: &Path, dir: &Path) -> bool {
+    let dir = create(dir);
+    let canonical = create(path);
+
+    canonical.starts_with(&dir) && canonical != dir
+}
+
+fn assert_inside(path: &Path, dir: &Path) {
+    assert!(
+        is_inside(path, dir),
+        "{} is outside of {}",
+        path.display(),
+        dir.display()
+    );
+}
+
+#[test]
+fn layout_directories_stay_inside_the_root() {
+    let root = root("dirs");
+
+    for layout in layouts(&root) {
+        assert_inside(layout.mountpoint(), &root);
+        assert_inside(layout.snapshot_dir(), &root);
+        assert_inside(layout.backup_dir(), &root);
+        assert_inside(layout.archive_dir().unwrap_or(layout.backup_dir()), &root);
+        assert_inside(layout.pre_restore_dir(), layout.mountpoint());
+        assert_inside(&layout.estimate_cache(), layout.mountpoint());
+    }
+
+    fs::remove_dir_all(root).unwrap();
+}
+
+proptest! {
+    #![proptest_config(ProptestConfig::with_cases(64))]
+
+    #[test]
+    fn valid_snapshots_stay_inside_their_directories(snapshot in snapshot()) {
+        if snapshot.validate().is_err() {
+            return Ok(());
+        }
+
+        let root = root("snapshots");
+        for layout in layouts(&root) {
+            assert_inside(&layout.subvol_path(snapshot.subvol()), layout.mountpoint());
+            assert_inside(&layout.snapshot_path(&snapshot), layout.snapshot_dir());
+            assert_inside(&layout.staging_dir(&snapshot), layout.snapshot_dir());
+            assert_inside(&layout.volume_dir(&snapshot), layout.backup_dir());
+            assert_inside(&layout.backup_path(&snapshot), &layout.volume_dir(&snapshot));
+            assert_inside(&layout.streaming_path(&snapshot), &layout.volume_dir(&snapshot));
+            assert_inside(&layout.legacy_backup_path(&snapshot), layout.backup_dir());
+
+            if let Some(archive_dir) = layout.archive_dir() {
+                assert_inside(&layout.archive_path(&snapshot).unwrap(), archive_dir);
+            }
+        }
+
+        fs::remove_dir_all(root).unwrap();
+    }
+}
+
+#[test]
+fn traversing_snapshots_escape_their_directories() {
+    let root = root("traversal");
+    let snapshot = Snapshot::try_from("node_subvol_full_20240101000000").unwrap();
+
+    for layout in layouts(&root) {
+        for (node_name, subvol) in [("node", "../.."), ("..", "subvol"), ("node", "..")] {
+            let snapshot = snapshot.relabel(node_name, subvol);
+            assert!(snapshot.validate().is_err());
+            assert!(!is_inside(
+                &layout.volume_dir(&snapshot),
+                layout.backup_dir()
+            ));
+        }
+    }
+
+    fs::remove_dir_all(root).unwrap();
+}
@@ -0,0 +1,107 @@
+// hbak_common is the main hbak library implementing the protocol shared logic.
+// Copyright (C) 2024  Himbeer <himbeerserverde@gmail.com>
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use hbak_common::proto::{Promotion, Snapshot};
+
+/// Returns the snapshots of a subvolume described by a sequence of `f` (full)
+/// and `i` (incremental) taken one day apart in this order.
+fn history(types: &str) -> Vec<Snapshot> {
+    types
+        .chars()
+        .enumerate()
+        .map(|(day, ty)| {
+            let ty = match ty {
+                'f' => "full",
+                'i' => "incr",
+                _ => unreachable!(),
+            };
+
+            Snapshot::try_from(format!("node_subvol_{}_202401{:02}000000", ty, day + 1).as_str())
+                .unwrap()
+        })
+        .collect()
+}
+
+#[test]
+fn first_snapshot_is_always_full() {
+    for max_incrementals in [None, Some(1), Some(10)] {
+        assert_eq!(
+            Promotion::required(&[], max_incrementals),
+            Some(Promotion::NoFullSnapshot)
+        );
+    }
+
+    // Incremental snapshots without a full one cannot be continued either.
+    assert_eq!(
+        Promotion::required(&history("ii"), None),
+        Some(Promotion::NoFullSnapshot)
+    );
+}
+
+#[test]
+fn promotion_starts_at_the_threshold() {
+    assert_eq!(Promotion::required(&history("f"), Some(3)), None);
+    assert_eq!(Promotion::required(&history("fi"), Some(3)), None);
+    assert_eq!(Promotion::required(&history("fii"), Some(3)), None);
+    assert_eq!(
+        Promotion::required(&history("fiii"), Some(3)),
+        Some(Promotion::MaxIncrementals(3))
+    );
+
+    // Forced incremental snapshots exceed the threshold.
+    assert_eq!(
+        Promotion::required(&history("fiiii"), Some(3)),
+        Some(Promotion::MaxIncrementals(4))
+    );
+}
+
+#[test]
+fn threshold_of_one_allows_a_single_incremental() {
+    assert_eq!(Promotion::required(&history("f"), Some(1)), None);
+    assert_eq!(
+        Promotion::required(&history("fi"), Some(1)),
+        Some(Promotion::MaxIncrementals(1))
+    );
+}
+
+#[test]
+fn incrementals_are_counted_since_the_latest_full() {
+    assert_eq!(Promotion::required(&history("fiiifi"), Some(3)), None);
+    assert_eq!(Promotion::required(&history("fiiifii"), Some(3)), None);
+    assert_eq!(
+        Promotion::required(&history("fiiifiii"), Some(3)),
+        Some(Promotion::MaxIncrementals(3))
+    );
+
+    // The order of the snapshots doesn't matter.
+    let mut snapshots = history("fiiifii");
+    snapshots.reverse();
+    assert_eq!(
+        Promotion::required(&snapshots, Some(2)),
+        Some(Promotion::MaxIncrementals(2))
+    );
+}
+
+#[test]
+fn incrementals_are_unlimited_by_default() {
+    assert_eq!(Promotion::required(&history("fiiiiiiiiii"), None), None);
+}
+
+#[test]
+fn only_the_threshold_can_be_overridden() {
+    assert!(Promotion::MaxIncrementals(3).can_be_overridden());
+    assert!(!Promotion::NoFullSnapshot.can_be_overridden());
+}
@@ -0,0 +1,72 @@
+// hbak_common is the main hbak library implementing the protocol shared logic.
+// Copyright (C) 2024  Himbeer <himbeerserverde@gmail.com>
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use hbak_common::proto::Snapshot;
+use hbak_common::sync;
+
+fn snapshots(values: &[&str]) -> Vec<Snapshot> {
+    values
+        .iter()
+        .map(|value| Snapshot::try_from(*value).unwrap())
+        .collect()
+}
+
+#[test]
+fn parents_precede_children() {
+    let queue = sync::order_queue([snapshots(&[
+        "node_subvol_incr_20240103000000",
+        "node_subvol_incr_20240102000000",
+        "node_subvol_full_20240102000000",
+        "node_subvol_full_20240101000000",
+    ])]);
+
+    assert_eq!(
+        queue,
+        snapshots(&[
+            "node_subvol_full_20240101000000",
+            "node_subvol_full_20240102000000",
+            "node_subvol_incr_20240102000000",
+            "node_subvol_incr_20240103000000",
+        ])
+    );
+}
+
+#[test]
+fn restore_and_pull_of_own_volume_queue_once() {
+    // A client granted to pull its own volume is also entitled to restore it.
+    let restore = snapshots(&[
+        "client_subvol_incr_20240102000000",
+        "client_subvol_full_20240101000000",
+    ]);
+    let pull = snapshots(&[
+        "client_subvol_full_20240101000000",
+        "client_subvol_incr_20240102000000",
+        "client_subvol_incr_20240103000000",
+    ]);
+    let other = snapshots(&["other_subvol_full_20240101000000"]);
+
+    let queue = sync::order_queue([restore, other, pull]);
+
+    assert_eq!(
+        queue,
+        snapshots(&[
+            "client_subvol_full_20240101000000",
+            "client_subvol_incr_20240102000000",
+            "other_subvol_full_20240101000000",
+            "client_subvol_incr_20240103000000",
+        ])
+    );
+}
@@ -0,0 +1,55 @@
+// hbak_common is the main hbak library implementing the protocol shared logic.
+// Copyright (C) 2024  Himbeer <himbeerserverde@gmail.com>
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use hbak_common::system;
+
+use std::collections::HashSet;
+
+const CALLS: usize = 64;
+
+#[test]
+fn secret_bytes_have_requested_length() {
+    for n in [0, 1, 16, 32, 1000] {
+        assert_eq!(system::random_bytes_secret(n).unwrap().len(), n);
+    }
+}
+
+#[test]
+fn secret_bytes_dont_repeat() {
+    let values: HashSet<_> = (0..CALLS)
+        .map(|_| system::random_bytes_secret(32).unwrap())
+        .collect();
+
+    assert_eq!(values.len(), CALLS);
+}
+
+#[test]
+fn random_bytes_dont_repeat() {
+    let values: HashSet<_> = (0..CALLS).map(|_| system::random_bytes(32)).collect();
+
+    assert_eq!(values.len(), CALLS);
+    assert!(values.iter().all(|value| value.len() == 32));
+}
+
+#[test]
+fn generators_dont_reproduce_each_other() {
+    let values: HashSet<_> = (0..CALLS)
+        .map(|_| system::random_bytes_secret(32).unwrap())
+        .chain((0..CALLS).map(|_| system::random_bytes(32)))
+        .collect();
+
+    assert_eq!(values.len(), 2 * CALLS);
+}
@@ -0,0 +1,330 @@
+// hbak_common is the main hbak library implementing the protocol shared logic.
+// Copyright (C) 2024  Himbeer <himbeerserverde@gmail.com>
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use hbak_common::config::{HumanDuration, RemoteNodeAuth};
+use hbak_common::proto::{Snapshot, VolumeSpec};
+use hbak_common::state::{RevokeImpact, ServerState};
+
+use chrono::{NaiveDate, NaiveDateTime, TimeDelta};
+
+const DAY: u64 = 24 * 60 * 60;
+
+fn grant(node_name: &str, label: Option<&str>, push_interval: Option<u64>) -> RemoteNodeAuth {
+    RemoteNodeAuth {
+        node_name: node_name.to_string(),
+        label: label.map(String::from),
+        verifier: vec![0; 32],
+        key: vec![0; 32],
+        push: Vec::new(),
+        pull: Vec::new(),
+        push_interval: push_interval.map(HumanDuration::from_secs),
+        instance_id: None,
+        rate_limit: None,
+        allowed_sources: Vec::new(),
+    }
+}
+
+/// Returns a grant that allows pushing and pulling the specified volumes.
+fn permit(node_name: &str, label: Option<&str>, push: &[&str], pull: &[&str]) -> RemoteNodeAuth {
+    RemoteNodeAuth {
+        push: push.iter().map(|spec| spec_of(spec)).collect(),
+        pull: pull.iter().map(|spec| spec_of(spec)).collect(),
+        ..grant(node_name, label, None)
+    }
+}
+
+fn spec_of(spec: &str) -> VolumeSpec {
+    VolumeSpec::try_from(spec).unwrap()
+}
+
+/// Returns a backup of the specified volume taken the specified number of days ago.
+fn backup(volume: &str, days_ago: i64) -> Snapshot {
+    let taken = now() - TimeDelta::days(days_ago);
+    Snapshot::try_from(format!("{}_full_{}", volume, taken.format("%Y%m%d%H%M%S")).as_str())
+        .unwrap()
+}
+
+fn now() -> NaiveDateTime {
+    NaiveDate::from_ymd_opt(2024, 3, 1)
+        .unwrap()
+        .and_hms_opt(12, 0, 0)
+        .unwrap()
+}
+
+/// Returns a `ServerState` in which the client pushed at the specified times.
+fn pushed(node_name: &str, times: &[NaiveDateTime]) -> ServerState {
+    let mut server_state = ServerState::default();
+    for (i, time) in times.iter().enumerate() {
+        let snapshot = Snapshot::try_from(
+            format!(
+                "{}_subvol{}_full_{}",
+                node_name,
+                i,
+                time.format("%Y%m%d%H%M%S")
+            )
+            .as_str(),
+        )
+        .unwrap();
+
+        server_state.record_received(node_name, snapshot, *time);
+    }
+
+    server_state
+}
+
+#[test]
+fn clients_without_interval_are_never_stale() {
+    let server_state = ServerState::default();
+    let auth = [grant("laptop", None, None)];
+
+    assert!(server_state.stale_clients(&auth, now()).is_empty());
+}
+
+#[test]
+fn clients_that_never_pushed_are_stale() {
+    let server_state = ServerState::default();
+    let auth = [grant("laptop", None, Some(DAY))];
+
+    let stale = server_state.stale_clients(&auth, now());
+    assert_eq!(stale.len(), 1);
+    assert_eq!(stale[0].node_name, "laptop");
+    assert_eq!(stale[0].last_push, None);
+    assert_eq!(stale[0].push_interval, HumanDuration::from_secs(DAY));
+}
+
+#[test]
+fn staleness_starts_after_the_interval() {
+    let auth = [grant("laptop", None, Some(DAY))];
+    let interval = TimeDelta::seconds(DAY as i64);
+
+    let on_time = pushed("laptop", &[now() - interval]);
+    assert!(on_time.stale_clients(&auth, now()).is_empty());
+
+    let late = pushed("laptop", &[now() - interval - TimeDelta::seconds(1)]);
+    let stale = late.stale_clients(&auth, now());
+    assert_eq!(stale.len(), 1);
+    assert_eq!(
+        stale[0].last_push,
+        Some(now() - interval - TimeDelta::seconds(1))
+    );
+}
+
+#[test]
+fn latest_push_of_any_volume_counts() {
+    let auth = [grant("laptop", None, Some(DAY))];
+    let server_state = pushed(
+        "laptop",
+        &[now() - TimeDelta::days(30), now() - TimeDelta::hours(1)],
+    );
+
+    assert!(server_state.stale_clients(&auth, now()).is_empty());
+}
+
+#[test]
+fn pushes_of_other_clients_dont_count() {
+    let auth = [
+        grant("laptop", None, Some(DAY)),
+        grant("desktop", None, Some(DAY)),
+    ];
+    let server_state = pushed("desktop", &[now()]);
+
+    let stale = server_state.stale_clients(&auth, now());
+    assert_eq!(stale.len(), 1);
+    assert_eq!(stale[0].node_name, "laptop");
+}
+
+#[test]
+fn pushes_from_the_future_are_not_stale() {
+    let auth = [grant("laptop", None, Some(DAY))];
+    let server_state = pushed("laptop", &[now() + TimeDelta::days(2)]);
+
+    assert!(server_state.stale_clients(&auth, now()).is_empty());
+}
+
+#[test]
+fn clients_with_multiple_grants_are_reported_once() {
+    let auth = [
+        grant("laptop", Some("breakglass"), None),
+        grant("laptop", None, Some(DAY)),
+        grant("laptop", Some("backup"), Some(7 * DAY)),
+    ];
+    let server_state = pushed("laptop", &[now() - TimeDelta::days(3)]);
+
+    // The first grant with an interval applies.
+    let stale = server_state.stale_clients(&auth, now());
+    assert_eq!(stale.len(), 1);
+    assert_eq!(stale[0].push_interval, HumanDuration::from_secs(DAY));
+}
+
+#[test]
+fn revoking_unknown_grants_has_no_impact() {
+    let auth = [permit("laptop", Some("backup"), &["laptop_home"], &[])];
+
+    let analyze = |node_name, label| {
+        RevokeImpact::analyze(node_name, label, &auth, &[], &ServerState::default())
+    };
+    assert_eq!(analyze("desktop", None), None);
+    assert_eq!(analyze("laptop", Some("breakglass")), None);
+    assert_eq!(analyze("laptop", None).unwrap().label, None);
+}
+
+#[test]
+fn revoke_impact_counts_stored_backups() {
+    let auth = [permit("laptop", None, &["laptop_home", "laptop/*"], &["*"])];
+    let backups = [
+        backup("laptop_home", 3),
+        backup("laptop_home", 1),
+        backup("laptop_root", 2),
+        backup("desktop_home", 0),
+    ];
+
+    let impact =
+        RevokeImpact::analyze("laptop", None, &auth, &backups, &ServerState::default()).unwrap();
+
+    let push: Vec<_> = impact
+        .push
+        .iter()
+        .map(|volume| (volume.volume.to_string(), volume.backups, volume.latest))
+        .collect();
+    assert_eq!(
+        push,
+        [
+            (
+                String::from("laptop_home"),
+                2,
+                Some(now() - TimeDelta::days(1))
+            ),
+            (
+                String::from("laptop/*"),
+                3,
+                Some(now() - TimeDelta::days(1))
+            ),
+        ]
+    );
+
+    assert_eq!(impact.pull.len(), 1);
+    assert_eq!(impact.pull[0].volume, VolumeSpec::All);
+    assert_eq!(impact.pull[0].backups, 4);
+    assert_eq!(impact.pull[0].latest, Some(now()));
+}
+
+#[test]
+fn revoke_impact_of_volumes_without_backups() {
+    let auth = [permit("laptop", None, &["laptop_home"], &[])];
+
+    let impact =
+        RevokeImpact::analyze("laptop", None, &auth, &[], &ServerState::default()).unwrap();
+    assert_eq!(impact.push.len(), 1);
+    assert_eq!(impact.push[0].backups, 0);
+    assert_eq!(impact.push[0].latest, None);
+    assert!(impact.pull.is_empty());
+}
+
+#[test]
+fn revoke_impact_lists_other_clients_covering_the_volume() {
+    let auth = [
+        permit("laptop", None, &["laptop_home"], &["laptop/*"]),
+        permit("desktop", None, &[], &["*"]),
+        permit("phone", None, &["laptop/*"], &["laptop_home"]),
+        permit("tablet", None, &["desktop/*"], &[]),
+    ];
+
+    let impact =
+        RevokeImpact::analyze("laptop", None, &auth, &[], &ServerState::default()).unwrap();
+
+    // Granting a single volume doesn't cover all volumes of the node.
+    assert_eq!(impact.push[0].others, ["phone"]);
+    assert!(!impact.push[0].is_exclusive());
+    assert_eq!(impact.pull[0].others, ["desktop"]);
+}
+
+#[test]
+fn revoke_impact_of_exclusive_volumes() {
+    let auth = [
+        permit("laptop", None, &["laptop_home"], &[]),
+        permit("desktop", None, &[], &["laptop_home"]),
+    ];
+
+    // Pull permissions don't cover pushes.
+    let impact =
+        RevokeImpact::analyze("laptop", None, &auth, &[], &ServerState::default()).unwrap();
+    assert!(impact.push[0].is_exclusive());
+}
+
+#[test]
+fn revoking_a_single_grant_keeps_the_others() {
+    let auth = [
+        permit("laptop", None, &["laptop_home"], &[]),
+        permit("laptop", Some("breakglass"), &["laptop_home"], &["*"]),
+    ];
+
+    let impact = RevokeImpact::analyze(
+        "laptop",
+        Some("breakglass"),
+        &auth,
+        &[],
+        &ServerState::default(),
+    )
+    .unwrap();
+
+    assert_eq!(impact.label.as_deref(), Some("breakglass"));
+    assert_eq!(impact.push[0].others, ["laptop"]);
+    assert!(impact.pull[0].is_exclusive());
+}
+
+#[test]
+fn revoking_all_grants_merges_their_volumes() {
+    let auth = [
+        permit("laptop", None, &["laptop_home", "laptop_root"], &[]),
+        permit("laptop", Some("breakglass"), &["laptop_home"], &["*"]),
+        permit("desktop", None, &["laptop_root"], &[]),
+    ];
+
+    let impact =
+        RevokeImpact::analyze("laptop", None, &auth, &[], &ServerState::default()).unwrap();
+
+    let push: Vec<_> = impact
+        .push
+        .iter()
+        .map(|volume| (volume.volume.to_string(), volume.others.clone()))
+        .collect();
+    assert_eq!(
+        push,
+        [
+            (String::from("laptop_home"), Vec::new()),
+            (String::from("laptop_root"), vec![String::from("desktop")]),
+        ]
+    );
+    assert_eq!(impact.pull.len(), 1);
+}
+
+#[test]
+fn revoke_impact_reports_the_last_activity() {
+    let auth = [permit("laptop", None, &["laptop_home"], &[])];
+
+    let mut server_state = pushed("laptop", &[now() - TimeDelta::days(2)]);
+    server_state.record_session("laptop", now() - TimeDelta::hours(1));
+    server_state.record_session("desktop", now());
+
+    let impact = RevokeImpact::analyze("laptop", None, &auth, &[], &server_state).unwrap();
+    assert_eq!(impact.last_session, Some(now() - TimeDelta::hours(1)));
+    assert_eq!(impact.last_push, Some(now() - TimeDelta::days(2)));
+
+    let unknown =
+        RevokeImpact::analyze("laptop", None, &auth, &[], &ServerState::default()).unwrap();
+    assert_eq!(unknown.last_session, None);
+    assert_eq!(unknown.last_push, None);
+}
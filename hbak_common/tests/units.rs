@@ -0,0 +1,241 @@
+// hbak_common is the main hbak library implementing the protocol shared logic.
+// Copyright (C) 2024  Himbeer <himbeerserverde@gmail.com>
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use hbak_common::config::{ByteSize, HumanDuration};
+use hbak_common::{ByteSizeParseError, DurationParseError};
+
+use serde::{Deserialize, Serialize};
+
+use proptest::prelude::*;
+
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+struct Units {
+    size: ByteSize,
+    duration: HumanDuration,
+}
+
+/// Numbers followed by a unit as a user might type them,
+/// with arbitrary case and optional whitespace before the unit.
+fn byte_size_input() -> impl Strategy<Value = String> {
+    (
+        0u64..100_000,
+        prop::sample::select(vec![
+            "", "B", "KB", "MB", "GB", "TB", "KiB", "MiB", "GiB", "TiB", "kib", "gb", "TIB",
+        ]),
+        prop::bool::ANY,
+    )
+        .prop_map(|(number, unit, space)| {
+            format!("{}{}{}", number, if space { " " } else { "" }, unit)
+        })
+}
+
+/// Sequences of numbers followed by units, including repeated and unordered units.
+fn duration_input() -> impl Strategy<Value = String> {
+    prop::collection::vec(
+        (
+            0u64..1000,
+            prop::sample::select(vec!['s', 'm', 'h', 'd', 'w', 'H', 'D']),
+        ),
+        1..5,
+    )
+    .prop_map(|components| {
+        components
+            .into_iter()
+            .map(|(number, unit)| format!("{}{}", number, unit))
+            .collect()
+    })
+}
+
+proptest! {
+    #[test]
+    fn byte_sizes_round_trip_through_their_formatting(bytes in prop_oneof![
+        any::<u64>(),
+        (0u64..1 << 24, 0u32..41).prop_map(|(number, shift)| number << shift),
+        (0u64..1 << 24, 0u32..5).prop_map(|(number, exp)| number * 1000u64.pow(exp)),
+    ]) {
+        let formatted = ByteSize(bytes).to_string();
+        prop_assert_eq!(formatted.parse::<ByteSize>().unwrap(), ByteSize(bytes), "{}", formatted);
+    }
+
+    #[test]
+    fn durations_round_trip_through_their_formatting(secs in prop_oneof![
+        any::<u64>(),
+        0u64..100 * 24 * 60 * 60,
+    ]) {
+        let formatted = HumanDuration::from_secs(secs).to_string();
+        prop_assert_eq!(
+            formatted.parse::<HumanDuration>().unwrap(),
+            HumanDuration::from_secs(secs),
+            "{}",
+            formatted
+        );
+    }
+
+    #[test]
+    fn parsed_byte_sizes_format_canonically(input in byte_size_input()) {
+        let parsed: ByteSize = input.parse().unwrap();
+        let formatted = parsed.to_string();
+
+        prop_assert_eq!(formatted.parse::<ByteSize>().unwrap(), parsed);
+        prop_assert_eq!(formatted.parse::<ByteSize>().unwrap().to_string(), formatted);
+    }
+
+    #[test]
+    fn parsed_durations_format_canonically(input in duration_input()) {
+        let parsed: HumanDuration = input.parse().unwrap();
+        let formatted = parsed.to_string();
+
+        prop_assert_eq!(formatted.parse::<HumanDuration>().unwrap(), parsed);
+        prop_assert_eq!(formatted.parse::<HumanDuration>().unwrap().to_string(), formatted);
+    }
+
+    #[test]
+    fn units_round_trip_through_the_configuration_file(bytes in any::<u64>(), secs in any::<u64>()) {
+        let units = Units {
+            size: ByteSize(bytes),
+            duration: HumanDuration::from_secs(secs),
+        };
+
+        let serialized = toml::to_string(&units).unwrap();
+        prop_assert_eq!(toml::from_str::<Units>(&serialized).unwrap(), units);
+    }
+}
+
+#[test]
+fn byte_sizes_use_the_smallest_exact_unit() {
+    for (bytes, formatted) in [
+        (0, "0B"),
+        (1, "1B"),
+        (1000, "1KB"),
+        (1024, "1KiB"),
+        (1536, "1536B"),
+        (4096, "4KiB"),
+        (1 << 30, "1GiB"),
+        (2_000_000_000_000, "2TB"),
+        (u64::MAX, "18446744073709551615B"),
+    ] {
+        assert_eq!(ByteSize(bytes).to_string(), formatted);
+    }
+}
+
+#[test]
+fn byte_sizes_parse_documented_forms() {
+    for (input, bytes) in [
+        ("4096", 4096),
+        ("4 KiB", 4096),
+        ("500GiB", 500 << 30),
+        ("2TB", 2_000_000_000_000),
+        ("  3 mb ", 3_000_000),
+    ] {
+        assert_eq!(
+            input.parse::<ByteSize>().unwrap(),
+            ByteSize(bytes),
+            "{}",
+            input
+        );
+    }
+}
+
+#[test]
+fn malformed_byte_sizes_are_rejected() {
+    for input in ["", "KiB", "-1", "1.5GiB", "4 XB", "4 KiB extra"] {
+        assert!(
+            matches!(
+                input.parse::<ByteSize>(),
+                Err(ByteSizeParseError::Malformed(_))
+            ),
+            "{}",
+            input
+        );
+    }
+
+    assert!(matches!(
+        "16777216TiB".parse::<ByteSize>(),
+        Err(ByteSizeParseError::Overflow(_))
+    ));
+}
+
+#[test]
+fn durations_omit_zero_components() {
+    for (secs, formatted) in [
+        (0, "0s"),
+        (59, "59s"),
+        (60, "1m"),
+        (3601, "1h1s"),
+        (9000, "2h30m"),
+        (7 * 24 * 60 * 60, "7d"),
+        (90 * 24 * 60 * 60, "90d"),
+    ] {
+        assert_eq!(HumanDuration::from_secs(secs).to_string(), formatted);
+    }
+}
+
+#[test]
+fn durations_parse_documented_forms() {
+    for (input, secs) in [
+        ("90", 90),
+        ("90s", 90),
+        ("2h30m", 9000),
+        ("2h 30m", 9000),
+        ("90d", 90 * 24 * 60 * 60),
+        ("1w", 7 * 24 * 60 * 60),
+        ("30m2h", 9000),
+    ] {
+        assert_eq!(
+            input.parse::<HumanDuration>().unwrap(),
+            HumanDuration::from_secs(secs),
+            "{}",
+            input
+        );
+    }
+}
+
+#[test]
+fn malformed_durations_are_rejected() {
+    for input in ["", "s", "2x", "2h30", "1.5h", "-1s"] {
+        assert!(
+            matches!(
+                input.parse::<HumanDuration>(),
+                Err(DurationParseError::Malformed(_))
+            ),
+            "{}",
+            input
+        );
+    }
+
+    assert!(matches!(
+        "18446744073709551616".parse::<HumanDuration>(),
+        Err(DurationParseError::Overflow(_))
+    ));
+    assert!(matches!(
+        "31000000000000w".parse::<HumanDuration>(),
+        Err(DurationParseError::Overflow(_))
+    ));
+}
+
+#[test]
+fn legacy_integers_are_accepted_in_the_configuration_file() {
+    let units: Units = toml::from_str("size = 4096\nduration = 90").unwrap();
+
+    assert_eq!(
+        units,
+        Units {
+            size: ByteSize(4096),
+            duration: HumanDuration::from_secs(90),
+        }
+    );
+    assert!(toml::from_str::<Units>("size = -1\nduration = 90").is_err());
+}
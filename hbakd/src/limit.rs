@@ -0,0 +1,237 @@
+// hbakd is an hbak server providing clients with push and pull access.
+// Copyright (C) 2024  Himbeer <himbeerserverde@gmail.com>
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use std::borrow::Borrow;
+use std::collections::HashMap;
+use std::hash::Hash;
+use std::net::IpAddr;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// The default number of recent failed authentications from a single address
+/// after which further connections are refused.
+pub const DEFAULT_MAX_AUTH_FAILURES: u32 = 10;
+
+/// The default number of concurrent sessions of a single client.
+pub const DEFAULT_MAX_SESSIONS_PER_CLIENT: u32 = 1;
+
+/// The default number of connections handled at the same time.
+pub const DEFAULT_MAX_CLIENTS: usize = 64;
+
+/// The time after which a single failed authentication is forgotten.
+const DECAY_INTERVAL: Duration = Duration::from_secs(600);
+
+/// The response delay after the first failed authentication.
+/// It doubles with every further failure.
+const BASE_DELAY: Duration = Duration::from_millis(500);
+/// The upper bound of the response delay.
+const MAX_DELAY: Duration = Duration::from_secs(30);
+
+/// The number of recent failed authentications of a single source.
+#[derive(Clone, Copy, Debug)]
+struct Failures {
+    count: u32,
+    last_decay: Instant,
+}
+
+impl Failures {
+    fn decay(&mut self, now: Instant) {
+        let intervals = now.duration_since(self.last_decay).as_secs() / DECAY_INTERVAL.as_secs();
+
+        if intervals > 0 {
+            self.count = self
+                .count
+                .saturating_sub(intervals.try_into().unwrap_or(u32::MAX));
+            self.last_decay += DECAY_INTERVAL * intervals as u32;
+        }
+    }
+}
+
+/// An `AuthLimiter` tracks failed authentications per network address
+/// and per claimed node name to throttle online guessing.
+/// Failures are forgotten gradually over time.
+#[derive(Debug)]
+pub struct AuthLimiter {
+    max_failures: u32,
+    addrs: HashMap<IpAddr, Failures>,
+    nodes: HashMap<String, Failures>,
+}
+
+impl AuthLimiter {
+    /// Constructs a new `AuthLimiter` that locks out addresses
+    /// with at least `max_failures` recent failed authentications.
+    pub fn new(max_failures: u32) -> Self {
+        Self {
+            max_failures,
+            addrs: HashMap::new(),
+            nodes: HashMap::new(),
+        }
+    }
+
+    /// Reports whether connections from the specified address are refused.
+    pub fn is_locked_out(&mut self, addr: IpAddr) -> bool {
+        count(&mut self.addrs, &addr, Instant::now()) >= self.max_failures
+    }
+
+    /// Returns the time to wait before responding to an identity proof
+    /// from the specified address claiming to be the specified node.
+    pub fn delay(&mut self, addr: IpAddr, node_name: &str) -> Duration {
+        let now = Instant::now();
+        let failures =
+            count(&mut self.addrs, &addr, now).max(count(&mut self.nodes, node_name, now));
+
+        match failures {
+            0 => Duration::ZERO,
+            n => BASE_DELAY
+                .saturating_mul(2u32.saturating_pow(n - 1))
+                .min(MAX_DELAY),
+        }
+    }
+
+    /// Records a failed authentication from the specified address,
+    /// optionally claiming to be the specified node.
+    /// Returns the number of recent failures from the address.
+    pub fn record_failure(&mut self, addr: IpAddr, node_name: Option<&str>) -> u32 {
+        let now = Instant::now();
+
+        if let Some(node_name) = node_name {
+            increment(&mut self.nodes, node_name.to_string(), now);
+        }
+
+        increment(&mut self.addrs, addr, now)
+    }
+
+    /// Forgets all failed authentications claiming to be the specified node.
+    pub fn record_success(&mut self, node_name: &str) {
+        self.nodes.remove(node_name);
+    }
+
+    /// Applies the decay to all counters and forgets sources
+    /// without any recent failures.
+    pub fn decay(&mut self) {
+        let now = Instant::now();
+
+        self.addrs.retain(|_, failures| {
+            failures.decay(now);
+            failures.count > 0
+        });
+        self.nodes.retain(|_, failures| {
+            failures.decay(now);
+            failures.count > 0
+        });
+    }
+}
+
+/// `ActiveSessions` counts the sessions in progress
+/// per node name and per network address.
+#[derive(Debug, Default)]
+pub struct ActiveSessions {
+    counts: Mutex<SessionCounts>,
+}
+
+#[derive(Debug, Default)]
+struct SessionCounts {
+    nodes: HashMap<String, u32>,
+    addrs: HashMap<IpAddr, u32>,
+}
+
+impl ActiveSessions {
+    /// Registers a session of the specified node from the specified address
+    /// unless either of them already has `max_sessions` sessions in progress.
+    /// The session ends when the returned [`SessionSlot`] is dropped.
+    pub fn try_start(
+        &self,
+        node_name: &str,
+        addr: IpAddr,
+        max_sessions: u32,
+    ) -> Option<SessionSlot<'_>> {
+        let mut counts = self.counts.lock().unwrap();
+
+        if counts.nodes.get(node_name).copied().unwrap_or(0) >= max_sessions
+            || counts.addrs.get(&addr).copied().unwrap_or(0) >= max_sessions
+        {
+            return None;
+        }
+
+        *counts.nodes.entry(node_name.to_string()).or_default() += 1;
+        *counts.addrs.entry(addr).or_default() += 1;
+
+        Some(SessionSlot {
+            active: self,
+            node_name: node_name.to_string(),
+            addr,
+        })
+    }
+}
+
+/// A `SessionSlot` is a session registered with [`ActiveSessions`].
+/// It is released when dropped.
+#[derive(Debug)]
+pub struct SessionSlot<'a> {
+    active: &'a ActiveSessions,
+    node_name: String,
+    addr: IpAddr,
+}
+
+impl Drop for SessionSlot<'_> {
+    fn drop(&mut self) {
+        let mut counts = self.active.counts.lock().unwrap();
+        release(&mut counts.nodes, &self.node_name);
+        release(&mut counts.addrs, &self.addr);
+    }
+}
+
+fn count<K, Q>(map: &mut HashMap<K, Failures>, key: &Q, now: Instant) -> u32
+where
+    K: Eq + Hash + Borrow<Q>,
+    Q: Eq + Hash + ?Sized,
+{
+    match map.get_mut(key) {
+        Some(failures) => {
+            failures.decay(now);
+            failures.count
+        }
+        None => 0,
+    }
+}
+
+fn increment<K: Eq + Hash>(map: &mut HashMap<K, Failures>, key: K, now: Instant) -> u32 {
+    let failures = map.entry(key).or_insert(Failures {
+        count: 0,
+        last_decay: now,
+    });
+
+    failures.decay(now);
+    if failures.count == 0 {
+        failures.last_decay = now;
+    }
+
+    failures.count += 1;
+    failures.count
+}
+
+fn release<K, Q>(map: &mut HashMap<K, u32>, key: &Q)
+where
+    K: Eq + Hash + Borrow<Q>,
+    Q: Eq + Hash + ?Sized,
+{
+    if let Some(count) = map.get_mut(key) {
+        *count -= 1;
+        if *count == 0 {
+            map.remove(key);
+        }
+    }
+}
@@ -17,29 +17,109 @@
 mod error;
 use error::*;
 
-use hbak_common::conn::{AuthServ, DEFAULT_PORT, READ_TIMEOUT};
-use hbak_common::message::SyncInfo;
+mod limit;
+use limit::{
+    ActiveSessions, AuthLimiter, DEFAULT_MAX_AUTH_FAILURES, DEFAULT_MAX_CLIENTS,
+    DEFAULT_MAX_SESSIONS_PER_CLIENT,
+};
+
+mod maintenance;
+use maintenance::{Maintenance, ServiceMode};
+
+mod partials;
+use partials::{ActivePartials, SessionPartials};
+
+mod peer;
+use peer::Peer;
+
+mod reload;
+use reload::ConfigChanges;
+
+mod schedule;
+use schedule::NextSnapshot;
+
+mod systemd;
+
+mod upstream;
+use upstream::UpstreamSchedule;
+
+use hbak_common::config::{NodeConfig, RemoteNode};
+use hbak_common::conn::{
+    AuthConn, AuthServ, SyncStats, Transport, Window, READ_TIMEOUT, VERIFY_RATE,
+};
+use hbak_common::message::{Challenge, Credentials};
+use hbak_common::output::{self, Level};
 use hbak_common::proto::{LocalNode, Mode, Node, Snapshot};
-use hbak_common::stream::CHUNKSIZE;
-use hbak_common::RemoteError;
+use hbak_common::report::{self, FailureReport};
+use hbak_common::state::ServerState;
+use hbak_common::stream::ThrottledReader;
+use hbak_common::sync::{Role, SyncEvent, SyncSession};
+use hbak_common::system::{self, PIDFILE};
+use hbak_common::{LocalNodeError, NetworkError, RemoteError};
 
 use std::collections::HashMap;
-use std::fs::{self, File};
-use std::io::{self, BufWriter};
-use std::net::{IpAddr, Ipv6Addr, SocketAddr, TcpListener, TcpStream};
-use std::path::PathBuf;
+use std::fs::{self, Permissions};
+use std::io;
+use std::net::{SocketAddr, TcpListener};
+use std::os::unix::fs::{FileTypeExt, PermissionsExt};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::{Path, PathBuf};
 use std::sync::atomic::{AtomicBool, Ordering};
-use std::sync::{Arc, Mutex};
-use std::{cmp, process, thread};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, Instant};
+use std::{process, thread};
 
+use chrono::prelude::*;
 use clap::Parser;
 use daemonizr::{Daemonizr, DaemonizrError, Stderr, Stdout};
 
+/// Prints a result to stdout. Failing to do so never aborts an operation.
+macro_rules! out {
+    ($($arg:tt)*) => {
+        output::println(format_args!($($arg)*))
+    };
+}
+
+/// Writes a message of the specified [`output::Level`] to the log on stderr,
+/// optionally preceded by `key = value` pairs describing its context,
+/// e.g. `log!(Info, peer = peer_addr, "Disconnected")`.
+/// Failing to do so never aborts an operation.
+macro_rules! log {
+    ($level:ident, $($key:ident = $value:expr,)* $fmt:literal $(, $arg:expr)* $(,)?) => {
+        output::log(
+            output::Level::$level,
+            &[$((stringify!($key), &$value as &dyn std::fmt::Display)),*],
+            format_args!($fmt $(, $arg)*),
+        )
+    };
+}
+
 const PWD: &str = "/";
-const PIDFILE: &str = "/run/hbakd.pid";
 const LOGFILE_STDOUT: &str = "/var/log/hbakd.out";
 const LOGFILE_STDERR: &str = "/var/log/hbakd.err";
 
+/// The default time to wait for connections to finish on shutdown before cutting them.
+const DEFAULT_DRAIN_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// The time to wait for aborted sessions to end after the drain timeout before cutting them.
+const ABORT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// The interval at which clients are checked for exceeding their push interval.
+const STALENESS_CHECK_INTERVAL: Duration = Duration::from_secs(3600);
+
+/// The interval at which incomplete backups of failed transmissions are cleaned up.
+const PARTIAL_SWEEP_INTERVAL: Duration = Duration::from_secs(3600);
+
+/// The interval at which the maintenance flag file is checked for changes.
+const MAINTENANCE_CHECK_INTERVAL: Duration = Duration::from_secs(5);
+
+/// The interval at which scheduled snapshots are checked for being due.
+/// Failed snapshots are retried after it.
+const SNAPSHOT_CHECK_INTERVAL: Duration = Duration::from_secs(60);
+
+/// The length of verifiers and keys accepted by credential rotations in bytes.
+const SECRET_LEN: usize = 32;
+
 #[derive(Debug, Parser)]
 #[command(author, version, about, long_about = None)]
 /// Background process to serve push and pull requests.
@@ -47,12 +127,49 @@ struct Args {
     /// Stay attached to the terminal instead of daemonizing.
     #[arg(short, long)]
     debug: bool,
+    /// Log debug messages regardless of the configured `log_level`.
+    #[arg(short, long)]
+    verbose: bool,
+    /// Print the backup freshness of all clients and exit.
+    #[arg(short, long)]
+    status: bool,
+    /// Run under systemd: Stay attached, log to stderr for the journal
+    /// and notify the service manager of readiness. Implied by socket activation,
+    /// whose listeners are used instead of binding `bind_addr` and `unix_socket`.
+    #[arg(long)]
+    systemd: bool,
+    /// Print a sample systemd unit file and exit.
+    #[arg(long)]
+    print_systemd_unit: bool,
+    /// Refuse all uploads and upstream pulls until restarted without this flag.
+    /// Downloads keep working. See the maintenance flag file for a runtime toggle.
+    #[arg(long)]
+    read_only: bool,
 }
 
 fn main() {
+    output::ignore_sigpipe();
+
     let args = Args::parse();
 
-    if !args.debug {
+    if args.status {
+        match status() {
+            Ok(_) => process::exit(0),
+            Err(e) => {
+                log!(Error, "{}", e);
+                process::exit(1);
+            }
+        }
+    }
+
+    if args.print_systemd_unit {
+        out!("{}", systemd::UNIT);
+        process::exit(0);
+    }
+
+    let systemd = args.systemd || systemd::is_activated();
+
+    if !args.debug && !systemd {
         match Daemonizr::new()
             .work_dir(PathBuf::from(PWD))
             .expect("invalid workdir")
@@ -72,204 +189,1498 @@ fn main() {
                     .search()
                 {
                     Ok(pid) => {
-                        eprintln!("Another daemon with PID {} is already running", pid);
+                        log!(Error, "Another daemon with PID {} is already running", pid);
                         process::exit(1);
                     }
-                    Err(e) => eprintln!("Daemonization PID search error: {}", e),
+                    Err(e) => log!(Warn, "Daemonization PID search error: {}", e),
                 }
             }
             Err(e) => {
-                eprintln!("Daemonization error: {}", e);
+                log!(Error, "Daemonization error: {}", e);
                 process::exit(1);
             }
         }
     }
 
-    match serve() {
-        Ok(_) => {}
-        Err(e) => {
-            eprintln!("Error: {}", e);
-            process::exit(1);
+    let result = serve(args.debug, args.verbose, systemd, args.read_only);
+    if let Err(e) = &result {
+        log!(Error, "{}", e);
+    }
+
+    let suppressed = output::suppressed();
+    if suppressed > 0 {
+        log!(Warn, "{} log message(s) could not be written", suppressed);
+    }
+
+    if result.is_err() {
+        process::exit(1);
+    }
+}
+
+fn status() -> Result<()> {
+    let node_config = NodeConfig::load()?;
+    let server_state = ServerState::load()?;
+
+    let stale = server_state.stale_clients(&node_config.auth, Utc::now().naive_utc());
+
+    // The read-only flag of a running daemon cannot be determined from here.
+    out!("mode: {}", ServiceMode::from_flag());
+
+    for auth in &node_config.auth {
+        let client = server_state.clients.get(&auth.node_name);
+
+        let last_session = client
+            .and_then(|client| client.last_session)
+            .map(|time| time.to_string())
+            .unwrap_or(String::from("never"));
+        let last_push = client
+            .and_then(|client| client.last_push())
+            .map(|time| time.to_string())
+            .unwrap_or(String::from("never"));
+        let freshness = if stale.iter().any(|item| item.node_name == auth.node_name) {
+            "stale"
+        } else {
+            "ok"
+        };
+
+        out!(
+            "{}: last session {}, last push {}, {}",
+            auth.display_name(),
+            last_session,
+            last_push,
+            freshness
+        );
+    }
+
+    for upstream in &node_config.upstreams {
+        let address = upstream.remote.address.to_string();
+        let upstream_state = server_state.upstreams.get(&address);
+
+        let last_success = upstream_state
+            .and_then(|upstream_state| upstream_state.last_success)
+            .map(|time| time.to_string())
+            .unwrap_or(String::from("never"));
+        let next_attempt = upstream_state
+            .and_then(|upstream_state| upstream_state.next_attempt)
+            .map(|time| time.to_string())
+            .unwrap_or(String::from("unknown"));
+        let health = match upstream_state.and_then(|upstream_state| {
+            upstream_state
+                .last_error
+                .as_ref()
+                .map(|e| (upstream_state.failures, e))
+        }) {
+            Some((failures, e)) => format!("{} failure(s), last: {}", failures, e),
+            None => String::from("ok"),
+        };
+
+        out!(
+            "upstream {}: last success {}, next attempt {}, {}",
+            address,
+            last_success,
+            next_attempt,
+            health
+        );
+    }
+
+    Ok(())
+}
+
+fn warn_stale(shared: &Shared) {
+    let node_config = Arc::clone(&shared.config.read().unwrap());
+    let stale = shared
+        .server_state
+        .lock()
+        .unwrap()
+        .stale_clients(&node_config.auth, Utc::now().naive_utc());
+
+    for staleness in stale {
+        match staleness.last_push {
+            Some(last_push) => log!(
+                Warn,
+                node = staleness.node_name,
+                "No push since {} (expected every {})",
+                last_push,
+                staleness.push_interval
+            ),
+            None => log!(
+                Warn,
+                node = staleness.node_name,
+                "Never pushed (expected every {})",
+                staleness.push_interval
+            ),
         }
     }
 }
 
-fn serve() -> Result<()> {
+/// Logs the push and pull permissions of the granted nodes
+/// that cannot take effect.
+fn warn_ineffective(node_config: &NodeConfig) {
+    for (node_name, e) in node_config.grant_errors() {
+        log!(Warn, node = node_name, "{}", e);
+    }
+}
+
+/// Sends the specified state to the service manager.
+fn notify(state: &str) {
+    if let Err(e) = systemd::notify(state) {
+        log!(Warn, "Cannot notify systemd: {}", e);
+    }
+}
+
+fn save_state(server_state: &ServerState) {
+    if let Err(e) = server_state.save() {
+        log!(Error, "Cannot save state: {}", e);
+    }
+}
+
+/// Persists the binding of the specified node name to an instance identifier
+/// in the configuration file and the current configuration.
+fn save_binding(config: &RwLock<Arc<NodeConfig>>, node_name: &str, instance_id: &str) {
+    // Serialize with rotations and reloads so that none of the edits are lost.
+    let mut config = config.write().unwrap();
+
+    let result = NodeConfig::load().and_then(|mut file_config| {
+        for node_config in [&mut file_config, Arc::make_mut(&mut config)] {
+            for auth in &mut node_config.auth {
+                if auth.node_name == node_name {
+                    auth.instance_id = Some(instance_id.to_string());
+                }
+            }
+        }
+
+        file_config.save()
+    });
+
+    if let Err(e) = result {
+        log!(
+            Error,
+            node = node_name,
+            "Cannot save instance binding: {}",
+            e
+        );
+    }
+}
+
+/// Records the specified error in the failure report and writes it to disk
+/// if the session got past authentication.
+fn save_report(mut report: FailureReport, e: &Error, peer_addr: Peer) {
+    if report.remote_node.is_none() {
+        return;
+    }
+
+    report.fail(e);
+
+    match report.save(report::SERVER_DIR) {
+        Ok(path) => log!(
+            Info,
+            peer = peer_addr,
+            "Failure report written to {}",
+            path.display()
+        ),
+        Err(e) => log!(
+            Error,
+            peer = peer_addr,
+            "Cannot write failure report: {}",
+            e
+        ),
+    }
+}
+
+/// Logs the transition to the specified [`ServiceMode`].
+fn log_mode(mode: &ServiceMode) {
+    match mode {
+        ServiceMode::Normal => log!(Info, "Leaving maintenance mode, accepting uploads"),
+        ServiceMode::Maintenance(_) | ServiceMode::ReadOnly => {
+            log!(Warn, "Entering {} mode, refusing uploads", mode)
+        }
+    }
+}
+
+fn serve(debug: bool, verbose: bool, systemd: bool, read_only: bool) -> Result<()> {
     let should_exit = Arc::new(AtomicBool::new(false));
     let should_exit2 = Arc::clone(&should_exit);
 
     ctrlc::set_handler(move || {
-        eprintln!("[info] Caught SIGINT, SIGTERM or SIGHUP, exiting");
+        log!(Info, "Caught SIGINT or SIGTERM, exiting");
         should_exit2.store(true, Ordering::SeqCst);
     })?;
 
+    // Takes over SIGHUP from the handler above.
+    system::catch_sighup();
+
     let client_threads = Arc::new(Mutex::new(0));
 
     let local_node = Arc::new(LocalNode::new(Mode::Server)?);
 
-    let bind_addr = local_node.config().bind_addr.unwrap_or(SocketAddr::new(
-        IpAddr::V6(Ipv6Addr::UNSPECIFIED),
-        DEFAULT_PORT,
-    ));
+    output::set_log_format(local_node.config().log_format.unwrap_or_default());
+    output::set_log_level(if verbose {
+        Level::Debug
+    } else {
+        local_node.config().log_level.unwrap_or(Level::Info)
+    });
 
-    let listener = TcpListener::bind(bind_addr)?;
+    // Fail early instead of on the first connection
+    // if the passphrase is unavailable.
+    local_node.passphrase()?;
+    let shared = Arc::new(Shared {
+        config: RwLock::new(Arc::new(local_node.config().clone())),
+        verify_lock: Mutex::new(()),
+        server_state: Mutex::new(ServerState::load()?),
+        auth_limiter: Mutex::new(AuthLimiter::new(
+            local_node
+                .config()
+                .max_auth_failures
+                .unwrap_or(DEFAULT_MAX_AUTH_FAILURES),
+        )),
+        active_partials: ActivePartials::default(),
+        active_sessions: ActiveSessions::default(),
+        window: Window::default(),
+        bindings: Mutex::new(
+            local_node
+                .config()
+                .auth
+                .iter()
+                .filter_map(|auth| Some((auth.node_name.clone(), auth.instance_id.clone()?)))
+                .collect(),
+        ),
+        maintenance: Maintenance::new(read_only),
+    });
 
-    listener.set_nonblocking(true)?;
+    let mode = shared.maintenance.mode();
+    if mode != ServiceMode::Normal {
+        log_mode(&mode);
+    }
+    let mut last_maintenance_check = Instant::now();
 
-    eprintln!("[info] <{}> Listening", bind_addr);
-
-    for stream in listener.incoming() {
-        match stream {
-            Ok(stream) => {
-                let peer_addr = stream.peer_addr()?;
-
-                *client_threads.lock().unwrap() += 1;
-
-                let local_node = Arc::clone(&local_node);
-                let client_threads = Arc::clone(&client_threads);
-                thread::spawn(move || {
-                    match handle_client(&local_node, stream) {
-                        Ok(_) => {
-                            eprintln!("[info] <{}> Disconnected", peer_addr)
-                        }
-                        Err(e) => {
-                            eprintln!("[warn] <{}> Cannot handle client: {}", peer_addr, e)
-                        }
-                    }
+    // Socket activation replaces the configured listeners.
+    let activated = systemd::listeners()?;
+    let socket_activated = activated.is_some();
+    let (mut listeners, unix_listener) = match activated {
+        Some(activated) => {
+            log!(
+                Info,
+                "Listening on {} socket(s) passed by systemd",
+                activated.tcp.len() + activated.unix.len()
+            );
 
-                    *client_threads.lock().unwrap() -= 1;
-                });
+            if activated.unix.len() > 1 {
+                log!(
+                    Warn,
+                    "Only using the first Unix domain socket passed by systemd"
+                );
             }
-            Err(e) if e.kind() == io::ErrorKind::WouldBlock => {
-                if should_exit.load(Ordering::SeqCst) {
-                    break;
-                } else {
-                    thread::sleep(READ_TIMEOUT);
+
+            (activated.tcp, activated.unix.into_iter().next())
+        }
+        None => (
+            listen(&reload::bind_addrs(local_node.config()), &[])?,
+            local_node
+                .config()
+                .unix_socket
+                .as_deref()
+                .map(listen_unix)
+                .transpose()?,
+        ),
+    };
+
+    warn_ineffective(local_node.config());
+
+    warn_stale(&shared);
+    let mut last_staleness_check = Instant::now();
+
+    sweep_partials(&local_node, &shared.active_partials);
+    let mut last_partial_sweep = Instant::now();
+
+    // Upstream synchronizations run on their own thread
+    // so that they never delay accepting clients.
+    if !local_node.config().upstreams.is_empty() {
+        let local_node = Arc::clone(&local_node);
+        let shared = Arc::clone(&shared);
+        let should_exit = Arc::clone(&should_exit);
+        let client_threads = Arc::clone(&client_threads);
+        thread::spawn(move || {
+            schedule_upstreams(&local_node, &shared, &should_exit, &client_threads)
+        });
+    }
+
+    // Scheduled snapshots run on their own thread
+    // so that hooks never delay accepting clients.
+    if !local_node.config().schedule.is_empty() {
+        if debug {
+            print_snapshot_schedule(&local_node);
+        }
+
+        let local_node = Arc::clone(&local_node);
+        let should_exit = Arc::clone(&should_exit);
+        thread::spawn(move || schedule_snapshots(&local_node, &should_exit));
+    }
+
+    if systemd {
+        notify(systemd::READY);
+    }
+
+    loop {
+        // Leave new connections in the backlog while at capacity.
+        let max_clients = shared
+            .config
+            .read()
+            .unwrap()
+            .max_clients
+            .unwrap_or(DEFAULT_MAX_CLIENTS);
+        let at_capacity = *client_threads.lock().unwrap() >= max_clients;
+
+        // Poll all listeners, only idle once none of them has a pending connection.
+        let mut accepted = false;
+        for listener in listeners.iter().filter(|_| !at_capacity) {
+            match listener.accept() {
+                Ok((stream, peer_addr)) => {
+                    accepted = true;
+                    spawn_client(
+                        &local_node,
+                        &shared,
+                        &client_threads,
+                        stream.into(),
+                        Peer::Inet(peer_addr),
+                    );
                 }
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => {}
+                Err(e) => return Err(e.into()),
+            }
+        }
+
+        if let Some(unix_listener) = unix_listener.as_ref().filter(|_| !at_capacity) {
+            match unix_listener.accept() {
+                Ok((stream, _)) => {
+                    accepted = true;
+                    spawn_client(
+                        &local_node,
+                        &shared,
+                        &client_threads,
+                        stream.into(),
+                        Peer::Unix,
+                    );
+                }
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => {}
+                Err(e) => return Err(e.into()),
+            }
+        }
+
+        if accepted {
+            continue;
+        }
+
+        if system::take_sighup() {
+            if let Some(new_listeners) = reload_config(&shared, &listeners, socket_activated) {
+                listeners = new_listeners;
             }
-            Err(e) => return Err(e.into()),
         }
+
+        if last_maintenance_check.elapsed() >= MAINTENANCE_CHECK_INTERVAL {
+            if let Some(mode) = shared.maintenance.poll() {
+                log_mode(&mode);
+            }
+            last_maintenance_check = Instant::now();
+        }
+
+        if last_staleness_check.elapsed() >= STALENESS_CHECK_INTERVAL {
+            warn_stale(&shared);
+            last_staleness_check = Instant::now();
+        }
+
+        if last_partial_sweep.elapsed() >= PARTIAL_SWEEP_INTERVAL {
+            sweep_partials(&local_node, &shared.active_partials);
+            last_partial_sweep = Instant::now();
+        }
+
+        shared.auth_limiter.lock().unwrap().decay();
+
+        if should_exit.load(Ordering::SeqCst) {
+            break;
+        } else {
+            thread::sleep(READ_TIMEOUT);
+        }
+    }
+
+    if systemd {
+        notify(systemd::STOPPING);
     }
 
+    // Refuse new connections on the Unix domain socket while draining.
+    // Sockets passed by systemd remain open for the next instance.
+    if let Some(path) = local_node
+        .config()
+        .unix_socket
+        .as_deref()
+        .filter(|_| !socket_activated)
+    {
+        drop(unix_listener);
+        if let Err(e) = fs::remove_file(path) {
+            log!(
+                Warn,
+                "Cannot remove Unix domain socket {}: {}",
+                path.display(),
+                e
+            );
+        }
+    }
+
+    let drain_timeout = local_node
+        .config()
+        .drain_timeout
+        .map(Duration::from)
+        .unwrap_or(DEFAULT_DRAIN_TIMEOUT);
+    let drain_start = Instant::now();
+
+    // Ask the clients to wrap up, then abort the remaining transmissions
+    // once the drain timeout is exceeded.
+    let deadline = Utc::now().naive_utc()
+        + chrono::Duration::from_std(drain_timeout).unwrap_or(chrono::Duration::zero());
+    shared.window.close(deadline);
+
+    log!(
+        Info,
+        "Closing backup window, sessions must complete by {}",
+        deadline
+    );
+
     while *client_threads.lock().unwrap() > 0 {
+        if drain_start.elapsed() >= drain_timeout && !shared.window.is_aborted() {
+            log!(
+                Warn,
+                "Drain timeout exceeded, aborting {} session(s)",
+                client_threads.lock().unwrap()
+            );
+            shared.window.abort();
+        }
+
+        if drain_start.elapsed() >= drain_timeout + ABORT_TIMEOUT {
+            log!(
+                Warn,
+                "Abort timeout exceeded, cutting {} connection(s)",
+                client_threads.lock().unwrap()
+            );
+            break;
+        }
+
         thread::sleep(READ_TIMEOUT);
     }
 
     Ok(())
 }
 
-fn handle_client(local_node: &LocalNode, stream: TcpStream) -> Result<()> {
-    let peer_addr = stream.peer_addr()?;
+/// Binds a non-blocking listener to each of the specified network addresses,
+/// reusing the `current` listeners bound to any of them.
+/// Fails if any of them cannot be bound.
+fn listen(bind_addrs: &[SocketAddr], current: &[TcpListener]) -> Result<Vec<TcpListener>> {
+    let mut listeners = Vec::new();
+    for bind_addr in bind_addrs {
+        let existing = current
+            .iter()
+            .find(|listener| listener.local_addr().is_ok_and(|addr| addr == *bind_addr));
 
-    let auth_serv = AuthServ::from(stream);
-    let (stream_conn, remote_node_auth) =
-        auth_serv.secure_stream(local_node.config().auth.clone())?;
+        let listener = match existing {
+            Some(listener) => listener.try_clone()?,
+            None => {
+                let listener = TcpListener::bind(bind_addr)?;
+                listener.set_nonblocking(true)?;
 
-    eprintln!(
-        "[info] <{}@{}> Authentication successful",
-        remote_node_auth.node_name, peer_addr
-    );
+                log!(Info, "Listening on {}", bind_addr);
 
-    let mut local_sync_info = SyncInfo {
-        volumes: HashMap::new(),
-    };
+                listener
+            }
+        };
 
-    for volume in &remote_node_auth.push {
-        let latest_snapshots = local_node.latest_snapshots(volume.clone())?;
-        local_sync_info
-            .volumes
-            .insert(volume.clone(), latest_snapshots);
+        listeners.push(listener);
     }
 
-    let (stream_conn, remote_sync_info) = stream_conn.meta_sync(local_sync_info)?;
+    Ok(listeners)
+}
+
+/// Binds a non-blocking listener to the specified Unix domain socket
+/// that is only accessible by the owner. Replaces a stale socket
+/// left behind by a previous instance, but fails if it is still in use.
+fn listen_unix(path: &Path) -> Result<UnixListener> {
+    match fs::symlink_metadata(path) {
+        Ok(metadata) if metadata.file_type().is_socket() => {
+            if UnixStream::connect(path).is_ok() {
+                return Err(io::Error::from(io::ErrorKind::AddrInUse).into());
+            }
 
-    let mut tx = Vec::new();
-    for (volume, latest_snapshots) in remote_sync_info.volumes.into_iter().filter(|(volume, _)| {
-        remote_node_auth.pull.contains(volume) || volume.node_name() == remote_node_auth.node_name
-    }) {
-        // Full backup: Either restoring or remote is out of date.
-        if volume.node_name() == remote_node_auth.node_name {
-            let snapshot = local_node.latest_backup_full(volume.clone())?;
+            fs::remove_file(path)?;
+        }
+        Ok(_) => return Err(io::Error::from(io::ErrorKind::AlreadyExists).into()),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+        Err(e) => return Err(e.into()),
+    }
 
-            if snapshot.taken() > latest_snapshots.last_full {
-                let r = local_node.export(&snapshot)?;
-                tx.push((r, snapshot));
+    let listener = UnixListener::bind(path)?;
+    fs::set_permissions(path, Permissions::from_mode(0o600))?;
+    listener.set_nonblocking(true)?;
+
+    log!(Info, "Listening on unix:{}", path.display());
+
+    Ok(listener)
+}
+
+/// Handles the specified client connection on a new thread.
+fn spawn_client(
+    local_node: &Arc<LocalNode>,
+    shared: &Arc<Shared>,
+    client_threads: &Arc<Mutex<usize>>,
+    stream: Transport,
+    peer_addr: Peer,
+) {
+    *client_threads.lock().unwrap() += 1;
+
+    let local_node = Arc::clone(local_node);
+    let shared = Arc::clone(shared);
+    let client_threads = Arc::clone(client_threads);
+    log!(Debug, peer = peer_addr, "Connected");
+
+    thread::spawn(move || {
+        let report = Mutex::new(FailureReport::new(
+            env!("CARGO_PKG_VERSION"),
+            local_node.name().to_string(),
+            peer_addr.to_string(),
+        ));
+
+        match handle_client(&local_node, &shared, &report, stream, peer_addr) {
+            Ok(_) => {
+                log!(Info, peer = peer_addr, "Disconnected")
             }
-        } else {
-            for snapshot in local_node.all_full_after(volume.clone(), latest_snapshots.last_full)? {
-                let r = local_node.export(&snapshot)?;
-                tx.push((r, snapshot));
+            Err(e) => {
+                log!(Error, peer = peer_addr, "Cannot handle client: {}", e);
+                save_report(report.into_inner().unwrap(), &e, peer_addr);
+            }
+        }
+
+        *client_threads.lock().unwrap() -= 1;
+    });
+}
+
+/// Reloads the configuration file and applies it to new connections.
+/// Sessions in progress keep the permissions they authenticated with.
+/// Returns the new listeners if the network addresses to listen on changed.
+/// The old configuration remains in effect if the new one is invalid.
+fn reload_config(
+    shared: &Shared,
+    listeners: &[TcpListener],
+    socket_activated: bool,
+) -> Option<Vec<TcpListener>> {
+    let new_config = match NodeConfig::load() {
+        Ok(new_config) => new_config,
+        Err(e) => {
+            log!(
+                Error,
+                "Cannot reload configuration, keeping the current one: {}",
+                e
+            );
+            return None;
+        }
+    };
+
+    let old_config = Arc::clone(&shared.config.read().unwrap());
+    let changes = ConfigChanges::between(&old_config, &new_config);
+
+    if changes.is_empty() {
+        log!(Info, "Reloaded configuration, nothing changed");
+        return None;
+    }
+
+    for (what, node_names) in [
+        ("Granted access to", &changes.clients_added),
+        ("Revoked access of", &changes.clients_removed),
+        ("Modified grant of", &changes.clients_changed),
+        ("Now tracking", &changes.subvols_added),
+        ("No longer tracking", &changes.subvols_removed),
+    ] {
+        if !node_names.is_empty() {
+            log!(Info, "{} {}", what, node_names.join(", "));
+        }
+    }
+
+    if changes.requires_restart {
+        log!(Warn, "Some changes only take effect after a restart");
+    }
+
+    warn_ineffective(&new_config);
+
+    if socket_activated && changes.bind_addrs.is_some() {
+        log!(
+            Warn,
+            "Listening on the sockets passed by systemd, ignoring new addresses"
+        );
+    }
+
+    let listeners = match changes
+        .bind_addrs
+        .filter(|_| !socket_activated)
+        .map(|bind_addrs| listen(&bind_addrs, listeners))
+    {
+        Some(Ok(listeners)) => Some(listeners),
+        Some(Err(e)) => {
+            log!(
+                Error,
+                "Cannot listen on new addresses, keeping the current ones: {}",
+                e
+            );
+            None
+        }
+        None => None,
+    };
+
+    // Revocations and rebinds reset the instance binding.
+    {
+        let mut bindings = shared.bindings.lock().unwrap();
+        for node_name in changes
+            .clients_removed
+            .iter()
+            .chain(&changes.clients_changed)
+        {
+            bindings.remove(node_name);
+        }
+
+        for auth in &new_config.auth {
+            if let Some(instance_id) = &auth.instance_id {
+                bindings
+                    .entry(auth.node_name.clone())
+                    .or_insert_with(|| instance_id.clone());
             }
         }
+    }
+
+    *shared.config.write().unwrap() = Arc::new(new_config);
+
+    log!(Info, "Reloaded configuration");
+
+    listeners
+}
+
+/// The state shared between all client sessions.
+struct Shared {
+    /// The configuration applied to new connections. Replaced on `SIGHUP`.
+    config: RwLock<Arc<NodeConfig>>,
+    /// Only one client may read backups for verification at a time
+    /// to prevent excessive disk I/O.
+    verify_lock: Mutex<()>,
+    server_state: Mutex<ServerState>,
+    auth_limiter: Mutex<AuthLimiter>,
+    active_partials: ActivePartials,
+    active_sessions: ActiveSessions,
+    window: Window,
+    /// The instance identifiers the node names of the clients are bound to.
+    bindings: Mutex<HashMap<String, String>>,
+    /// Whether uploads are refused. Checked before receiving each snapshot.
+    maintenance: Maintenance,
+}
+
+/// Prints the next scheduled snapshot of each subvolume.
+fn print_snapshot_schedule(local_node: &LocalNode) {
+    let now = Utc::now().naive_utc();
+
+    for (subvol, schedule) in &local_node.config().schedule {
+        match schedule::next_snapshot(local_node, subvol, schedule) {
+            Ok(Some(next)) => log!(
+                Info,
+                subvol = subvol,
+                "Next {} snapshot {}",
+                if next.is_incremental {
+                    "incremental"
+                } else {
+                    "full"
+                },
+                if next.is_due(now) {
+                    String::from("now")
+                } else {
+                    format!("at {}", next.at)
+                }
+            ),
+            Ok(None) => log!(Info, subvol = subvol, "No snapshots scheduled"),
+            Err(e) => log!(
+                Error,
+                subvol = subvol,
+                "Cannot determine next snapshot: {}",
+                e
+            ),
+        }
+    }
+}
+
+/// Takes the scheduled snapshots of the owned subvolumes whenever they are due
+/// until the daemon exits. Failures are retried at the next check.
+fn schedule_snapshots(local_node: &LocalNode, should_exit: &AtomicBool) {
+    let mut last_check = None;
+
+    while !should_exit.load(Ordering::SeqCst) {
+        if last_check
+            .is_some_and(|last_check: Instant| last_check.elapsed() < SNAPSHOT_CHECK_INTERVAL)
+        {
+            thread::sleep(READ_TIMEOUT);
+            continue;
+        }
+
+        for (subvol, schedule) in &local_node.config().schedule {
+            if should_exit.load(Ordering::SeqCst) {
+                break;
+            }
+
+            let now = Utc::now().naive_utc();
+            let result = schedule::next_snapshot(local_node, subvol, schedule).and_then(|next| {
+                match next.filter(|next| next.is_due(now)) {
+                    Some(NextSnapshot { is_incremental, .. }) => {
+                        let promotion = if is_incremental {
+                            local_node.promotion(subvol)?
+                        } else {
+                            None
+                        };
+
+                        let snapshot = local_node.snapshot_now(
+                            subvol.clone(),
+                            is_incremental && promotion.is_none(),
+                            None,
+                            true,
+                        )?;
 
-        // Incremental backup: Either restoring or remote is out of date.
-        let incr = if volume.node_name() == remote_node_auth.node_name {
-            local_node.backup_incremental_after(
-                volume.clone(),
-                cmp::max(
-                    cmp::max(
-                        latest_snapshots.last_full,
-                        local_node.latest_backup_full(volume)?.taken(),
-                    ),
-                    latest_snapshots.last_incremental,
+                        Ok(snapshot.map(|snapshot| (snapshot, promotion)))
+                    }
+                    None => Ok(None),
+                }
+            });
+
+            match result {
+                Ok(Some((snapshot, None))) => {
+                    log!(
+                        Info,
+                        subvol = subvol,
+                        "Took scheduled snapshot {}",
+                        snapshot
+                    )
+                }
+                Ok(Some((snapshot, Some(promotion)))) => log!(
+                    Info,
+                    subvol = subvol,
+                    "Took scheduled snapshot {} as a full snapshot, {}",
+                    snapshot,
+                    promotion
                 ),
-            )?
-        } else {
-            local_node.all_incremental_after(volume, latest_snapshots.last_incremental)?
-        };
+                Ok(None) => {}
+                Err(e) => log!(
+                    Error,
+                    subvol = subvol,
+                    "Cannot take scheduled snapshot: {}",
+                    e
+                ),
+            }
+        }
+
+        last_check = Some(Instant::now());
+    }
+}
+
+/// Synchronizes with the configured upstreams whenever they are due
+/// until the daemon exits. Sessions in progress count as client threads
+/// so that shutting down drains them.
+fn schedule_upstreams(
+    local_node: &LocalNode,
+    shared: &Shared,
+    should_exit: &AtomicBool,
+    client_threads: &Mutex<usize>,
+) {
+    let now = Utc::now().naive_utc();
+    let mut schedules: Vec<_> = local_node
+        .config()
+        .upstreams
+        .iter()
+        .cloned()
+        .map(|upstream| UpstreamSchedule::new(upstream, now))
+        .collect();
 
-        for snapshot in incr {
-            let r = local_node.export(&snapshot)?;
-            tx.push((r, snapshot));
+    for schedule in &schedules {
+        log!(
+            Info,
+            upstream = schedule.upstream().remote.address,
+            "Next upstream synchronization at {}",
+            schedule.next()
+        );
+    }
+
+    while !should_exit.load(Ordering::SeqCst) {
+        for schedule in &mut schedules {
+            if !schedule.is_due(Utc::now().naive_utc()) || should_exit.load(Ordering::SeqCst) {
+                continue;
+            }
+
+            let remote_node = &schedule.upstream().remote;
+            let address = remote_node.address.to_string();
+
+            *client_threads.lock().unwrap() += 1;
+            let result = sync_upstream(local_node, shared, remote_node);
+            *client_threads.lock().unwrap() -= 1;
+
+            let now = Utc::now().naive_utc();
+            let mut server_state = shared.server_state.lock().unwrap();
+
+            match result {
+                Ok(_) => {
+                    schedule.succeed(now);
+                    server_state.record_upstream_success(&address, now, schedule.next());
+
+                    log!(
+                        Info,
+                        upstream = address,
+                        "Upstream synchronization complete, next at {}",
+                        schedule.next()
+                    );
+                }
+                Err(e) => {
+                    schedule.fail(now);
+                    server_state.record_upstream_failure(
+                        &address,
+                        now,
+                        e.to_string(),
+                        schedule.next(),
+                    );
+
+                    log!(
+                        Error,
+                        upstream = address,
+                        "Upstream synchronization failed, retrying at {}: {}",
+                        schedule.next(),
+                        e
+                    );
+                }
+            }
+
+            save_state(&server_state);
         }
+
+        thread::sleep(READ_TIMEOUT);
+    }
+}
+
+/// Pushes to and pulls from the specified upstream using the mounted [`LocalNode`].
+/// Ends early if the backup window closes.
+fn sync_upstream(local_node: &LocalNode, shared: &Shared, remote_node: &RemoteNode) -> Result<()> {
+    let address = &remote_node.address;
+
+    let auth_conn = AuthConn::connect(address, local_node.keepalive())?
+        .with_plaintext(local_node.unix_plaintext());
+    let stream_conn = auth_conn
+        .secure_stream(
+            local_node.name().to_string(),
+            local_node.config().instance_id.clone(),
+            address.to_string(),
+            local_node.passphrase()?,
+        )?
+        .with_stall_timeout(local_node.stall_timeout())
+        .with_heartbeat_interval(local_node.heartbeat_interval())
+        .with_rekey_interval(local_node.rekey_interval())
+        .with_rate_limit(remote_node.rate_limit.map(|rate| rate.0));
+
+    log!(
+        Info,
+        upstream = address,
+        "Authentication to and of upstream successful"
+    );
+
+    let session_partials = SessionPartials::new(&shared.active_partials);
+
+    let reception_gate = || shared.maintenance.check();
+    let events = |event: SyncEvent| match event {
+        SyncEvent::Queued(snapshot) => {
+            log!(
+                Info,
+                upstream = address,
+                "Queueing {} for transmission",
+                snapshot
+            );
+        }
+        SyncEvent::Accepted(snapshot) => {
+            session_partials.register(snapshot.streaming_path(local_node.layout()));
+        }
+        SyncEvent::Rejected(snapshot, e) => {
+            log!(Warn, upstream = address, "Rejecting {}: {}", snapshot, e);
+        }
+        SyncEvent::Invalid(e) => {
+            log!(Warn, upstream = address, "Rejecting snapshot: {}", e);
+        }
+        SyncEvent::Receiving(snapshot) => {
+            log!(Info, upstream = address, "Receiving {}", snapshot);
+        }
+        SyncEvent::Received(snapshot) => {
+            log!(Info, upstream = address, "Received {}", snapshot);
+        }
+    };
+
+    let sync_session = SyncSession::new(
+        local_node,
+        Role::Initiator,
+        remote_node.push.clone(),
+        remote_node.pull.clone(),
+        events,
+    )
+    .with_reception_gate(&reception_gate);
+
+    let plan = sync_session.initiate(stream_conn)?;
+
+    let stats = sync_session.data_sync(plan, &shared.window)?;
+
+    if let Some(wrap_up) = &stats.wrap_up {
+        log!(
+            Info,
+            upstream = address,
+            "Wrapped up early for deadline {}, skipped {} transmission(s)",
+            wrap_up.deadline,
+            wrap_up.skipped.len()
+        );
     }
 
-    for (_, snapshot) in &tx {
-        eprintln!(
-            "[info] <{}@{}> Queueing {} for transmission",
-            remote_node_auth.node_name, peer_addr, snapshot
+    for (snapshot, e) in stats.rejected() {
+        log!(
+            Warn,
+            upstream = address,
+            "Skipped {}, rejected: {}",
+            snapshot,
+            e
         );
     }
 
-    let rx_setup =
-        |snapshot: &Snapshot| {
-            if !remote_node_auth.push.iter().any(|volume| {
-                snapshot.is_of_volume(volume) && volume.node_name() != local_node.name()
-            }) {
-                return Err(RemoteError::AccessDenied);
+    log!(
+        Info,
+        upstream = address,
+        "Upstream synchronization {}",
+        stats.summary("pushed", "pulled")
+    );
+
+    let audit = sync_session.audit();
+    if audit.is_degraded() {
+        log!(
+            Warn,
+            upstream = address,
+            "Upstream deviated from the announced gaps: {} unexpected, {} duplicate, {} missing",
+            audit.unexpected.len(),
+            audit.duplicates.len(),
+            audit.missing.len()
+        );
+    }
+
+    Ok(())
+}
+
+/// Deletes outdated incomplete backups that aren't written to by any session.
+fn sweep_partials(local_node: &LocalNode, active_partials: &ActivePartials) {
+    // Hold the lock to prevent sessions from registering paths during the sweep.
+    let active_partials = active_partials.lock().unwrap();
+
+    match local_node.clean_partials(local_node.partial_max_age(), &active_partials) {
+        Ok(reclaimed) if reclaimed.files > 0 => log!(
+            Info,
+            "Deleted {} incomplete backup(s), reclaimed {} bytes",
+            reclaimed.files,
+            reclaimed.bytes
+        ),
+        Ok(_) => {}
+        Err(e) => log!(Error, "Cannot clean up incomplete backups: {}", e),
+    }
+}
+
+fn handle_client(
+    local_node: &LocalNode,
+    shared: &Shared,
+    report: &Mutex<FailureReport>,
+    stream: Transport,
+    peer_addr: Peer,
+) -> Result<Option<SyncStats>> {
+    let Shared {
+        config,
+        verify_lock,
+        server_state,
+        auth_limiter,
+        active_partials,
+        active_sessions,
+        window,
+        bindings,
+        maintenance,
+    } = shared;
+
+    if let Transport::Tcp(stream) = &stream {
+        local_node.keepalive().apply(stream)?;
+    }
+
+    let session_partials = SessionPartials::new(active_partials);
+
+    // Sessions keep the configuration they started with.
+    let node_config = Arc::clone(&config.read().unwrap());
+
+    let auth_serv = AuthServ::from(stream).with_plaintext(local_node.unix_plaintext());
+
+    if auth_limiter.lock().unwrap().is_locked_out(peer_addr.ip()) {
+        log!(
+            Warn,
+            peer = peer_addr,
+            "Refusing connection, too many failed authentications"
+        );
+
+        auth_serv.refuse(RemoteError::TooManyAttempts)?;
+        return Ok(None);
+    }
+
+    let mut claimed_node_name = None;
+    let delay = |node_name: &str| {
+        claimed_node_name = Some(node_name.to_string());
+
+        let delay = auth_limiter
+            .lock()
+            .unwrap()
+            .delay(peer_addr.ip(), node_name);
+        if !delay.is_zero() {
+            log!(
+                Debug,
+                node = node_name,
+                peer = peer_addr,
+                "Delaying authentication response by {:?}",
+                delay
+            );
+        }
+
+        delay
+    };
+
+    // Grants that don't allow the source address are treated as nonexistent,
+    // so the client is refused before its credentials are checked.
+    let permitted_auth = node_config
+        .auth
+        .iter()
+        .filter(|auth| auth.allows_source(peer_addr.ip()))
+        .cloned();
+
+    let (stream_conn, remote_node_auth) =
+        match auth_serv.secure_stream_delayed(permitted_auth, delay) {
+            Ok((stream_conn, remote_node_auth)) => (
+                stream_conn
+                    .with_stall_timeout(local_node.stall_timeout())
+                    .with_heartbeat_interval(local_node.heartbeat_interval())
+                    .with_rekey_interval(local_node.rekey_interval())
+                    .with_rate_limit(remote_node_auth.rate_limit.map(|rate| rate.0)),
+                remote_node_auth,
+            ),
+            Err(NetworkError::RemoteError(RemoteError::Unauthorized)) => {
+                let node_name = claimed_node_name.as_deref();
+
+                let mut grants = node_config
+                    .auth
+                    .iter()
+                    .filter(|auth| Some(auth.node_name.as_str()) == node_name);
+                if grants.any(|auth| !auth.allows_source(peer_addr.ip())) {
+                    log!(
+                        Warn,
+                        node = node_name.unwrap_or("?"),
+                        peer = peer_addr,
+                        "Refusing connection from {}, not an allowed source",
+                        peer_addr.ip()
+                    );
+                }
+
+                let failures = auth_limiter
+                    .lock()
+                    .unwrap()
+                    .record_failure(peer_addr.ip(), node_name);
+
+                log!(
+                    Warn,
+                    node = node_name.unwrap_or("?"),
+                    peer = peer_addr,
+                    "Authentication failed ({} recent failures from this address)",
+                    failures
+                );
+
+                let max_failures = node_config
+                    .max_auth_failures
+                    .unwrap_or(DEFAULT_MAX_AUTH_FAILURES);
+                if failures == max_failures {
+                    log!(
+                        Warn,
+                        peer = peer_addr,
+                        "Locked out after {} failed authentications",
+                        failures
+                    );
+                }
+
+                return Err(NetworkError::RemoteError(RemoteError::Unauthorized).into());
+            }
+            Err(e) => return Err(e.into()),
+        };
+
+    auth_limiter
+        .lock()
+        .unwrap()
+        .record_success(&remote_node_auth.node_name);
+
+    match &remote_node_auth.label {
+        Some(label) => log!(
+            Info,
+            node = remote_node_auth.node_name,
+            label = label,
+            peer = peer_addr,
+            "Authentication successful"
+        ),
+        None => log!(
+            Info,
+            node = remote_node_auth.node_name,
+            peer = peer_addr,
+            "Authentication successful"
+        ),
+    }
+
+    report.lock().unwrap().authenticated(&stream_conn);
+
+    // Nodes initialized by previous versions don't send an instance identifier.
+    // They can only connect while their node name isn't bound, a bound node name
+    // requires the identifier so that omitting it cannot bypass the check.
+    {
+        let mut bindings = bindings.lock().unwrap();
+
+        let instance_id = stream_conn.remote_instance_id();
+        match (instance_id, bindings.get(&remote_node_auth.node_name)) {
+            (None, None) => {}
+            (Some(instance_id), Some(bound)) if bound == instance_id => {}
+            (instance_id, Some(bound)) => {
+                log!(
+                    Warn,
+                    node = remote_node_auth.node_name,
+                    peer = peer_addr,
+                    "Node name conflict: Instance {} is not the bound instance {}",
+                    instance_id.unwrap_or("(none)"),
+                    bound
+                );
+
+                drop(bindings);
+                stream_conn.refuse(RemoteError::InstanceConflict)?;
+                return Err(NetworkError::RemoteError(RemoteError::InstanceConflict).into());
             }
+            (Some(instance_id), None) => {
+                bindings.insert(remote_node_auth.node_name.clone(), instance_id.to_string());
+                save_binding(config, &remote_node_auth.node_name, instance_id);
 
-            if snapshot.backup_path(Mode::Server).exists() {
-                return Err(RemoteError::Immutable);
+                log!(
+                    Info,
+                    node = remote_node_auth.node_name,
+                    peer = peer_addr,
+                    "Bound to instance {}",
+                    instance_id
+                );
             }
+        }
+    }
+
+    let max_sessions = node_config
+        .max_sessions_per_client
+        .unwrap_or(DEFAULT_MAX_SESSIONS_PER_CLIENT);
+    let Some(_session_slot) =
+        active_sessions.try_start(&remote_node_auth.node_name, peer_addr.ip(), max_sessions)
+    else {
+        log!(
+            Warn,
+            node = remote_node_auth.node_name,
+            peer = peer_addr,
+            "Refusing session, too many concurrent sessions of this client"
+        );
 
-            let file = File::create(snapshot.streaming_path(Mode::Server))
-                .map_err(|_| RemoteError::RxError)?;
+        stream_conn.refuse(RemoteError::TooManySessions)?;
+        return Ok(None);
+    };
+
+    {
+        let mut server_state = server_state.lock().unwrap();
+        server_state.record_session(&remote_node_auth.node_name, Utc::now().naive_utc());
+        save_state(&server_state);
+    }
 
-            eprintln!(
-                "[info] <{}@{}> Receiving {}",
-                remote_node_auth.node_name, peer_addr, snapshot
+    // Storage paths are derived from the identities chosen by the client.
+    let check_identity = |snapshot: &Snapshot| {
+        snapshot.validate().map_err(|e| {
+            log!(
+                Warn,
+                node = remote_node_auth.node_name,
+                peer = peer_addr,
+                "Rejecting request: {}",
+                e
             );
+            RemoteError::AccessDenied
+        })
+    };
+
+    let prove = |challenge: &Challenge| {
+        let snapshot = &challenge.snapshot;
+        check_identity(snapshot)?;
+
+        if local_node.owns_backup(snapshot)
+            || !(remote_node_auth
+                .pull
+                .iter()
+                .any(|spec| spec.matches(&snapshot.volume()))
+                || snapshot.node_name() == remote_node_auth.node_name)
+        {
+            return Err(RemoteError::AccessDenied);
+        }
 
-            Ok(BufWriter::with_capacity(2 * CHUNKSIZE, file))
+        if !local_node.exists(snapshot) {
+            return Err(RemoteError::NoSuchBackup);
+        }
+
+        let _guard = verify_lock.lock().unwrap();
+
+        let r = local_node
+            .export(snapshot, local_node.send_protocol(), true)
+            .map_err(|_| RemoteError::ProofError)?;
+        let proof =
+            system::hash_hmac_reader(&challenge.challenge, ThrottledReader::new(r, VERIFY_RATE))
+                .map_err(|_| RemoteError::ProofError)?;
+
+        log!(
+            Debug,
+            node = remote_node_auth.node_name,
+            peer = peer_addr,
+            "Proved possession of {}",
+            snapshot
+        );
+
+        Ok(proof)
+    };
+
+    let list = || {
+        let visible = |snapshot: &Snapshot| {
+            snapshot.node_name() == remote_node_auth.node_name
+                || remote_node_auth
+                    .pull
+                    .iter()
+                    .any(|spec| spec.matches(&snapshot.volume()))
         };
 
-    let rx_finish = |snapshot: Snapshot| {
-        fs::rename(
-            snapshot.streaming_path(Mode::Server),
-            snapshot.backup_path(Mode::Server),
-        )
-        .map_err(|_| RemoteError::RxError)?;
+        let mut snapshots = local_node
+            .all_snapshots(None)
+            .map_err(|_| RemoteError::InventoryError)?;
+        snapshots.extend(
+            local_node
+                .all_backups(None)
+                .map_err(|_| RemoteError::InventoryError)?,
+        );
+        snapshots.retain(visible);
 
-        eprintln!(
-            "[info] <{}@{}> Received {}",
-            remote_node_auth.node_name, peer_addr, snapshot
+        log!(
+            Debug,
+            node = remote_node_auth.node_name,
+            peer = peer_addr,
+            "Listed {} snapshot(s)",
+            snapshots.len()
+        );
+
+        Ok(snapshots)
+    };
+
+    let prune = |snapshots: Vec<Snapshot>| {
+        snapshots
+            .iter()
+            .map(|snapshot| {
+                check_identity(snapshot)?;
+
+                // Only the owner can reason about its chains.
+                if snapshot.node_name() != remote_node_auth.node_name {
+                    return Err(RemoteError::AccessDenied);
+                }
+
+                if local_node.backup_tier(snapshot).is_none() {
+                    return Err(RemoteError::NoSuchBackup);
+                }
+
+                if !snapshot.is_incremental()
+                    && !local_node
+                        .all_backups(Some(&snapshot.volume()))
+                        .map_err(|_| RemoteError::DeleteError)?
+                        .iter()
+                        .any(|backup| backup != snapshot && !backup.is_incremental())
+                {
+                    return Err(RemoteError::LastFullBackup);
+                }
+
+                match local_node.delete(snapshot) {
+                    Ok(_) => {}
+                    Err(LocalNodeError::Cooloff(_)) => return Err(RemoteError::Cooloff),
+                    Err(_) => return Err(RemoteError::DeleteError),
+                }
+
+                log!(
+                    Info,
+                    node = remote_node_auth.node_name,
+                    peer = peer_addr,
+                    "Deleted {}",
+                    snapshot
+                );
+
+                Ok(())
+            })
+            .collect()
+    };
+
+    // Clients may only replace their own credentials.
+    let rotate = |credentials: Credentials| {
+        if credentials.verifier.len() != SECRET_LEN || credentials.key.len() != SECRET_LEN {
+            return Err(RemoteError::RotationError);
+        }
+
+        // Serialize rotations and reloads, new connections keep using
+        // the previous credentials until the file has been replaced.
+        let mut config = config.write().unwrap();
+
+        // Only update the grant so that pending edits of the file are preserved.
+        let mut file_config = NodeConfig::load().map_err(|_| RemoteError::RotationError)?;
+        for node_config in [&mut file_config, Arc::make_mut(&mut config)] {
+            let auth = node_config
+                .auth
+                .iter_mut()
+                .find(|auth| {
+                    auth.is(
+                        &remote_node_auth.node_name,
+                        remote_node_auth.label.as_deref(),
+                    )
+                })
+                .ok_or(RemoteError::AccessDenied)?;
+
+            auth.verifier.clone_from(&credentials.verifier);
+            auth.key.clone_from(&credentials.key);
+        }
+
+        file_config.save().map_err(|e| {
+            log!(
+                Error,
+                node = remote_node_auth.node_name,
+                peer = peer_addr,
+                "Cannot save rotated credentials: {}",
+                e
+            );
+            RemoteError::RotationError
+        })?;
+
+        log!(
+            Info,
+            node = remote_node_auth.node_name,
+            peer = peer_addr,
+            "Rotated credentials"
         );
 
         Ok(())
     };
 
-    stream_conn.data_sync(tx, rx_setup, rx_finish)?;
+    let reception_gate = || maintenance.check();
+    let events = |event: SyncEvent| match event {
+        SyncEvent::Queued(snapshot) => {
+            log!(
+                Info,
+                node = remote_node_auth.node_name,
+                peer = peer_addr,
+                "Queueing {} for transmission",
+                snapshot
+            );
+            report.lock().unwrap().queued.push(snapshot.clone());
+        }
+        SyncEvent::Accepted(snapshot) => {
+            session_partials.register(snapshot.streaming_path(local_node.layout()));
+        }
+        SyncEvent::Rejected(snapshot, e) => {
+            log!(
+                Warn,
+                node = remote_node_auth.node_name,
+                peer = peer_addr,
+                "Rejecting {}: {}",
+                snapshot,
+                e
+            );
+        }
+        SyncEvent::Invalid(e) => {
+            log!(
+                Warn,
+                node = remote_node_auth.node_name,
+                peer = peer_addr,
+                "Rejecting snapshot: {}",
+                e
+            );
+        }
+        SyncEvent::Receiving(snapshot) => {
+            log!(
+                Info,
+                node = remote_node_auth.node_name,
+                peer = peer_addr,
+                "Receiving {}",
+                snapshot
+            );
+            report.lock().unwrap().start_receiving(snapshot);
+        }
+        SyncEvent::Received(snapshot) => {
+            log!(
+                Info,
+                node = remote_node_auth.node_name,
+                peer = peer_addr,
+                "Received {}",
+                snapshot
+            );
+            report.lock().unwrap().finish_receiving(snapshot);
 
-    Ok(())
+            let mut server_state = server_state.lock().unwrap();
+            server_state.record_received(
+                &remote_node_auth.node_name,
+                snapshot.clone(),
+                Utc::now().naive_utc(),
+            );
+            save_state(&server_state);
+        }
+    };
+
+    let sync_session = SyncSession::new(
+        local_node,
+        Role::Responder,
+        remote_node_auth.pull.clone(),
+        remote_node_auth.push.clone(),
+        events,
+    )
+    .with_reception_gate(&reception_gate);
+
+    let plan = match sync_session.respond(stream_conn, prove, list, prune, rotate)? {
+        Some(plan) => plan,
+        None => return Ok(None),
+    };
+
+    let stats = sync_session.data_sync(plan, window)?;
+
+    if let Some(wrap_up) = &stats.wrap_up {
+        log!(
+            Info,
+            node = remote_node_auth.node_name,
+            peer = peer_addr,
+            "Wrapped up early for deadline {}, skipped {} transmission(s)",
+            wrap_up.deadline,
+            wrap_up.skipped.len()
+        );
+    }
+
+    for (snapshot, e) in stats.rejected() {
+        log!(
+            Warn,
+            node = remote_node_auth.node_name,
+            peer = peer_addr,
+            "Skipped {}, rejected: {}",
+            snapshot,
+            e
+        );
+    }
+
+    log!(
+        Info,
+        node = remote_node_auth.node_name,
+        peer = peer_addr,
+        "Synchronization complete, {}",
+        stats.summary("sent", "received")
+    );
+
+    Ok(Some(stats))
 }
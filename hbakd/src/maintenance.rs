@@ -0,0 +1,112 @@
+// hbakd is an hbak server providing clients with push and pull access.
+// Copyright (C) 2024  Himbeer <himbeerserverde@gmail.com>
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use hbak_common::RemoteError;
+
+use std::fmt;
+use std::fs;
+use std::io;
+use std::sync::RwLock;
+
+/// The file whose existence puts `hbakd` into maintenance mode at runtime.
+/// Its contents, if any, are shown to the clients whose uploads are refused.
+pub const FLAG_PATH: &str = "/var/lib/hbakd/maintenance";
+
+/// Whether `hbakd` accepts uploads. Downloads are served in all modes.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum ServiceMode {
+    /// Uploads are accepted.
+    Normal,
+    /// Uploads are refused until the flag file is removed,
+    /// optionally with a message for the clients.
+    Maintenance(Option<String>),
+    /// Uploads are refused until the daemon is restarted without `--read-only`.
+    ReadOnly,
+}
+
+impl fmt::Display for ServiceMode {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::Normal => write!(f, "normal"),
+            Self::Maintenance(None) => write!(f, "maintenance"),
+            Self::Maintenance(Some(message)) => write!(f, "maintenance ({})", message),
+            Self::ReadOnly => write!(f, "read-only"),
+        }
+    }
+}
+
+impl ServiceMode {
+    /// Reads the [`FLAG_PATH`] to determine the runtime mode.
+    /// A flag file that exists but cannot be read still enables maintenance mode.
+    pub fn from_flag() -> Self {
+        match fs::read_to_string(FLAG_PATH) {
+            Ok(s) => Self::Maintenance(Some(s.trim().to_string()).filter(|s| !s.is_empty())),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Self::Normal,
+            Err(_) => Self::Maintenance(None),
+        }
+    }
+}
+
+/// `Maintenance` tracks the [`ServiceMode`] of the daemon.
+#[derive(Debug)]
+pub struct Maintenance {
+    mode: RwLock<ServiceMode>,
+}
+
+impl Maintenance {
+    /// Constructs a new `Maintenance` that refuses all uploads if `read_only` is set
+    /// and follows the flag file otherwise.
+    pub fn new(read_only: bool) -> Self {
+        Self {
+            mode: RwLock::new(if read_only {
+                ServiceMode::ReadOnly
+            } else {
+                ServiceMode::from_flag()
+            }),
+        }
+    }
+
+    /// Returns the current [`ServiceMode`].
+    pub fn mode(&self) -> ServiceMode {
+        self.mode.read().unwrap().clone()
+    }
+
+    /// Re-reads the flag file unless the daemon is read-only.
+    /// Returns the new [`ServiceMode`] if it changed.
+    pub fn poll(&self) -> Option<ServiceMode> {
+        let mut mode = self.mode.write().unwrap();
+        if *mode == ServiceMode::ReadOnly {
+            return None;
+        }
+
+        let new_mode = ServiceMode::from_flag();
+        if new_mode == *mode {
+            return None;
+        }
+
+        *mode = new_mode.clone();
+        Some(new_mode)
+    }
+
+    /// Returns the error to refuse uploads with unless they are accepted.
+    pub fn check(&self) -> Result<(), RemoteError> {
+        match &*self.mode.read().unwrap() {
+            ServiceMode::Normal => Ok(()),
+            ServiceMode::Maintenance(message) => Err(RemoteError::Maintenance(message.clone())),
+            ServiceMode::ReadOnly => Err(RemoteError::Maintenance(None)),
+        }
+    }
+}
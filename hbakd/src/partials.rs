@@ -0,0 +1,55 @@
+// hbakd is an hbak server providing clients with push and pull access.
+// Copyright (C) 2024  Himbeer <himbeerserverde@gmail.com>
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use std::collections::HashSet;
+use std::path::PathBuf;
+use std::sync::Mutex;
+
+/// The streaming paths currently written to by any session.
+/// The sweeper must not delete these.
+pub type ActivePartials = Mutex<HashSet<PathBuf>>;
+
+/// A `SessionPartials` registers the streaming paths of a single session
+/// in the [`ActivePartials`], unregistering them when dropped.
+pub struct SessionPartials<'a> {
+    active: &'a ActivePartials,
+    paths: Mutex<Vec<PathBuf>>,
+}
+
+impl<'a> SessionPartials<'a> {
+    /// Constructs a new `SessionPartials` registering paths in `active`.
+    pub fn new(active: &'a ActivePartials) -> Self {
+        Self {
+            active,
+            paths: Mutex::new(Vec::new()),
+        }
+    }
+
+    /// Marks the specified streaming path as in use by the session.
+    pub fn register(&self, path: PathBuf) {
+        self.active.lock().unwrap().insert(path.clone());
+        self.paths.lock().unwrap().push(path);
+    }
+}
+
+impl Drop for SessionPartials<'_> {
+    fn drop(&mut self) {
+        let mut active = self.active.lock().unwrap();
+        for path in self.paths.get_mut().unwrap().drain(..) {
+            active.remove(&path);
+        }
+    }
+}
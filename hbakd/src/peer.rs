@@ -0,0 +1,48 @@
+// hbakd is an hbak server providing clients with push and pull access.
+// Copyright (C) 2024  Himbeer <himbeerserverde@gmail.com>
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use std::fmt;
+use std::net::{IpAddr, Ipv6Addr, SocketAddr};
+
+/// A `Peer` is the remote end of a client connection.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Peer {
+    /// A TCP connection from the specified network address.
+    Inet(SocketAddr),
+    /// A Unix domain socket connection. Clients don't bind their end,
+    /// so there is no address.
+    Unix,
+}
+
+impl Peer {
+    /// Returns the IP address failed authentications are attributed to.
+    /// Unix domain socket connections count towards the IPv6 loopback address.
+    pub fn ip(&self) -> IpAddr {
+        match self {
+            Self::Inet(addr) => addr.ip(),
+            Self::Unix => IpAddr::V6(Ipv6Addr::LOCALHOST),
+        }
+    }
+}
+
+impl fmt::Display for Peer {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::Inet(addr) => write!(f, "{}", addr),
+            Self::Unix => write!(f, "unix"),
+        }
+    }
+}
@@ -0,0 +1,126 @@
+// hbakd is an hbak server providing clients with push and pull access.
+// Copyright (C) 2024  Himbeer <himbeerserverde@gmail.com>
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use hbak_common::config::{NodeConfig, RemoteNodeAuth};
+use hbak_common::conn::DEFAULT_PORT;
+
+use std::net::{IpAddr, Ipv6Addr, SocketAddr};
+
+/// `ConfigChanges` describe the differences between two [`NodeConfig`]s
+/// relevant to a configuration reload.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct ConfigChanges {
+    /// The node names of the clients that have been granted access.
+    pub clients_added: Vec<String>,
+    /// The node names of the clients whose access has been revoked.
+    pub clients_removed: Vec<String>,
+    /// The node names of the clients whose grant has been modified.
+    pub clients_changed: Vec<String>,
+    /// The subvolumes that are now tracked.
+    pub subvols_added: Vec<String>,
+    /// The subvolumes that are no longer tracked.
+    pub subvols_removed: Vec<String>,
+    /// The new network addresses to listen on if they changed.
+    pub bind_addrs: Option<Vec<SocketAddr>>,
+    /// Whether any other setting changed. Those only take effect after a restart.
+    pub requires_restart: bool,
+}
+
+impl ConfigChanges {
+    /// Returns the changes from the `old` to the `new` [`NodeConfig`].
+    pub fn between(old: &NodeConfig, new: &NodeConfig) -> Self {
+        // Grants are identified by node name and label.
+        let clients_added = node_names(new.auth.iter().filter(|auth| {
+            !old.auth
+                .iter()
+                .any(|item| item.is(&auth.node_name, auth.label.as_deref()))
+        }));
+        let clients_removed = node_names(old.auth.iter().filter(|auth| {
+            !new.auth
+                .iter()
+                .any(|item| item.is(&auth.node_name, auth.label.as_deref()))
+        }));
+        let clients_changed = node_names(new.auth.iter().filter(|auth| {
+            old.auth
+                .iter()
+                .any(|item| item.is(&auth.node_name, auth.label.as_deref()) && item != *auth)
+        }));
+
+        let old_subvols = old.subvol_names();
+        let new_subvols = new.subvol_names();
+
+        let subvols_added = new_subvols
+            .iter()
+            .filter(|subvol| !old_subvols.contains(subvol))
+            .cloned()
+            .collect();
+        let subvols_removed = old_subvols
+            .iter()
+            .filter(|subvol| !new_subvols.contains(subvol))
+            .cloned()
+            .collect();
+
+        let bind_addrs = Some(bind_addrs(new)).filter(|addrs| *addrs != self::bind_addrs(old));
+
+        // Compare everything else by carrying over the settings applied on reload.
+        let requires_restart = NodeConfig {
+            auth: old.auth.clone(),
+            bind_addr: old.bind_addr.clone(),
+            max_sessions_per_client: old.max_sessions_per_client,
+            max_clients: old.max_clients,
+            ..new.clone()
+        } != *old;
+
+        Self {
+            clients_added,
+            clients_removed,
+            clients_changed,
+            subvols_added,
+            subvols_removed,
+            bind_addrs,
+            requires_restart,
+        }
+    }
+
+    /// Reports whether nothing changed.
+    pub fn is_empty(&self) -> bool {
+        *self == Self::default()
+    }
+}
+
+/// Returns the distinct node names of the [`RemoteNodeAuth`]s in order.
+fn node_names<'a>(auth: impl Iterator<Item = &'a RemoteNodeAuth>) -> Vec<String> {
+    let mut node_names: Vec<String> = Vec::new();
+    for auth in auth {
+        if !node_names.contains(&auth.node_name) {
+            node_names.push(auth.node_name.clone());
+        }
+    }
+
+    node_names
+}
+
+/// Returns the network addresses `hbakd` listens on according to the [`NodeConfig`].
+pub fn bind_addrs(node_config: &NodeConfig) -> Vec<SocketAddr> {
+    if node_config.bind_addr.is_empty() {
+        vec![SocketAddr::new(
+            IpAddr::V6(Ipv6Addr::UNSPECIFIED),
+            DEFAULT_PORT,
+        )]
+    } else {
+        node_config.bind_addr.clone()
+    }
+}
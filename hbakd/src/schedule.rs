@@ -0,0 +1,93 @@
+// hbakd is an hbak server providing clients with push and pull access.
+// Copyright (C) 2024  Himbeer <himbeerserverde@gmail.com>
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use hbak_common::config::SnapshotSchedule;
+use hbak_common::proto::LocalNode;
+use hbak_common::LocalNodeError;
+
+use std::time::Duration;
+
+use chrono::prelude::*;
+
+/// A `NextSnapshot` is the next snapshot of a subvolume a [`SnapshotSchedule`] calls for.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct NextSnapshot {
+    /// Whether the snapshot is incremental.
+    pub is_incremental: bool,
+    /// The time the snapshot is due at.
+    /// Initial full snapshots are due immediately.
+    pub at: NaiveDateTime,
+}
+
+impl NextSnapshot {
+    /// Reports whether the snapshot is due.
+    pub fn is_due(&self, now: NaiveDateTime) -> bool {
+        now >= self.at
+    }
+}
+
+/// Returns the next snapshot of the specified subvolume of the [`LocalNode`]
+/// according to the [`SnapshotSchedule`] or `None` if none is scheduled.
+///
+/// Due times are derived from the existing snapshots,
+/// so restarting the daemon doesn't cause additional snapshots.
+/// Full snapshots take precedence over incremental snapshots due at the same time.
+pub fn next_snapshot(
+    local_node: &LocalNode,
+    subvol: &str,
+    schedule: &SnapshotSchedule,
+) -> Result<Option<NextSnapshot>, LocalNodeError> {
+    let latest_full = match local_node.latest_snapshot_full(subvol.to_string()) {
+        Ok(snapshot) => snapshot.taken(),
+        Err(LocalNodeError::NoFullSnapshot(_)) => {
+            // Incremental snapshots need a full snapshot to refer to.
+            return Ok(Some(NextSnapshot {
+                is_incremental: false,
+                at: NaiveDateTime::MIN,
+            }));
+        }
+        Err(e) => return Err(e),
+    };
+
+    let latest = match local_node.latest_snapshot_incremental(subvol.to_string()) {
+        Ok(snapshot) => latest_full.max(snapshot.taken()),
+        Err(LocalNodeError::NoIncrementalSnapshot(_)) => latest_full,
+        Err(e) => return Err(e),
+    };
+
+    let full = schedule.full.map(|full| NextSnapshot {
+        is_incremental: false,
+        at: after(latest_full, full.into()),
+    });
+    let incremental = schedule.incremental.map(|incremental| NextSnapshot {
+        is_incremental: true,
+        at: after(latest, incremental.into()),
+    });
+
+    Ok(match (full, incremental) {
+        (Some(full), Some(incremental)) if incremental.at < full.at => Some(incremental),
+        (Some(full), _) => Some(full),
+        (None, incremental) => incremental,
+    })
+}
+
+/// Returns the time the specified interval after `time`.
+fn after(time: NaiveDateTime, interval: Duration) -> NaiveDateTime {
+    chrono::Duration::from_std(interval)
+        .ok()
+        .and_then(|interval| time.checked_add_signed(interval))
+        .unwrap_or(NaiveDateTime::MAX)
+}
@@ -0,0 +1,129 @@
+// hbakd is an hbak server providing clients with push and pull access.
+// Copyright (C) 2024  Himbeer <himbeerserverde@gmail.com>
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use std::env;
+use std::io;
+use std::net::TcpListener;
+use std::os::fd::{FromRawFd, OwnedFd};
+use std::os::linux::net::SocketAddrExt;
+use std::os::unix::net::{SocketAddr, UnixDatagram, UnixListener};
+use std::process;
+
+/// The notification sent once `hbakd` accepts connections.
+pub const READY: &str = "READY=1";
+/// The notification sent once `hbakd` begins shutting down.
+pub const STOPPING: &str = "STOPPING=1";
+
+/// The first file descriptor passed by socket activation.
+const LISTEN_FDS_START: i32 = 3;
+
+/// The sample unit printed by `hbakd --print-systemd-unit`.
+pub const UNIT: &str = "\
+[Unit]
+Description=hbak server
+Documentation=https://github.com/HimbeerserverDE/hbak
+After=network-online.target
+Wants=network-online.target
+
+# Optional socket activation keeps the listening socket open across restarts.
+# Create hbakd.socket containing the following, adjusting the port
+# to the bind_addr setting, and enable it instead of this service:
+#
+#   [Socket]
+#   ListenStream=20406
+#
+#   [Install]
+#   WantedBy=sockets.target
+
+[Service]
+Type=notify
+ExecStart=/usr/bin/hbakd --systemd
+ExecReload=/bin/kill -HUP $MAINPID
+Restart=on-failure
+
+[Install]
+WantedBy=multi-user.target
+";
+
+/// The listeners passed by socket activation.
+#[derive(Debug, Default)]
+pub struct Activated {
+    /// The TCP listeners.
+    pub tcp: Vec<TcpListener>,
+    /// The Unix domain socket listeners.
+    pub unix: Vec<UnixListener>,
+}
+
+/// Reports whether `hbakd` was started by socket activation.
+pub fn is_activated() -> bool {
+    env::var_os("LISTEN_FDS").is_some()
+}
+
+/// Takes over the non-blocking listeners passed by socket activation, if any.
+/// The environment variables are removed so that child processes
+/// don't mistake the listeners for their own.
+pub fn listeners() -> io::Result<Option<Activated>> {
+    let listen_pid = env::var("LISTEN_PID").ok();
+    let listen_fds = env::var("LISTEN_FDS").ok();
+
+    env::remove_var("LISTEN_PID");
+    env::remove_var("LISTEN_FDS");
+    env::remove_var("LISTEN_FDNAMES");
+
+    if listen_pid.and_then(|pid| pid.parse().ok()) != Some(process::id()) {
+        return Ok(None);
+    }
+
+    let Some(n) = listen_fds.and_then(|n| n.parse::<i32>().ok()) else {
+        return Ok(None);
+    };
+
+    let mut activated = Activated::default();
+    for fd in LISTEN_FDS_START..LISTEN_FDS_START + n {
+        // SAFETY: Socket activation passes open descriptors starting at `LISTEN_FDS_START`
+        // and transfers their ownership. The environment was cleared above,
+        // so they are taken over exactly once.
+        let listener = TcpListener::from(unsafe { OwnedFd::from_raw_fd(fd) });
+
+        // The standard library only resolves the addresses of IP sockets.
+        if listener.local_addr().is_ok() {
+            listener.set_nonblocking(true)?;
+            activated.tcp.push(listener);
+        } else {
+            let listener = UnixListener::from(OwnedFd::from(listener));
+            listener.set_nonblocking(true)?;
+            activated.unix.push(listener);
+        }
+    }
+
+    Ok(Some(activated))
+}
+
+/// Sends the specified state to the service manager, e.g. `READY=1`.
+/// Does nothing if the service manager doesn't expect notifications.
+pub fn notify(state: &str) -> io::Result<()> {
+    let Some(notify_socket) = env::var_os("NOTIFY_SOCKET") else {
+        return Ok(());
+    };
+
+    let addr = match notify_socket.as_encoded_bytes().strip_prefix(b"@") {
+        Some(name) => SocketAddr::from_abstract_name(name)?,
+        None => SocketAddr::from_pathname(&notify_socket)?,
+    };
+
+    UnixDatagram::unbound()?.send_to_addr(state.as_bytes(), &addr)?;
+    Ok(())
+}
@@ -0,0 +1,99 @@
+// hbakd is an hbak server providing clients with push and pull access.
+// Copyright (C) 2024  Himbeer <himbeerserverde@gmail.com>
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use hbak_common::config::Upstream;
+
+use std::cmp;
+use std::time::Duration;
+
+use chrono::prelude::*;
+
+/// The delay before retrying a failed synchronization for the first time.
+/// It doubles with every further failure.
+const BASE_RETRY_DELAY: Duration = Duration::from_secs(300);
+/// The upper bound of the retry delay.
+const MAX_RETRY_DELAY: Duration = Duration::from_secs(3600);
+
+/// An `UpstreamSchedule` tracks when to synchronize with an [`Upstream`] next.
+/// Failed synchronizations are retried with exponential backoff
+/// unless the next scheduled time comes first.
+#[derive(Debug)]
+pub struct UpstreamSchedule {
+    upstream: Upstream,
+    next: NaiveDateTime,
+    failures: u32,
+}
+
+impl UpstreamSchedule {
+    /// Constructs a new `UpstreamSchedule` that is due at the next scheduled time.
+    pub fn new(upstream: Upstream, now: NaiveDateTime) -> Self {
+        Self {
+            next: next_scheduled(&upstream.at, now),
+            upstream,
+            failures: 0,
+        }
+    }
+
+    /// Returns the [`Upstream`] to synchronize with.
+    pub fn upstream(&self) -> &Upstream {
+        &self.upstream
+    }
+
+    /// Returns the time of the next synchronization attempt.
+    pub fn next(&self) -> NaiveDateTime {
+        self.next
+    }
+
+    /// Reports whether a synchronization attempt is due.
+    pub fn is_due(&self, now: NaiveDateTime) -> bool {
+        now >= self.next
+    }
+
+    /// Resets the backoff and schedules the next synchronization.
+    pub fn succeed(&mut self, now: NaiveDateTime) {
+        self.failures = 0;
+        self.next = next_scheduled(&self.upstream.at, now);
+    }
+
+    /// Schedules a retry after the backoff delay or at the next scheduled time,
+    /// whichever comes first.
+    pub fn fail(&mut self, now: NaiveDateTime) {
+        self.failures = self.failures.saturating_add(1);
+
+        let delay = BASE_RETRY_DELAY
+            .saturating_mul(2_u32.saturating_pow(self.failures - 1))
+            .min(MAX_RETRY_DELAY);
+        let retry = now + chrono::Duration::from_std(delay).unwrap_or(chrono::Duration::zero());
+
+        self.next = cmp::min(retry, next_scheduled(&self.upstream.at, now));
+    }
+}
+
+/// Returns the first of the specified times of day (UTC) after `now`.
+fn next_scheduled(at: &[NaiveTime], now: NaiveDateTime) -> NaiveDateTime {
+    at.iter()
+        .map(|time| {
+            let today = now.date().and_time(*time);
+
+            if today > now {
+                today
+            } else {
+                today + chrono::Duration::days(1)
+            }
+        })
+        .min()
+        .unwrap_or(NaiveDateTime::MAX)
+}